@@ -1,6 +1,7 @@
 
 varying vec4 clip_position;
+varying vec4 instance_color;
 
 void main() {
-    gl_FragColor = color * texture2D(emissive, clip_position.xy);
+    gl_FragColor = color * instance_color * texture2D(emissive, clip_position.xy);
 }
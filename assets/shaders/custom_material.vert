@@ -1,10 +1,14 @@
 attribute vec3 Vertex_Position;
+attribute mat4 Instance_WorldFromLocal;
+attribute vec4 Instance_Color;
 
-uniform mat4 clip_from_local;
+uniform mat4 clip_from_world;
 
 varying vec4 clip_position;
+varying vec4 instance_color;
 
 void main() {
-    clip_position = clip_from_local * vec4(Vertex_Position, 1.0);
+    instance_color = Instance_Color;
+    clip_position = clip_from_world * Instance_WorldFromLocal * vec4(Vertex_Position, 1.0);
     gl_Position = clip_position;
 }
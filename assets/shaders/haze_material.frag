@@ -1,6 +1,13 @@
 #include std::math
+#include std::oit
 
 varying vec2 uv_0;
+varying float view_depth;
+
+#ifdef SOFT_FADE
+uniform sampler2D prepass_depth;
+uniform mat4 view_from_clip;
+#endif // SOFT_FADE
 
 void main() {
     vec4 base_color = ub_haze_color;
@@ -15,6 +22,21 @@ void main() {
     float alpha = a * b * c * d;
     alpha *= alpha;
 
+    #ifdef SOFT_FADE
+    {
+        // Fade in over the distance to the opaque geometry behind this fragment.
+        vec2 window_uv = gl_FragCoord.xy / ub_view_resolution;
+        float depth = texture2D(prepass_depth, window_uv).r;
+        vec4 view = view_from_clip * vec4(window_uv * 2.0 - 1.0, depth * 2.0 - 1.0, 1.0);
+        // Reverse z, nothing drawn is at infinity where w is 0.
+        float scene_depth = view.w > 0.0 ? -view.z / view.w : 1e30;
+        alpha *= saturate((scene_depth - view_depth) / max(ub_soft_fade_distance, 1e-4));
+    }
+    #endif // SOFT_FADE
+
     gl_FragColor = vec4(base_color.rgb, alpha * base_color.a);
     gl_FragColor.rgb = from_linear(gl_FragColor.rgb);
+    #if defined(OIT_ACCUMULATE) || defined(OIT_REVEALAGE)
+    gl_FragColor = oit_output(gl_FragColor, view_depth);
+    #endif
 }
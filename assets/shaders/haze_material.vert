@@ -4,8 +4,11 @@ attribute vec2 Vertex_Uv;
 uniform mat4 ub_world_from_local;
 
 varying vec2 uv_0;
+varying float view_depth;
 
 void main() {
-    gl_Position = (ub_clip_from_world * ub_world_from_local) * vec4(Vertex_Position, 1.0);
+    vec4 ws_position = ub_world_from_local * vec4(Vertex_Position, 1.0);
+    gl_Position = ub_clip_from_world * ws_position;
+    view_depth = -(ub_view_from_world * ws_position).z;
     uv_0 = Vertex_Uv;
 }
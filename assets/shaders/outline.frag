@@ -0,0 +1,5 @@
+uniform vec4 outline_color;
+
+void main() {
+    gl_FragColor = outline_color;
+}
@@ -0,0 +1,12 @@
+attribute vec3 Vertex_Position;
+attribute vec3 Vertex_Normal;
+
+uniform mat4 world_from_local;
+uniform float outline_width;
+
+void main() {
+    // Extruded in world space so the width doesn't depend on the scale of the mesh.
+    vec3 normal = normalize((world_from_local * vec4(Vertex_Normal, 0.0)).xyz);
+    vec3 position = (world_from_local * vec4(Vertex_Position, 1.0)).xyz + normal * outline_width;
+    gl_Position = ub_clip_from_world * vec4(position, 1.0);
+}
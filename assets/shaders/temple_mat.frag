@@ -5,6 +5,7 @@
 #include std::agx
 #include std::shadow_sampling
 #include std::pbr_lighting
+#include std::oit
 
 varying vec4 clip_position;
 varying vec3 ws_position;
@@ -85,6 +86,9 @@ void main() {
         gl_FragColor.rgb = mix(gl_FragColor.rgb, post_tonemap_emissive, emissive_v);
     #endif // WRITE_REFLECTION
     gl_FragColor = clamp(gl_FragColor, vec4(0.0), vec4(1.0));
+    #if defined(OIT_ACCUMULATE) || defined(OIT_REVEALAGE)
+    gl_FragColor = oit_output(gl_FragColor, -(ub_view_from_world * vec4(ws_position, 1.0)).z);
+    #endif
 
     #endif // NOT RENDER_DEPTH_ONLY
 }
@@ -1,6 +1,7 @@
 use bevy::{
     asset::RenderAssetUsages,
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
+    platform::collections::HashMap,
     prelude::*,
     render::{RenderPlugin, settings::WgpuSettings},
     window::PresentMode,
@@ -10,7 +11,7 @@ use bgl2::{
     UniformSet,
     command_encoder::CommandEncoder,
     prepare_image::{GpuImages, TextureRef},
-    prepare_mesh::GpuMeshes,
+    prepare_mesh::{GpuMeshes, InstanceAttribute},
     render::{OpenGLRenderPlugins, RenderPhase, register_render_system},
 };
 use uniform_set_derive::UniformSet;
@@ -54,24 +55,24 @@ fn setup(
     mut meshes: ResMut<Assets<Mesh>>,
     mut enc: ResMut<CommandEncoder>,
 ) {
+    let material_id = commands
+        .spawn(CustomMaterial {
+            color: LinearRgba::WHITE,
+            emissive: enc.bevy_image(create_test_image([255; 4])),
+        })
+        .id();
+    // All 8000 cubes share one mesh and material, so they're drawn with a single instanced draw call.
+    let mesh = meshes.add(Cuboid::default());
     for x in -10..10 {
         for y in -10..10 {
             for z in -10..10 {
                 let p = vec3(x as f32, y as f32, z as f32);
                 let color = (p + 10.0) / 20.0;
-                let linear_rgb = LinearRgba::rgb(color.x, color.y, color.z);
-                let material_id = commands
-                    .spawn(CustomMaterial {
-                        color: linear_rgb.to_vec4(),
-                        emissive: enc.bevy_image(create_test_image(linear_rgb.to_u8_array())),
-                    })
-                    .id();
-                // Note: it would be more efficient to share materials/textures/meshes where possible, but this is being
-                // used as somewhat of a stress test.
                 commands.spawn((
-                    Mesh3d(meshes.add(Cuboid::default())),
-                    Transform::from_translation(p),
+                    Mesh3d(mesh.clone()),
+                    Transform::from_translation(p).with_scale(Vec3::splat(0.8)),
                     CustomMaterialHandle(material_id),
+                    InstanceColor(color.extend(1.0)),
                 ));
             }
         }
@@ -98,19 +99,28 @@ fn create_test_image(color: [u8; 4]) -> Image {
 
 #[derive(Clone, Component, UniformSet)]
 struct CustomMaterial {
-    color: Vec4,
+    color: LinearRgba,
     emissive: TextureRef,
 }
 
 #[derive(Component, Deref, DerefMut)]
 struct CustomMaterialHandle(Entity);
 
+#[derive(Component)]
+struct InstanceColor(Vec4);
+
+const INSTANCE_ATTRIBUTES: [InstanceAttribute; 2] = [
+    InstanceAttribute::new("Instance_WorldFromLocal", 16),
+    InstanceAttribute::new("Instance_Color", 4),
+];
+
 fn render_custom_mat(
     mesh_entities: Query<(
         &ViewVisibility,
         &GlobalTransform,
         &Mesh3d,
         &CustomMaterialHandle,
+        &InstanceColor,
     )>,
     camera: Single<(Entity, &Camera, &GlobalTransform, &Projection)>,
     materials: Query<&CustomMaterial>,
@@ -129,31 +139,23 @@ fn render_custom_mat(
         }
     };
 
-    let mut draws = Vec::new();
-
-    struct DrawData {
-        clip_from_local: Mat4,
-        material: CustomMaterial,
-        mesh: AssetId<Mesh>,
-    }
-
-    for (view_vis, transform, mesh, material_h) in mesh_entities.iter() {
+    // Instances are grouped by material and mesh, each group is one instanced draw.
+    let mut instances: HashMap<(Entity, AssetId<Mesh>), Vec<f32>> = HashMap::new();
+    for (view_vis, transform, mesh, material_h, color) in mesh_entities.iter() {
         if !view_vis.get() {
             continue;
         }
-
-        let Ok(material) = materials.get(**material_h) else {
-            continue;
-        };
-        let world_from_local = transform.to_matrix();
-        let clip_from_local = clip_from_world * world_from_local;
-
-        draws.push(DrawData {
-            clip_from_local,
-            material: material.clone(),
-            mesh: mesh.id(),
-        });
+        let data = instances.entry((**material_h, mesh.id())).or_default();
+        data.extend_from_slice(&transform.to_matrix().to_cols_array());
+        data.extend_from_slice(&color.0.to_array());
     }
+    let draws = instances
+        .into_iter()
+        .filter_map(|((material_h, mesh), data)| {
+            let material = materials.get(material_h).ok()?.clone();
+            Some((material, mesh, data))
+        })
+        .collect::<Vec<_>>();
 
     enc.record(move |ctx, world| {
         let shader_index = bgl2::shader_cached!(
@@ -167,15 +169,19 @@ fn render_custom_mat(
 
         world.resource_mut::<GpuMeshes>().reset_mesh_bind_cache();
         ctx.use_cached_program(shader_index);
+        ctx.load("clip_from_world", clip_from_world);
 
         ctx.map_uniform_set_locations::<CustomMaterial>();
 
-        for draw in &draws {
-            ctx.load("clip_from_local", draw.clip_from_local);
-            ctx.bind_uniforms_set(world.resource::<GpuImages>(), &draw.material);
-            world
-                .resource_mut::<GpuMeshes>()
-                .draw_mesh(ctx, draw.mesh, shader_index);
+        for (material, mesh, data) in &draws {
+            ctx.bind_uniforms_set(world.resource::<GpuImages>(), material);
+            world.resource_mut::<GpuMeshes>().draw_mesh_instanced(
+                ctx,
+                *mesh,
+                shader_index,
+                &INSTANCE_ATTRIBUTES,
+                data,
+            );
         }
     });
 }
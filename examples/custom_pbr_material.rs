@@ -8,14 +8,13 @@ use bevy::{
 };
 use bgl2::{
     UniformSet,
-    bevy_standard_lighting::{
-        DEFAULT_MAX_LIGHTS_DEF, OpenGLStandardLightingPlugin, StandardLightingUniforms,
-    },
-    bevy_standard_material::{OpenGLStandardMaterialPlugin, ViewUniforms},
+    bevy_standard_lighting::{OpenGLStandardLightingPlugin, StandardLightingUniforms},
+    bevy_standard_material::OpenGLStandardMaterialPlugin,
     command_encoder::CommandEncoder,
-    phase_shadow::{DirectionalLightShadow, ShadowBounds},
+    phase_shadow::{DEFAULT_MAX_CASCADES_DEF, DirectionalLightShadow, ShadowBounds},
     prepare_image::{GpuImages, TextureRef},
     prepare_mesh::GpuMeshes,
+    prepare_view::ViewUniforms,
     render::{OpenGLRenderPlugins, RenderPhase, register_render_system},
 };
 use uniform_set_derive::UniformSet;
@@ -188,7 +187,14 @@ fn render_custom_mat(
             ctx,
             "../assets/shaders/custom_pbr_material.vert",
             "../assets/shaders/custom_pbr_material.frag",
-            [DEFAULT_MAX_LIGHTS_DEF].iter().chain(
+            [
+                world
+                    .resource::<StandardLightingUniforms>()
+                    .max_lights_def(),
+                DEFAULT_MAX_CASCADES_DEF,
+            ]
+            .iter()
+            .chain(
                 world
                     .resource::<StandardLightingUniforms>()
                     .shader_defs(true, shadow.is_some(), &phase)
@@ -0,0 +1,129 @@
+//! Draws line list, line strip and point list meshes. The spiral is a dynamic mesh that is rebuilt every frame.
+
+use bevy::{
+    asset::RenderAssetUsages,
+    mesh::PrimitiveTopology,
+    prelude::*,
+    render::{RenderPlugin, settings::WgpuSettings},
+    window::PresentMode,
+    winit::WinitSettings,
+};
+use bgl2::{
+    bevy_standard_lighting::OpenGLStandardLightingPlugin,
+    bevy_standard_material::OpenGLStandardMaterialPlugin, prepare_mesh::DynamicMeshes,
+    render::OpenGLRenderPlugins,
+};
+
+fn main() {
+    App::new()
+        .insert_resource(WinitSettings::continuous())
+        .add_plugins((
+            DefaultPlugins
+                .set(RenderPlugin {
+                    render_creation: WgpuSettings {
+                        backends: None,
+                        ..default()
+                    }
+                    .into(),
+                    ..default()
+                })
+                .set(WindowPlugin {
+                    primary_window: Some(Window {
+                        present_mode: PresentMode::Immediate,
+                        ..default()
+                    }),
+                    ..default()
+                }),
+            OpenGLRenderPlugins,
+            OpenGLStandardLightingPlugin,
+            OpenGLStandardMaterialPlugin,
+        ))
+        .add_systems(Startup, setup)
+        .add_systems(Update, update_spiral)
+        .run();
+}
+
+#[derive(Component)]
+struct Spiral;
+
+fn line_mesh(topology: PrimitiveTopology, positions: Vec<Vec3>) -> Mesh {
+    Mesh::new(topology, RenderAssetUsages::default())
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+}
+
+fn spiral_positions(t: f32) -> Vec<Vec3> {
+    (0..512)
+        .map(|i| {
+            let f = i as f32 / 512.0;
+            let angle = f * 24.0 + t;
+            vec3(angle.cos() * f * 4.0, f * 3.0, angle.sin() * f * 4.0)
+        })
+        .collect()
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut dynamic_meshes: ResMut<DynamicMeshes>,
+) {
+    let mut grid = Vec::new();
+    for i in -10..=10 {
+        let i = i as f32 * 0.5;
+        grid.extend([vec3(i, 0.0, -5.0), vec3(i, 0.0, 5.0)]);
+        grid.extend([vec3(-5.0, 0.0, i), vec3(5.0, 0.0, i)]);
+    }
+    commands.spawn((
+        Mesh3d(meshes.add(line_mesh(PrimitiveTopology::LineList, grid))),
+        MeshMaterial3d(materials.add(StandardMaterial {
+            base_color: Color::srgb(0.4, 0.4, 0.4),
+            unlit: true,
+            ..default()
+        })),
+    ));
+
+    let spiral = meshes.add(line_mesh(
+        PrimitiveTopology::LineStrip,
+        spiral_positions(0.0),
+    ));
+    dynamic_meshes.ids.insert(spiral.id());
+    commands.spawn((
+        Mesh3d(spiral),
+        MeshMaterial3d(materials.add(StandardMaterial {
+            base_color: Color::srgb(1.0, 0.6, 0.1),
+            unlit: true,
+            ..default()
+        })),
+        Spiral,
+    ));
+
+    let points = (0..10)
+        .flat_map(|x| (0..10).map(move |z| vec3(x as f32 - 4.5, 3.5, z as f32 - 4.5)))
+        .collect();
+    commands.spawn((
+        Mesh3d(meshes.add(line_mesh(PrimitiveTopology::PointList, points))),
+        MeshMaterial3d(materials.add(StandardMaterial {
+            base_color: Color::srgb(0.2, 0.8, 1.0),
+            unlit: true,
+            ..default()
+        })),
+    ));
+
+    commands.spawn((
+        Camera3d::default(),
+        Transform::from_xyz(8.0, 6.0, 8.0).looking_at(vec3(0.0, 1.5, 0.0), Vec3::Y),
+    ));
+}
+
+fn update_spiral(
+    time: Res<Time>,
+    spiral: Single<&Mesh3d, With<Spiral>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    if let Some(mesh) = meshes.get_mut(&spiral.0) {
+        mesh.insert_attribute(
+            Mesh::ATTRIBUTE_POSITION,
+            spiral_positions(time.elapsed_secs()),
+        );
+    }
+}
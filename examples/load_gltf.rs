@@ -12,7 +12,7 @@ use bevy_mod_mipmap_generator::{MipmapGeneratorPlugin, generate_mipmaps};
 use bgl2::{
     bevy_standard_lighting::OpenGLStandardLightingPlugin,
     bevy_standard_material::OpenGLStandardMaterialPlugin, phase_shadow::ShadowBounds,
-    render::OpenGLRenderPlugins,
+    render::OpenGLRenderPlugins, skybox::SkyboxPlugin,
 };
 
 #[derive(FromArgs, Resource, Clone, Default)]
@@ -64,6 +64,7 @@ fn main() {
             OpenGLRenderPlugins,
             OpenGLStandardLightingPlugin,
             OpenGLStandardMaterialPlugin,
+            SkyboxPlugin,
         ));
     }
 
@@ -0,0 +1,126 @@
+use bevy::{
+    camera_controller::free_camera::{FreeCamera, FreeCameraPlugin},
+    diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
+    prelude::*,
+    render::{RenderPlugin, settings::WgpuSettings},
+    window::PresentMode,
+    winit::WinitSettings,
+};
+use bgl2::{
+    bevy_standard_lighting::OpenGLStandardLightingPlugin,
+    bevy_standard_material::OpenGLStandardMaterialPlugin,
+    bloom::{BloomPlugin, BloomSettings},
+    clustered_lighting::{CLUSTERED_MAX_LIGHTS, ClusteredLightingPlugin},
+    render::OpenGLRenderPlugins,
+};
+
+// A grid of small point lights over a floor, more than fit without ClusteredLightingPlugin. B toggles bloom.
+fn main() {
+    let mut app = App::new();
+    app.insert_resource(ClearColor(Color::BLACK))
+        .insert_resource(WinitSettings::continuous())
+        .insert_resource(GlobalAmbientLight {
+            brightness: 10.0,
+            ..default()
+        })
+        .add_plugins((
+            DefaultPlugins
+                .set(RenderPlugin {
+                    render_creation: WgpuSettings {
+                        backends: None,
+                        ..default()
+                    }
+                    .into(),
+                    ..default()
+                })
+                .set(WindowPlugin {
+                    primary_window: Some(Window {
+                        present_mode: PresentMode::Immediate,
+                        ..default()
+                    }),
+                    ..default()
+                }),
+            FreeCameraPlugin,
+            LogDiagnosticsPlugin::default(),
+            FrameTimeDiagnosticsPlugin::default(),
+        ));
+
+    app.add_plugins((
+        OpenGLRenderPlugins,
+        OpenGLStandardLightingPlugin,
+        OpenGLStandardMaterialPlugin,
+        ClusteredLightingPlugin,
+        BloomPlugin,
+    ));
+
+    app.init_resource::<BloomSettings>()
+        .add_systems(Startup, setup)
+        .add_systems(Update, (move_lights, toggle_bloom))
+        .run();
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands.spawn((
+        Camera3d::default(),
+        Transform::from_xyz(0.0, 12.0, 22.0).looking_at(Vec3::ZERO, Vec3::Y),
+        FreeCamera::default(),
+    ));
+
+    commands.spawn((
+        Mesh3d(meshes.add(Plane3d::default().mesh().size(60.0, 60.0))),
+        MeshMaterial3d(materials.add(StandardMaterial {
+            base_color: Color::srgb(0.8, 0.8, 0.8),
+            perceptual_roughness: 0.5,
+            ..default()
+        })),
+    ));
+
+    let bulb = meshes.add(Sphere::new(0.1));
+    let columns = 8;
+    for i in 0..CLUSTERED_MAX_LIGHTS {
+        let x = (i % columns) as f32 * 5.0 - 17.5;
+        let z = (i / columns) as f32 * 5.0 - 7.5;
+        let color = Color::hsl(i as f32 * 360.0 / CLUSTERED_MAX_LIGHTS as f32, 0.8, 0.6);
+        commands.spawn((
+            Transform::from_xyz(x, 1.0, z),
+            PointLight {
+                range: 4.0,
+                intensity: 20_000.0,
+                color,
+                shadows_enabled: false,
+                ..default()
+            },
+            // Bright enough after exposure to pass the bloom threshold.
+            Mesh3d(bulb.clone()),
+            MeshMaterial3d(materials.add(StandardMaterial {
+                base_color: Color::BLACK,
+                emissive: color.to_linear() * 5000.0,
+                ..default()
+            })),
+        ));
+    }
+}
+
+fn move_lights(time: Res<Time>, mut lights: Query<&mut Transform, With<PointLight>>) {
+    for (i, mut transform) in lights.iter_mut().enumerate() {
+        transform.translation.y = 1.0 + (time.elapsed_secs() * 1.5 + i as f32).sin() * 0.75;
+    }
+}
+
+fn toggle_bloom(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    bloom: Option<Res<BloomSettings>>,
+) {
+    if keys.just_pressed(KeyCode::KeyB) {
+        if bloom.is_some() {
+            commands.remove_resource::<BloomSettings>();
+        } else {
+            commands.init_resource::<BloomSettings>();
+        }
+    }
+}
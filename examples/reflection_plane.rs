@@ -13,9 +13,10 @@ use bevy_mod_mipmap_generator::{MipmapGeneratorPlugin, generate_mipmaps};
 use bgl2::{
     bevy_standard_lighting::OpenGLStandardLightingPlugin,
     bevy_standard_material::{OpenGLStandardMaterialPlugin, ReadReflection, SkipReflection},
-    phase_shadow::ShadowBounds,
+    phase_shadow::{ShadowBounds, ShadowFilter},
     plane_reflect::ReflectionPlane,
     render::OpenGLRenderPlugins,
+    taa::{TaaPlugin, TaaSettings},
 };
 
 fn main() {
@@ -50,10 +51,13 @@ fn main() {
         OpenGLRenderPlugins,
         OpenGLStandardLightingPlugin,
         OpenGLStandardMaterialPlugin,
+        TaaPlugin,
     ));
 
-    app.add_systems(Startup, setup)
+    app.init_resource::<TaaSettings>()
+        .add_systems(Startup, setup)
         .add_systems(Update, generate_mipmaps::<StandardMaterial>)
+        .add_systems(Update, (cycle_shadow_filter, toggle_taa))
         .run();
 }
 
@@ -111,3 +115,31 @@ fn setup(
         ShadowBounds::cube(10.0),
     ));
 }
+
+/// Press F to cycle through the shadow filters
+fn cycle_shadow_filter(keys: Res<ButtonInput<KeyCode>>, mut filter: ResMut<ShadowFilter>) {
+    if keys.just_pressed(KeyCode::KeyF) {
+        *filter = match *filter {
+            ShadowFilter::Hard => ShadowFilter::Pcf3x3,
+            ShadowFilter::Pcf3x3 => ShadowFilter::Pcf5x5,
+            ShadowFilter::Pcf5x5 => ShadowFilter::Hard,
+        };
+        info!("Shadow filter: {:?}", *filter);
+    }
+}
+
+/// Press T to toggle temporal anti-aliasing
+fn toggle_taa(
+    mut commands: Commands,
+    keys: Res<ButtonInput<KeyCode>>,
+    taa: Option<Res<TaaSettings>>,
+) {
+    if keys.just_pressed(KeyCode::KeyT) {
+        if taa.is_some() {
+            commands.remove_resource::<TaaSettings>();
+        } else {
+            commands.init_resource::<TaaSettings>();
+        }
+        info!("TAA: {}", taa.is_none());
+    }
+}
@@ -5,7 +5,9 @@ use bevy::{
     camera_controller::free_camera::{FreeCamera, FreeCameraPlugin},
     core_pipeline::{prepass::DepthPrepass, tonemapping::Tonemapping},
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
-    light::{TransmittedShadowReceiver, light_consts::lux::DIRECT_SUNLIGHT},
+    light::{
+        CascadeShadowConfigBuilder, TransmittedShadowReceiver, light_consts::lux::DIRECT_SUNLIGHT,
+    },
     prelude::*,
     render::{RenderPlugin, settings::WgpuSettings},
     scene::SceneInstanceReady,
@@ -14,10 +16,12 @@ use bevy::{
 };
 use bevy_mod_mipmap_generator::{MipmapGeneratorPlugin, generate_mipmaps};
 use bgl2::{
-    bevy_standard_lighting::OpenGLStandardLightingPlugin,
+    bevy_standard_lighting::{FogFalloff, FogSettings, OpenGLStandardLightingPlugin},
     bevy_standard_material::{OpenGLStandardMaterialPlugin, OpenGLStandardMaterialSettings},
-    phase_shadow::ShadowBounds,
-    render::OpenGLRenderPlugins,
+    debug_lines::{DebugLines, DebugLinesPlugin},
+    phase_shadow::DirectionalLightShadow,
+    render::{OpenGLRenderPlugins, WireframeSettings},
+    ssao::{SsaoPlugin, SsaoSettings},
 };
 use wgpu_types::Face;
 
@@ -30,6 +34,12 @@ pub struct Args {
     /// the windows xp driver often doesn't like point lights (for loop code gen too long, sometimes other things)
     #[argh(switch)]
     no_point: bool,
+    /// add distance fog
+    #[argh(switch)]
+    fog: bool,
+    /// add screen space ambient occlusion
+    #[argh(switch)]
+    ssao: bool,
 }
 
 fn main() {
@@ -41,6 +51,7 @@ fn main() {
     let mut app = App::new();
     app.insert_resource(OpenGLStandardMaterialSettings {
         no_point: args.no_point,
+        ..default()
     })
     .insert_resource(args.clone())
     .insert_resource(ClearColor(Color::srgb(1.75 * 0.5, 1.9 * 0.5, 1.99 * 0.5)))
@@ -78,7 +89,19 @@ fn main() {
             OpenGLRenderPlugins,
             OpenGLStandardLightingPlugin,
             OpenGLStandardMaterialPlugin,
+            DebugLinesPlugin,
+            SsaoPlugin,
         ));
+        app.add_systems(Update, draw_shadow_bounds);
+        if args.fog {
+            app.insert_resource(FogSettings {
+                color: Color::srgb(1.75 * 0.5, 1.9 * 0.5, 1.99 * 0.5),
+                falloff: FogFalloff::ExponentialSquared { density: 0.03 },
+            });
+        }
+        if args.ssao {
+            app.init_resource::<SsaoSettings>();
+        }
     }
 
     app.add_systems(Startup, setup)
@@ -87,7 +110,11 @@ fn main() {
         .run();
 }
 
-fn input(keyboard_input: Res<ButtonInput<KeyCode>>, mut window: Single<&mut Window>) {
+fn input(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut window: Single<&mut Window>,
+    wireframe: Option<ResMut<WireframeSettings>>,
+) {
     if keyboard_input.just_pressed(KeyCode::F11) || keyboard_input.just_pressed(KeyCode::KeyF) {
         if window.mode == WindowMode::Windowed {
             window.mode = WindowMode::BorderlessFullscreen(MonitorSelection::Current);
@@ -98,6 +125,35 @@ fn input(keyboard_input: Res<ButtonInput<KeyCode>>, mut window: Single<&mut Wind
     if keyboard_input.just_pressed(KeyCode::Escape) {
         window.mode = WindowMode::Windowed;
     }
+    if keyboard_input.just_pressed(KeyCode::KeyL)
+        && let Some(mut wireframe) = wireframe
+    {
+        wireframe.enabled = !wireframe.enabled;
+    }
+}
+
+/// B toggles drawing the volume covered by each shadow cascade.
+fn draw_shadow_bounds(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut enabled: Local<bool>,
+    shadow: Option<Res<DirectionalLightShadow>>,
+    mut lines: ResMut<DebugLines>,
+) {
+    if keyboard_input.just_pressed(KeyCode::KeyB) {
+        *enabled = !*enabled;
+    }
+    let Some(shadow) = shadow.filter(|_| *enabled) else {
+        return;
+    };
+    let colors = [
+        Color::srgb(1.0, 0.2, 0.2),
+        Color::srgb(0.2, 1.0, 0.2),
+        Color::srgb(0.2, 0.4, 1.0),
+        Color::srgb(1.0, 1.0, 0.2),
+    ];
+    for (cascade, color) in shadow.cascades.iter().zip(colors.iter().cycle()) {
+        lines.frustum(cascade.clip_from_view * cascade.view_from_world, *color);
+    }
 }
 
 fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
@@ -163,7 +219,13 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
             shadow_normal_bias: 0.6,
             ..default()
         },
-        ShadowBounds::cube(35.0),
+        CascadeShadowConfigBuilder {
+            num_cascades: 3,
+            first_cascade_far_bound: 6.0,
+            maximum_distance: 40.0,
+            ..default()
+        }
+        .build(),
     ));
 
     let point_spot_mult = 1000.0;
@@ -0,0 +1,128 @@
+use std::f32::consts::PI;
+
+use bevy::{
+    camera_controller::free_camera::{FreeCamera, FreeCameraPlugin},
+    diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
+    prelude::*,
+    render::{RenderPlugin, settings::WgpuSettings},
+    window::PresentMode,
+    winit::WinitSettings,
+};
+use bgl2::{
+    bevy_standard_lighting::OpenGLStandardLightingPlugin,
+    bevy_standard_material::OpenGLStandardMaterialPlugin, render::OpenGLRenderPlugins,
+};
+
+// Spot lights with different cone angles next to a point light, each should light a distinct circle on the floor.
+fn main() {
+    let mut app = App::new();
+    app.insert_resource(ClearColor(Color::BLACK))
+        .insert_resource(WinitSettings::continuous())
+        .insert_resource(GlobalAmbientLight {
+            brightness: 20.0,
+            ..default()
+        })
+        .add_plugins((
+            DefaultPlugins
+                .set(RenderPlugin {
+                    render_creation: WgpuSettings {
+                        backends: None,
+                        ..default()
+                    }
+                    .into(),
+                    ..default()
+                })
+                .set(WindowPlugin {
+                    primary_window: Some(Window {
+                        present_mode: PresentMode::Immediate,
+                        ..default()
+                    }),
+                    ..default()
+                }),
+            FreeCameraPlugin,
+            LogDiagnosticsPlugin::default(),
+            FrameTimeDiagnosticsPlugin::default(),
+        ));
+
+    app.add_plugins((
+        OpenGLRenderPlugins,
+        OpenGLStandardLightingPlugin,
+        OpenGLStandardMaterialPlugin,
+    ));
+
+    app.add_systems(Startup, setup)
+        .add_systems(Update, rotate_spot_lights)
+        .run();
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands.spawn((
+        Camera3d::default(),
+        Transform::from_xyz(0.0, 9.0, 12.0).looking_at(Vec3::ZERO, Vec3::Y),
+        FreeCamera::default(),
+    ));
+
+    commands.spawn((
+        Mesh3d(meshes.add(Plane3d::default().mesh().size(40.0, 40.0))),
+        MeshMaterial3d(materials.add(StandardMaterial {
+            base_color: Color::srgb(0.8, 0.8, 0.8),
+            perceptual_roughness: 0.6,
+            ..default()
+        })),
+    ));
+
+    let cube = meshes.add(Cuboid::from_length(0.5));
+    let cube_material = materials.add(StandardMaterial::default());
+
+    // Narrow to wide cones
+    for (i, (inner_angle, outer_angle, color)) in [
+        (0.0, PI * 0.05, Color::srgb(1.0, 0.3, 0.3)),
+        (PI * 0.05, PI * 0.1, Color::srgb(0.3, 1.0, 0.3)),
+        (PI * 0.1, PI * 0.2, Color::srgb(0.3, 0.3, 1.0)),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let x = i as f32 * 5.0 - 5.0;
+        commands.spawn((
+            Transform::from_xyz(x, 5.0, -2.0).looking_at(vec3(x, 0.0, -2.0), Vec3::Z),
+            SpotLight {
+                range: 20.0,
+                intensity: 200_000.0,
+                color,
+                shadows_enabled: false,
+                inner_angle,
+                outer_angle,
+                ..default()
+            },
+        ));
+        commands.spawn((
+            Mesh3d(cube.clone()),
+            MeshMaterial3d(cube_material.clone()),
+            Transform::from_xyz(x, 0.25, -2.0),
+        ));
+    }
+
+    // Point light for comparison, lights all around it.
+    commands.spawn((
+        Transform::from_xyz(0.0, 2.0, 4.0),
+        PointLight {
+            range: 8.0,
+            intensity: 50_000.0,
+            shadows_enabled: false,
+            ..default()
+        },
+    ));
+}
+
+fn rotate_spot_lights(time: Res<Time>, mut spot_lights: Query<&mut Transform, With<SpotLight>>) {
+    let tilt = (time.elapsed_secs() * 0.7).sin() * 0.4;
+    for mut transform in &mut spot_lights {
+        let target = transform.translation + vec3(tilt * 5.0, -5.0, 0.0);
+        transform.look_at(target, Vec3::Z);
+    }
+}
@@ -0,0 +1,146 @@
+//! Screen space sprites and text drawn over a 3D scene. The textured sprites share one image, so they're drawn
+//! together.
+
+use bevy::{
+    asset::RenderAssetUsages,
+    image::ImageSampler,
+    prelude::*,
+    render::{
+        RenderPlugin,
+        render_resource::{Extent3d, TextureDimension, TextureFormat},
+        settings::WgpuSettings,
+    },
+    window::PresentMode,
+    winit::WinitSettings,
+};
+use bgl2::{
+    bevy_standard_lighting::OpenGLStandardLightingPlugin,
+    bevy_standard_material::OpenGLStandardMaterialPlugin,
+    render::OpenGLRenderPlugins,
+    sprite::{Sprite, SpritePlugin},
+    text::{ScreenText, TextPlugin},
+};
+
+fn main() {
+    App::new()
+        .insert_resource(WinitSettings::continuous())
+        .add_plugins((
+            DefaultPlugins
+                .set(RenderPlugin {
+                    render_creation: WgpuSettings {
+                        backends: None,
+                        ..default()
+                    }
+                    .into(),
+                    ..default()
+                })
+                .set(WindowPlugin {
+                    primary_window: Some(Window {
+                        present_mode: PresentMode::Immediate,
+                        ..default()
+                    }),
+                    ..default()
+                }),
+            OpenGLRenderPlugins,
+            OpenGLStandardLightingPlugin,
+            OpenGLStandardMaterialPlugin,
+            SpritePlugin,
+            TextPlugin,
+        ))
+        .add_systems(Startup, setup)
+        .add_systems(Update, (spin, draw_text))
+        .run();
+}
+
+#[derive(Component)]
+struct Spin;
+
+fn checker_image() -> Image {
+    let size = 8;
+    let data = (0..size * size)
+        .flat_map(|i| {
+            if (i % size + i / size) % 2 == 0 {
+                [255, 255, 255, 255]
+            } else {
+                [40, 40, 40, 160]
+            }
+        })
+        .collect();
+    let mut image = Image::new(
+        Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+        RenderAssetUsages::default(),
+    );
+    image.sampler = ImageSampler::nearest();
+    image
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut images: ResMut<Assets<Image>>,
+) {
+    commands.spawn((
+        Mesh3d(meshes.add(Cuboid::default())),
+        MeshMaterial3d(materials.add(StandardMaterial {
+            base_color: Color::srgb(0.8, 0.3, 0.2),
+            ..default()
+        })),
+    ));
+    commands.spawn((
+        DirectionalLight::default(),
+        Transform::from_xyz(2.0, 4.0, 3.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+    commands.spawn((
+        Camera3d::default(),
+        Transform::from_xyz(2.0, 2.0, 3.0).looking_at(Vec3::ZERO, Vec3::Y),
+    ));
+
+    // A translucent panel behind the checkers, lower z draws first.
+    commands.spawn((
+        Sprite::from_color(Color::srgba(0.1, 0.2, 0.5, 0.6), vec2(560.0, 140.0)),
+        Transform::from_xyz(0.0, -220.0, 0.0),
+    ));
+    let checker = images.add(checker_image());
+    for i in 0..4 {
+        commands.spawn((
+            Sprite {
+                custom_size: Some(vec2(96.0, 96.0)),
+                flip_x: i % 2 == 1,
+                ..Sprite::from_image(checker.clone())
+            },
+            Transform::from_xyz(i as f32 * 128.0 - 192.0, -220.0, 1.0),
+            Spin,
+        ));
+    }
+}
+
+fn spin(time: Res<Time>, mut sprites: Query<&mut Transform, With<Spin>>) {
+    for mut transform in &mut sprites {
+        transform.rotate_z(time.delta_secs());
+    }
+}
+
+fn draw_text(time: Res<Time>, window: Single<&Window>, mut text: ResMut<ScreenText>) {
+    let top_left = vec2(-window.width(), window.height()) * 0.5 + vec2(16.0, -16.0);
+    text.draw_text(
+        format!("{:.1} ms", time.delta_secs() * 1000.0),
+        top_left,
+        24.0,
+        Color::WHITE,
+    );
+    text.draw_text_justified(
+        "Sprites\nand text",
+        vec2(0.0, -120.0),
+        32.0,
+        Color::srgb(1.0, 0.8, 0.3),
+        Justify::Center,
+    );
+}
@@ -0,0 +1,186 @@
+//! Outlines meshes using the stencil buffer. Each outlined mesh is first drawn into the stencil buffer only, then again
+//! extruded along its normals where the stencil wasn't written, which leaves just the outline.
+
+use bevy::{
+    prelude::*,
+    render::{RenderPlugin, settings::WgpuSettings},
+    window::PresentMode,
+    winit::WinitSettings,
+};
+use bgl2::{
+    BevyGlContextConfig, RenderState, StencilState,
+    bevy_standard_lighting::OpenGLStandardLightingPlugin,
+    bevy_standard_material::OpenGLStandardMaterialPlugin,
+    command_encoder::CommandEncoder,
+    prepare_image::GpuImages,
+    prepare_mesh::GpuMeshes,
+    prepare_view::ViewUniforms,
+    render::{OpenGLRenderPlugins, RenderPhase, register_render_system},
+    shader_cached,
+};
+
+fn main() {
+    let mut app = App::new();
+    app.insert_resource(WinitSettings::continuous())
+        .insert_resource(BevyGlContextConfig {
+            stencil_bits: 8,
+            ..default()
+        })
+        .add_plugins((
+            DefaultPlugins
+                .set(RenderPlugin {
+                    render_creation: WgpuSettings {
+                        backends: None,
+                        ..default()
+                    }
+                    .into(),
+                    ..default()
+                })
+                .set(WindowPlugin {
+                    primary_window: Some(Window {
+                        present_mode: PresentMode::Immediate,
+                        ..default()
+                    }),
+                    ..default()
+                }),
+            OpenGLRenderPlugins,
+            OpenGLStandardLightingPlugin,
+            OpenGLStandardMaterialPlugin,
+        ));
+
+    register_render_system::<Outline, _>(app.world_mut(), render_outlines);
+
+    app.add_systems(Startup, setup)
+        .add_systems(Update, rotate)
+        .run();
+}
+
+#[derive(Component, Clone, Copy)]
+struct Outline {
+    color: Color,
+    /// World space
+    width: f32,
+}
+
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands.spawn((
+        Mesh3d(meshes.add(Plane3d::default().mesh().size(10.0, 10.0))),
+        MeshMaterial3d(materials.add(Color::srgb(0.3, 0.5, 0.3))),
+    ));
+    commands.spawn((
+        Mesh3d(meshes.add(Sphere::new(0.75).mesh().uv(64, 32))),
+        MeshMaterial3d(materials.add(Color::srgb(0.8, 0.7, 0.6))),
+        Transform::from_xyz(-1.2, 0.75, 0.0),
+        Outline {
+            color: Color::srgb(1.0, 0.6, 0.1),
+            width: 0.04,
+        },
+    ));
+    commands.spawn((
+        Mesh3d(meshes.add(Torus::new(0.4, 0.8))),
+        MeshMaterial3d(materials.add(Color::srgb(0.3, 0.4, 0.8))),
+        Transform::from_xyz(1.2, 1.0, 0.0),
+        Outline {
+            color: Color::srgb(0.2, 0.9, 1.0),
+            width: 0.03,
+        },
+    ));
+    commands.spawn((
+        Transform::default().looking_at(vec3(0.5, -1.0, 0.3), Vec3::Y),
+        DirectionalLight {
+            shadows_enabled: true,
+            ..default()
+        },
+    ));
+    commands.spawn((
+        Camera3d::default(),
+        Transform::from_xyz(0.0, 3.0, 6.0).looking_at(vec3(0.0, 0.75, 0.0), Vec3::Y),
+    ));
+}
+
+fn rotate(time: Res<Time>, mut outlined: Query<&mut Transform, With<Outline>>) {
+    for mut transform in &mut outlined {
+        transform.rotate_x(time.delta_secs() * 0.5);
+    }
+}
+
+fn render_outlines(
+    phase: Res<RenderPhase>,
+    outlined: Query<(&ViewVisibility, &GlobalTransform, &Mesh3d, &Outline)>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    if *phase != RenderPhase::Opaque {
+        return;
+    }
+    let draws = outlined
+        .iter()
+        .filter(|(view_vis, ..)| view_vis.get())
+        .map(|(_, transform, mesh, outline)| (transform.to_matrix(), mesh.id(), *outline))
+        .collect::<Vec<_>>();
+    if draws.is_empty() {
+        return;
+    }
+
+    enc.record(move |ctx, world| {
+        if !ctx.has_stencil {
+            warn_once!("The window has no stencil buffer, outlines are not drawn");
+            return;
+        }
+        let shader_index = shader_cached!(
+            ctx,
+            "../assets/shaders/outline.vert",
+            "../assets/shaders/outline.frag",
+            &[],
+            &[ViewUniforms::bindings()]
+        )
+        .unwrap();
+        world.resource_mut::<GpuMeshes>().reset_mesh_bind_cache();
+        ctx.use_cached_program(shader_index);
+        ctx.map_uniform_set_locations::<ViewUniforms>();
+        ctx.bind_uniforms_set(
+            world.resource::<GpuImages>(),
+            world.resource::<ViewUniforms>(),
+        );
+        let previous_state = ctx.render_state;
+
+        // Mask the whole silhouette, without depth testing so it doesn't depend on the depth of the standard material
+        // draw of the same mesh.
+        ctx.apply_render_state(RenderState {
+            depth_test: false,
+            depth_write: false,
+            color_write: [false; 4],
+            ..previous_state
+        });
+        ctx.set_stencil_state(Some(StencilState::write(1)));
+        ctx.load("outline_width", 0.0f32);
+        for (world_from_local, mesh, _) in &draws {
+            ctx.load("world_from_local", *world_from_local);
+            world
+                .resource_mut::<GpuMeshes>()
+                .draw_mesh(ctx, *mesh, shader_index);
+        }
+
+        // Only the extruded part outside the mask is drawn. Depth tested so outlines go behind other geometry.
+        ctx.apply_render_state(RenderState {
+            depth_write: false,
+            color_write: [true, true, true, false],
+            ..previous_state
+        });
+        ctx.set_stencil_state(Some(StencilState::not_equal(1)));
+        for (world_from_local, mesh, outline) in &draws {
+            ctx.load("world_from_local", *world_from_local);
+            ctx.load("outline_width", outline.width);
+            ctx.load("outline_color", outline.color.to_srgba().to_vec4());
+            world
+                .resource_mut::<GpuMeshes>()
+                .draw_mesh(ctx, *mesh, shader_index);
+        }
+
+        ctx.set_stencil_state(None);
+        ctx.apply_render_state(previous_state);
+    });
+}
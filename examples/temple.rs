@@ -3,6 +3,7 @@ use std::f32::consts::PI;
 use bevy::{
     camera::primitives::Aabb,
     camera_controller::free_camera::{FreeCamera, FreeCameraPlugin},
+    core_pipeline::prepass::DepthPrepass,
     diagnostic::{FrameTimeDiagnosticsPlugin, LogDiagnosticsPlugin},
     prelude::*,
     render::{RenderPlugin, settings::WgpuSettings},
@@ -12,32 +13,29 @@ use bevy::{
 };
 use bevy_mod_mipmap_generator::{MipmapGeneratorPlugin, generate_mipmaps};
 use bgl2::{
-    UniformSet, UniformValue,
+    BlendState, Tex, UniformSet, UniformValue,
     bevy_standard_lighting::{OpenGLStandardLightingPlugin, StandardLightingUniforms},
     bevy_standard_material::{
         DrawsSortedByMaterial, ReadReflection, SkipReflection, StandardMaterialUniforms,
-        ViewUniforms, init_std_shader_includes, sort_std_mat_by_material,
-        standard_material_prepare_view,
+        init_std_shader_includes, sort_std_mat_by_material,
     },
     command_encoder::CommandEncoder,
     flip_cull_mode,
-    phase_shadow::DirectionalLightShadow,
-    phase_transparent::DeferredAlphaBlendDraws,
+    phase_shadow::{DEFAULT_MAX_CASCADES_DEF, DirectionalLightShadow},
+    phase_transparent::{DeferredAlphaBlendDraws, OitPass, TransparencyMode},
     plane_reflect::{ReflectionPlane, ReflectionUniforms},
     prepare_image::GpuImages,
     prepare_joints::JointData,
     prepare_mesh::GpuMeshes,
+    prepare_view::{ViewUniforms, ViewUniformsPlugin},
+    prepass::{PrepassTextures, PrepassTexturesPlugin, prepass_depth_texture},
     render::{
-        OpenGLRenderPlugins, RenderPhase, RenderSet, register_prepare_system,
-        set_blend_func_from_alpha_mode, transparent_draw_from_alpha_mode,
+        DrawCollector, OpenGLRenderPlugins, RenderPhase, RenderSet, set_blend_func_from_alpha_mode,
+        transparent_draw_from_alpha_mode,
     },
     shader_cached,
 };
-use bgl2::{
-    bevy_standard_lighting::{DEFAULT_MAX_JOINTS_DEF, DEFAULT_MAX_LIGHTS_DEF},
-    render::register_render_system,
-};
-use itertools::Either;
+use bgl2::{bevy_standard_lighting::DEFAULT_MAX_JOINTS_DEF, render::register_material};
 use uniform_set_derive::UniformSet;
 
 fn main() {
@@ -69,16 +67,20 @@ fn main() {
         ));
 
     app.init_resource::<DrawsSortedByMaterial>()
-        .add_plugins((OpenGLRenderPlugins, OpenGLStandardLightingPlugin))
+        .add_plugins((
+            OpenGLRenderPlugins,
+            OpenGLStandardLightingPlugin,
+            ViewUniformsPlugin,
+            PrepassTexturesPlugin,
+        ))
         .add_systems(Update, sort_std_mat_by_material.in_set(RenderSet::Prepare))
         .add_systems(
             Startup,
             init_std_shader_includes.in_set(RenderSet::Pipeline),
         );
 
-    register_prepare_system(app.world_mut(), standard_material_prepare_view);
-    register_render_system::<StandardMaterial, _>(app.world_mut(), standard_material_render);
-    register_render_system::<HazeMaterial, _>(app.world_mut(), render_haze_mat);
+    register_material::<StandardMaterial, _>(&mut app, standard_material_render);
+    register_material::<HazeMaterial, _>(&mut app, render_haze_mat);
 
     app.add_systems(Startup, setup)
         .add_systems(Update, generate_mipmaps::<StandardMaterial>)
@@ -95,6 +97,8 @@ fn setup(
     // Camera
     commands.spawn((
         Camera3d::default(),
+        // The haze fades out near the walls using the prepass depth.
+        DepthPrepass,
         Transform::from_xyz(-45.0, 4.0, 0.0).looking_at(Vec3::new(0.0, 18.0, 0.0), Vec3::Y),
         FreeCamera {
             walk_speed: 5.0,
@@ -166,7 +170,11 @@ fn setup(
         .observe(remove_std_mat)
         .observe(
             |ready: On<SceneInstanceReady>, mut commands: Commands, children: Query<&Children>| {
-                let m = HazeMaterial::spawn(&mut commands, vec4(1.0, 0.7, 0.5, 0.9));
+                let m = HazeMaterial::spawn_with_blend(
+                    &mut commands,
+                    vec4(1.0, 0.7, 0.5, 0.9),
+                    BlendState::ADDITIVE_ALPHA,
+                );
                 decend_haze(ready.entity, &mut commands, children, m);
             },
         );
@@ -331,7 +339,7 @@ pub fn standard_material_render(
         Has<ReadReflection>,
         Option<&JointData>,
     )>,
-    view_uniforms: Single<&ViewUniforms>,
+    view_uniforms: Res<ViewUniforms>,
     materials: Res<Assets<StandardMaterial>>,
     phase: Res<RenderPhase>,
     light_map: Res<LightMap>,
@@ -340,31 +348,24 @@ pub fn standard_material_render(
     sorted: Res<DrawsSortedByMaterial>,
     mut enc: ResMut<CommandEncoder>,
     shadow: Option<Res<DirectionalLightShadow>>,
+    oit_pass: Res<OitPass>,
 ) {
     let view_uniforms = view_uniforms.clone();
 
     let phase = *phase;
+    let oit_pass = *oit_pass;
 
-    let iter = if phase.transparent() {
-        Either::Right(mesh_entities.iter_many(transparent_draws.take()))
-    } else {
-        Either::Left(mesh_entities.iter_many(&**sorted))
-    };
-
-    struct Draw {
-        world_from_local: Mat4,
+    struct DrawData {
         joint_data: Option<JointData>,
-        material_h: AssetId<StandardMaterial>,
-        material_idx: u32,
         read_reflect: bool,
-        mesh: Handle<Mesh>,
     }
 
-    let mut draws = Vec::new();
-    let mut render_materials: Vec<StandardMaterialUniforms> = Vec::new();
-
-    let mut last_material = None;
-    let mut current_material_idx = 0;
+    let mut collector = DrawCollector::<StandardMaterial, _, StandardMaterialUniforms, _>::new(
+        phase,
+        &view_uniforms,
+        &mut transparent_draws,
+    );
+    let entities = collector.entities(sorted.iter().copied());
     for (
         entity,
         view_vis,
@@ -375,11 +376,9 @@ pub fn standard_material_render(
         skip_reflect,
         read_reflect,
         joint_data,
-    ) in iter
+    ) in mesh_entities.iter_many(entities)
     {
-        if (phase.can_use_camera_frustum_cull() && !view_vis.get())
-            || (skip_reflect && phase.reflection())
-        {
+        if skip_reflect && phase.reflection() {
             continue;
         }
 
@@ -387,37 +386,28 @@ pub fn standard_material_render(
             continue;
         };
 
-        let world_from_local = transform.to_matrix();
-
-        // If in opaque phase we must defer any alpha blend draws so they can be sorted and run in order.
-        if !transparent_draws.maybe_defer::<StandardMaterial>(
-            transparent_draw_from_alpha_mode(&material.alpha_mode),
-            phase,
+        let Some(world_from_local) = collector.cull_or_defer(
             entity,
+            view_vis,
             transform,
             aabb,
-            &view_uniforms.view_from_world,
-            &world_from_local,
-        ) {
+            transparent_draw_from_alpha_mode(&material.alpha_mode),
+        ) else {
             continue;
-        }
-
-        if last_material != Some(material_h) {
-            current_material_idx = render_materials.len() as u32;
-            last_material = Some(material_h);
-            render_materials.push(material.into());
-        }
+        };
 
-        draws.push(Draw {
-            // TODO don't copy full material
-            material_idx: current_material_idx,
+        collector.push(
+            material_h.id(),
+            || material.into(),
+            mesh.id(),
             world_from_local,
-            joint_data: joint_data.cloned(),
-            material_h: material_h.id(),
-            read_reflect,
-            mesh: mesh.0.clone(),
-        });
+            DrawData {
+                joint_data: joint_data.cloned(),
+                read_reflect,
+            },
+        );
     }
+    let (render_materials, draws) = collector.finish();
 
     let reflect_uniforms = reflect_uniforms.as_deref().cloned();
 
@@ -429,14 +419,15 @@ pub fn standard_material_render(
             ctx,
             "../assets/shaders/temple_mat.vert",
             "../assets/shaders/temple_mat.frag",
-            [DEFAULT_MAX_LIGHTS_DEF]
+            [lighting_uniforms.max_lights_def(), DEFAULT_MAX_CASCADES_DEF,]
                 .iter()
                 .chain(
                     lighting_uniforms
                         .shader_defs(true, shadow.is_some(), &phase)
                         .iter()
                 )
-                .chain(phase.shader_defs().iter()),
+                .chain(phase.shader_defs().iter())
+                .chain(oit_pass.shader_defs().iter()),
             &[
                 ViewUniforms::bindings(),
                 StandardMaterialUniforms::bindings(),
@@ -474,30 +465,33 @@ pub fn standard_material_render(
         let mut last_material = None;
         for draw in &draws {
             let material = &render_materials[draw.material_idx as usize];
-            set_blend_func_from_alpha_mode(&ctx.gl, &material.alpha_mode);
+            match oit_pass.blend_state() {
+                Some(blend_state) => blend_state.apply(&ctx.gl),
+                None => set_blend_func_from_alpha_mode(&ctx.gl, &material.alpha_mode),
+            }
 
             ctx.load("world_from_local", draw.world_from_local);
 
-            if let Some(joint_data) = &draw.joint_data {
+            if let Some(joint_data) = &draw.data.joint_data {
                 ctx.load("joint_data", joint_data.as_slice());
             }
-            ctx.load("has_joint_data", draw.joint_data.is_some());
+            ctx.load("has_joint_data", draw.data.joint_data.is_some());
 
             reflect_bool_location.clone().map(|loc| {
-                (draw.read_reflect && phase.read_reflect() && reflect_uniforms.is_some())
+                (draw.data.read_reflect && phase.read_reflect() && reflect_uniforms.is_some())
                     .load(&ctx.gl, &loc)
             });
 
             // Only re-bind if the material has changed.
-            if last_material != Some(draw.material_h) {
+            if last_material != Some(draw.material_idx) {
                 ctx.set_cull_mode(flip_cull_mode(material.cull_mode, phase.reflection()));
                 ctx.bind_uniforms_set(world.resource::<GpuImages>(), material);
             }
 
             world
                 .resource_mut::<GpuMeshes>()
-                .draw_mesh(ctx, draw.mesh.id(), shader_index);
-            last_material = Some(draw.material_h);
+                .draw_mesh(ctx, draw.mesh, shader_index);
+            last_material = Some(draw.material_idx);
         }
     });
 }
@@ -510,11 +504,26 @@ pub fn standard_material_render(
 #[uniform_set(prefix = "ub_")]
 struct HazeMaterial {
     haze_color: Vec4,
+    /// View space distance over which the haze fades in in front of opaque geometry, so it doesn't show a hard edge
+    /// where it intersects. Only with the prepass depth texture.
+    soft_fade_distance: f32,
+    #[exclude]
+    blend: BlendState,
 }
 
 impl HazeMaterial {
     pub fn spawn(commands: &mut Commands, color: Vec4) -> Entity {
-        commands.spawn(HazeMaterial { haze_color: color }).id()
+        Self::spawn_with_blend(commands, color, BlendState::ALPHA)
+    }
+
+    pub fn spawn_with_blend(commands: &mut Commands, color: Vec4, blend: BlendState) -> Entity {
+        commands
+            .spawn(HazeMaterial {
+                haze_color: color,
+                soft_fade_distance: 1.5,
+                blend,
+            })
+            .id()
     }
 }
 
@@ -543,55 +552,42 @@ fn render_haze_mat(
     )>,
     materials: Query<&HazeMaterial>,
     phase: If<Res<RenderPhase>>,
+    oit_pass: Res<OitPass>,
     mut enc: ResMut<CommandEncoder>,
     shadow: Option<Res<DirectionalLightShadow>>,
     mut transparent_draws: ResMut<DeferredAlphaBlendDraws>,
-    view_uniforms: Single<&ViewUniforms>,
+    view_uniforms: Res<ViewUniforms>,
 ) {
     let phase = **phase;
+    let oit_pass = *oit_pass;
     if !(phase.defer_transparent() || phase.transparent()) {
         return;
     }
 
-    let mut draws = Vec::new();
-
-    struct DrawData {
-        world_from_local: Mat4,
-        material: HazeMaterial,
-        mesh: AssetId<Mesh>,
-    }
-
-    let iter = if phase.transparent() {
-        Either::Right(mesh_entities.iter_many(transparent_draws.take()))
-    } else {
-        Either::Left(mesh_entities.iter())
-    };
-
-    for (entity, view_vis, transform, aabb, mesh, material_h) in iter {
-        if !view_vis.get() {
-            continue;
-        }
+    let mut collector = DrawCollector::<HazeMaterial, Entity, HazeMaterial>::new(
+        phase,
+        &view_uniforms,
+        &mut transparent_draws,
+    );
+    let entities = collector.entities(mesh_entities.iter().map(|item| item.0));
+    for (entity, view_vis, transform, aabb, mesh, material_h) in mesh_entities.iter_many(entities) {
         let Ok(material) = materials.get(**material_h) else {
             continue;
         };
-
-        let world_from_local = transform.to_matrix();
-        if phase.defer_transparent() {
-            let ws_radius = transform.radius_vec3a(aabb.half_extents);
-            let ws_center = world_from_local.transform_point3a(aabb.center);
-            transparent_draws.defer::<HazeMaterial>(
-                // Use closest point on bounding sphere
-                view_uniforms.view_from_world.project_point3a(ws_center).z + ws_radius,
-                entity,
-            );
-        } else if phase.transparent() {
-            draws.push(DrawData {
-                world_from_local,
-                material: material.clone(),
-                mesh: mesh.id(),
-            });
-        }
+        let Some(world_from_local) =
+            collector.cull_or_defer(entity, view_vis, transform, aabb, true)
+        else {
+            continue;
+        };
+        collector.push(
+            **material_h,
+            || material.clone(),
+            mesh.id(),
+            world_from_local,
+            (),
+        );
     }
+    let (haze_materials, draws) = collector.finish();
 
     if !phase.transparent() {
         return;
@@ -599,19 +595,32 @@ fn render_haze_mat(
     let shadow = shadow.as_deref().cloned();
 
     enc.record(move |ctx, world| {
+        let soft_fade = prepass_depth_texture(world).is_some();
         let shader_index = bgl2::shader_cached!(
             ctx,
             "../assets/shaders/haze_material.vert",
             "../assets/shaders/haze_material.frag",
-            [DEFAULT_MAX_LIGHTS_DEF, DEFAULT_MAX_JOINTS_DEF]
-                .iter()
-                .chain(
-                    world
-                        .resource::<StandardLightingUniforms>()
-                        .shader_defs(true, shadow.is_some(), &phase)
-                        .iter()
-                )
-                .chain(phase.shader_defs().iter()),
+            [
+                world
+                    .resource::<StandardLightingUniforms>()
+                    .max_lights_def(),
+                DEFAULT_MAX_JOINTS_DEF,
+                DEFAULT_MAX_CASCADES_DEF,
+                if soft_fade {
+                    ("SOFT_FADE", "")
+                } else {
+                    ("", "")
+                }
+            ]
+            .iter()
+            .chain(
+                world
+                    .resource::<StandardLightingUniforms>()
+                    .shader_defs(true, shadow.is_some(), &phase)
+                    .iter()
+            )
+            .chain(phase.shader_defs().iter())
+            .chain(oit_pass.shader_defs().iter()),
             &[
                 ViewUniforms::bindings(),
                 StandardLightingUniforms::bindings(),
@@ -637,11 +646,27 @@ fn render_haze_mat(
                 world.resource::<StandardLightingUniforms>(),
             );
         }
+        if soft_fade {
+            let view = world.resource::<ViewUniforms>();
+            let view_from_clip = view.view_from_world * view.clip_from_world.inverse();
+            ctx.load("view_from_clip", view_from_clip);
+            ctx.load_tex(
+                world.resource::<GpuImages>(),
+                "prepass_depth",
+                &Tex::Ref(world.resource::<PrepassTextures>().depth.clone()),
+            );
+        }
         ctx.set_cull_mode(None);
 
+        let mut last_material = None;
         for draw in &draws {
+            if last_material != Some(draw.material_idx) {
+                let material = &haze_materials[draw.material_idx as usize];
+                ctx.set_blend_state(oit_pass.blend_state().unwrap_or(material.blend));
+                ctx.bind_uniforms_set(world.resource::<GpuImages>(), material);
+                last_material = Some(draw.material_idx);
+            }
             ctx.load("ub_world_from_local", draw.world_from_local);
-            ctx.bind_uniforms_set(world.resource::<GpuImages>(), &draw.material);
             world
                 .resource_mut::<GpuMeshes>()
                 .draw_mesh(ctx, draw.mesh, shader_index);
@@ -649,7 +674,11 @@ fn render_haze_mat(
     });
 }
 
-fn input(keyboard_input: Res<ButtonInput<KeyCode>>, mut window: Single<&mut Window>) {
+fn input(
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut window: Single<&mut Window>,
+    mut transparency: ResMut<TransparencyMode>,
+) {
     if keyboard_input.just_pressed(KeyCode::F11) || keyboard_input.just_pressed(KeyCode::KeyF) {
         if window.mode == WindowMode::Windowed {
             window.mode = WindowMode::BorderlessFullscreen(MonitorSelection::Current);
@@ -660,6 +689,21 @@ fn input(keyboard_input: Res<ButtonInput<KeyCode>>, mut window: Single<&mut Wind
     if keyboard_input.just_pressed(KeyCode::Escape) {
         window.mode = WindowMode::Windowed;
     }
+    // The haze volumes overlap, toggle order independent transparency
+    if keyboard_input.just_pressed(KeyCode::KeyO) {
+        *transparency = match *transparency {
+            TransparencyMode::Sorted => TransparencyMode::WeightedBlended,
+            TransparencyMode::WeightedBlended => TransparencyMode::Sorted,
+        };
+        info!("{:?}", *transparency);
+    }
+    if keyboard_input.just_pressed(KeyCode::KeyV) {
+        window.present_mode = match window.present_mode {
+            PresentMode::Immediate => PresentMode::AutoVsync,
+            _ => PresentMode::Immediate,
+        };
+        info!("{:?}", window.present_mode);
+    }
 }
 
 fn position_camera(
@@ -60,6 +60,7 @@ fn init(world: &mut World, params: &mut SystemState<Query<(Entity, &mut Window)>
             present_mode: bevy_window.present_mode,
             width: bevy_window.physical_size().x as u32,
             height: bevy_window.physical_size().y as u32,
+            config: Default::default(),
         };
 
         let sender = CommandEncoderSender::new(window_init_data);
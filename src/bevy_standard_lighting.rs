@@ -1,15 +1,23 @@
-use std::{f32::consts::PI, ops::Deref};
+use std::{f32::consts::PI, sync::LazyLock};
 
 use bevy::prelude::*;
 use uniform_set_derive::UniformSet;
 
 use crate::{
+    capabilities::GlCapabilities,
     clone2,
+    clustered_lighting::{
+        CLUSTERED_MAX_LIGHTS, CLUSTERED_MAX_LIGHTS_DEF, ClusteredLightingSettings,
+    },
     command_encoder::CommandEncoder,
     mesh_util::octahedral_encode,
-    phase_shadow::DirectionalLightShadow,
+    phase_point_shadow::{PointLightShadow, point_shadow_available},
+    phase_shadow::{
+        DEFAULT_MAX_CASCADES, DirectionalLightShadow, ShadowFilter, shadow_depth_texture,
+    },
     prepare_image::TextureRef,
     render::{RenderPhase, RenderSet},
+    tonemapping_lut::{TonyMcMapfaceLut, prepare_tony_mc_mapface_lut},
 };
 
 // It seems like some drivers are limited by code length.
@@ -17,6 +25,11 @@ use crate::{
 // Also fragment shader uniform capacity can be very limited on some drivers.
 pub const DEFAULT_MAX_POINT_LIGHTS: usize = 8;
 pub const DEFAULT_MAX_LIGHTS_DEF: (&str, &str) = ("MAX_POINT_LIGHTS", "8");
+/// vec4 fragment uniforms per point light, see StandardLightingUniforms::point_light_position_range.
+pub const POINT_LIGHT_UNIFORM_VECTORS: usize = 3;
+/// Roughly how many fragment uniform vectors the standard material uses besides the point light arrays, with
+/// DEFAULT_MAX_CASCADES. Subtracted from GlCapabilities::max_fragment_uniform_vectors in max_point_lights().
+pub const STANDARD_FRAGMENT_UNIFORM_VECTORS: usize = 64;
 
 // vertex shader uniform capacity can be limited on some drivers (though not as much as in the frag shader.)
 pub const DEFAULT_MAX_JOINTS: usize = 32;
@@ -29,6 +42,8 @@ pub struct StandardLightingUniforms {
     pub point_light_position_range: Vec<Vec4>,
     #[array_max("MAX_POINT_LIGHTS")]
     pub point_light_color_radius: Vec<Vec4>,
+    /// Octahedral encoded direction, offset, scale. See calc_spot_dir_offset_scale(). POINT_LIGHT_DIR_OFFSET_SCALE for
+    /// point lights.
     #[array_max("MAX_POINT_LIGHTS")]
     pub spot_light_dir_offset_scale: Vec<Vec4>,
     pub directional_light_dir: Vec3,
@@ -37,10 +52,123 @@ pub struct StandardLightingUniforms {
     pub specular_map: Option<Handle<Image>>,
     #[base_type("samplerCube")]
     pub diffuse_map: Option<Handle<Image>>,
+    /// All shadow cascades, see DirectionalLightShadow::tile_offset()
+    #[base_type("shadow_sampler2D")]
     pub shadow_texture: TextureRef,
-    pub env_intensity: f32,
-    pub shadow_clip_from_world: Mat4,
+    pub shadow_resolution: Vec2,
+    pub shadow_atlas_tiles: Vec2,
+    /// EnvironmentMapLight::intensity times EnvironmentMapTint.
+    pub env_intensity: Vec3,
+    /// World space to environment map directions, the inverse of EnvironmentMapLight::rotation.
+    pub env_rotation: Mat3,
+    /// From GlobalAmbientLight. Only used when there is no environment map.
+    pub ambient_color: Vec3,
+    pub ambient_brightness: f32,
+    #[array_max("MAX_CASCADES")]
+    pub shadow_clip_from_world: Vec<Mat4>,
+    #[array_max("MAX_CASCADES")]
+    pub shadow_cascade_far_bounds: Vec<f32>,
+    pub shadow_cascade_count: i32,
+    pub shadow_view_z_from_world: Vec4,
+    /// From DirectionalLight. World space offset towards the light.
+    pub shadow_depth_bias: f32,
+    /// From DirectionalLight. Offset along the normal in shadow map texels.
+    pub shadow_normal_bias: f32,
+    #[exclude]
+    pub shadow_filter: ShadowFilter,
+    /// Set in the render world. The shadow map is a depth texture sampled with sampler2DShadow.
+    #[exclude]
+    pub shadow_depth_texture: bool,
+    /// See PointLightShadow
+    #[base_type("samplerCube")]
+    pub point_shadow_texture: TextureRef,
+    pub point_shadow_position: Vec3,
+    /// near, depth_bias, normal_bias, cube face size
+    pub point_shadow_params: Vec4,
+    /// Index into the point light arrays of the light that casts shadows, -1 if none.
+    pub point_shadow_index: i32,
+    /// Point and spot lights share the point light arrays. Point lights come first, the lights from point_light_count
+    /// up to light_count are spot lights.
+    pub point_light_count: i32,
     pub light_count: i32,
+    /// Linear rgb, alpha is the maximum amount of fog.
+    pub fog_color: LinearRgba,
+    /// start, end, density, unused. See FogFalloff
+    pub fog_params: Vec4,
+    #[exclude]
+    pub fog_falloff: Option<FogFalloff>,
+    /// See TonyMcMapfaceLut. Unset until it's uploaded.
+    #[base_type("tonemapping_lut_sampler")]
+    pub tonemapping_lut: TextureRef,
+    /// Set in the render world from BevyGlContext::has_texture_3d. If false the LUT slices are tiled in a 2D texture.
+    #[exclude]
+    pub tonemapping_lut_3d: bool,
+    /// Length of the point light arrays, see max_point_lights(). 0 until prepare_standard_lighting first runs, in which
+    /// case max_lights_def() uses the unreduced default.
+    #[exclude]
+    pub max_point_lights: usize,
+    /// Set by ClusteredLightingPlugin, see max_lights_def(). The cluster uniforms are filled in the render world.
+    #[exclude]
+    pub clustered: bool,
+    /// Light indices for each cluster, see ClusteredLightingPlugin.
+    #[base_type("sampler2D")]
+    pub cluster_texture: TextureRef,
+    /// x, y, z cluster counts, unused
+    pub cluster_grid: Vec4,
+    /// depth slice scale, depth slice bias, cluster_texture width, cluster_texture height
+    pub cluster_z_params: Vec4,
+    pub cluster_view_z_from_world: Vec4,
+}
+
+/// Distance fog for the standard material, based on view space depth. Without this resource fog is compiled out with
+/// the NO_FOG shader def.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct FogSettings {
+    /// The alpha is the maximum amount of fog.
+    pub color: Color,
+    pub falloff: FogFalloff,
+}
+
+impl Default for FogSettings {
+    fn default() -> Self {
+        FogSettings {
+            color: Color::srgb(0.5, 0.6, 0.7),
+            falloff: FogFalloff::Linear {
+                start: 0.0,
+                end: 100.0,
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FogFalloff {
+    /// No fog before start, full fog after end.
+    Linear { start: f32, end: f32 },
+    /// 1.0 - exp(-depth * density)
+    Exponential { density: f32 },
+    /// 1.0 - exp(-(depth * density)^2)
+    ExponentialSquared { density: f32 },
+}
+
+impl FogFalloff {
+    pub fn shader_def(&self) -> (&'static str, &'static str) {
+        match self {
+            FogFalloff::Linear { .. } => ("FOG_LINEAR", ""),
+            FogFalloff::Exponential { .. } => ("FOG_EXPONENTIAL", ""),
+            FogFalloff::ExponentialSquared { .. } => ("FOG_EXPONENTIAL_SQUARED", ""),
+        }
+    }
+
+    /// start, end, density, unused
+    pub fn params(&self) -> Vec4 {
+        match *self {
+            FogFalloff::Linear { start, end } => vec4(start, end, 0.0, 0.0),
+            FogFalloff::Exponential { density } | FogFalloff::ExponentialSquared { density } => {
+                vec4(0.0, 0.0, density, 0.0)
+            }
+        }
+    }
 }
 
 impl StandardLightingUniforms {
@@ -49,7 +177,7 @@ impl StandardLightingUniforms {
         point: bool,
         shadow: bool,
         phase: &RenderPhase,
-    ) -> [(&'static str, &'static str); 3] {
+    ) -> [(&'static str, &'static str); 11] {
         [
             if !point || self.light_count == 0 {
                 ("NO_POINT", "")
@@ -61,7 +189,7 @@ impl StandardLightingUniforms {
             } else {
                 ("NO_ENV", "")
             },
-            if phase.depth_only() && shadow {
+            if phase.depth_only() && (shadow || self.point_shadow_index >= 0) {
                 ("RENDER_DEPTH_ONLY", "")
             } else {
                 if shadow {
@@ -70,8 +198,94 @@ impl StandardLightingUniforms {
                     ("", "")
                 }
             },
+            if shadow && !phase.depth_only() {
+                self.shadow_filter.shader_def()
+            } else {
+                ("", "")
+            },
+            // Always defined since it's used in the shadow_texture binding.
+            if self.shadow_depth_texture {
+                ("shadow_sampler2D", "sampler2DShadow")
+            } else {
+                ("shadow_sampler2D", "sampler2D")
+            },
+            if self.shadow_depth_texture {
+                ("SHADOW_DEPTH_TEXTURE", "")
+            } else {
+                ("", "")
+            },
+            if point && self.point_shadow_index >= 0 && !phase.depth_only() {
+                ("SAMPLE_POINT_SHADOW", "")
+            } else {
+                ("", "")
+            },
+            match &self.fog_falloff {
+                Some(falloff) if !phase.depth_only() => falloff.shader_def(),
+                _ => ("NO_FOG", ""),
+            },
+            // Always defined since it's used in the tonemapping_lut binding.
+            if self.tonemapping_lut_3d {
+                ("tonemapping_lut_sampler", "sampler3D")
+            } else {
+                ("tonemapping_lut_sampler", "sampler2D")
+            },
+            if self.tonemapping_lut_3d {
+                ("TONEMAPPING_LUT_3D", "")
+            } else {
+                ("", "")
+            },
+            // Clusters are only built for the main view.
+            if point
+                && self.clustered
+                && matches!(phase, RenderPhase::Opaque | RenderPhase::Transparent)
+            {
+                ("CLUSTERED_LIGHTING", "")
+            } else {
+                ("", "")
+            },
         ]
     }
+
+    /// MAX_POINT_LIGHTS shader def to use instead of DEFAULT_MAX_LIGHTS_DEF, matches the length of the point light
+    /// arrays when ClusteredLightingPlugin is used or when they were lowered to fit the driver's uniform limits.
+    pub fn max_lights_def(&self) -> (&'static str, &'static str) {
+        match self.max_point_lights {
+            0 if self.clustered => CLUSTERED_MAX_LIGHTS_DEF,
+            0 => DEFAULT_MAX_LIGHTS_DEF,
+            n => ("MAX_POINT_LIGHTS", max_lights_value(n)),
+        }
+    }
+}
+
+fn max_lights_value(n: usize) -> &'static str {
+    static VALUES: LazyLock<Vec<String>> =
+        LazyLock::new(|| (0..=CLUSTERED_MAX_LIGHTS).map(|n| n.to_string()).collect());
+    &VALUES[n.min(CLUSTERED_MAX_LIGHTS)]
+}
+
+/// DEFAULT_MAX_POINT_LIGHTS or CLUSTERED_MAX_LIGHTS, lowered to what fits in GlCapabilities::max_fragment_uniform_vectors.
+/// Warns when it's lowered. Without capabilities, before the context is created, the default is used.
+pub fn max_point_lights(clustered: bool, capabilities: Option<&GlCapabilities>) -> usize {
+    let max = if clustered {
+        CLUSTERED_MAX_LIGHTS
+    } else {
+        DEFAULT_MAX_POINT_LIGHTS
+    };
+    let Some(capabilities) = capabilities else {
+        return max;
+    };
+    let fit = capabilities.fragment_array_fit(
+        max,
+        POINT_LIGHT_UNIFORM_VECTORS,
+        STANDARD_FRAGMENT_UNIFORM_VECTORS,
+    );
+    if fit < max {
+        warn_once!(
+            "The driver only has {} fragment uniform vectors, MAX_POINT_LIGHTS is lowered from {max} to {fit}",
+            capabilities.max_fragment_uniform_vectors
+        );
+    }
+    fit
 }
 
 #[derive(Default)]
@@ -80,7 +294,24 @@ pub struct OpenGLStandardLightingPlugin;
 impl Plugin for OpenGLStandardLightingPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<StandardLightingUniforms>()
-            .add_systems(Update, prepare_standard_lighting.in_set(RenderSet::Prepare));
+            .init_resource::<TonyMcMapfaceLut>()
+            .init_resource::<LightImportance>()
+            .add_systems(Update, prepare_standard_lighting.in_set(RenderSet::Prepare))
+            .add_systems(
+                PostUpdate,
+                prepare_tony_mc_mapface_lut.in_set(RenderSet::Prepare),
+            );
+    }
+}
+
+/// Should accompany an EnvironmentMapLight. Per channel multiplier on top of EnvironmentMapLight::intensity, to tint
+/// the image based lighting.
+#[derive(Component, Clone, Copy)]
+pub struct EnvironmentMapTint(pub Color);
+
+impl Default for EnvironmentMapTint {
+    fn default() -> Self {
+        Self(Color::WHITE)
     }
 }
 
@@ -89,23 +320,104 @@ fn prepare_standard_lighting(
     spot_lights: Query<(&SpotLight, &GlobalTransform)>,
     directional_lights: Query<(&DirectionalLight, &GlobalTransform)>,
     shadow: Option<Res<DirectionalLightShadow>>,
-    env_light: Single<Option<&EnvironmentMapLight>, With<Camera3d>>,
+    point_shadow: Option<Res<PointLightShadow>>,
+    cameras: Query<
+        (
+            &Camera,
+            &GlobalTransform,
+            Option<&EnvironmentMapLight>,
+            Option<&EnvironmentMapTint>,
+        ),
+        With<Camera3d>,
+    >,
+    light_importance: Res<LightImportance>,
+    clustered: Option<Res<ClusteredLightingSettings>>,
+    ambient_light: Option<Res<GlobalAmbientLight>>,
+    fog: Option<Res<FogSettings>>,
+    tony_mc_mapface_lut: Res<TonyMcMapfaceLut>,
+    shadow_filter: Res<ShadowFilter>,
+    capabilities: Option<Res<GlCapabilities>>,
     mut enc: ResMut<CommandEncoder>,
 ) {
-    let lighting_uniform = StandardLightingUniforms::new(
-        point_lights,
-        spot_lights,
+    // Use the environment light of the first camera, the same one shadows are rendered for.
+    let first_camera = cameras
+        .iter()
+        .filter(|(camera, ..)| camera.is_active)
+        .min_by_key(|(camera, ..)| camera.order);
+    let env_light = first_camera.and_then(|(_, _, env_light, _)| env_light);
+    let env_tint = first_camera.and_then(|(_, _, _, env_tint)| env_tint);
+    let view_position = first_camera.map_or(Vec3::ZERO, |(_, trans, ..)| trans.translation());
+
+    // If there are too many point and spot lights keep the most important ones.
+    let point_lights = point_lights.iter().collect::<Vec<_>>();
+    let spot_lights = spot_lights.iter().collect::<Vec<_>>();
+    let mut selected = point_lights
+        .iter()
+        .map(|(light, trans)| LightCandidate {
+            position: trans.translation(),
+            range: light.range,
+            intensity: light.intensity,
+            spot: false,
+            view_position,
+        })
+        .chain(spot_lights.iter().map(|(light, trans)| LightCandidate {
+            position: trans.translation(),
+            range: light.range,
+            intensity: light.intensity,
+            spot: true,
+            view_position,
+        }))
+        .enumerate()
+        .collect::<Vec<_>>();
+    let max_point_spot = max_point_lights(clustered.is_some(), capabilities.as_deref());
+    if selected.len() > max_point_spot {
+        let importance = light_importance.0;
+        selected.sort_by(|(_, a), (_, b)| importance(b).total_cmp(&importance(a)));
+        selected.truncate(max_point_spot);
+    }
+    let selected_points = selected
+        .iter()
+        .filter(|(_, light)| !light.spot)
+        .map(|(i, _)| point_lights[*i]);
+    let selected_spots = selected
+        .iter()
+        .filter(|(_, light)| light.spot)
+        .map(|(i, _)| spot_lights[*i - point_lights.len()]);
+
+    let mut lighting_uniform = StandardLightingUniforms::new(
+        selected_points,
+        selected_spots,
         clone2(directional_lights.single().ok()),
-        *env_light.deref(),
+        env_light,
+        ambient_light.as_deref(),
         shadow.as_deref(),
-        DEFAULT_MAX_POINT_LIGHTS,
+        point_shadow.as_deref(),
+        max_point_spot,
+        DEFAULT_MAX_CASCADES,
     );
-    enc.record(move |_ctx, world| {
+    if let Some(env_tint) = env_tint {
+        lighting_uniform.env_intensity *= env_tint.0.to_linear().to_vec3();
+    }
+    lighting_uniform.clustered = clustered.is_some();
+    lighting_uniform.max_point_lights = max_point_spot;
+    lighting_uniform.shadow_filter = *shadow_filter;
+    lighting_uniform.tonemapping_lut = tony_mc_mapface_lut.texture.clone();
+    if let Some(fog) = fog {
+        lighting_uniform.fog_color = fog.color.to_linear();
+        lighting_uniform.fog_params = fog.falloff.params();
+        lighting_uniform.fog_falloff = Some(fog.falloff);
+    }
+    enc.record(move |ctx, world| {
+        lighting_uniform.tonemapping_lut_3d = ctx.has_texture_3d;
+        lighting_uniform.shadow_depth_texture = shadow_depth_texture(world);
+        if !point_shadow_available(world) {
+            lighting_uniform.point_shadow_index = -1;
+        }
         world.insert_resource(lighting_uniform);
     });
 }
 
-/// Expects SAMPLE_SHADOW shader def based on shadow availability
+/// Expects SAMPLE_SHADOW and SAMPLE_POINT_SHADOW shader defs based on shadow availability
 pub fn standard_pbr_lighting_glsl() -> &'static str {
     include_str!("shaders/standard_pbr_lighting.glsl")
 }
@@ -124,19 +436,40 @@ impl StandardLightingUniforms {
         spot_lights: SI,
         directional_light: Option<(DirectionalLight, GlobalTransform)>,
         env_light: Option<&EnvironmentMapLight>,
+        ambient_light: Option<&GlobalAmbientLight>,
         shadow: Option<&DirectionalLightShadow>,
+        point_shadow: Option<&PointLightShadow>,
         max_point_spot: usize,
+        max_cascades: usize,
     ) -> Self
     where
         PI: IntoIterator<Item = (&'a PointLight, &'a GlobalTransform)>,
         SI: IntoIterator<Item = (&'a SpotLight, &'a GlobalTransform)>,
     {
-        let mut data = StandardLightingUniforms::default();
+        let mut data = StandardLightingUniforms {
+            point_shadow_index: -1,
+            ..default()
+        };
 
         for (light, trans) in point_lights {
             if data.point_light_position_range.len() >= max_point_spot {
                 break;
             }
+            if let Some(point_shadow) = point_shadow
+                && data.point_shadow_index < 0
+                && light.shadows_enabled
+                && trans.translation() == point_shadow.position
+            {
+                data.point_shadow_index = data.point_light_position_range.len() as i32;
+                data.point_shadow_texture = point_shadow.texture.clone();
+                data.point_shadow_position = point_shadow.position;
+                data.point_shadow_params = vec4(
+                    point_shadow.near,
+                    point_shadow.depth_bias,
+                    point_shadow.normal_bias,
+                    point_shadow.size as f32,
+                );
+            }
             data.point_light_position_range
                 .push(trans.translation().extend(light.range));
             data.point_light_color_radius.push(
@@ -144,8 +477,9 @@ impl StandardLightingUniforms {
                     .extend(light.radius),
             );
             data.spot_light_dir_offset_scale
-                .push(vec4(1.0, 0.0, 2.0, 1.0));
+                .push(POINT_LIGHT_DIR_OFFSET_SCALE);
         }
+        data.point_light_count = data.point_light_position_range.len() as i32;
 
         for (light, trans) in spot_lights {
             if data.point_light_position_range.len() >= max_point_spot {
@@ -166,23 +500,74 @@ impl StandardLightingUniforms {
         if let Some((light, trans)) = directional_light {
             data.directional_light_dir = trans.forward().as_vec3();
             data.directional_light_color = light.color.to_linear().to_vec3() * light.illuminance;
+            data.shadow_depth_bias = light.shadow_depth_bias;
+            data.shadow_normal_bias = light.shadow_normal_bias;
         }
 
         if let Some(env_light) = env_light {
             data.specular_map = Some(env_light.specular_map.clone());
             data.diffuse_map = Some(env_light.diffuse_map.clone());
-            data.env_intensity = env_light.intensity;
+            data.env_intensity = Vec3::splat(env_light.intensity);
+            data.env_rotation = Mat3::from_quat(env_light.rotation.inverse());
+        }
+
+        if let Some(ambient_light) = ambient_light {
+            data.ambient_color = ambient_light.color.to_linear().to_vec3();
+            data.ambient_brightness = ambient_light.brightness;
         }
 
         if let Some(shadow) = &shadow {
             data.shadow_texture = shadow.texture.clone();
-            data.shadow_clip_from_world = shadow.clip_from_view * shadow.view_from_world;
+            data.shadow_resolution = shadow.atlas_size().as_vec2();
+            data.shadow_atlas_tiles = shadow.atlas_tiles().as_vec2();
+            for cascade in shadow.cascades.iter().take(max_cascades) {
+                data.shadow_clip_from_world
+                    .push(cascade.clip_from_view * cascade.view_from_world);
+                data.shadow_cascade_far_bounds.push(cascade.far_bound);
+            }
+            data.shadow_cascade_count = data.shadow_clip_from_world.len() as i32;
+            data.shadow_view_z_from_world = shadow.view_z_from_world;
         }
 
         data
     }
 }
 
+/// Estimates how much a point or spot light contributes to the view. When there are more than DEFAULT_MAX_POINT_LIGHTS
+/// (CLUSTERED_MAX_LIGHTS with ClusteredLightingPlugin) point and spot lights only the ones with the highest importance
+/// are used. Replace this resource to change the
+/// heuristic, the default is light_intensity_over_distance_squared().
+#[derive(Resource, Clone, Copy)]
+pub struct LightImportance(pub fn(&LightCandidate) -> f32);
+
+impl Default for LightImportance {
+    fn default() -> Self {
+        LightImportance(light_intensity_over_distance_squared)
+    }
+}
+
+pub struct LightCandidate {
+    pub position: Vec3,
+    pub range: f32,
+    /// Luminous power in lumens
+    pub intensity: f32,
+    pub spot: bool,
+    /// Position of the first camera
+    pub view_position: Vec3,
+}
+
+pub fn light_intensity_over_distance_squared(light: &LightCandidate) -> f32 {
+    light.intensity
+        / light
+            .position
+            .distance_squared(light.view_position)
+            .max(1e-4)
+}
+
+/// Placeholder spot_light_dir_offset_scale for point lights. The shader uses point_light_count to tell them apart, but
+/// with an offset of 2 this also never attenuates.
+pub const POINT_LIGHT_DIR_OFFSET_SCALE: Vec4 = vec4(1.0, 0.0, 2.0, 1.0);
+
 pub fn calc_spot_dir_offset_scale(light: &SpotLight, trans: &GlobalTransform) -> Vec4 {
     // https://github.com/bevyengine/bevy/blob/abb8c353f49a6fe9e039e82adbe1040488ad910a/crates/bevy_pbr/src/render/light.rs#L846
     let cos_outer = light.outer_angle.cos();
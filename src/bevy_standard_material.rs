@@ -1,36 +1,108 @@
 use bevy::{
-    camera::{Exposure, primitives::Aabb},
-    diagnostic::FrameCount,
+    camera::primitives::Aabb, mesh::morph::MeshMorphWeights, platform::collections::HashSet,
     prelude::*,
 };
-use itertools::{Either, Itertools};
+use itertools::Itertools;
 use uniform_set_derive::UniformSet;
 use wgpu_types::Face;
 
 use crate::{
-    BevyGlContext, UniformSet, UniformValue,
+    BevyGlContext, ShaderDefs, UniformSet, UniformValue,
     bevy_standard_lighting::{
-        DEFAULT_MAX_JOINTS_DEF, DEFAULT_MAX_LIGHTS_DEF, StandardLightingUniforms,
-        standard_pbr_glsl, standard_pbr_lighting_glsl, standard_shadow_sampling_glsl,
+        DEFAULT_MAX_JOINTS, StandardLightingUniforms, standard_pbr_glsl,
+        standard_pbr_lighting_glsl, standard_shadow_sampling_glsl,
     },
+    capabilities::GlCapabilities,
     command_encoder::CommandEncoder,
     flip_cull_mode,
-    phase_shadow::DirectionalLightShadow,
-    phase_transparent::DeferredAlphaBlendDraws,
-    plane_reflect::{ReflectionPlane, ReflectionUniforms},
+    mesh_util::generate_tangents,
+    phase_shadow::{DEFAULT_MAX_CASCADES_DEF, DirectionalLightShadow},
+    phase_transparent::{DeferredAlphaBlendDraws, OitPass},
+    plane_reflect::ReflectionUniforms,
     prepare_image::GpuImages,
     prepare_joints::JointData,
-    prepare_mesh::GpuMeshes,
+    prepare_mesh::{GpuMeshes, MAX_MORPH_TARGETS, send_standard_meshes_to_gpu},
+    prepare_view::{ViewUniforms, ViewUniformsPlugin},
     render::{
-        RenderPhase, RenderSet, register_prepare_system, register_render_system,
+        DrawCollector, RenderPhase, RenderSet, register_render_system,
         set_blend_func_from_alpha_mode, transparent_draw_from_alpha_mode,
     },
     shader_cached,
+    ssao::{SsaoUniforms, ssao_active},
+    watchers::ShaderHotReload,
 };
 
-#[derive(Resource, Clone, Default)]
+#[derive(Resource, Clone)]
 pub struct OpenGLStandardMaterialSettings {
     pub no_point: bool, // no point light glsl code
+    /// Size of the joint matrix uniform array in std_mat.vert. Skeletons with more joints only upload the first
+    /// max_joints and warn. Vertex shader uniform space is limited, see joint_encoding.
+    pub max_joints: usize,
+    pub joint_encoding: JointEncoding,
+    /// Loop bound for StandardMaterial::max_parallax_layer_count, which is capped to it. Each layer is a depth map
+    /// sample, keep this low on GLES.
+    pub max_parallax_layers: usize,
+    /// Loop bound for ParallaxMappingMethod::Relief max_steps.
+    pub max_relief_steps: usize,
+}
+
+/// How joint matrices are uploaded to std_mat.vert.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum JointEncoding {
+    /// A mat4 per joint, 4 vec4 uniforms.
+    #[default]
+    Mat4,
+    /// Only the top 3 rows of each joint matrix since the bottom row of an affine transform is always 0,0,0,1. 3 vec4
+    /// uniforms per joint, so about a third more joints fit in the same uniform space.
+    Affine3x4,
+}
+
+/// The top 3 rows of each matrix, for JointEncoding::Affine3x4.
+pub fn encode_joints_affine(joints: &[Mat4], rows: &mut Vec<Vec4>) {
+    rows.clear();
+    rows.extend(joints.iter().flat_map(|m| [m.row(0), m.row(1), m.row(2)]));
+}
+
+/// Roughly how many vertex uniform vectors std_mat.vert uses besides joint_data.
+pub const STD_MAT_VERTEX_UNIFORM_VECTORS: usize = 32;
+
+impl OpenGLStandardMaterialSettings {
+    /// max_joints, lowered to what fits in GlCapabilities::max_vertex_uniform_vectors with joint_encoding. Warns when
+    /// it's lowered.
+    pub fn supported_max_joints(&self, capabilities: Option<&GlCapabilities>) -> usize {
+        let Some(capabilities) = capabilities else {
+            return self.max_joints;
+        };
+        let vectors_per_joint = match self.joint_encoding {
+            JointEncoding::Mat4 => 4,
+            JointEncoding::Affine3x4 => 3,
+        };
+        let fit = capabilities.vertex_array_fit(
+            self.max_joints,
+            vectors_per_joint,
+            STD_MAT_VERTEX_UNIFORM_VECTORS,
+        );
+        if fit < self.max_joints {
+            warn_once!(
+                "The driver only has {} vertex uniform vectors, MAX_JOINTS is lowered from {} to {fit}",
+                capabilities.max_vertex_uniform_vectors,
+                self.max_joints
+            );
+        }
+        fit
+    }
+}
+
+impl Default for OpenGLStandardMaterialSettings {
+    fn default() -> Self {
+        Self {
+            no_point: false,
+            max_joints: DEFAULT_MAX_JOINTS,
+            joint_encoding: JointEncoding::Mat4,
+            max_parallax_layers: 16,
+            max_relief_steps: 5,
+        }
+    }
 }
 
 #[derive(Default)]
@@ -40,23 +112,46 @@ impl Plugin for OpenGLStandardMaterialPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<DrawsSortedByMaterial>();
         app.init_resource::<OpenGLStandardMaterialSettings>();
-        register_prepare_system(app.world_mut(), standard_material_prepare_view);
+        if !app.is_plugin_added::<ViewUniformsPlugin>() {
+            app.add_plugins(ViewUniformsPlugin);
+        }
         register_render_system::<StandardMaterial, _>(app.world_mut(), standard_material_render);
         app.add_systems(
             Startup,
             init_std_shader_includes.in_set(RenderSet::Pipeline),
         );
         app.add_systems(Update, sort_std_mat_by_material.in_set(RenderSet::Prepare));
+        app.add_systems(
+            PostUpdate,
+            generate_missing_tangents
+                .in_set(RenderSet::Prepare)
+                .before(send_standard_meshes_to_gpu),
+        );
     }
 }
 
 pub fn init_std_shader_includes(mut enc: ResMut<CommandEncoder>) {
-    enc.record(|ctx, _world| {
+    enc.record(|ctx, world| {
         ctx.add_shader_include("std::agx", include_str!("shaders/agx.glsl"));
+        ctx.add_shader_include("std::tonemapping", include_str!("shaders/tonemapping.glsl"));
         ctx.add_shader_include("std::math", include_str!("shaders/math.glsl"));
         ctx.add_shader_include("std::shadow_sampling", standard_shadow_sampling_glsl());
         ctx.add_shader_include("std::pbr", standard_pbr_glsl());
         ctx.add_shader_include("std::pbr_lighting", standard_pbr_lighting_glsl());
+        ctx.add_shader_include("std::oit", include_str!("shaders/oit.glsl"));
+
+        let dir = std::path::Path::new(file!())
+            .parent()
+            .unwrap()
+            .join("shaders");
+        let mut hot_reload = world.get_resource_or_init::<ShaderHotReload>();
+        hot_reload.watch_include("std::agx", dir.join("agx.glsl"));
+        hot_reload.watch_include("std::tonemapping", dir.join("tonemapping.glsl"));
+        hot_reload.watch_include("std::math", dir.join("math.glsl"));
+        hot_reload.watch_include("std::shadow_sampling", dir.join("shadow_sampling.glsl"));
+        hot_reload.watch_include("std::pbr", dir.join("pbr.glsl"));
+        hot_reload.watch_include("std::pbr_lighting", dir.join("standard_pbr_lighting.glsl"));
+        hot_reload.watch_include("std::oit", dir.join("oit.glsl"));
     });
 }
 
@@ -66,19 +161,6 @@ pub struct SkipReflection;
 #[derive(Component, Default)]
 pub struct ReadReflection;
 
-#[derive(UniformSet, Component, Resource, Clone)]
-#[uniform_set(prefix = "ub_")]
-pub struct ViewUniforms {
-    pub world_from_view: Mat4,
-    pub view_from_world: Mat4,
-    pub clip_from_world: Mat4,
-    pub view_position: Vec3,
-    pub view_resolution: Vec2,
-    pub view_exposure: f32,
-    pub frame: f32,
-    pub time: f32,
-}
-
 #[derive(Resource, Default, Deref, DerefMut)]
 pub struct DrawsSortedByMaterial(Vec<Entity>);
 
@@ -95,73 +177,50 @@ pub fn sort_std_mat_by_material(
     }
 }
 
-// Runs at each view transition: Before shadows, before reflections, etc..
-pub fn standard_material_prepare_view(
-    mut commands: Commands,
-    phase: Res<RenderPhase>,
-    camera: Single<(
-        Entity,
-        &Camera,
-        &GlobalTransform,
-        &Projection,
-        Option<&Exposure>,
-    )>,
-    shadow: Option<Res<DirectionalLightShadow>>,
-    reflect: Option<Single<&ReflectionPlane>>,
-    bevy_window: Single<&Window>,
-    mut enc: ResMut<CommandEncoder>,
-    frame: Res<FrameCount>,
-    time: Res<Time>,
+/// Adds tangents from mesh_util::generate_tangents() to meshes drawn with a normal or depth mapped StandardMaterial that
+/// don't have Mesh::ATTRIBUTE_TANGENT, which normal and parallax mapping need. The mesh asset is modified, so it's uploaded again.
+pub fn generate_missing_tangents(
+    mesh_entities: Query<(&Mesh3d, &MeshMaterial3d<StandardMaterial>)>,
+    materials: Res<Assets<StandardMaterial>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut mesh_events: MessageReader<AssetEvent<Mesh>>,
+    mut checked: Local<HashSet<AssetId<Mesh>>>,
 ) {
-    let (camera_entity, _camera, cam_global_trans, cam_proj, exposure) = *camera;
-    let view_resolution = vec2(
-        bevy_window.physical_width() as f32,
-        bevy_window.physical_height() as f32,
-    );
-
-    let view_position;
-    let mut world_from_view;
-    let view_from_world;
-    let clip_from_world;
-
-    if *phase == RenderPhase::Shadow {
-        if let Some(shadow) = &shadow {
-            view_position = shadow.light_position;
-            view_from_world = shadow.view_from_world;
-            world_from_view = shadow.view_from_world.inverse();
-            clip_from_world = shadow.clip_from_view * shadow.view_from_world;
-        } else {
-            return;
+    for event in mesh_events.read() {
+        if let AssetEvent::Modified { id } | AssetEvent::Removed { id } = event {
+            checked.remove(id);
+        }
+    }
+    for (mesh_h, material_h) in &mesh_entities {
+        if checked.contains(&mesh_h.id()) {
+            continue;
+        }
+        let Some(material) = materials.get(material_h) else {
+            continue;
+        };
+        if material.normal_map_texture.is_none() && material.depth_map.is_none() {
+            continue;
+        }
+        let Some(mesh) = meshes.get(mesh_h) else {
+            continue;
+        };
+        checked.insert(mesh_h.id());
+        if mesh.contains_attribute(Mesh::ATTRIBUTE_TANGENT) {
+            continue;
         }
-    } else {
-        view_position = cam_global_trans.translation();
-        let clip_from_view = cam_proj.get_clip_from_view();
-        world_from_view = cam_global_trans.to_matrix();
-        if let Some(reflect) = reflect
-            && phase.reflection()
-        {
-            world_from_view = reflect.0 * world_from_view;
+        match generate_tangents(mesh) {
+            Some(tangents) => {
+                if let Some(mesh) = meshes.get_mut(mesh_h) {
+                    mesh.insert_attribute(Mesh::ATTRIBUTE_TANGENT, tangents);
+                }
+            }
+            None => warn!(
+                "Mesh {:?} has a normal or depth map but no tangents, and they can't be generated without a triangle list with \
+                normals and UV_0",
+                mesh_h.id()
+            ),
         }
-        view_from_world = world_from_view.inverse();
-        clip_from_world = clip_from_view * view_from_world;
     }
-
-    let view_uniforms = ViewUniforms {
-        world_from_view,
-        view_from_world,
-        clip_from_world,
-        view_position,
-        view_resolution,
-        view_exposure: exposure
-            .map(|e| e.exposure())
-            .unwrap_or_else(|| Exposure::default().exposure()),
-        frame: frame.0 as f32,
-        time: time.elapsed_secs(),
-    };
-    commands.entity(camera_entity).insert(view_uniforms.clone());
-    enc.record(move |_ctx, world| {
-        world.insert_resource(view_uniforms.clone());
-    });
 }
 
 pub fn standard_material_render(
@@ -175,42 +234,43 @@ pub fn standard_material_render(
         Has<SkipReflection>,
         Has<ReadReflection>,
         Option<&JointData>,
+        Option<&MeshMorphWeights>,
+        Option<&ShaderDefs>,
     )>,
-    view_uniforms: Single<&ViewUniforms>,
+    view_uniforms: Res<ViewUniforms>,
     materials: Res<Assets<StandardMaterial>>,
     phase: Res<RenderPhase>,
+    oit_pass: Res<OitPass>,
     mut transparent_draws: ResMut<DeferredAlphaBlendDraws>,
     reflect_uniforms: Option<Res<ReflectionUniforms>>,
     sorted: Res<DrawsSortedByMaterial>,
     mut enc: ResMut<CommandEncoder>,
     prefs: Res<OpenGLStandardMaterialSettings>,
+    capabilities: Option<Res<GlCapabilities>>,
     shadow: Option<Res<DirectionalLightShadow>>,
 ) {
     let view_uniforms = view_uniforms.clone();
 
     let phase = *phase;
+    let oit_pass = *oit_pass;
 
-    let iter = if phase.transparent() {
-        Either::Right(mesh_entities.iter_many(transparent_draws.take()))
-    } else {
-        Either::Left(mesh_entities.iter_many(&**sorted))
-        // Either::Left(mesh_entities.iter()) // <- Unsorted alternative
-    };
-
-    struct Draw {
-        world_from_local: Mat4,
+    struct DrawData {
         joint_data: Option<JointData>,
-        material_h: AssetId<StandardMaterial>,
-        material_idx: u32,
+        morph_weights: Option<Vec4>,
         read_reflect: bool,
-        mesh: Handle<Mesh>,
+        /// Index into shader_def_sets
+        shader_defs: usize,
     }
 
-    let mut draws = Vec::new();
-    let mut render_materials: Vec<StandardMaterialUniforms> = Vec::new();
-
-    let mut last_material = None;
-    let mut current_material_idx = 0;
+    let mut collector = DrawCollector::<StandardMaterial, _, StandardMaterialUniforms, _>::new(
+        phase,
+        &view_uniforms,
+        &mut transparent_draws,
+    );
+    // mesh_entities.iter().map(|item| item.0) <- Unsorted alternative
+    let entities = collector.entities(sorted.iter().copied());
+    // The distinct ShaderDefs of this frame's draws, the first is for entities without any.
+    let mut shader_def_sets = vec![ShaderDefs::default()];
     for (
         entity,
         view_vis,
@@ -221,11 +281,11 @@ pub fn standard_material_render(
         skip_reflect,
         read_reflect,
         joint_data,
-    ) in iter
+        morph_weights,
+        shader_defs,
+    ) in mesh_entities.iter_many(entities)
     {
-        if (phase.can_use_camera_frustum_cull() && !view_vis.get())
-            || (skip_reflect && phase.reflection())
-        {
+        if skip_reflect && phase.reflection() {
             continue;
         }
 
@@ -233,58 +293,99 @@ pub fn standard_material_render(
             continue;
         };
 
-        let world_from_local = transform.to_matrix();
-
-        // If in opaque phase we must defer any alpha blend draws so they can be sorted and run in order.
-        if !transparent_draws.maybe_defer::<StandardMaterial>(
-            transparent_draw_from_alpha_mode(&material.alpha_mode),
-            phase,
+        let Some(world_from_local) = collector.cull_or_defer(
             entity,
+            view_vis,
             transform,
             aabb,
-            &view_uniforms.view_from_world,
-            &world_from_local,
-        ) {
+            transparent_draw_from_alpha_mode(&material.alpha_mode),
+        ) else {
             continue;
-        }
-
-        if last_material != Some(material_h) {
-            current_material_idx = render_materials.len() as u32;
-            last_material = Some(material_h);
-            render_materials.push(material.into());
-        }
+        };
 
-        draws.push(Draw {
-            // TODO don't copy full material
-            material_idx: current_material_idx,
+        collector.push(
+            material_h.id(),
+            || material.into(),
+            mesh.id(),
             world_from_local,
-            joint_data: joint_data.cloned(),
-            material_h: material_h.id(),
-            read_reflect,
-            mesh: mesh.0.clone(),
-        });
+            DrawData {
+                joint_data: joint_data.cloned(),
+                morph_weights: morph_weights.map(|w| morph_weights_vec4(w.weights())),
+                read_reflect,
+                shader_defs: shader_defs
+                    .filter(|defs| !defs.is_empty())
+                    .map_or(0, |defs| {
+                        shader_def_sets
+                            .iter()
+                            .position(|set| set == defs)
+                            .unwrap_or_else(|| {
+                                shader_def_sets.push(defs.clone());
+                                shader_def_sets.len() - 1
+                            })
+                    }),
+            },
+        );
     }
+    let (render_materials, draws) = collector.finish();
+    let has_morph_targets = draws.iter().any(|draw| draw.data.morph_weights.is_some());
 
     let reflect_uniforms = reflect_uniforms.as_deref().cloned();
-    let prefs = prefs.clone();
+    let mut prefs = prefs.clone();
+    prefs.max_joints = prefs.supported_max_joints(capabilities.as_deref());
     let shadow = shadow.as_deref().cloned();
     enc.record(move |ctx, world| {
         let lighting_uniforms = world.resource::<StandardLightingUniforms>().clone();
         let mut reflect_bool_location = None;
-
-        let change_shader_program = |ctx: &mut BevyGlContext, world: &mut World, alpha_mask| {
+        let max_joints = prefs.max_joints.to_string();
+        let max_parallax_layers = prefs.max_parallax_layers.to_string();
+        let max_relief_steps = prefs.max_relief_steps.to_string();
+        let tonemapping_def = view_uniforms
+            .tonemapping_shader_def(lighting_uniforms.tonemapping_lut.get().is_some());
+
+        let change_shader_program = |ctx: &mut BevyGlContext,
+                                     world: &mut World,
+                                     alpha_mask,
+                                     parallax: Option<ParallaxMappingMethod>,
+                                     shader_defs: usize| {
+            let entity_defs = shader_def_sets[shader_defs].pairs();
+            let ssao = phase == RenderPhase::Opaque && ssao_active(world);
             let shader_index = shader_cached!(
                 ctx,
                 "shaders/std_mat.vert",
                 "shaders/pbr_std_mat.frag",
                 [
-                    DEFAULT_MAX_LIGHTS_DEF,
-                    DEFAULT_MAX_JOINTS_DEF,
+                    lighting_uniforms.max_lights_def(),
+                    ("MAX_JOINTS", max_joints.as_str()),
+                    DEFAULT_MAX_CASCADES_DEF,
                     if alpha_mask {
                         ("ALPHA_MASK", "")
                     } else {
                         ("", "")
-                    }
+                    },
+                    if has_morph_targets {
+                        ("HAS_MORPH_TARGETS", "")
+                    } else {
+                        ("", "")
+                    },
+                    if prefs.joint_encoding == JointEncoding::Affine3x4 {
+                        ("JOINT_AFFINE", "")
+                    } else {
+                        ("", "")
+                    },
+                    if parallax.is_some() {
+                        ("PARALLAX", "")
+                    } else {
+                        ("", "")
+                    },
+                    if let Some(ParallaxMappingMethod::Relief { .. }) = parallax {
+                        ("RELIEF_MAPPING", "")
+                    } else {
+                        ("", "")
+                    },
+                    ("MAX_PARALLAX_LAYERS", max_parallax_layers.as_str()),
+                    ("MAX_RELIEF_STEPS", max_relief_steps.as_str()),
+                    if ssao { ("SSAO", "") } else { ("", "") },
+                    tonemapping_def,
                 ]
                 .iter()
                 .chain(
@@ -292,7 +393,9 @@ pub fn standard_material_render(
                         .shader_defs(!prefs.no_point, shadow.is_some(), &phase)
                         .iter()
                 )
-                .chain(phase.shader_defs().iter()),
+                .chain(phase.shader_defs().iter())
+                .chain(oit_pass.shader_defs().iter())
+                .chain(entity_defs.iter()),
                 &[
                     ViewUniforms::bindings(),
                     StandardMaterialUniforms::bindings(),
@@ -301,7 +404,6 @@ pub fn standard_material_render(
             )
             .unwrap();
 
-            world.resource_mut::<GpuMeshes>().reset_mesh_bind_cache();
             ctx.use_cached_program(shader_index);
 
             ctx.map_uniform_set_locations::<ViewUniforms>();
@@ -321,47 +423,104 @@ pub fn standard_material_render(
                     reflect_uniforms.as_ref().unwrap_or(&Default::default()),
                 );
             }
+            if ssao {
+                ctx.map_uniform_set_locations::<SsaoUniforms>();
+                ctx.bind_uniforms_set(
+                    world.resource::<GpuImages>(),
+                    world.resource::<SsaoUniforms>(),
+                );
+            }
             shader_index
         };
 
         let mut current_mask_mode = false;
-        let mut shader_index = change_shader_program(ctx, world, current_mask_mode);
+        let mut current_parallax = None;
+        let mut current_shader_defs = 0;
+        let mut shader_index = change_shader_program(
+            ctx,
+            world,
+            current_mask_mode,
+            current_parallax,
+            current_shader_defs,
+        );
         let mut last_material = None;
+        let mut joint_rows = Vec::new();
         for draw in &draws {
             let material = &render_materials[draw.material_idx as usize];
-            // Alpha mask is the only per-material thing the our std mat currently specializes on. Since we sort by
-            // material this shader program change shouldn't happen often.
-            if is_alpha_mask(material.alpha_mode) != current_mask_mode {
-                current_mask_mode = !current_mask_mode;
-                shader_index = change_shader_program(ctx, world, current_mask_mode);
+            // Alpha mask and parallax are the only per-material things our std mat currently specializes on, besides
+            // per-entity ShaderDefs. Since we sort by material this shader program change shouldn't happen often.
+            if is_alpha_mask(material.alpha_mode) != current_mask_mode
+                || material.parallax != current_parallax
+                || draw.data.shader_defs != current_shader_defs
+            {
+                current_mask_mode = is_alpha_mask(material.alpha_mode);
+                current_parallax = material.parallax;
+                current_shader_defs = draw.data.shader_defs;
+                shader_index = change_shader_program(
+                    ctx,
+                    world,
+                    current_mask_mode,
+                    current_parallax,
+                    current_shader_defs,
+                );
+                // The new program doesn't have the material bound yet.
+                last_material = None;
+                reflect_bool_location = None;
+            }
+            match oit_pass.blend_state() {
+                Some(blend_state) => blend_state.apply(&ctx.gl),
+                None => set_blend_func_from_alpha_mode(&ctx.gl, &material.alpha_mode),
             }
-            set_blend_func_from_alpha_mode(&ctx.gl, &material.alpha_mode);
 
             ctx.load("world_from_local", draw.world_from_local);
 
-            if let Some(joint_data) = &draw.joint_data {
-                ctx.load("joint_data", joint_data.as_slice());
+            if let Some(joint_data) = &draw.data.joint_data {
+                if joint_data.len() > prefs.max_joints {
+                    warn_once!(
+                        "Skinned mesh has {} joints but OpenGLStandardMaterialSettings::max_joints is {}, only the \
+                        first {} are uploaded",
+                        joint_data.len(),
+                        prefs.max_joints,
+                        prefs.max_joints
+                    );
+                }
+                let joints = &joint_data[..joint_data.len().min(prefs.max_joints)];
+                match prefs.joint_encoding {
+                    JointEncoding::Mat4 => {
+                        ctx.load("joint_data", joints);
+                    }
+                    JointEncoding::Affine3x4 => {
+                        encode_joints_affine(joints, &mut joint_rows);
+                        ctx.load("joint_data", joint_rows.as_slice());
+                    }
+                }
+            }
+            ctx.load("has_joint_data", draw.data.joint_data.is_some());
+            if has_morph_targets {
+                ctx.load(
+                    "morph_weights",
+                    draw.data.morph_weights.unwrap_or(Vec4::ZERO),
+                );
             }
-            ctx.load("has_joint_data", draw.joint_data.is_some());
 
             if phase.read_reflect() && reflect_uniforms.is_some() {
                 let reflect_bool_location = reflect_bool_location
                     .get_or_insert_with(|| ctx.get_uniform_location("read_reflection"))
                     .as_ref()
                     .unwrap();
-                draw.read_reflect.load(&ctx.gl, &reflect_bool_location);
+                draw.data.read_reflect.load(&ctx.gl, &reflect_bool_location);
             }
 
             // Only re-bind if the material has changed.
-            if last_material != Some(draw.material_h) {
+            if last_material != Some(draw.material_idx) {
                 ctx.set_cull_mode(flip_cull_mode(material.cull_mode, phase.reflection()));
                 ctx.bind_uniforms_set(world.resource::<GpuImages>(), material);
             }
 
             world
                 .resource_mut::<GpuMeshes>()
-                .draw_mesh(ctx, draw.mesh.id(), shader_index);
-            last_material = Some(draw.material_h);
+                .draw_mesh(ctx, draw.mesh, shader_index);
+            last_material = Some(draw.material_idx);
         }
     });
 }
@@ -369,8 +528,8 @@ pub fn standard_material_render(
 #[derive(UniformSet, Component, Clone)]
 #[uniform_set(prefix = "ub_")]
 pub struct StandardMaterialUniforms {
-    pub base_color: Vec4,
-    pub emissive: Vec4,
+    pub base_color: Color,
+    pub emissive: LinearRgba,
     pub perceptual_roughness: f32,
     pub metallic: f32,
     pub double_sided: bool,
@@ -379,11 +538,21 @@ pub struct StandardMaterialUniforms {
     pub flip_normal_map_y: bool,
     pub reflectance: Vec3,
     pub alpha_blend: bool,
+    /// Fragments with a lower alpha are discarded when alpha_mode is AlphaMode::Mask.
+    pub alpha_cutoff: f32,
     pub has_normal_map: bool,
     pub base_color_texture: Option<Handle<Image>>,
     pub normal_map_texture: Option<Handle<Image>>,
     pub metallic_roughness_texture: Option<Handle<Image>>,
     pub emissive_texture: Option<Handle<Image>>,
+    pub parallax_depth_scale: f32,
+    pub max_parallax_layer_count: f32,
+    pub max_relief_mapping_search_steps: f32,
+    /// Only sampled when parallax is set.
+    pub depth_map: Option<Handle<Image>>,
+    /// Selects the PARALLAX shader def, set when the material has a depth_map. Needs mesh tangents.
+    #[exclude]
+    pub parallax: Option<ParallaxMappingMethod>,
     #[exclude]
     pub alpha_mode: AlphaMode,
     #[exclude]
@@ -393,8 +562,8 @@ pub struct StandardMaterialUniforms {
 impl From<&StandardMaterial> for StandardMaterialUniforms {
     fn from(mat: &StandardMaterial) -> Self {
         Self {
-            base_color: mat.base_color.to_linear().to_vec4(),
-            emissive: mat.emissive.to_vec4(),
+            base_color: mat.base_color,
+            emissive: mat.emissive,
             perceptual_roughness: mat.perceptual_roughness,
             metallic: mat.metallic,
             double_sided: mat.double_sided,
@@ -403,17 +572,38 @@ impl From<&StandardMaterial> for StandardMaterialUniforms {
             flip_normal_map_y: mat.flip_normal_map_y,
             reflectance: mat.specular_tint.to_linear().to_vec3() * mat.reflectance,
             alpha_blend: transparent_draw_from_alpha_mode(&mat.alpha_mode),
+            alpha_cutoff: match mat.alpha_mode {
+                AlphaMode::Mask(cutoff) => cutoff,
+                _ => 0.5,
+            },
             has_normal_map: mat.normal_map_texture.is_some(),
             base_color_texture: mat.base_color_texture.clone(),
             normal_map_texture: mat.normal_map_texture.clone(),
             metallic_roughness_texture: mat.metallic_roughness_texture.clone(),
             emissive_texture: mat.emissive_texture.clone(),
+            parallax_depth_scale: mat.parallax_depth_scale,
+            max_parallax_layer_count: mat.max_parallax_layer_count,
+            max_relief_mapping_search_steps: match mat.parallax_mapping_method {
+                ParallaxMappingMethod::Relief { max_steps } => max_steps as f32,
+                ParallaxMappingMethod::Occlusion => 0.0,
+            },
+            depth_map: mat.depth_map.clone(),
+            parallax: mat.depth_map.as_ref().map(|_| mat.parallax_mapping_method),
             alpha_mode: mat.alpha_mode,
             cull_mode: mat.cull_mode,
         }
     }
 }
 
+/// The weights of the first MAX_MORPH_TARGETS morph targets, the ones uploaded as vertex attributes.
+pub fn morph_weights_vec4(weights: &[f32]) -> Vec4 {
+    let mut v = [0.0; MAX_MORPH_TARGETS];
+    for (v, w) in v.iter_mut().zip(weights) {
+        *v = *w;
+    }
+    Vec4::from_array(v)
+}
+
 pub fn is_alpha_mask(alpha_mode: AlphaMode) -> bool {
     match alpha_mode {
         AlphaMode::Mask(_) => true,
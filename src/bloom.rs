@@ -0,0 +1,245 @@
+use bevy::prelude::*;
+use glow::HasContext;
+
+use crate::{
+    BevyGlContext, BlendState, RenderState, ShaderIndex,
+    command_encoder::remove_render_resource_on_restore,
+    framebuffer::{ColorTargetFormat, Framebuffer},
+    post_process::{
+        PostProcessPass, PostProcessPlugin, PostProcessSource, PostProcessStack,
+        bind_post_process_texture, set_linear_filter, update_post_process_hdr,
+        view_tonemapping_def,
+    },
+    render::RenderSet,
+    shader_cached,
+};
+
+const BLOOM_PASS: &str = "bloom";
+
+/// Adds a glow around the parts of the image brighter than BloomSettings::threshold while the resource exists. Runs
+/// as the first PostProcessStack pass, which reads the scene as linear HDR and does the tonemapping in place of the
+/// materials. Without half float render targets the bloom is taken from and added to the tonemapped image.
+///
+/// The bloom is blurred through a chain of half size textures: the source is downsampled down the chain, then each
+/// level is upsampled and added onto the next larger one.
+///
+/// Needs OpenGLStandardMaterialPlugin for the std::tonemapping shader include.
+pub struct BloomPlugin;
+
+impl Plugin for BloomPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<PostProcessPlugin>() {
+            app.add_plugins(PostProcessPlugin);
+        }
+        app.add_systems(
+            PostUpdate,
+            (
+                remove_render_resource_on_restore::<BloomTextures>.in_set(RenderSet::Acquire),
+                sync_bloom_pass
+                    .in_set(RenderSet::Prepare)
+                    .before(update_post_process_hdr),
+            ),
+        );
+    }
+}
+
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct BloomSettings {
+    /// Brightness in linear exposed color above which pixels bloom. Without half float render targets the bloom is
+    /// taken from the tonemapped image, where this needs to be below 1.
+    pub threshold: f32,
+    /// How much of the blurred bright parts is added to the image.
+    pub intensity: f32,
+    /// Number of textures in the chain, each half the size of the previous. More spread the glow further.
+    pub iterations: u32,
+}
+
+impl Default for BloomSettings {
+    fn default() -> Self {
+        Self {
+            threshold: 1.0,
+            intensity: 0.3,
+            iterations: 5,
+        }
+    }
+}
+
+impl BloomSettings {
+    pub fn pass(self) -> PostProcessPass {
+        let mut pass = PostProcessPass::new(BLOOM_PASS, bloom_composite_program)
+            .with_prepare(move |ctx, world, source| render_bloom(ctx, world, source, self))
+            .with_uniforms(move |ctx, world| {
+                let Some(textures) = world.get_resource::<BloomTextures>() else {
+                    return;
+                };
+                let Some(first) = textures.mips.first() else {
+                    return;
+                };
+                bind_post_process_texture(ctx, 1, first.texture);
+                ctx.load("bloom_texture", 1i32);
+                // Each level adds its own blur onto the one above it.
+                ctx.load(
+                    "bloom_intensity",
+                    self.intensity / textures.mips.len() as f32,
+                );
+                ctx.load("bloom_tonemap", textures.hdr_input);
+            });
+        pass.hdr_input = true;
+        pass
+    }
+}
+
+/// Keeps the bloom pass first in the stack while BloomSettings exists.
+fn sync_bloom_pass(settings: Option<Res<BloomSettings>>, mut stack: ResMut<PostProcessStack>) {
+    let index = stack.passes.iter().position(|pass| pass.name == BLOOM_PASS);
+    match (settings, index) {
+        (Some(settings), Some(index)) if settings.is_changed() => {
+            stack.passes[index] = settings.pass()
+        }
+        (Some(settings), None) => stack.passes.insert(0, settings.pass()),
+        (None, Some(index)) => {
+            stack.passes.remove(index);
+        }
+        _ => (),
+    }
+}
+
+fn bloom_composite_program(ctx: &mut BevyGlContext, world: &mut World) -> Option<ShaderIndex> {
+    shader_cached!(
+        ctx,
+        "shaders/post_process.vert",
+        "shaders/bloom_composite.frag",
+        [view_tonemapping_def(world)].iter(),
+        &[]
+    )
+}
+
+/// Render world mip chain, see BloomPlugin.
+#[derive(Resource)]
+struct BloomTextures {
+    source_size: UVec2,
+    iterations: u32,
+    /// The source was linear HDR, so the composite tonemaps.
+    hdr_input: bool,
+    mips: Vec<BloomMip>,
+}
+
+struct BloomMip {
+    texture: glow::Texture,
+    framebuffer: Framebuffer,
+    size: UVec2,
+}
+
+impl BloomTextures {
+    fn new(ctx: &BevyGlContext, source_size: UVec2, iterations: u32) -> Self {
+        let mut mips = Vec::new();
+        let mut size = source_size;
+        for i in 0..iterations.max(1) {
+            size = (size / 2).max(UVec2::ONE);
+            let texture = ColorTargetFormat::hdr(ctx, false).create_texture(ctx, size);
+            ctx.label_texture(texture, &format!("bloom_{i}"));
+            set_linear_filter(ctx, texture);
+            let Some(framebuffer) = Framebuffer::new(ctx, texture, false, size.x, size.y) else {
+                unsafe { ctx.gl.delete_texture(texture) };
+                break;
+            };
+            mips.push(BloomMip {
+                texture,
+                framebuffer,
+                size,
+            });
+            if size == UVec2::ONE {
+                break;
+            }
+        }
+        Self {
+            source_size,
+            iterations,
+            hdr_input: false,
+            mips,
+        }
+    }
+
+    fn delete(&self, gl: &glow::Context) {
+        for mip in &self.mips {
+            mip.framebuffer.delete(gl);
+            unsafe { gl.delete_texture(mip.texture) };
+        }
+    }
+}
+
+fn render_bloom(
+    ctx: &mut BevyGlContext,
+    world: &mut World,
+    source: &PostProcessSource,
+    settings: BloomSettings,
+) {
+    let current = world
+        .get_resource::<BloomTextures>()
+        .map(|textures| (textures.source_size, textures.iterations));
+    if current != Some((source.size, settings.iterations)) {
+        if let Some(textures) = world.remove_resource::<BloomTextures>() {
+            textures.delete(&ctx.gl);
+        }
+        world.insert_resource(BloomTextures::new(ctx, source.size, settings.iterations));
+    }
+    let mut textures = world.resource_mut::<BloomTextures>();
+    textures.hdr_input = source.hdr;
+    let textures = &*textures;
+
+    let (Some(prefilter), Some(downsample), Some(upsample)) = (
+        shader_cached!(
+            ctx,
+            "shaders/post_process.vert",
+            "shaders/bloom_downsample.frag",
+            [("BLOOM_PREFILTER", "")].iter(),
+            &[]
+        ),
+        shader_cached!(
+            ctx,
+            "shaders/post_process.vert",
+            "shaders/bloom_downsample.frag",
+            &[],
+            &[]
+        ),
+        shader_cached!(
+            ctx,
+            "shaders/post_process.vert",
+            "shaders/bloom_upsample.frag",
+            &[],
+            &[]
+        ),
+    ) else {
+        return;
+    };
+
+    let mut input = (source.texture, source.size);
+    for (i, mip) in textures.mips.iter().enumerate() {
+        let program = if i == 0 { prefilter } else { downsample };
+        mip.framebuffer.bind(ctx);
+        ctx.use_cached_program(program);
+        bind_post_process_texture(ctx, 0, input.0);
+        ctx.load("source_texture", 0i32);
+        ctx.load("source_size", input.1.as_vec2());
+        ctx.load("bloom_threshold", settings.threshold);
+        source.triangle.draw(ctx, program);
+        input = (mip.texture, mip.size);
+    }
+
+    let state = ctx.render_state;
+    ctx.apply_render_state(RenderState {
+        blend: true,
+        ..state
+    });
+    BlendState::ADDITIVE.apply(&ctx.gl);
+    for pair in textures.mips.windows(2).rev() {
+        let (target, smaller) = (&pair[0], &pair[1]);
+        target.framebuffer.bind(ctx);
+        ctx.use_cached_program(upsample);
+        bind_post_process_texture(ctx, 0, smaller.texture);
+        ctx.load("source_texture", 0i32);
+        ctx.load("source_size", smaller.size.as_vec2());
+        source.triangle.draw(ctx, upsample);
+    }
+    ctx.apply_render_state(state);
+}
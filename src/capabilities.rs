@@ -0,0 +1,194 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use glow::HasContext;
+
+use crate::{command_encoder::CommandEncoder, render::RenderSet};
+
+/// Sends the GlCapabilities of the context to the main world, where it's inserted as a resource once the context has
+/// been created. Render systems that run before that see no resource and should assume the defaults.
+pub struct GlCapabilitiesPlugin;
+
+impl Plugin for GlCapabilitiesPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PendingGlCapabilities>()
+            .add_systems(Startup, send_capabilities.in_set(RenderSet::Pipeline))
+            .add_systems(PostUpdate, insert_capabilities.in_set(RenderSet::Acquire));
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GlProfile {
+    /// GLES or WebGL
+    Es,
+    Core,
+    Compatibility,
+}
+
+/// Limits and optional features of the context, queried once when it's created. Available on the render thread as
+/// BevyGlContext::capabilities and in the main world as a resource.
+#[derive(Resource, Clone, Debug)]
+pub struct GlCapabilities {
+    pub profile: GlProfile,
+    pub major_version: u32,
+    pub minor_version: u32,
+    pub max_texture_size: u32,
+    pub max_cube_map_texture_size: u32,
+    /// Texture units available to the fragment shader.
+    pub max_texture_units: u32,
+    /// Texture units available to the vertex shader, 0 on some GLES 2 drivers.
+    pub max_vertex_texture_units: u32,
+    pub max_vertex_uniform_vectors: u32,
+    pub max_fragment_uniform_vectors: u32,
+    pub max_varying_vectors: u32,
+    pub max_vertex_attribs: u32,
+    /// 1.0 without EXT_texture_filter_anisotropic.
+    pub max_anisotropy: f32,
+    /// UNSIGNED_INT index buffers, otherwise meshes are split into u16 index buffers.
+    pub uint_indices: bool,
+    /// Half float textures can be sampled.
+    pub half_float_textures: bool,
+    /// R11F_G11F_B10F color attachments. Core in GL 3.0, needs EXT_color_buffer_float on GLES 3 and WebGL 2.
+    pub r11f_g11f_b10f_render_target: bool,
+    pub vertex_array_object: bool,
+    pub instancing: bool,
+    /// Samples per pixel of the window framebuffer, 0 without MSAA.
+    pub window_samples: u32,
+}
+
+impl GlCapabilities {
+    pub fn query(gl: &glow::Context, vertex_array_object: bool, instancing: bool) -> Self {
+        let version = gl.version();
+        let ext = gl.supported_extensions();
+        // WebGL extension names don't have the GL_ prefix.
+        let has_ext = |name: &str| ext.contains(name) || ext.contains(&format!("GL_{name}"));
+        let get = |param| unsafe { gl.get_parameter_i32(param) }.max(0) as u32;
+
+        let profile = if version.is_embedded {
+            GlProfile::Es
+        } else if (version.major, version.minor) >= (3, 2)
+            && unsafe { gl.get_parameter_i32(glow::CONTEXT_PROFILE_MASK) }
+                & glow::CONTEXT_CORE_PROFILE_BIT as i32
+                != 0
+        {
+            GlProfile::Core
+        } else {
+            GlProfile::Compatibility
+        };
+
+        // Desktop GL only has the *_VECTORS limits since 4.1, before that they're given in components.
+        let (max_vertex_uniform_vectors, max_fragment_uniform_vectors, max_varying_vectors) =
+            if version.is_embedded || (version.major, version.minor) >= (4, 1) {
+                (
+                    get(glow::MAX_VERTEX_UNIFORM_VECTORS),
+                    get(glow::MAX_FRAGMENT_UNIFORM_VECTORS),
+                    get(glow::MAX_VARYING_VECTORS),
+                )
+            } else {
+                (
+                    get(glow::MAX_VERTEX_UNIFORM_COMPONENTS) / 4,
+                    get(glow::MAX_FRAGMENT_UNIFORM_COMPONENTS) / 4,
+                    if profile == GlProfile::Core {
+                        get(glow::MAX_FRAGMENT_INPUT_COMPONENTS) / 4
+                    } else {
+                        get(glow::MAX_VARYING_FLOATS) / 4
+                    },
+                )
+            };
+
+        let max_anisotropy = if has_ext("EXT_texture_filter_anisotropic") {
+            unsafe { gl.get_parameter_f32(glow::MAX_TEXTURE_MAX_ANISOTROPY_EXT) }.max(1.0)
+        } else {
+            1.0
+        };
+
+        let (uint_indices, half_float_textures, r11f_g11f_b10f_render_target) =
+            if version.is_embedded {
+                (
+                    version.major >= 3 || has_ext("OES_element_index_uint"),
+                    version.major >= 3 || has_ext("OES_texture_half_float"),
+                    version.major >= 3 && has_ext("EXT_color_buffer_float"),
+                )
+            } else {
+                (
+                    true,
+                    version.major >= 3 || has_ext("ARB_half_float_pixel"),
+                    version.major >= 3,
+                )
+            };
+
+        GlCapabilities {
+            profile,
+            major_version: version.major,
+            minor_version: version.minor,
+            max_texture_size: get(glow::MAX_TEXTURE_SIZE),
+            max_cube_map_texture_size: get(glow::MAX_CUBE_MAP_TEXTURE_SIZE),
+            max_texture_units: get(glow::MAX_TEXTURE_IMAGE_UNITS),
+            max_vertex_texture_units: get(glow::MAX_VERTEX_TEXTURE_IMAGE_UNITS),
+            max_vertex_uniform_vectors,
+            max_fragment_uniform_vectors,
+            max_varying_vectors,
+            max_vertex_attribs: get(glow::MAX_VERTEX_ATTRIBS),
+            max_anisotropy,
+            uint_indices,
+            half_float_textures,
+            r11f_g11f_b10f_render_target,
+            vertex_array_object,
+            instancing,
+            // The window framebuffer is still bound when the context is created.
+            window_samples: get(glow::SAMPLES),
+        }
+    }
+
+    /// How many elements of `vectors_per_element` vec4 uniforms each fit in the fragment shader after `reserved`
+    /// vectors for its other uniforms. At most `max` and at least 1, arrays can't be empty.
+    pub fn fragment_array_fit(
+        &self,
+        max: usize,
+        vectors_per_element: usize,
+        reserved: usize,
+    ) -> usize {
+        array_fit(
+            self.max_fragment_uniform_vectors,
+            max,
+            vectors_per_element,
+            reserved,
+        )
+    }
+
+    /// Like fragment_array_fit() for the vertex shader.
+    pub fn vertex_array_fit(
+        &self,
+        max: usize,
+        vectors_per_element: usize,
+        reserved: usize,
+    ) -> usize {
+        array_fit(
+            self.max_vertex_uniform_vectors,
+            max,
+            vectors_per_element,
+            reserved,
+        )
+    }
+}
+
+fn array_fit(available: u32, max: usize, vectors_per_element: usize, reserved: usize) -> usize {
+    ((available as usize).saturating_sub(reserved) / vectors_per_element.max(1))
+        .clamp(1, max.max(1))
+}
+
+#[derive(Resource, Default)]
+struct PendingGlCapabilities(Arc<Mutex<Option<GlCapabilities>>>);
+
+fn send_capabilities(pending: Res<PendingGlCapabilities>, mut enc: ResMut<CommandEncoder>) {
+    let pending = pending.0.clone();
+    enc.record(move |ctx, _world| {
+        *pending.lock().unwrap() = Some(ctx.capabilities.clone());
+    });
+}
+
+fn insert_capabilities(mut commands: Commands, pending: Res<PendingGlCapabilities>) {
+    if let Some(capabilities) = pending.0.lock().unwrap().take() {
+        commands.insert_resource(capabilities);
+    }
+}
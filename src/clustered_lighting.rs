@@ -0,0 +1,289 @@
+use bevy::{image::ImageFilterMode, prelude::*, render::render_resource::TextureFormat};
+use glow::{HasContext, PixelUnpackData};
+
+use crate::{
+    bevy_standard_lighting::StandardLightingUniforms,
+    command_encoder::{CommandEncoder, GlContextRestored},
+    prepare_image::{GpuImages, TextureRef, create_raw_texture},
+    render::{RenderCamera, RenderSet},
+};
+
+/// Length of the point light arrays with ClusteredLightingPlugin. Each light takes 3 vec4 fragment uniforms.
+pub const CLUSTERED_MAX_LIGHTS: usize = 32;
+pub const CLUSTERED_MAX_LIGHTS_DEF: (&str, &str) = ("MAX_POINT_LIGHTS", "32");
+/// Light indices stored per cluster, 4 per texel. Must match CLUSTER_TEXELS in standard_pbr_lighting.glsl. Lights past
+/// this are dropped from the cluster.
+pub const MAX_LIGHTS_PER_CLUSTER: usize = 16;
+
+/// Splits the view of the first camera into a grid of clusters, screen space tiles and exponential depth slices, and
+/// assigns point and spot lights to the clusters their range overlaps. Materials using std::pbr_lighting then only
+/// evaluate the lights in each fragment's cluster, which allows up to CLUSTERED_MAX_LIGHTS lights instead of
+/// the 8 of DEFAULT_MAX_LIGHTS_DEF. Shaders need StandardLightingUniforms::max_lights_def().
+///
+/// Costs a texture unit and a texture lookup per 4 lights in the cluster. Clusters are only built for the first
+/// camera, reflections and shadows loop over every light. Other cameras would use the first camera's clusters, so
+/// don't combine this with several cameras.
+///
+/// WebGL 1 / GLES 2: GLSL ES 1.00 only guarantees indexing uniform arrays with loop indices, the light index read from
+/// the cluster texture relies on the driver supporting more (ANGLE does). The light arrays also need about
+/// 3 * CLUSTERED_MAX_LIGHTS fragment uniform vectors, far more than WebGL 1's minimum of 16.
+pub struct ClusteredLightingPlugin;
+
+impl Plugin for ClusteredLightingPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ClusteredLightingSettings>()
+            .init_resource::<LightClusterTexture>()
+            .add_systems(PostUpdate, assign_light_clusters.in_set(RenderSet::Prepare));
+    }
+}
+
+#[derive(Resource, Clone, Copy)]
+pub struct ClusteredLightingSettings {
+    /// Tiles across x and y of the screen, depth slices in z.
+    pub grid: UVec3,
+    /// Depth of the last slice. Anything further uses the last slice.
+    pub far: f32,
+}
+
+impl Default for ClusteredLightingSettings {
+    fn default() -> Self {
+        Self {
+            grid: uvec3(16, 9, 24),
+            far: 200.0,
+        }
+    }
+}
+
+#[derive(Resource, Default)]
+struct LightClusterTexture {
+    texture: TextureRef,
+    size: UVec2,
+}
+
+fn assign_light_clusters(
+    settings: Res<ClusteredLightingSettings>,
+    mut cluster_texture: ResMut<LightClusterTexture>,
+    render_camera: Option<Res<RenderCamera>>,
+    cameras: Query<(&GlobalTransform, &Projection)>,
+    mut enc: ResMut<CommandEncoder>,
+    mut restored: MessageReader<GlContextRestored>,
+) {
+    if !restored.is_empty() {
+        restored.clear();
+        // The texture went with the old context, start over with a new slot instead of deleting it.
+        *cluster_texture = LightClusterTexture::default();
+    }
+    let Some((cam_global_trans, cam_proj)) = render_camera.and_then(|c| cameras.get(**c).ok())
+    else {
+        return;
+    };
+    let grid = settings.grid.max(UVec3::ONE);
+    let view_from_world = cam_global_trans.to_matrix().inverse();
+    let clip_from_view = cam_proj.get_clip_from_view();
+    let near = match cam_proj {
+        Projection::Perspective(p) => p.near,
+        Projection::Orthographic(o) => o.near,
+        Projection::Custom(_) => 0.1,
+    }
+    .max(0.01);
+    let far = settings.far.max(near * 2.0);
+    let z_scale = grid.z as f32 / (far / near).ln();
+    let z_bias = -near.ln() * z_scale;
+
+    let size = uvec2(
+        grid.x * (MAX_LIGHTS_PER_CLUSTER as u32 / 4),
+        grid.y * grid.z,
+    );
+    let resize = cluster_texture.size != size;
+    cluster_texture.size = size;
+    let texture_ref = cluster_texture.texture.clone();
+
+    enc.record(move |ctx, world| {
+        let data = {
+            let mut lighting = world.resource_mut::<StandardLightingUniforms>();
+            lighting.cluster_texture = texture_ref.clone();
+            lighting.cluster_grid = grid.as_vec3().extend(0.0);
+            lighting.cluster_z_params = vec4(z_scale, z_bias, size.x as f32, size.y as f32);
+            lighting.cluster_view_z_from_world = view_from_world.row(2);
+            let light_count = (lighting.light_count.max(0) as usize)
+                .min(lighting.point_light_position_range.len());
+            cluster_light_indices(
+                &lighting.point_light_position_range[..light_count],
+                view_from_world,
+                clip_from_view,
+                near,
+                [z_scale, z_bias],
+                grid,
+            )
+        };
+
+        let mut gpu_images = world.resource_mut::<GpuImages>();
+        match gpu_images.texture_from_ref(&texture_ref) {
+            Some((texture, _)) if !resize => unsafe {
+                ctx.gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+                ctx.gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 4);
+                ctx.gl.tex_sub_image_2d(
+                    glow::TEXTURE_2D,
+                    0,
+                    0,
+                    0,
+                    size.x as i32,
+                    size.y as i32,
+                    glow::RGBA,
+                    glow::UNSIGNED_BYTE,
+                    PixelUnpackData::Slice(Some(&data)),
+                );
+            },
+            old => {
+                if let Some((texture, target)) = create_raw_texture(
+                    ctx,
+                    size.x,
+                    size.y,
+                    TextureFormat::Rgba8Unorm,
+                    data,
+                    ImageFilterMode::Nearest,
+                ) {
+                    ctx.label_texture(texture, "light_clusters");
+                    // A resize reuses the slot of the old texture.
+                    match texture_ref.get() {
+                        Some(idx) => gpu_images.raw_textures[idx as usize] = (texture, target),
+                        None => {
+                            gpu_images.add_texture_set_ref(texture, target, &texture_ref);
+                        }
+                    }
+                }
+                if let Some((texture, _)) = old {
+                    unsafe { ctx.gl.delete_texture(texture) };
+                }
+            }
+        }
+    });
+}
+
+/// Rows of (grid.x * MAX_LIGHTS_PER_CLUSTER / 4) RGBA8 texels, one row per tile row and depth slice. Each cluster's
+/// light indices are packed in its texels, 255 marks an empty slot. Lights are bounding spheres of position_range.
+pub fn cluster_light_indices(
+    position_range: &[Vec4],
+    view_from_world: Mat4,
+    clip_from_view: Mat4,
+    near: f32,
+    [z_scale, z_bias]: [f32; 2],
+    grid: UVec3,
+) -> Vec<u8> {
+    let row_bytes = (grid.x as usize) * MAX_LIGHTS_PER_CLUSTER;
+    let mut data = vec![255u8; row_bytes * (grid.y * grid.z) as usize];
+    let mut counts = vec![0usize; (grid.x * grid.y * grid.z) as usize];
+    let slice = |depth: f32| {
+        ((depth.max(near).ln() * z_scale + z_bias).floor() as i32).clamp(0, grid.z as i32 - 1)
+            as u32
+    };
+    let tile = |ndc: Vec2| {
+        ((ndc * 0.5 + 0.5) * grid.xy().as_vec2())
+            .floor()
+            .as_ivec2()
+            .clamp(IVec2::ZERO, grid.xy().as_ivec2() - 1)
+            .as_uvec2()
+    };
+
+    for (i, light) in position_range.iter().enumerate().take(255) {
+        let center = view_from_world.transform_point3(light.xyz());
+        let radius = light.w;
+        let min_depth = -center.z - radius;
+        let max_depth = -center.z + radius;
+        if max_depth < near {
+            continue;
+        }
+        let (tile_min, tile_max) = if min_depth <= near {
+            // Crosses the near plane, the projection of the bounds isn't reliable.
+            (UVec2::ZERO, grid.xy() - 1)
+        } else {
+            let mut ndc_min = Vec2::MAX;
+            let mut ndc_max = Vec2::MIN;
+            for corner in 0..8 {
+                let offset = vec3(
+                    if corner & 1 == 0 { -radius } else { radius },
+                    if corner & 2 == 0 { -radius } else { radius },
+                    if corner & 4 == 0 { -radius } else { radius },
+                );
+                let clip = clip_from_view * (center + offset).extend(1.0);
+                let ndc = clip.xy() / clip.w;
+                ndc_min = ndc_min.min(ndc);
+                ndc_max = ndc_max.max(ndc);
+            }
+            if ndc_max.cmplt(Vec2::NEG_ONE).any() || ndc_min.cmpgt(Vec2::ONE).any() {
+                continue;
+            }
+            (tile(ndc_min), tile(ndc_max))
+        };
+        for z in slice(min_depth)..=slice(max_depth) {
+            for y in tile_min.y..=tile_max.y {
+                for x in tile_min.x..=tile_max.x {
+                    let cluster = (x + y * grid.x + z * grid.x * grid.y) as usize;
+                    if counts[cluster] >= MAX_LIGHTS_PER_CLUSTER {
+                        continue;
+                    }
+                    let row = (y + z * grid.y) as usize;
+                    data[row * row_bytes + x as usize * MAX_LIGHTS_PER_CLUSTER + counts[cluster]] =
+                        i as u8;
+                    counts[cluster] += 1;
+                }
+            }
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cluster_light_indices() {
+        let grid = uvec3(4, 4, 4);
+        let near = 0.1;
+        let far = 100.0;
+        let z_scale = grid.z as f32 / (far / near).ln();
+        let z_bias = -near.ln() * z_scale;
+        let lights = [
+            // Straight ahead at a depth of 9 to 11, slice 2.
+            vec4(0.0, 0.0, -10.0, 1.0),
+            // Behind the camera
+            vec4(0.0, 0.0, 5.0, 1.0),
+            // Around the camera, crosses the near plane.
+            vec4(0.0, 0.0, 0.0, 1.0),
+        ];
+        let data = cluster_light_indices(
+            &lights,
+            Mat4::IDENTITY,
+            Mat4::perspective_infinite_reverse_rh(std::f32::consts::FRAC_PI_2, 1.0, near),
+            near,
+            [z_scale, z_bias],
+            grid,
+        );
+        let row_bytes = grid.x as usize * MAX_LIGHTS_PER_CLUSTER;
+        let cluster = |x: u32, y: u32, z: u32| {
+            let start = (y + z * grid.y) as usize * row_bytes + x as usize * MAX_LIGHTS_PER_CLUSTER;
+            data[start..start + MAX_LIGHTS_PER_CLUSTER]
+                .iter()
+                .copied()
+                .take_while(|&i| i != 255)
+                .collect::<Vec<_>>()
+        };
+        for z in 0..grid.z {
+            for y in 0..grid.y {
+                for x in 0..grid.x {
+                    let mut expected = Vec::new();
+                    // Covers the center 2x2 tiles.
+                    if z == 2 && (1..=2).contains(&x) && (1..=2).contains(&y) {
+                        expected.push(0);
+                    }
+                    // Every tile up to a depth of 1.
+                    if z <= 1 {
+                        expected.push(2);
+                    }
+                    assert_eq!(cluster(x, y, z), expected, "cluster {x} {y} {z}");
+                }
+            }
+        }
+    }
+}
@@ -1,38 +1,128 @@
+#[cfg(target_arch = "wasm32")]
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
 #[cfg(not(target_arch = "wasm32"))]
 use std::{
     sync::mpsc::{Receiver, SyncSender, sync_channel},
     thread,
 };
 
-use bevy::prelude::*;
+use bevy::{image::ImageFilterMode, prelude::*, render::render_resource::TextureFormat};
 use glow::HasContext;
 use wgpu_types::Face;
 
 use crate::{
-    BevyGlContext, WindowInitData,
-    prepare_image::{GpuImages, TextureRef},
+    BevyGlContext, BlendState, StencilState, WindowInitData,
+    framebuffer::TextureTargets,
+    prepare_image::{GpuImages, TextureQualitySettings, TextureRef},
     render::RenderSet,
 };
 
+#[cfg(target_arch = "wasm32")]
+use crate::prepare_mesh::GpuMeshes;
+
 pub struct CommandEncoderPlugin;
 
 impl Plugin for CommandEncoderPlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<CommandEncoder>()
+            .add_message::<GlContextRestored>()
             .add_systems(PostUpdate, send.in_set(RenderSet::SubmitEncoder));
     }
 }
 
+/// Whether the render thread has been started with init_gl(). Until then recorded commands stay queued in
+/// CommandEncoder and only RenderSet::Init and RenderSet::SubmitEncoder run. Systems outside the render sets that
+/// record every frame should also run_if(gl_initialized), so the queue doesn't grow while there's no window.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn gl_initialized(sender: Option<Res<CommandEncoderSender>>) -> bool {
+    sender.is_some()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn gl_initialized(sender: Option<NonSend<CommandEncoderSender>>) -> bool {
+    sender.is_some()
+}
+
 #[cfg(not(target_arch = "wasm32"))]
-fn send(mut enc: ResMut<CommandEncoder>, sender: Res<CommandEncoderSender>) {
+fn send(mut enc: ResMut<CommandEncoder>, sender: Option<Res<CommandEncoderSender>>) {
+    // Keep uploads recorded before there's a window for the context to be created with.
+    let Some(sender) = sender else {
+        return;
+    };
     let mut new_cmd_encoder = CommandEncoder::default();
     std::mem::swap(&mut *enc, &mut new_cmd_encoder);
     sender.sender.send(new_cmd_encoder).unwrap();
 }
 
+/// Sent after a lost WebGL context is restored. Every GL object is gone by then: the cached programs, GpuImages,
+/// GpuMeshes and TextureTargets are reset, and every Image and Mesh is uploaded again. Resources that hold GL objects
+/// or refer to their textures are removed with remove_render_resource_on_restore() and remove_resource_on_restore(),
+/// or reset by the systems that create them, so they're created again. Textures from CommandEncoder::bevy_image()
+/// have to be created again by whoever requested them.
+#[derive(Message, Clone, Copy, Debug)]
+pub struct GlContextRestored;
+
+/// Removes the render world resource R after GlContextRestored, for resources holding GL objects that are created again
+/// when they're missing. Run in RenderSet::Acquire. The objects went with the old context, there's nothing to delete.
+pub fn remove_render_resource_on_restore<R: Resource>(
+    mut restored: MessageReader<GlContextRestored>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    if restored.is_empty() {
+        return;
+    }
+    restored.clear();
+    enc.record(|_, world| {
+        world.remove_resource::<R>();
+    });
+}
+
+/// Removes the main world resource R after GlContextRestored, for resources with TextureRefs to textures that are
+/// created along with R. Run in RenderSet::Acquire, so the Prepare systems create it again.
+pub fn remove_resource_on_restore<R: Resource>(
+    mut restored: MessageReader<GlContextRestored>,
+    mut commands: Commands,
+) {
+    if restored.is_empty() {
+        return;
+    }
+    restored.clear();
+    commands.remove_resource::<R>();
+}
+
 #[cfg(target_arch = "wasm32")]
-fn send(mut enc: ResMut<CommandEncoder>, mut sender: NonSendMut<CommandEncoderSender>) {
-    let CommandEncoderSender { ctx, world } = &mut *sender;
+fn send(
+    mut enc: ResMut<CommandEncoder>,
+    sender: Option<NonSendMut<CommandEncoderSender>>,
+    mut restored: MessageWriter<GlContextRestored>,
+) {
+    let Some(mut sender) = sender else {
+        return;
+    };
+    let CommandEncoderSender {
+        ctx,
+        world,
+        context_loss,
+    } = &mut *sender;
+    if context_loss.take_restored() {
+        ctx.forget_gl_objects();
+        if let Some(mut images) = world.get_resource_mut::<GpuImages>() {
+            images.forget_gl_objects();
+        }
+        if let Some(mut meshes) = world.get_resource_mut::<GpuMeshes>() {
+            *meshes = GpuMeshes::default();
+        }
+        world.remove_resource::<TextureTargets>();
+        restored.write(GlContextRestored);
+    }
+    if context_loss.is_lost() {
+        // Nothing can be drawn. Uploads recorded meanwhile are redone after GlContextRestored.
+        *enc = CommandEncoder::default();
+        return;
+    }
     enc.commands.drain(..).for_each(|cmd| cmd(ctx, world));
     *enc = CommandEncoder::default();
 }
@@ -47,6 +137,61 @@ pub struct CommandEncoderSender {
 pub struct CommandEncoderSender {
     pub ctx: BevyGlContext,
     pub world: World,
+    context_loss: ContextLoss,
+}
+
+/// Set by the canvas' webglcontextlost and webglcontextrestored events.
+#[cfg(target_arch = "wasm32")]
+#[derive(Default)]
+struct ContextLoss {
+    lost: Arc<AtomicBool>,
+    restored: Arc<AtomicBool>,
+}
+
+#[cfg(target_arch = "wasm32")]
+impl ContextLoss {
+    fn listen(canvas: &web_sys::HtmlCanvasElement) -> Self {
+        use wasm_bindgen::{JsCast, closure::Closure};
+
+        let context_loss = ContextLoss::default();
+        let lost = context_loss.lost.clone();
+        let on_lost = Closure::<dyn FnMut(web_sys::Event)>::new(move |event: web_sys::Event| {
+            // Otherwise the browser doesn't restore the context.
+            event.prevent_default();
+            lost.store(true, Ordering::Relaxed);
+        });
+        let restored = context_loss.restored.clone();
+        let on_restored = Closure::<dyn FnMut(web_sys::Event)>::new(move |_: web_sys::Event| {
+            restored.store(true, Ordering::Relaxed);
+        });
+        for (event, callback) in [
+            ("webglcontextlost", &on_lost),
+            ("webglcontextrestored", &on_restored),
+        ] {
+            if let Err(e) =
+                canvas.add_event_listener_with_callback(event, callback.as_ref().unchecked_ref())
+            {
+                warn!("Couldn't listen for {event}: {e:?}");
+            }
+        }
+        // The listeners stay registered for as long as the canvas exists.
+        on_lost.forget();
+        on_restored.forget();
+        context_loss
+    }
+
+    fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Relaxed)
+    }
+
+    /// True once after the context was restored.
+    fn take_restored(&self) -> bool {
+        let restored = self.restored.swap(false, Ordering::Relaxed);
+        if restored {
+            self.lost.store(false, Ordering::Relaxed);
+        }
+        restored
+    }
 }
 
 impl CommandEncoderSender {
@@ -60,6 +205,7 @@ impl CommandEncoderSender {
         #[cfg(target_arch = "wasm32")]
         {
             CommandEncoderSender {
+                context_loss: ContextLoss::listen(&window_init_data.canvas),
                 ctx: BevyGlContext::new(window_init_data),
                 world: World::new(),
             }
@@ -82,6 +228,9 @@ impl CommandEncoderSender {
     }
 }
 
+/// Called on the render thread with RGBA8 pixels ordered top row first, and the size of the region that was read.
+pub type FramebufferReadCallback = Box<dyn FnOnce(Vec<u8>, UVec2) + Send + Sync>;
+
 #[derive(Resource, Default)]
 /// Used to record commands, deferring rendering to be executed on the render thread.
 /// The ECS world here is very minimal. It has no entities, systems or components. Just a few resources. This is to
@@ -89,6 +238,10 @@ impl CommandEncoderSender {
 pub struct CommandEncoder {
     pub commands: Vec<Box<dyn FnOnce(&mut BevyGlContext, &mut World) + Send + Sync>>,
     pub next_buffer_id: usize,
+    /// Reads requested with read_framebuffer(). These are recorded in RenderSet::Present, just before swapping.
+    pub framebuffer_reads: Vec<(Option<URect>, FramebufferReadCallback)>,
+    /// The texture bound with bind_target(), None while drawing to the window. Unbound in present() before swapping.
+    pub target: Option<TextureRef>,
 }
 
 impl CommandEncoder {
@@ -103,9 +256,14 @@ impl CommandEncoder {
         let texture_ref = TextureRef::new();
         let return_tex = texture_ref.clone();
         self.record(move |ctx, world| {
+            let settings = world
+                .get_resource::<TextureQualitySettings>()
+                .copied()
+                .unwrap_or_default();
             world.resource_mut::<GpuImages>().add_bevy_image_set_ref(
                 ctx,
                 None,
+                &settings,
                 &image,
                 &texture_ref,
             );
@@ -113,6 +271,36 @@ impl CommandEncoder {
         return_tex
     }
 
+    /// Uploads pixels generated on the CPU, like a LUT or noise, without going through Assets<Image>. See
+    /// GpuImages::add_raw_texture() for the supported formats and data layout. A 1D LUT is a texture with a height of 1.
+    pub fn raw_texture(
+        &mut self,
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+        data: Vec<u8>,
+        filter: ImageFilterMode,
+    ) -> TextureRef {
+        let texture_ref = TextureRef::new();
+        let return_tex = texture_ref.clone();
+        self.record(move |ctx, world| {
+            if let Some(idx) = world
+                .resource_mut::<GpuImages>()
+                .add_raw_texture(ctx, width, height, format, data, filter)
+            {
+                texture_ref.set(idx);
+            }
+        });
+        return_tex
+    }
+
+    /// See BevyGlContext::set_viewport()
+    pub fn set_viewport(&mut self, rect: Option<URect>) {
+        self.record(move |ctx, _world| {
+            ctx.set_viewport(rect);
+        });
+    }
+
     pub fn clear_color_and_depth(&mut self, color: Option<Vec4>) {
         self.record(move |ctx, _world| {
             ctx.clear_color_and_depth(color);
@@ -156,6 +344,98 @@ impl CommandEncoder {
         });
     }
 
+    /// glow::FILL, glow::LINE or glow::POINT. Desktop GL only, a no-op on WebGL.
+    pub fn set_polygon_mode(&mut self, polygon_mode: u32) {
+        self.record(move |ctx, _world| {
+            ctx.set_polygon_mode(polygon_mode);
+        });
+    }
+
+    /// See BevyGlContext::set_blend_state(). The start_* functions reset the blend state.
+    pub fn set_blend_state(&mut self, blend_state: BlendState) {
+        self.record(move |ctx, _world| {
+            ctx.set_blend_state(blend_state);
+        });
+    }
+
+    /// See BevyGlContext::set_stencil_state(). The start_* functions disable the stencil test.
+    pub fn set_stencil_state(&mut self, stencil_state: Option<StencilState>) {
+        self.record(move |ctx, _world| {
+            ctx.set_stencil_state(stencil_state);
+        });
+    }
+
+    /// See BevyGlContext::set_polygon_offset(). Call clear_polygon_offset() after the draws that need it.
+    pub fn set_polygon_offset(&mut self, factor: f32, units: f32) {
+        self.record(move |ctx, _world| {
+            ctx.set_polygon_offset(factor, units);
+        });
+    }
+
+    pub fn clear_polygon_offset(&mut self) {
+        self.record(move |ctx, _world| {
+            ctx.clear_polygon_offset();
+        });
+    }
+
+    /// Reads back the window framebuffer after this frame has been rendered, just before it's presented. rect is in
+    /// physical pixels with the origin at the top left, None reads the whole window. The callback runs on the render
+    /// thread. This stalls until the GPU has finished the frame.
+    /// On WebGL the drawing buffer is cleared once the browser composites it, so it can't be read after present.
+    pub fn read_framebuffer<F>(&mut self, rect: Option<URect>, callback: F)
+    where
+        F: FnOnce(Vec<u8>, UVec2) + Send + Sync + 'static,
+    {
+        self.framebuffer_reads.push((rect, Box::new(callback)));
+    }
+
+    /// Reads texture_ref back to the CPU where this is recorded, for thumbnails or inspecting render targets like the
+    /// shadow map. size is needed since GL ES can't query it. The callback runs on the render thread and gets None if
+    /// the texture isn't available or can't be read, see BevyGlContext::read_texture().
+    pub fn read_texture<F>(&mut self, texture_ref: TextureRef, size: UVec2, callback: F)
+    where
+        F: FnOnce(Option<Image>) + Send + Sync + 'static,
+    {
+        self.record(move |ctx, world| {
+            let texture = world
+                .resource_mut::<GpuImages>()
+                .texture_from_ref(&texture_ref);
+            callback(texture.and_then(|(texture, target)| ctx.read_texture(texture, target, size)));
+        });
+    }
+
+    /// Redirects the commands recorded after this into texture_ref until unbind_target(), for render to texture effects
+    /// like a minimap or portal. texture_ref must be a 2D texture of size, e.g. from raw_texture() with Rgba8Unorm. It
+    /// gets a depth buffer, clear it along with the color. The viewport is set to the whole texture.
+    pub fn bind_target(&mut self, texture_ref: TextureRef, size: UVec2) {
+        self.target = Some(texture_ref.clone());
+        self.record(move |ctx, world| {
+            let Some((texture, target)) = world
+                .resource_mut::<GpuImages>()
+                .texture_from_ref(&texture_ref)
+            else {
+                return;
+            };
+            if target != glow::TEXTURE_2D {
+                warn_once!("bind_target() only supports 2D textures");
+                return;
+            }
+            world
+                .get_resource_or_init::<TextureTargets>()
+                .bind(ctx, texture, size);
+        });
+    }
+
+    /// Goes back to drawing to what was bound before bind_target() and resets the viewport to the full window.
+    pub fn unbind_target(&mut self) {
+        self.target = None;
+        self.record(move |ctx, world| {
+            if let Some(mut targets) = world.get_resource_mut::<TextureTargets>() {
+                targets.unbind(ctx);
+            }
+        });
+    }
+
     /// Only calls flush on webgl
     pub fn swap(&mut self) {
         self.record(move |ctx, _world| {
@@ -169,6 +449,9 @@ impl CommandEncoder {
                 .resource_mut::<GpuImages>()
                 .texture_from_ref(&texture_ref)
             {
+                if let Some(mut targets) = world.get_resource_mut::<TextureTargets>() {
+                    targets.remove(&ctx.gl, tex);
+                }
                 ctx.gl.delete_texture(tex);
             }
         });
@@ -182,3 +465,47 @@ impl CommandEncoder {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::render::configure_render_sets;
+
+    #[derive(Resource, Default)]
+    struct Prepared(u32);
+
+    fn prepare_every_frame(mut enc: ResMut<CommandEncoder>, mut prepared: ResMut<Prepared>) {
+        prepared.0 += 1;
+        enc.record(|_ctx, _world| {});
+    }
+
+    #[test]
+    fn test_commands_queue_until_gl_initialized() {
+        let mut app = App::new();
+        app.add_plugins(CommandEncoderPlugin)
+            .init_resource::<Prepared>()
+            .add_systems(PostUpdate, prepare_every_frame.in_set(RenderSet::Prepare));
+        configure_render_sets(&mut app);
+        app.world_mut()
+            .resource_mut::<CommandEncoder>()
+            .record(|_ctx, _world| {});
+
+        // No window, so init_gl() hasn't created the render thread. The upload has to survive until it does, without
+        // per-frame commands piling up behind it.
+        for _ in 0..10 {
+            app.update();
+        }
+        assert!(!app.world_mut().run_system_cached(gl_initialized).unwrap());
+        assert_eq!(app.world().resource::<Prepared>().0, 0);
+        assert_eq!(app.world().resource::<CommandEncoder>().commands.len(), 1);
+
+        // The window shows up and init_gl() starts the render thread.
+        let (sender, receiver) = sync_channel(1);
+        app.insert_resource(CommandEncoderSender { sender });
+        app.update();
+        assert!(app.world_mut().run_system_cached(gl_initialized).unwrap());
+        assert_eq!(app.world().resource::<Prepared>().0, 1);
+        assert_eq!(receiver.try_recv().unwrap().commands.len(), 2);
+        assert!(app.world().resource::<CommandEncoder>().commands.is_empty());
+    }
+}
@@ -0,0 +1,233 @@
+use std::f32::consts::TAU;
+
+use bevy::{camera::primitives::Aabb, prelude::*};
+use bytemuck::cast_slice;
+use glow::{Buffer, HasContext, VertexArray};
+
+use crate::{
+    AttribType, BevyGlContext, RenderState,
+    command_encoder::{CommandEncoder, remove_render_resource_on_restore},
+    phase_opaque::camera_viewport,
+    prepare_image::GpuImages,
+    prepare_view::ViewUniforms,
+    render::{RenderCamera, RenderSet},
+    shader_cached,
+};
+
+/// Draws the lines added to DebugLines each frame over the first camera, after the transparent phase. Uses the
+/// camera's ViewUniforms, so needs ViewUniformsPlugin or OpenGLStandardMaterialPlugin.
+pub struct DebugLinesPlugin;
+
+impl Plugin for DebugLinesPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DebugLines>().add_systems(
+            PostUpdate,
+            (
+                remove_render_resource_on_restore::<DebugLineBuffers>.in_set(RenderSet::Acquire),
+                render_debug_lines.in_set(RenderSet::RenderDebug),
+            ),
+        );
+    }
+}
+
+/// Immediate mode lines. Everything added is drawn once with glow::LINES and then cleared. Colors are written after
+/// tonemapping, so they show up as specified instead of being affected by exposure.
+#[derive(Resource)]
+pub struct DebugLines {
+    /// Hide lines behind the scene depth. Lines never write depth.
+    pub depth_test: bool,
+    positions: Vec<Vec3>,
+    colors: Vec<Vec4>,
+}
+
+impl Default for DebugLines {
+    fn default() -> Self {
+        Self {
+            depth_test: true,
+            positions: Vec::new(),
+            colors: Vec::new(),
+        }
+    }
+}
+
+impl DebugLines {
+    pub fn line(&mut self, a: Vec3, b: Vec3, color: impl Into<Color>) {
+        let color = color.into().to_srgba().to_vec4();
+        self.positions.extend([a, b]);
+        self.colors.extend([color, color]);
+    }
+
+    /// The 12 edges of aabb, transformed by transform.
+    pub fn aabb(&mut self, aabb: &Aabb, transform: &GlobalTransform, color: impl Into<Color>) {
+        let center = Vec3::from(aabb.center);
+        let half_extents = Vec3::from(aabb.half_extents);
+        let corners = box_corners(|corner| {
+            transform.transform_point(center + half_extents * (corner * 2.0 - 1.0))
+        });
+        self.box_edges(corners, color.into());
+    }
+
+    /// The volume clip_from_world maps to NDC, -1 to 1 on x and y and 0 to 1 on z. Shows the bounds of orthographic
+    /// views like shadow cascades, the far plane of infinite perspective projections can't be drawn.
+    pub fn frustum(&mut self, clip_from_world: Mat4, color: impl Into<Color>) {
+        let world_from_clip = clip_from_world.inverse();
+        let corners = box_corners(|corner| {
+            world_from_clip.project_point3(vec3(
+                corner.x * 2.0 - 1.0,
+                corner.y * 2.0 - 1.0,
+                corner.z,
+            ))
+        });
+        self.box_edges(corners, color.into());
+    }
+
+    /// Circles around each axis, for light ranges and other bounding spheres.
+    pub fn sphere(&mut self, center: Vec3, radius: f32, color: impl Into<Color>) {
+        const SEGMENTS: usize = 32;
+        let color = color.into();
+        for (u, v) in [(Vec3::X, Vec3::Y), (Vec3::Y, Vec3::Z), (Vec3::Z, Vec3::X)] {
+            let point = |i: usize| {
+                let (sin, cos) = (i as f32 / SEGMENTS as f32 * TAU).sin_cos();
+                center + (u * cos + v * sin) * radius
+            };
+            for i in 0..SEGMENTS {
+                self.line(point(i), point(i + 1), color);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.positions.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.positions.clear();
+        self.colors.clear();
+    }
+
+    fn box_edges(&mut self, corners: [Vec3; 8], color: Color) {
+        for (i, corner) in corners.iter().enumerate() {
+            for axis in [1, 2, 4] {
+                if i & axis == 0 {
+                    self.line(*corner, corners[i | axis], color);
+                }
+            }
+        }
+    }
+}
+
+/// Corners of a box, f is given each corner of the unit cube. Bits 0, 1, 2 of the index select x, y, z.
+fn box_corners(f: impl Fn(Vec3) -> Vec3) -> [Vec3; 8] {
+    std::array::from_fn(|i| {
+        f(vec3(
+            (i & 1) as f32,
+            ((i >> 1) & 1) as f32,
+            ((i >> 2) & 1) as f32,
+        ))
+    })
+}
+
+/// Render world vertex buffers, grown as needed.
+#[derive(Resource)]
+struct DebugLineBuffers {
+    positions: Buffer,
+    colors: Buffer,
+    /// In vertices
+    capacity: usize,
+    vertex_array: Option<VertexArray>,
+}
+
+impl DebugLineBuffers {
+    fn new(ctx: &BevyGlContext, capacity: usize) -> Self {
+        Self {
+            positions: ctx.gen_vbo(&vec![0; capacity * 12], glow::DYNAMIC_DRAW),
+            colors: ctx.gen_vbo(&vec![0; capacity * 16], glow::DYNAMIC_DRAW),
+            capacity,
+            vertex_array: ctx
+                .has_vertex_array_object
+                .then(|| unsafe { ctx.gl.create_vertex_array().unwrap() }),
+        }
+    }
+
+    fn delete(&self, gl: &glow::Context) {
+        unsafe {
+            gl.delete_buffer(self.positions);
+            gl.delete_buffer(self.colors);
+            if let Some(vertex_array) = self.vertex_array {
+                gl.delete_vertex_array(vertex_array);
+            }
+        }
+    }
+}
+
+fn render_debug_lines(
+    mut lines: ResMut<DebugLines>,
+    render_camera: Option<Res<RenderCamera>>,
+    cameras: Query<(&Camera, &ViewUniforms)>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    if lines.is_empty() {
+        return;
+    }
+    let Some((camera, view_uniforms)) = render_camera.and_then(|c| cameras.get(**c).ok()) else {
+        lines.clear();
+        return;
+    };
+    let viewport = camera_viewport(camera);
+    let view_uniforms = view_uniforms.clone();
+    let depth_test = lines.depth_test;
+    let positions = std::mem::take(&mut lines.positions);
+    let colors = std::mem::take(&mut lines.colors);
+
+    enc.record(move |ctx, world| {
+        // Later cameras may have left their viewport set.
+        ctx.set_viewport(viewport);
+        let Some(shader_index) = shader_cached!(
+            ctx,
+            "shaders/debug_lines.vert",
+            "shaders/debug_lines.frag",
+            &[],
+            &[ViewUniforms::bindings()]
+        ) else {
+            return;
+        };
+        ctx.use_cached_program(shader_index);
+        ctx.map_uniform_set_locations::<ViewUniforms>();
+        ctx.bind_uniforms_set(world.resource::<GpuImages>(), &view_uniforms);
+        ctx.start_alpha_blend();
+        ctx.apply_render_state(RenderState {
+            cull_mode: None,
+            depth_test,
+            ..ctx.render_state
+        });
+
+        let count = positions.len();
+        let buffers = match world.remove_resource::<DebugLineBuffers>() {
+            Some(buffers) if buffers.capacity >= count => buffers,
+            old => {
+                if let Some(old) = old {
+                    old.delete(&ctx.gl);
+                }
+                DebugLineBuffers::new(ctx, count.next_power_of_two().max(1024))
+            }
+        };
+        ctx.update_vbo(buffers.positions, cast_slice(&positions));
+        ctx.update_vbo(buffers.colors, cast_slice(&colors));
+
+        let position = ctx.cached_attrib_location(shader_index, "position");
+        let color = ctx.cached_attrib_location(shader_index, "color");
+        if let (Some(position), Some(color)) = (position, color) {
+            unsafe {
+                if buffers.vertex_array.is_some() {
+                    ctx.bind_vertex_array(buffers.vertex_array);
+                }
+                ctx.bind_vertex_attrib(position, 3, AttribType::Float, buffers.positions);
+                ctx.bind_vertex_attrib(color, 4, AttribType::Float, buffers.colors);
+                ctx.gl.draw_arrays(glow::LINES, 0, count as i32);
+                ctx.gl.disable_vertex_attrib_array(position);
+                ctx.gl.disable_vertex_attrib_array(color);
+            }
+        }
+        world.insert_resource(buffers);
+    });
+}
@@ -11,6 +11,7 @@ use bevy_egui::{EguiContext, EguiPlugin, EguiPostUpdateSet, EguiRenderOutput};
 
 use bevy::prelude::IntoScheduleConfigs;
 use egui_glow::{Painter, ShaderVersion};
+use glow::HasContext;
 
 use crate::{command_encoder::CommandEncoder, render::RenderSet};
 
@@ -59,7 +60,13 @@ fn egui_render(
         let paint_jobs = render_output.paint_jobs.clone();
         let textures_delta = render_output.textures_delta.clone();
         let pixels_per_point = context.get_mut().pixels_per_point();
-        enc.record(move |_ctx, world| {
+        enc.record(move |ctx, world| {
+            // Don't draw the UI in wireframe
+            ctx.set_polygon_mode(glow::FILL);
+            // egui outputs sRGB, don't encode it again.
+            if ctx.srgb_framebuffer {
+                unsafe { ctx.gl.disable(glow::FRAMEBUFFER_SRGB) };
+            }
             let painter = &mut world.non_send_resource_mut::<EguiPainter>().0;
             painter.paint_and_update_textures(
                 [width, height],
@@ -67,6 +74,11 @@ fn egui_render(
                 &paint_jobs,
                 &textures_delta,
             );
+            if ctx.srgb_framebuffer {
+                unsafe { ctx.gl.enable(glow::FRAMEBUFFER_SRGB) };
+            }
+            // The painter changes GL state directly
+            ctx.restore_render_state();
         });
     }
 }
@@ -0,0 +1,155 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU32, Ordering},
+};
+
+use bevy::{camera::Exposure, diagnostic::FrameCount, prelude::*};
+
+use crate::command_encoder::CommandEncoder;
+
+/// Overrides the exposure of every camera. Without this resource the camera's Exposure component is used. Used by
+/// prepare_view(), see ViewUniformsPlugin.
+#[derive(Resource, Clone, Copy, Default)]
+pub struct ExposureSettings {
+    /// Manual EV100, same units as Exposure::ev100. Also the starting point for auto exposure.
+    pub ev100: Option<f32>,
+    pub auto: Option<AutoExposure>,
+}
+
+impl ExposureSettings {
+    pub fn manual(ev100: f32) -> Self {
+        Self {
+            ev100: Some(ev100),
+            auto: None,
+        }
+    }
+
+    pub fn auto() -> Self {
+        Self {
+            ev100: None,
+            auto: Some(AutoExposure::default()),
+        }
+    }
+}
+
+/// Adjusts exposure so the average luminance of the presented image approaches target_luminance. The window is read back every
+/// interval frames with CommandEncoder::read_framebuffer(), which stalls until the GPU has finished the frame. Only a
+/// grid of about sample_count x sample_count pixels is averaged.
+#[derive(Clone, Copy)]
+pub struct AutoExposure {
+    pub min_ev100: f32,
+    pub max_ev100: f32,
+    /// Target geometric mean of the linear luminance after tonemapping.
+    pub target_luminance: f32,
+    /// How quickly exposure adapts, higher is faster. Roughly the inverse of the time in seconds to adapt.
+    pub speed: f32,
+    pub interval: u32,
+    pub sample_count: u32,
+}
+
+impl Default for AutoExposure {
+    fn default() -> Self {
+        Self {
+            min_ev100: -4.0,
+            max_ev100: 16.0,
+            target_luminance: 0.18,
+            speed: 2.0,
+            interval: 4,
+            sample_count: 64,
+        }
+    }
+}
+
+/// The exposure being used when ExposureSettings::auto is set.
+#[derive(Resource)]
+pub struct AutoExposureState {
+    pub ev100: Option<f32>,
+    /// f32 bits of the EV100 the last read back frame would have needed. u32::MAX if there is no new measurement.
+    target_ev100: Arc<AtomicU32>,
+}
+
+impl Default for AutoExposureState {
+    fn default() -> Self {
+        Self {
+            ev100: None,
+            target_ev100: Arc::new(AtomicU32::new(u32::MAX)),
+        }
+    }
+}
+
+impl AutoExposureState {
+    /// The exposure that should be used in place of the camera's, if any.
+    pub fn exposure(&self, settings: Option<&ExposureSettings>) -> Option<f32> {
+        let settings = settings?;
+        let ev100 = if settings.auto.is_some() {
+            self.ev100.or(settings.ev100)?
+        } else {
+            settings.ev100?
+        };
+        Some(Exposure { ev100 }.exposure())
+    }
+}
+
+pub fn update_auto_exposure(
+    settings: Option<Res<ExposureSettings>>,
+    mut state: ResMut<AutoExposureState>,
+    cameras: Query<(&Camera, Option<&Exposure>)>,
+    frame: Res<FrameCount>,
+    time: Res<Time>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    let Some(auto) = settings.as_ref().and_then(|settings| settings.auto) else {
+        state.ev100 = None;
+        return;
+    };
+    let mut ev100 = *state.ev100.get_or_insert_with(|| {
+        settings.as_ref().and_then(|s| s.ev100).unwrap_or_else(|| {
+            cameras
+                .iter()
+                .filter(|(camera, _)| camera.is_active)
+                .min_by_key(|(camera, _)| camera.order)
+                .and_then(|(_, exposure)| exposure.cloned())
+                .unwrap_or_default()
+                .ev100
+        })
+    });
+
+    let target_bits = state.target_ev100.swap(u32::MAX, Ordering::Relaxed);
+    if target_bits != u32::MAX {
+        let target = f32::from_bits(target_bits).clamp(auto.min_ev100, auto.max_ev100);
+        ev100 += (target - ev100) * (1.0 - (-time.delta_secs() * auto.speed).exp());
+        state.ev100 = Some(ev100);
+    }
+
+    if frame.0 % auto.interval.max(1) == 0 {
+        let target_ev100 = state.target_ev100.clone();
+        enc.read_framebuffer(None, move |pixels, size| {
+            if let Some(luminance) = average_log_luminance(&pixels, size, auto.sample_count) {
+                // Exposure is relative to 2^-ev100, so raising ev100 by one halves the luminance.
+                let target = ev100 + (luminance / auto.target_luminance.max(1e-4)).log2();
+                target_ev100.store(target.to_bits(), Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+/// Geometric mean of the linear luminance of tightly packed srgb RGBA8 pixels, sampled on a grid of about
+/// sample_count x sample_count.
+fn average_log_luminance(pixels: &[u8], size: UVec2, sample_count: u32) -> Option<f32> {
+    if size.x == 0 || size.y == 0 {
+        return None;
+    }
+    let step = (size / sample_count.max(1)).max(UVec2::ONE);
+    let mut sum = 0.0;
+    let mut count = 0;
+    for y in (0..size.y).step_by(step.y as usize) {
+        for x in (0..size.x).step_by(step.x as usize) {
+            let i = ((y * size.x + x) * 4) as usize;
+            let color = vec3(pixels[i] as f32, pixels[i + 1] as f32, pixels[i + 2] as f32) / 255.0;
+            let luminance = color.powf(2.2).dot(vec3(0.2126, 0.7152, 0.0722));
+            sum += luminance.max(1e-4).ln();
+            count += 1;
+        }
+    }
+    Some((sum / count as f32).exp())
+}
@@ -0,0 +1,421 @@
+use bevy::{platform::collections::HashMap, prelude::*};
+use glow::HasContext;
+
+use crate::BevyGlContext;
+
+/// A framebuffer object with a color texture attachment and an optional depth renderbuffer, or only a depth texture
+/// attachment. Lets passes render directly into a texture at any resolution instead of rendering to the window and
+/// copying with copy_tex_image_2d.
+pub struct Framebuffer {
+    pub fbo: glow::Framebuffer,
+    pub depth: Option<glow::Renderbuffer>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Framebuffer {
+    /// color_texture must be a TEXTURE_2D that is already allocated at width x height. Returns None if
+    /// BevyGlContext::has_framebuffer_object is false or the framebuffer isn't complete, in which case the caller should
+    /// fall back to copying from the window.
+    pub fn new(
+        ctx: &BevyGlContext,
+        color_texture: glow::Texture,
+        with_depth: bool,
+        width: u32,
+        height: u32,
+    ) -> Option<Self> {
+        Self::new_with_target(
+            ctx,
+            glow::TEXTURE_2D,
+            color_texture,
+            with_depth,
+            width,
+            height,
+        )
+    }
+
+    /// Like new() but color_target can be a cube map face like TEXTURE_CUBE_MAP_POSITIVE_X. See set_color_target()
+    /// for switching faces.
+    pub fn new_with_target(
+        ctx: &BevyGlContext,
+        color_target: u32,
+        color_texture: glow::Texture,
+        with_depth: bool,
+        width: u32,
+        height: u32,
+    ) -> Option<Self> {
+        Self::new_with_depth_format(
+            ctx,
+            color_target,
+            color_texture,
+            with_depth.then_some(glow::DEPTH_COMPONENT16),
+            width,
+            height,
+        )
+    }
+
+    /// Like new_with_target() but with a depth renderbuffer of depth_format, e.g. DEPTH24_STENCIL8 to match the window
+    /// so depth can be copied over with glBlitFramebuffer. Formats with stencil are attached as
+    /// DEPTH_STENCIL_ATTACHMENT.
+    pub fn new_with_depth_format(
+        ctx: &BevyGlContext,
+        color_target: u32,
+        color_texture: glow::Texture,
+        depth_format: Option<u32>,
+        width: u32,
+        height: u32,
+    ) -> Option<Self> {
+        if !ctx.has_framebuffer_object {
+            return None;
+        }
+        unsafe {
+            let fbo = ctx.gl.create_framebuffer().ok()?;
+            ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, Some(fbo));
+            ctx.gl.framebuffer_texture_2d(
+                glow::FRAMEBUFFER,
+                glow::COLOR_ATTACHMENT0,
+                color_target,
+                Some(color_texture),
+                0,
+            );
+            let depth = depth_format.map(|depth_format| {
+                let depth = ctx.gl.create_renderbuffer().unwrap();
+                ctx.gl.bind_renderbuffer(glow::RENDERBUFFER, Some(depth));
+                ctx.gl.renderbuffer_storage(
+                    glow::RENDERBUFFER,
+                    depth_format,
+                    width as i32,
+                    height as i32,
+                );
+                ctx.gl.bind_renderbuffer(glow::RENDERBUFFER, None);
+                let attachment = match depth_format {
+                    glow::DEPTH24_STENCIL8 | glow::DEPTH32F_STENCIL8 | glow::DEPTH_STENCIL => {
+                        glow::DEPTH_STENCIL_ATTACHMENT
+                    }
+                    _ => glow::DEPTH_ATTACHMENT,
+                };
+                ctx.gl.framebuffer_renderbuffer(
+                    glow::FRAMEBUFFER,
+                    attachment,
+                    glow::RENDERBUFFER,
+                    Some(depth),
+                );
+                depth
+            });
+            Self::finish(
+                ctx,
+                Framebuffer {
+                    fbo,
+                    depth,
+                    width,
+                    height,
+                },
+            )
+        }
+    }
+
+    /// Framebuffer with only a depth attachment. depth_texture must be a DEPTH_COMPONENT TEXTURE_2D that is already
+    /// allocated at width x height. Returns None if BevyGlContext::has_depth_texture is false or the framebuffer isn't
+    /// complete.
+    pub fn new_depth(
+        ctx: &BevyGlContext,
+        depth_texture: glow::Texture,
+        width: u32,
+        height: u32,
+    ) -> Option<Self> {
+        if !ctx.has_depth_texture {
+            return None;
+        }
+        unsafe {
+            let fbo = ctx.gl.create_framebuffer().ok()?;
+            ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, Some(fbo));
+            ctx.gl.framebuffer_texture_2d(
+                glow::FRAMEBUFFER,
+                glow::DEPTH_ATTACHMENT,
+                glow::TEXTURE_2D,
+                Some(depth_texture),
+                0,
+            );
+            // Without a color attachment the draw and read buffers need to be NONE for the framebuffer to be complete.
+            #[cfg(not(target_arch = "wasm32"))]
+            {
+                ctx.gl.draw_buffer(glow::NONE);
+                ctx.gl.read_buffer(glow::NONE);
+            }
+            Self::finish(
+                ctx,
+                Framebuffer {
+                    fbo,
+                    depth: None,
+                    width,
+                    height,
+                },
+            )
+        }
+    }
+
+    /// Checks completeness of the bound framebuffer and unbinds it.
+    fn finish(ctx: &BevyGlContext, framebuffer: Framebuffer) -> Option<Self> {
+        unsafe {
+            let status = ctx.gl.check_framebuffer_status(glow::FRAMEBUFFER);
+            ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+            if status != glow::FRAMEBUFFER_COMPLETE {
+                warn!("Framebuffer incomplete (status 0x{status:x}), falling back to copying");
+                framebuffer.delete(&ctx.gl);
+                return None;
+            }
+        }
+        Some(framebuffer)
+    }
+
+    /// Binds the framebuffer and replaces the color attachment. Used to render each face of a cube map.
+    pub fn set_color_target(
+        &self,
+        ctx: &BevyGlContext,
+        color_target: u32,
+        color_texture: glow::Texture,
+    ) {
+        unsafe {
+            ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, Some(self.fbo));
+            ctx.gl.framebuffer_texture_2d(
+                glow::FRAMEBUFFER,
+                glow::COLOR_ATTACHMENT0,
+                color_target,
+                Some(color_texture),
+                0,
+            );
+        }
+    }
+
+    /// Binds the framebuffer and sets the viewport to its size.
+    pub fn bind(&self, ctx: &BevyGlContext) {
+        unsafe {
+            ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, Some(self.fbo));
+            ctx.gl.viewport(0, 0, self.width as i32, self.height as i32);
+            ctx.gl.scissor(0, 0, self.width as i32, self.height as i32);
+        }
+    }
+
+    /// Binds the default framebuffer and sets the viewport back to the window size.
+    pub fn unbind(ctx: &BevyGlContext) {
+        let size = ctx.window_size;
+        unsafe {
+            ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, None);
+            ctx.gl.viewport(0, 0, size.x as i32, size.y as i32);
+            ctx.gl.scissor(0, 0, size.x as i32, size.y as i32);
+        }
+    }
+
+    /// Deletes the framebuffer and depth renderbuffer. The color texture is owned by the caller.
+    pub fn delete(&self, gl: &glow::Context) {
+        unsafe {
+            gl.delete_framebuffer(self.fbo);
+            if let Some(depth) = self.depth {
+                gl.delete_renderbuffer(depth);
+            }
+        }
+    }
+}
+
+/// Color texture formats render targets can be created with. The float formats keep lighting above 1.0 for HDR.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ColorTargetFormat {
+    #[default]
+    Rgba8,
+    /// Needs BevyGlContext::has_float_render_target.
+    Rgba16F,
+    /// R11F_G11F_B10F, half the size of Rgba16F and without alpha. Needs GlCapabilities::r11f_g11f_b10f_render_target.
+    Rg11B10F,
+}
+
+impl ColorTargetFormat {
+    pub fn internal_format(self) -> u32 {
+        match self {
+            Self::Rgba8 => glow::RGBA8,
+            Self::Rgba16F => glow::RGBA16F,
+            Self::Rg11B10F => glow::R11F_G11F_B10F,
+        }
+    }
+
+    /// The format and type for allocating the texture with tex_image_2d.
+    pub fn format_and_type(self) -> (u32, u32) {
+        match self {
+            Self::Rgba8 => (glow::RGBA, glow::UNSIGNED_BYTE),
+            Self::Rgba16F => (glow::RGBA, glow::HALF_FLOAT),
+            Self::Rg11B10F => (glow::RGB, glow::HALF_FLOAT),
+        }
+    }
+
+    pub fn is_float(self) -> bool {
+        self != Self::Rgba8
+    }
+
+    /// Whether the context can render into the format.
+    pub fn supported(self, ctx: &BevyGlContext) -> bool {
+        match self {
+            Self::Rgba8 => true,
+            Self::Rgba16F => ctx.has_float_render_target,
+            Self::Rg11B10F => ctx.capabilities.r11f_g11f_b10f_render_target,
+        }
+    }
+
+    /// The smallest supported float format for HDR targets. Falls back to Rgba8 with a warning if neither is
+    /// supported, lighting is then clamped to 1.0 before tonemapping. alpha is for targets that need an alpha channel.
+    pub fn hdr(ctx: &BevyGlContext, alpha: bool) -> Self {
+        let candidates: &[Self] = if alpha {
+            &[Self::Rgba16F]
+        } else {
+            &[Self::Rg11B10F, Self::Rgba16F]
+        };
+        candidates
+            .iter()
+            .copied()
+            .find(|format| format.supported(ctx))
+            .unwrap_or_else(|| {
+                warn_once!(
+                    "Float render targets aren't supported (EXT_color_buffer_float or EXT_color_buffer_half_float), \
+                    HDR targets fall back to RGBA8"
+                );
+                Self::Rgba8
+            })
+    }
+
+    /// Allocates a TEXTURE_2D of size in the format to use as a color attachment. Nearest filtered and clamped to edge.
+    pub fn create_texture(self, ctx: &BevyGlContext, size: UVec2) -> glow::Texture {
+        let (format, ty) = self.format_and_type();
+        unsafe {
+            let texture = ctx.gl.create_texture().unwrap();
+            ctx.gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+            for (param, value) in [
+                (glow::TEXTURE_MIN_FILTER, glow::NEAREST),
+                (glow::TEXTURE_MAG_FILTER, glow::NEAREST),
+                (glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE),
+                (glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE),
+            ] {
+                ctx.gl
+                    .tex_parameter_i32(glow::TEXTURE_2D, param, value as i32);
+            }
+            ctx.gl.tex_image_2d(
+                glow::TEXTURE_2D,
+                0,
+                self.internal_format() as i32,
+                size.x as i32,
+                size.y as i32,
+                0,
+                format,
+                ty,
+                glow::PixelUnpackData::Slice(None),
+            );
+            ctx.gl.bind_texture(glow::TEXTURE_2D, None);
+            texture
+        }
+    }
+}
+
+/// Render world framebuffers for CommandEncoder::bind_target(), keyed by their color texture. Each has a depth
+/// renderbuffer so targets can be drawn to like the window.
+#[derive(Resource, Default)]
+pub struct TextureTargets {
+    framebuffers: HashMap<glow::Texture, Framebuffer>,
+    /// The framebuffer that was bound before the first bind(), None for the window. Outer None if no target is bound.
+    previous: Option<Option<glow::Framebuffer>>,
+}
+
+impl TextureTargets {
+    /// Binds a framebuffer drawing into texture, which must be a TEXTURE_2D of size. The viewport is set to the whole
+    /// texture. Returns false if a framebuffer can't be created, in which case the current one stays bound.
+    pub fn bind(&mut self, ctx: &BevyGlContext, texture: glow::Texture, size: UVec2) -> bool {
+        let previous = unsafe { ctx.gl.get_parameter_framebuffer(glow::FRAMEBUFFER_BINDING) };
+        if let Some(framebuffer) = self.framebuffers.get(&texture)
+            && uvec2(framebuffer.width, framebuffer.height) != size
+        {
+            framebuffer.delete(&ctx.gl);
+            self.framebuffers.remove(&texture);
+        }
+        if !self.framebuffers.contains_key(&texture) {
+            // Framebuffer::new() leaves the window bound.
+            let framebuffer = Framebuffer::new(ctx, texture, true, size.x, size.y);
+            unsafe { ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, previous) };
+            let Some(framebuffer) = framebuffer else {
+                return false;
+            };
+            self.framebuffers.insert(texture, framebuffer);
+        }
+        self.previous.get_or_insert(previous);
+        self.framebuffers[&texture].bind(ctx);
+        true
+    }
+
+    /// Binds the framebuffer from before bind() again and resets the viewport to the full window.
+    pub fn unbind(&mut self, ctx: &mut BevyGlContext) {
+        if let Some(previous) = self.previous.take() {
+            unsafe { ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, previous) };
+            ctx.set_viewport(None);
+        }
+    }
+
+    /// Deletes the framebuffer of texture, if it has one. Call before deleting the texture.
+    pub fn remove(&mut self, gl: &glow::Context, texture: glow::Texture) {
+        if let Some(framebuffer) = self.framebuffers.remove(&texture) {
+            framebuffer.delete(gl);
+        }
+    }
+}
+
+/// The render target of a context created with BevyGlContext::new_headless()
+pub struct HeadlessTarget {
+    pub framebuffer: Framebuffer,
+    /// RGBA8 color attachment
+    pub texture: glow::Texture,
+}
+
+impl HeadlessTarget {
+    pub fn new(ctx: &BevyGlContext, width: u32, height: u32) -> Option<Self> {
+        unsafe {
+            let texture = ctx.gl.create_texture().ok()?;
+            ctx.gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+            for (param, value) in [
+                (glow::TEXTURE_MIN_FILTER, glow::NEAREST),
+                (glow::TEXTURE_MAG_FILTER, glow::NEAREST),
+                (glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE),
+                (glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE),
+            ] {
+                ctx.gl
+                    .tex_parameter_i32(glow::TEXTURE_2D, param, value as i32);
+            }
+            ctx.gl.tex_image_2d(
+                glow::TEXTURE_2D,
+                0,
+                glow::RGBA as i32,
+                width as i32,
+                height as i32,
+                0,
+                glow::RGBA,
+                glow::UNSIGNED_BYTE,
+                glow::PixelUnpackData::Slice(None),
+            );
+            ctx.gl.bind_texture(glow::TEXTURE_2D, None);
+            let Some(framebuffer) = Framebuffer::new(ctx, texture, true, width, height) else {
+                ctx.gl.delete_texture(texture);
+                return None;
+            };
+            Some(HeadlessTarget {
+                framebuffer,
+                texture,
+            })
+        }
+    }
+
+    /// Reads the color attachment as tightly packed RGBA8 rows, top row first like
+    /// BevyGlContext::read_pixels_rgba8(). Binds the framebuffer.
+    pub fn read_pixels(&self, ctx: &BevyGlContext) -> Vec<u8> {
+        let (width, height) = (self.framebuffer.width, self.framebuffer.height);
+        self.framebuffer.bind(ctx);
+        ctx.read_pixels_rgba8(URect::new(0, 0, width, height), height)
+    }
+
+    pub fn delete(&self, gl: &glow::Context) {
+        self.framebuffer.delete(gl);
+        unsafe { gl.delete_texture(self.texture) };
+    }
+}
@@ -1,26 +1,43 @@
 pub mod bevy_standard_lighting;
 pub mod bevy_standard_material;
+pub mod bloom;
+pub mod capabilities;
+pub mod clustered_lighting;
 pub mod command_encoder;
+pub mod debug_lines;
 pub mod egui_plugin;
+pub mod exposure;
 pub mod faststack;
+pub mod framebuffer;
 pub mod macos_compat;
 pub mod mesh_util;
 pub mod phase_opaque;
+pub mod phase_point_shadow;
 pub mod phase_shadow;
 pub mod phase_transparent;
 pub mod plane_reflect;
+pub mod post_process;
 pub mod prepare_image;
 pub mod prepare_joints;
 pub mod prepare_mesh;
+pub mod prepare_view;
+pub mod prepass;
+pub mod program_binary_cache;
 pub mod render;
+pub mod skybox;
+pub mod sprite;
+pub mod ssao;
+pub mod taa;
+pub mod text;
+pub mod tonemapping_lut;
 pub mod watchers;
 
 extern crate self as bgl2;
 
-use anyhow::Error;
-use anyhow::anyhow;
+use bevy::asset::RenderAssetUsages;
 use bevy::mesh::MeshVertexAttribute;
 use bevy::platform::collections::HashSet;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
 use bytemuck::cast_slice;
 use core::slice;
 use glow::Context;
@@ -31,9 +48,11 @@ use raw_window_handle::RawDisplayHandle;
 use raw_window_handle::RawWindowHandle;
 use std::any::TypeId;
 use std::any::type_name;
+use std::cell::Cell;
 use std::hash::Hash;
 use std::hash::Hasher;
 use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
 use wgpu_types::Face;
 
@@ -44,10 +63,15 @@ use glow::ActiveUniform;
 use glow::Buffer;
 use glow::HasContext;
 
+use crate::capabilities::GlCapabilities;
 use crate::faststack::FastStack;
 use crate::faststack::StackStack;
+#[cfg(target_os = "linux")]
+use crate::framebuffer::HeadlessTarget;
 use crate::prepare_image::GpuImages;
+use crate::prepare_image::SamplerOverride;
 use crate::prepare_image::TextureRef;
+use crate::program_binary_cache::{ProgramBinaryCache, StableHasher};
 use crate::watchers::Watchers;
 
 pub type ShaderIndex = u32;
@@ -63,14 +87,84 @@ pub struct BevyGlContext {
     pub shader_cache: Vec<glow::Program>,
     pub shader_cache_map: HashMap<u64, (ShaderIndex, Watchers)>,
     pub shader_includes: HashMap<String, String>,
+    /// Set with enable_program_binary_cache()
+    pub program_binary_cache: Option<ProgramBinaryCache>,
     pub has_glsl_cube_lod: bool, // TODO move
     pub has_cube_map_seamless: bool,
-    pub last_cull_mode: Option<Face>,
+    pub has_vertex_array_object: bool,
+    /// Framebuffer objects are core in GL 3.0 and WebGL 1. If false, passes render to the window and copy from it.
+    pub has_framebuffer_object: bool,
+    /// Depth textures can be rendered to with a framebuffer object and sampled with sampler2DShadow. Desktop GL only,
+    /// WebGL 1 has no shadow samplers.
+    pub has_depth_texture: bool,
+    /// The window has a stencil buffer, see BevyGlContextConfig::stencil_bits. The clears also clear stencil then.
+    /// Framebuffer objects don't have one.
+    pub has_stencil: bool,
+    /// The window is sRGB and GL_FRAMEBUFFER_SRGB is enabled, see BevyGlContextConfig::srgb_framebuffer. Shaders are
+    /// compiled with SRGB_FRAMEBUFFER defined and should output linear color.
+    pub srgb_framebuffer: bool,
+    /// One and two channel R8 and RG8 textures, core in GL 3.0 and WebGL 2. If false they are expanded to RGBA on upload.
+    pub has_texture_rg: bool,
+    /// TEXTURE_2D_ARRAY and sampler2DArray, core in GL 3.0 and WebGL 2, GL_EXT_texture_array on GL 2.1. Images with
+    /// more than one layer that aren't cube maps can't be used without it.
+    pub has_texture_array: bool,
+    /// TEXTURE_3D and sampler3D, core in GL 1.2 and WebGL 2. Not available on WebGL 1 or GLES 2.
+    pub has_texture_3d: bool,
+    /// RGBA16F color attachments and glBlitFramebuffer. Core in GL 3.0, needs EXT_color_buffer_float on WebGL 2 and
+    /// EXT_color_buffer_half_float on GLES 3. Used by TransparencyMode::WeightedBlended.
+    pub has_float_render_target: bool,
+    /// draw_elements_instanced and vertex_attrib_divisor, core in GL 3.3, GLES 3.0 and WebGL 2, ANGLE_instanced_arrays on
+    /// WebGL 1. See GpuMeshes::draw_mesh_instanced().
+    pub has_instancing: bool,
+    /// glGenSamplers and glBindSampler, core in GL 3.3, GLES 3.0 and WebGL 2. Lets SampledImage fields sample a texture
+    /// with a SamplerOverride without changing the texture, see GpuImages::sampler(). Without them the override is set
+    /// on the texture itself.
+    pub has_sampler_objects: bool,
+    /// Limits and optional features queried when the context was created, also inserted as a main world resource by
+    /// GlCapabilitiesPlugin.
+    pub capabilities: GlCapabilities,
+    /// GLSL version and precision used for every shader, see ShaderLanguageSettings::detect(). Can be replaced after
+    /// the context is created, e.g. to turn off translation. Programs compiled with different settings don't share a
+    /// cache entry.
+    pub shader_language: ShaderLanguageSettings,
+    /// The canvas has a WebGL 2 context. Shaders are still written as GLSL 1.x and translated to GLSL ES 3.00. Always
+    /// false on desktop.
+    pub webgl2: bool,
+    /// Check glGetError after shader compilation, buffer and texture uploads and draws, see check_error(). Defaults to
+    /// true in debug builds. Each check can stall the pipeline, so it stays off in release unless set explicitly.
+    pub debug_gl_errors: bool,
+    /// GL_KHR_debug or GL 4.3 / GLES 3.2 is available, so objects can be named for RenderDoc and apitrace. Always false
+    /// on WebGL.
+    pub has_debug_labels: bool,
+    /// Name programs, buffers and textures after their shader or asset paths when has_debug_labels is set. Defaults to
+    /// true in debug builds.
+    pub debug_labels: bool,
+    /// Physical size of the window, used to restore the viewport after rendering to a framebuffer object.
+    pub window_size: UVec2,
+    pub render_state: RenderState,
+    /// See set_stencil_state(). None while the stencil test is disabled.
+    pub stencil_state: Option<StencilState>,
     pub uniform_slot_map: HashMap<TypeId, Vec<Option<SlotData>>>,
     pub current_program: Option<glow::Program>,
+    /// Index of current_program in shader_cache.
+    pub current_shader: Option<ShaderIndex>,
     pub temp_slot_data: StackStack<u32, 16>,
-    pub uniform_location_cache: HashMap<String, Option<UniformLocation>>,
+    /// Bumped when vertex array, element buffer or vertex attribute bindings change outside of GpuMeshes, and at the
+    /// start of each phase. GpuMeshes compares it to keep its last buffer set bound across render systems, see
+    /// invalidate_vertex_bindings().
+    pub vertex_bindings_generation: Cell<u32>,
+    /// Uniform locations by name for each shader, see get_uniform_location(). Entries for a shader are removed when
+    /// it's hot reloaded.
+    pub uniform_location_cache: HashMap<ShaderIndex, HashMap<String, Option<UniformLocation>>>,
+    /// See cached_attrib_location(). Entries for a shader are removed when it's hot reloaded.
+    pub attrib_location_cache: HashMap<(ShaderIndex, &'static str), Option<u32>>,
     pub current_texture_slot_count: usize,
+    /// Warn when map_uniform_set_locations() finds a UniformSet field without an active uniform in the current program,
+    /// because it's misnamed or was optimized out. Off by default since some bindings are intentionally unused, like
+    /// lighting in depth only passes.
+    pub warn_missing_uniforms: bool,
+    /// Program and uniform name of each missing uniform that was already warned about.
+    pub missing_uniform_warnings: HashSet<(glow::Program, String)>,
 }
 
 impl Drop for BevyGlContext {
@@ -96,6 +190,8 @@ impl Drop for BevyGlContext {
 #[derive(Clone, Copy)]
 pub struct BufferRef {
     pub buffer_index: usize,
+    /// GL primitive mode passed to draw_elements, e.g. glow::TRIANGLES or glow::LINES.
+    pub mode: u32,
     pub indices_start: usize,
     pub indices_count: usize,
     pub index_element_type: u32,
@@ -106,6 +202,12 @@ pub struct GpuMeshBufferSet {
     pub buffers: Vec<(MeshVertexAttribute, glow::Buffer)>,
     pub index: glow::Buffer,
     pub index_element_type: u32,
+    /// glow::STATIC_DRAW or glow::DYNAMIC_DRAW. Dynamic buffer sets hold a single mesh and are updated in place.
+    pub usage: u32,
+    /// Number of vertices the attribute buffers were allocated with.
+    pub vertex_capacity: usize,
+    /// Number of indices the index buffer was allocated with.
+    pub index_capacity: usize,
 }
 
 impl GpuMeshBufferSet {
@@ -130,44 +232,201 @@ pub struct WindowInitData {
     pub present_mode: bevy::window::PresentMode,
     pub width: u32,
     pub height: u32,
+    pub config: BevyGlContextConfig,
 }
 // TODO investigate if this usage is UB. Seems to work so far, even on macos.
 unsafe impl Send for WindowInitData {}
 unsafe impl Sync for WindowInitData {}
 
+/// Requested properties of the default framebuffer. Insert as a resource before RenderSet::Init to override the
+/// defaults.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct BevyGlContextConfig {
+    pub depth_bits: u8,
+    pub stencil_bits: u8,
+    /// Desired MSAA sample count (0, 1, 2, 4 or 8). The closest available config is used, 0 and 1 disable MSAA. On
+    /// WebGL this only toggles antialias, the browser picks the sample count.
+    /// This only applies to the window. Passes that render into framebuffer objects (shadows, reflections) are not
+    /// multisampled, multisampled FBOs would need a resolve step that GL 2.1 and WebGL 1 don't have.
+    pub msaa_samples: u8,
+    pub alpha_bits: u8,
+    /// Request an sRGB capable window and enable GL_FRAMEBUFFER_SRGB, so GL encodes the linear color written by
+    /// shaders to sRGB and blends in linear space. Every shader is compiled with SRGB_FRAMEBUFFER defined, tonemap()
+    /// in std::tonemapping then returns linear color, and custom shaders writing to the window should output linear
+    /// color too. Framebuffer objects aren't sRGB, so intermediate RGBA8 targets hold linear color in this mode.
+    /// Check BevyGlContext::srgb_framebuffer to see if it's active, it needs GL 3.0 or ARB_framebuffer_sRGB.
+    ///
+    /// Ignored on WebGL, which has no FRAMEBUFFER_SRGB. The canvas' drawingBufferColorSpace only tags the color space
+    /// of the values written, which are expected to already be sRGB encoded like they are without this option.
+    pub srgb_framebuffer: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GlslVersion {
+    /// OpenGL 2.1
+    Glsl120,
+    /// OpenGL 3.3 core
+    Glsl330,
+    /// WebGL 1 and GLES 2.0
+    Es100,
+    /// WebGL 2 and GLES 3.0
+    Es300,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PrecisionQualifier {
+    /// No default precision statement. GLSL 1.20 doesn't support precision qualifiers.
+    None,
+    Lowp,
+    Mediump,
+    Highp,
+}
+
+/// Selects the preamble that compile_shader() puts before every shader, see BevyGlContext::shader_language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ShaderLanguageSettings {
+    pub version: GlslVersion,
+    /// Default float and int precision.
+    pub precision: PrecisionQualifier,
+    /// The built in shaders are written in GLSL 1.x. If true they are translated when version is Glsl330 or Es300.
+    /// Set to false if all shaders used are already written for version.
+    pub translate_glsl1: bool,
+}
+
+impl ShaderLanguageSettings {
+    /// GLSL 1.20 on Windows and Linux, 3.30 on macOS, GLSL ES 3.00 on WebGL 2 and GLSL ES 1.00 on WebGL 1. Desktop
+    /// contexts use detect() instead.
+    pub fn for_platform(webgl2: bool) -> Self {
+        let version = if cfg!(target_arch = "wasm32") {
+            if webgl2 {
+                GlslVersion::Es300
+            } else {
+                GlslVersion::Es100
+            }
+        } else if cfg!(target_os = "macos") {
+            GlslVersion::Glsl330
+        } else {
+            GlslVersion::Glsl120
+        };
+        ShaderLanguageSettings {
+            version,
+            precision: if cfg!(target_arch = "wasm32") {
+                PrecisionQualifier::Highp
+            } else {
+                PrecisionQualifier::None
+            },
+            translate_glsl1: true,
+        }
+    }
+
+    /// Picks the version from the context. Core profiles (like on macOS) reject GLSL 1.20 so they get GLSL 3.30 with
+    /// translation, GLES contexts get GLSL ES. Everything else uses GLSL 1.20.
+    pub fn detect(gl: &glow::Context) -> Self {
+        let version = gl.version();
+        if version.is_embedded {
+            return ShaderLanguageSettings {
+                version: if version.major >= 3 {
+                    GlslVersion::Es300
+                } else {
+                    GlslVersion::Es100
+                },
+                precision: PrecisionQualifier::Highp,
+                translate_glsl1: true,
+            };
+        }
+        // The profile mask only exists since GL 3.2
+        let core_profile = (version.major, version.minor) >= (3, 2)
+            && unsafe { gl.get_parameter_i32(glow::CONTEXT_PROFILE_MASK) }
+                & glow::CONTEXT_CORE_PROFILE_BIT as i32
+                != 0;
+        ShaderLanguageSettings {
+            version: if core_profile {
+                GlslVersion::Glsl330
+            } else {
+                GlslVersion::Glsl120
+            },
+            precision: PrecisionQualifier::None,
+            translate_glsl1: true,
+        }
+    }
+
+    pub fn preamble(&self) -> String {
+        let mut preamble = self.version_directive().to_string();
+        preamble.push_str(&self.precision_statements());
+        preamble
+    }
+
+    pub fn version_directive(&self) -> &'static str {
+        match self.version {
+            GlslVersion::Glsl120 => "#version 120\n",
+            GlslVersion::Glsl330 => "#version 330\n",
+            GlslVersion::Es100 => "#version 100\n",
+            GlslVersion::Es300 => "#version 300 es\n",
+        }
+    }
+
+    /// Default precision statements for precision, empty for PrecisionQualifier::None.
+    pub fn precision_statements(&self) -> String {
+        let precision = match self.precision {
+            PrecisionQualifier::None => return String::new(),
+            PrecisionQualifier::Lowp => "lowp",
+            PrecisionQualifier::Mediump => "mediump",
+            PrecisionQualifier::Highp => "highp",
+        };
+        let mut statements = format!("precision {precision} float;\nprecision {precision} int;\n");
+        // Unlike sampler2D, GLSL ES 3.00 has no default precision for sampler2DArray or sampler3D
+        if self.version == GlslVersion::Es300 {
+            statements.push_str(&format!("precision {precision} sampler2DArray;\n"));
+            statements.push_str(&format!("precision {precision} sampler3D;\n"));
+        }
+        statements
+    }
+
+    /// True if GLSL 1.x shaders are translated to GLSL 3 syntax, see macos_compat::translate_shader_to_330()
+    pub fn translates(&self) -> bool {
+        self.translate_glsl1 && matches!(self.version, GlslVersion::Glsl330 | GlslVersion::Es300)
+    }
+}
+
+impl Default for BevyGlContextConfig {
+    fn default() -> Self {
+        Self {
+            depth_bits: 24,
+            stencil_bits: 0,
+            msaa_samples: 4,
+            alpha_bits: 8,
+            srgb_framebuffer: false,
+        }
+    }
+}
+
+#[cfg(feature = "gl21pipe")]
+fn set_gl21pipe_env() {
+    unsafe {
+        std::env::set_var(
+            "__EGL_VENDOR_LIBRARY_FILENAMES",
+            "/usr/share/glvnd/egl_vendor.d/50_mesa.json",
+        );
+        std::env::set_var("LIBGL_ALWAYS_SOFTWARE", "1");
+        std::env::set_var("MESA_LOADER_DRIVER_OVERRIDE", "llvmpipe");
+        std::env::set_var("MESA_GL_VERSION_OVERRIDE", "2.1");
+        std::env::set_var("MESA_GLSL_VERSION_OVERRIDE", "120");
+    }
+}
+
 impl BevyGlContext {
     pub fn new(win: WindowInitData) -> BevyGlContext {
         #[cfg(feature = "gl21pipe")]
-        unsafe {
-            std::env::set_var(
-                "__EGL_VENDOR_LIBRARY_FILENAMES",
-                "/usr/share/glvnd/egl_vendor.d/50_mesa.json",
-            );
-            std::env::set_var("LIBGL_ALWAYS_SOFTWARE", "1");
-            std::env::set_var("MESA_LOADER_DRIVER_OVERRIDE", "llvmpipe");
-            std::env::set_var("MESA_GL_VERSION_OVERRIDE", "2.1");
-            std::env::set_var("MESA_GLSL_VERSION_OVERRIDE", "120");
-        }
+        set_gl21pipe_env();
 
         #[cfg(not(target_arch = "wasm32"))]
         let ctx = {
-            let vsync = match win.present_mode {
-                bevy::window::PresentMode::AutoVsync => true,
-                bevy::window::PresentMode::AutoNoVsync => false,
-                bevy::window::PresentMode::Fifo => true,
-                bevy::window::PresentMode::FifoRelaxed => true,
-                bevy::window::PresentMode::Immediate => false,
-                bevy::window::PresentMode::Mailbox => false,
-            };
-
             use glutin::{
                 config::{ConfigSurfaceTypes, ConfigTemplateBuilder, GlConfig},
                 context::{ContextApi, ContextAttributesBuilder},
                 display::{Display, DisplayApiPreference},
                 prelude::{GlDisplay, NotCurrentGlContext},
-                surface::{GlSurface, SwapInterval},
             };
-            use std::num::NonZeroU32;
 
             #[cfg(target_os = "windows")]
             let preference = DisplayApiPreference::Wgl(Some(win.raw_window));
@@ -181,20 +440,28 @@ impl BevyGlContext {
 
             // TODO https://github.com/rust-windowing/glutin/blob/master/glutin-winit/src/lib.rs
             let template = ConfigTemplateBuilder::default()
-                // TODO depth buffer?
-                .with_alpha_size(8)
+                .with_alpha_size(win.config.alpha_bits)
+                .with_depth_size(win.config.depth_bits)
+                .with_stencil_size(win.config.stencil_bits)
                 .with_surface_type(ConfigSurfaceTypes::WINDOW)
                 .build();
             let gl_config = unsafe { gl_display.find_configs(template) }
                 .unwrap()
-                .reduce(|config, acc| {
-                    if config.num_samples() > acc.num_samples() {
-                        config
+                .min_by_key(|config| {
+                    // Configs without multisampling report 0 samples. Prefer more samples on ties.
+                    let target = if win.config.msaa_samples <= 1 {
+                        0
                     } else {
-                        acc
-                    }
+                        win.config.msaa_samples
+                    };
+                    (
+                        config.srgb_capable() != win.config.srgb_framebuffer,
+                        config.num_samples().abs_diff(target),
+                        std::cmp::Reverse(config.num_samples()),
+                    )
                 })
                 .expect("No available configs");
+            let msaa_samples = gl_config.num_samples();
 
             let context_attributes = ContextAttributesBuilder::new()
                 .with_context_api(ContextApi::OpenGl(Some(glutin::context::Version {
@@ -231,93 +498,327 @@ impl BevyGlContext {
                 println!("GL_VERSION  : {}", version);
             }
 
-            let interval = if vsync {
-                SwapInterval::Wait(NonZeroU32::new(1).unwrap())
-            } else {
-                SwapInterval::DontWait
-            };
-
-            match gl_surface.set_swap_interval(&gl_context, interval) {
-                Ok(_) => (),
-                Err(e) => eprintln!("Couldn't set_swap_interval wait: {e}"),
-            };
-
             unsafe { gl.viewport(0, 0, win.width as i32, win.height as i32) };
 
-            let has_cube_map_seamless = if gl
-                .supported_extensions()
-                .contains("GL_ARB_seamless_cube_map")
-            {
-                unsafe { gl.enable(glow::TEXTURE_CUBE_MAP_SEAMLESS) };
-                true
-            } else {
-                false
-            };
+            if msaa_samples > 1 {
+                unsafe { gl.enable(glow::MULTISAMPLE) };
+            }
 
-            let mut ctx = BevyGlContext {
-                gl: Arc::new(gl),
-                gl_context: Some(gl_context),
-                gl_surface: Some(gl_surface),
-                gl_display: Some(gl_display),
-                shader_cache: Default::default(),
-                shader_cache_map: Default::default(),
-                shader_includes: Default::default(),
-                has_glsl_cube_lod: true,
-                has_cube_map_seamless,
-                last_cull_mode: None,
-                uniform_slot_map: Default::default(),
-                current_program: Default::default(),
-                temp_slot_data: Default::default(),
-                uniform_location_cache: Default::default(),
-                current_texture_slot_count: 0,
-            };
-            ctx.test_for_glsl_lod();
+            let srgb_framebuffer = win.config.srgb_framebuffer
+                && gl_config.srgb_capable()
+                && (gl.version().major >= 3
+                    || gl
+                        .supported_extensions()
+                        .contains("GL_ARB_framebuffer_sRGB"));
+            if srgb_framebuffer {
+                unsafe { gl.enable(glow::FRAMEBUFFER_SRGB) };
+            } else if win.config.srgb_framebuffer {
+                warn!("No sRGB capable framebuffer, shaders need to output sRGB color");
+            }
+
+            let mut ctx = BevyGlContext::from_native_gl(
+                gl,
+                gl_context,
+                Some(gl_surface),
+                gl_display,
+                uvec2(win.width, win.height),
+            );
+            ctx.srgb_framebuffer = srgb_framebuffer;
+            ctx.set_vsync(present_mode_vsync(win.present_mode));
             ctx
         };
         #[cfg(target_arch = "wasm32")]
         let ctx = {
             use wasm_bindgen::JsCast;
+            if win.config.srgb_framebuffer {
+                warn!("BevyGlContextConfig::srgb_framebuffer is ignored on WebGL");
+            }
             win.canvas.set_width(win.width);
             win.canvas.set_height(win.height);
-            let webgl_context = win
+            let context_attributes = web_sys::WebGlContextAttributes::new();
+            context_attributes.set_alpha(win.config.alpha_bits > 0);
+            context_attributes.set_depth(win.config.depth_bits > 0);
+            context_attributes.set_stencil(win.config.stencil_bits > 0);
+            context_attributes.set_antialias(win.config.msaa_samples > 1);
+            // Prefer WebGL 2, shaders are translated to GLSL ES 3.00 in compile_shader()
+            let webgl2_context = win
                 .canvas
-                .get_context("webgl")
-                .unwrap()
-                .unwrap()
-                .dyn_into::<web_sys::WebGlRenderingContext>()
-                .unwrap();
-
-            let has_glsl_cube_lod = webgl_context
-                .get_extension("EXT_shader_texture_lod")
+                .get_context_with_context_options("webgl2", &context_attributes)
                 .ok()
                 .flatten()
-                .is_some();
+                .and_then(|context| context.dyn_into::<web_sys::WebGl2RenderingContext>().ok());
+            let webgl2 = webgl2_context.is_some();
 
-            let gl = glow::Context::from_webgl1_context(webgl_context);
+            let (gl, has_glsl_cube_lod) = if let Some(webgl2_context) = webgl2_context {
+                (glow::Context::from_webgl2_context(webgl2_context), true)
+            } else {
+                let webgl_context = win
+                    .canvas
+                    .get_context_with_context_options("webgl", &context_attributes)
+                    .unwrap()
+                    .unwrap()
+                    .dyn_into::<web_sys::WebGlRenderingContext>()
+                    .unwrap();
+
+                let has_glsl_cube_lod = webgl_context
+                    .get_extension("EXT_shader_texture_lod")
+                    .ok()
+                    .flatten()
+                    .is_some();
+
+                (
+                    glow::Context::from_webgl1_context(webgl_context),
+                    has_glsl_cube_lod,
+                )
+            };
             unsafe { gl.viewport(0, 0, win.width as i32, win.height as i32) };
+            let has_instancing =
+                webgl2 || gl.supported_extensions().contains("ANGLE_instanced_arrays");
+            let has_vertex_array_object = webgl2
+                || gl
+                    .supported_extensions()
+                    .contains("OES_vertex_array_object");
+            let has_float_render_target =
+                webgl2 && gl.supported_extensions().contains("EXT_color_buffer_float");
+            let capabilities = GlCapabilities::query(&gl, has_vertex_array_object, has_instancing);
             BevyGlContext {
                 gl: Arc::new(gl),
                 shader_cache: Default::default(),
                 shader_cache_map: Default::default(),
                 shader_includes: Default::default(),
+                program_binary_cache: None,
                 has_glsl_cube_lod,
                 has_cube_map_seamless: false,
-                last_cull_mode: None,
+                has_vertex_array_object,
+                has_framebuffer_object: true,
+                has_depth_texture: false,
+                has_stencil: win.config.stencil_bits > 0,
+                srgb_framebuffer: false,
+                has_texture_rg: webgl2,
+                has_texture_array: webgl2,
+                has_texture_3d: webgl2,
+                has_float_render_target,
+                has_instancing,
+                has_sampler_objects: webgl2,
+                capabilities,
+                shader_language: ShaderLanguageSettings::for_platform(webgl2),
+                webgl2,
+                debug_gl_errors: cfg!(debug_assertions),
+                has_debug_labels: false,
+                debug_labels: cfg!(debug_assertions),
+                window_size: uvec2(win.width, win.height),
+                render_state: Default::default(),
+                stencil_state: None,
                 uniform_slot_map: Default::default(),
                 current_program: Default::default(),
+                current_shader: Default::default(),
                 temp_slot_data: Default::default(),
+                vertex_bindings_generation: Default::default(),
                 uniform_location_cache: Default::default(),
+                attrib_location_cache: Default::default(),
                 current_texture_slot_count: 0,
+                warn_missing_uniforms: false,
+                missing_uniform_warnings: Default::default(),
             }
         };
         ctx
     }
 
+    /// Creates a context without a window for tests and headless rendering, along with a width x height RGBA8 target
+    /// to render into. Uses the first EGL device that can create a GL 2.1 context, with no surface at all
+    /// (EGL_KHR_surfaceless_context). Returns None if there is no such device or framebuffer objects aren't supported.
+    ///
+    /// Without a GPU, Mesa exposes llvmpipe as a software EGL device. Set `LIBGL_ALWAYS_SOFTWARE=1` to force it and
+    /// `EGL_PLATFORM=surfaceless` so Mesa doesn't try to connect to an X11 or Wayland display, e.g. in CI:
+    /// `EGL_PLATFORM=surfaceless LIBGL_ALWAYS_SOFTWARE=1 cargo test`
+    #[cfg(target_os = "linux")]
+    pub fn new_headless(width: u32, height: u32) -> Option<(BevyGlContext, HeadlessTarget)> {
+        #[cfg(feature = "gl21pipe")]
+        set_gl21pipe_env();
+
+        use glutin::{
+            api::egl::{device::Device, display::Display},
+            config::{ConfigSurfaceTypes, ConfigTemplateBuilder},
+            context::{ContextApi, ContextAttributesBuilder},
+            prelude::GlDisplay,
+        };
+
+        let template = ConfigTemplateBuilder::default()
+            .with_surface_type(ConfigSurfaceTypes::empty())
+            .build();
+        let context_attributes = ContextAttributesBuilder::new()
+            .with_context_api(ContextApi::OpenGl(Some(glutin::context::Version {
+                major: 2,
+                minor: 1,
+            })))
+            .build(None);
+
+        let (gl_display, gl_context) = Device::query_devices()
+            .map_err(|e| eprintln!("Couldn't query EGL devices: {e}"))
+            .ok()?
+            .find_map(|device| unsafe {
+                let gl_display = Display::with_device(&device, None).ok()?;
+                let gl_config = gl_display.find_configs(template.clone()).ok()?.next()?;
+                let gl_context = gl_display
+                    .create_context(&gl_config, &context_attributes)
+                    .ok()?
+                    .make_current_surfaceless()
+                    .ok()?;
+                Some((gl_display, gl_context))
+            })?;
+
+        let gl =
+            unsafe { glow::Context::from_loader_function_cstr(|s| gl_display.get_proc_address(s)) };
+
+        let ctx = BevyGlContext::from_native_gl(
+            gl,
+            glutin::context::PossiblyCurrentContext::Egl(gl_context),
+            None,
+            glutin::display::Display::Egl(gl_display),
+            uvec2(width, height),
+        );
+
+        let target = HeadlessTarget::new(&ctx, width, height)?;
+        target.framebuffer.bind(&ctx);
+        Some((ctx, target))
+    }
+
+    /// Shared by new() and new_headless() once the context is current.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn from_native_gl(
+        gl: glow::Context,
+        gl_context: glutin::context::PossiblyCurrentContext,
+        gl_surface: Option<glutin::surface::Surface<glutin::surface::WindowSurface>>,
+        gl_display: glutin::display::Display,
+        window_size: UVec2,
+    ) -> BevyGlContext {
+        let has_cube_map_seamless = if gl
+            .supported_extensions()
+            .contains("GL_ARB_seamless_cube_map")
+        {
+            unsafe { gl.enable(glow::TEXTURE_CUBE_MAP_SEAMLESS) };
+            true
+        } else {
+            false
+        };
+
+        let has_vertex_array_object = gl.version().major >= 3
+            || gl
+                .supported_extensions()
+                .contains("GL_ARB_vertex_array_object");
+
+        let has_framebuffer_object = gl.version().major >= 3
+            || gl
+                .supported_extensions()
+                .contains("GL_ARB_framebuffer_object");
+
+        // GLES 2 has EXT_texture_rg but only with unsized formats, so it's not used.
+        let has_texture_rg = gl.version().major >= 3
+            || !gl.version().is_embedded && gl.supported_extensions().contains("GL_ARB_texture_rg");
+
+        let has_texture_array = gl.version().major >= 3
+            || !gl.version().is_embedded
+                && gl.supported_extensions().contains("GL_EXT_texture_array");
+
+        let has_texture_3d = gl.version().major >= 3 || !gl.version().is_embedded;
+
+        // glow only loads the core entry points, not the ARB_instanced_arrays ones.
+        let version = gl.version();
+        let has_instancing = if version.is_embedded {
+            version.major >= 3
+        } else {
+            (version.major, version.minor) >= (3, 3)
+        };
+
+        let has_float_render_target = if version.is_embedded {
+            version.major >= 3
+                && (gl
+                    .supported_extensions()
+                    .contains("GL_EXT_color_buffer_half_float")
+                    || gl
+                        .supported_extensions()
+                        .contains("GL_EXT_color_buffer_float"))
+        } else {
+            version.major >= 3
+        };
+
+        // Like instancing, glow only loads the core entry points.
+        let has_sampler_objects = if version.is_embedded {
+            version.major >= 3
+        } else {
+            (version.major, version.minor) >= (3, 3)
+        };
+
+        // Headless contexts have no default framebuffer to query. Core profiles don't have STENCIL_BITS.
+        let has_stencil = gl_surface.is_some()
+            && unsafe {
+                if version.major >= 3 {
+                    gl.get_framebuffer_attachment_parameter_i32(
+                        glow::FRAMEBUFFER,
+                        glow::STENCIL,
+                        glow::FRAMEBUFFER_ATTACHMENT_STENCIL_SIZE,
+                    )
+                } else {
+                    gl.get_parameter_i32(glow::STENCIL_BITS)
+                }
+            } > 0;
+
+        let capabilities = GlCapabilities::query(&gl, has_vertex_array_object, has_instancing);
+        let shader_language = ShaderLanguageSettings::detect(&gl);
+        let has_debug_labels = gl.supports_debug();
+
+        let mut ctx = BevyGlContext {
+            gl: Arc::new(gl),
+            gl_context: Some(gl_context),
+            gl_surface,
+            gl_display: Some(gl_display),
+            shader_cache: Default::default(),
+            shader_cache_map: Default::default(),
+            shader_includes: Default::default(),
+            program_binary_cache: None,
+            has_glsl_cube_lod: true,
+            has_cube_map_seamless,
+            has_vertex_array_object,
+            has_framebuffer_object,
+            // Depth textures and shadow samplers are core since GL 1.4
+            has_depth_texture: has_framebuffer_object,
+            has_stencil,
+            srgb_framebuffer: false,
+            has_texture_rg,
+            has_texture_array,
+            has_texture_3d,
+            has_float_render_target,
+            has_instancing,
+            has_sampler_objects,
+            capabilities,
+            shader_language,
+            webgl2: false,
+            debug_gl_errors: cfg!(debug_assertions),
+            has_debug_labels,
+            debug_labels: cfg!(debug_assertions),
+            window_size,
+            render_state: Default::default(),
+            stencil_state: None,
+            uniform_slot_map: Default::default(),
+            current_program: Default::default(),
+            current_shader: Default::default(),
+            temp_slot_data: Default::default(),
+            vertex_bindings_generation: Default::default(),
+            uniform_location_cache: Default::default(),
+            attrib_location_cache: Default::default(),
+            current_texture_slot_count: 0,
+            warn_missing_uniforms: false,
+            missing_uniform_warnings: Default::default(),
+        };
+        ctx.test_for_glsl_lod();
+        ctx
+    }
+
     pub fn use_cached_program(&mut self, index: ShaderIndex) {
         self.uniform_slot_map.clear();
         self.temp_slot_data.clear();
-        self.uniform_location_cache.clear();
         self.current_program = Some(self.shader_cache[index as usize]);
+        self.current_shader = Some(index);
         self.current_texture_slot_count = 0;
         self.set_cull_mode(Some(Face::Back)); // Cull backfaces by default like bevy.
         unsafe { self.gl.use_program(self.current_program) };
@@ -330,6 +831,21 @@ impl BevyGlContext {
         }
     }
 
+    /// Like get_attrib_location() but only queries the driver the first time a name is looked up for a shader.
+    pub fn cached_attrib_location(
+        &mut self,
+        shader_index: ShaderIndex,
+        name: &'static str,
+    ) -> Option<u32> {
+        if let Some(location) = self.attrib_location_cache.get(&(shader_index, name)) {
+            return *location;
+        }
+        let location = self.get_attrib_location(shader_index, name);
+        self.attrib_location_cache
+            .insert((shader_index, name), location);
+        location
+    }
+
     pub fn get_attribute_count(&self, shader_index: ShaderIndex) -> u32 {
         unsafe {
             self.gl
@@ -378,36 +894,48 @@ impl BevyGlContext {
         }
     }
 
-    /// Get uniform location for the currently bound shader program
+    /// Get uniform location for the currently bound shader program. Lookups are cached per shader and name,
+    /// including names without a location, so the driver is only queried the first time a shader looks up a name.
+    /// The cache outlives use_cached_program() and is invalidated when the shader is hot reloaded.
     pub fn get_uniform_location(&mut self, name: &str) -> Option<glow::UniformLocation> {
-        if let Some(location) = self.uniform_location_cache.get(name) {
-            location.clone()
-        } else {
-            let location = unsafe {
-                self.gl.get_uniform_location(
-                    self.current_program
-                        .expect("Need to run use_cached_program() before get_uniform_location()"),
-                    name,
-                )
-            };
-            self.uniform_location_cache
-                .insert(name.to_string(), location.clone());
-            location
-        }
+        let shader_index = self
+            .current_shader
+            .expect("Need to run use_cached_program() before get_uniform_location()");
+        let program = self.shader_cache[shader_index as usize];
+        let gl = &self.gl;
+        let cache = self.uniform_location_cache.entry(shader_index).or_default();
+        cached_location(cache, name, || unsafe {
+            gl.get_uniform_location(program, name)
+        })
     }
 
-    /// Uploads immediately if location is found
-    pub fn load<V>(&mut self, name: &str, v: V)
+    /// Uploads v to the uniform name of the currently bound shader program immediately, for one off uniforms that
+    /// don't warrant a UniformSet. The location is looked up with get_uniform_location(), so only the first load of a
+    /// name per shader queries the driver. Returns false if the program has no active uniform with that name, either because
+    /// the shader doesn't declare it or because the driver optimized it out. Those are warned about once per program
+    /// and name with warn_missing_uniforms.
+    pub fn load<V>(&mut self, name: &str, v: V) -> bool
     where
         V: UniformValue,
     {
-        if let Some(location) = self.get_uniform_location(name) {
-            v.load(&self.gl, &location);
-        }
+        let Some(location) = self.get_uniform_location(name) else {
+            if self.warn_missing_uniforms
+                && let Some(program) = self.current_program
+                && self
+                    .missing_uniform_warnings
+                    .insert((program, name.to_string()))
+            {
+                warn!("Uniform {name} has no active location in program {program:?}");
+            }
+            return false;
+        };
+        v.load(&self.gl, &location);
+        true
     }
 
     // Binding locations are optional. If they are not used get_uniform_location or UniformSlotBuilder must be used to
     // correlate binding names to numbers.
+    /// Prints and returns None if the shader fails to compile. If a hot reload fails the last good program is kept.
     pub fn shader_cached<'a, P, I>(
         &mut self,
         vertex: &P,
@@ -415,6 +943,35 @@ impl BevyGlContext {
         shader_defs: I,
         bindings: &[&'static [&'static str]],
     ) -> Option<ShaderIndex>
+    where
+        I: IntoIterator<Item = &'a (&'a str, &'a str)> + Clone,
+        P: AsRef<Path> + ?Sized,
+    {
+        match self.try_shader_cached(vertex, fragment, shader_defs.clone(), bindings) {
+            Ok(index) => Some(index),
+            Err(e) => {
+                println!("{}", e);
+                let key = shader_key(
+                    vertex.as_ref(),
+                    fragment.as_ref(),
+                    shader_defs,
+                    bindings,
+                    &self.shader_language,
+                );
+                self.shader_cache_map.get(&key).map(|(index, _)| *index)
+            }
+        }
+    }
+
+    /// Like shader_cached() but returns the error instead of printing it. If a hot reload fails the error is returned
+    /// but the last good program stays in the cache and will be returned by subsequent calls.
+    pub fn try_shader_cached<'a, P, I>(
+        &mut self,
+        vertex: &P,
+        fragment: &P,
+        shader_defs: I,
+        bindings: &[&'static [&'static str]],
+    ) -> Result<ShaderIndex, ShaderError>
     where
         I: IntoIterator<Item = &'a (&'a str, &'a str)> + Clone,
         P: AsRef<Path> + ?Sized,
@@ -424,45 +981,88 @@ impl BevyGlContext {
             fragment.as_ref(),
             shader_defs.clone(),
             bindings,
+            &self.shader_language,
         );
         if let Some((index, watcher)) = self.shader_cache_map.get(&key) {
+            let index = *index;
             if watcher.check() {
                 let vertex_src = std::fs::read_to_string(vertex).unwrap();
                 let fragment_src = std::fs::read_to_string(fragment).unwrap();
-                let old_shader = self.shader_cache[*index as usize];
-                let new_shader =
-                    self.compile_shader(&vertex_src, &fragment_src, shader_defs, bindings);
-                match new_shader {
-                    Ok(shader) => {
-                        self.shader_cache[*index as usize] = shader;
-                        unsafe { self.gl.delete_program(old_shader) }
-                    }
-                    Err(e) => println!("{}", e),
+                let old_shader = self.shader_cache[index as usize];
+                let shader =
+                    self.compile_shader(&vertex_src, &fragment_src, shader_defs, bindings)?;
+                if let Some(cache) = &self.program_binary_cache {
+                    let binary_key = self.program_binary_key(key, &vertex_src, &fragment_src);
+                    cache.store(&self.gl, binary_key, shader);
                 }
+                self.shader_cache[index as usize] = shader;
+                self.attrib_location_cache
+                    .retain(|(shader_index, _), _| *shader_index != index);
+                self.uniform_location_cache.remove(&index);
+                // GpuMeshes would otherwise keep the old program's attribute bindings for this index.
+                self.invalidate_vertex_bindings();
+                self.label_program(shader, vertex.as_ref(), fragment.as_ref());
+                unsafe { self.gl.delete_program(old_shader) }
             }
-            Some(*index)
+            Ok(index)
         } else {
             let vertex_src = std::fs::read_to_string(vertex).unwrap();
             let fragment_src = std::fs::read_to_string(fragment).unwrap();
-            let new_shader = self.compile_shader(&vertex_src, &fragment_src, shader_defs, bindings);
-            match new_shader {
-                Ok(shader) => {
-                    let index = self.shader_cache.len() as u32;
-                    self.shader_cache.push(shader);
-                    self.shader_cache_map.insert(
-                        key,
-                        (index, Watchers::new(&[vertex.as_ref(), fragment.as_ref()])),
-                    );
-                    Some(index)
-                }
-                Err(e) => {
-                    println!("{}", e);
-                    None
+            let shader = if let Some(cache) = &self.program_binary_cache {
+                let binary_key = self.program_binary_key(key, &vertex_src, &fragment_src);
+                if let Some(shader) = cache.load(&self.gl, binary_key) {
+                    shader
+                } else {
+                    let shader =
+                        self.compile_shader(&vertex_src, &fragment_src, shader_defs, bindings)?;
+                    cache.store(&self.gl, binary_key, shader);
+                    shader
                 }
-            }
+            } else {
+                self.compile_shader(&vertex_src, &fragment_src, shader_defs, bindings)?
+            };
+            self.label_program(shader, vertex.as_ref(), fragment.as_ref());
+            let index = self.shader_cache.len() as u32;
+            self.shader_cache.push(shader);
+            self.shader_cache_map.insert(
+                key,
+                (index, Watchers::new(&[vertex.as_ref(), fragment.as_ref()])),
+            );
+            Ok(index)
         }
     }
 
+    /// Start persisting linked programs to dir. Programs are loaded from their binary when the source, includes, shader
+    /// defs and driver all match. Returns false if program binaries aren't supported. Desktop GL only, and only used
+    /// by shader_cached! when it reads the shaders from disk: with bundle_shaders they're always compiled from source.
+    pub fn enable_program_binary_cache(&mut self, dir: impl Into<PathBuf>) -> bool {
+        self.program_binary_cache = ProgramBinaryCache::new(&self.gl, dir);
+        self.program_binary_cache.is_some()
+    }
+
+    /// shader_key() doesn't cover the source text since it's used for hot reload. The binary also depends on the
+    /// sources and includes.
+    fn program_binary_key(&self, key: u64, vertex: &str, fragment: &str) -> u64 {
+        let mut hasher = StableHasher::default();
+        key.hash(&mut hasher);
+        vertex.hash(&mut hasher);
+        fragment.hash(&mut hasher);
+        let mut includes = self.shader_includes.iter().collect::<Vec<_>>();
+        includes.sort();
+        includes.hash(&mut hasher);
+        // Part of the preamble but not of key.
+        self.srgb_framebuffer.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Puts a preamble before each stage with the #version and precision from shader_language, shader_defs as
+    /// #defines, extensions and the uniform declarations from bindings, then expands #includes.
+    ///
+    /// Sources written for a specific GLSL version can opt out, so ported GLSL compiles unchanged:
+    /// - If the first line that isn't blank or a // comment is a #version directive, the stage gets no preamble at all
+    ///   and isn't translated. Only #includes are expanded, so shader_defs and bindings have to be declared by the
+    ///   source itself.
+    /// - Otherwise if it's a precision statement, the preamble leaves out its own default precision.
     #[must_use]
     pub fn compile_shader<'a, I>(
         &self,
@@ -470,7 +1070,7 @@ impl BevyGlContext {
         fragment: &str,
         shader_defs: I,
         bindings: &[&'static [&'static str]],
-    ) -> Result<glow::Program, anyhow::Error>
+    ) -> Result<glow::Program, ShaderError>
     where
         I: IntoIterator<Item = &'a (&'a str, &'a str)> + Clone,
     {
@@ -479,17 +1079,17 @@ impl BevyGlContext {
 
             let mut vertex = vertex.to_string();
             let mut fragment = fragment.to_string();
+            let raw_vertex = leading_statement(&vertex).starts_with("#version");
+            let raw_fragment = leading_statement(&fragment).starts_with("#version");
 
-            for (shader_type, shader_source) in [
-                (glow::VERTEX_SHADER, &mut vertex),
-                (glow::FRAGMENT_SHADER, &mut fragment),
+            for (shader_type, shader_source, raw) in [
+                (glow::VERTEX_SHADER, &mut vertex, raw_vertex),
+                (glow::FRAGMENT_SHADER, &mut fragment, raw_fragment),
             ] {
-                #[cfg(target_arch = "wasm32")]
-                let mut preamble = "precision highp float;\nprecision highp int;\n".to_string();
-                #[cfg(any(target_os = "windows", target_os = "linux"))]
-                let mut preamble = "#version 120\n".to_string();
-                #[cfg(target_os = "macos")]
-                let mut preamble = "#version 330\n".to_string();
+                let mut preamble = self.shader_language.version_directive().to_string();
+                if !leading_statement(shader_source).starts_with("precision") {
+                    preamble.push_str(&self.shader_language.precision_statements());
+                }
 
                 shader_defs.clone().into_iter().for_each(|shader_def| {
                     if !(shader_def.0.is_empty() && shader_def.1.is_empty()) {
@@ -497,24 +1097,33 @@ impl BevyGlContext {
                     }
                 });
 
+                // sampler2DArray is core in GLSL 1.30
+                if self.shader_language.version == GlslVersion::Glsl120 && self.has_texture_array {
+                    preamble.push_str("#extension GL_EXT_texture_array : enable\n");
+                }
+
+                if self.srgb_framebuffer {
+                    preamble.push_str("#define SRGB_FRAMEBUFFER\n");
+                }
+
                 #[cfg(target_arch = "wasm32")]
-                preamble.push_str(&format!("#define WEBGL1\n"));
+                if self.webgl2 {
+                    preamble.push_str("#define WEBGL2\n");
+                } else {
+                    preamble.push_str("#define WEBGL1\n");
+                }
 
+                // textureCubeLod is translated to textureLod for GLSL 3, GLSL ES 1.00 needs the extension.
                 if shader_type == glow::FRAGMENT_SHADER {
-                    //let ext = self.gl.supported_extensions();
-                    //#[cfg(not(target_arch = "wasm32"))]
-                    //if ext.contains("GL_ARB_shader_texture_lod") {
-                    if self.has_glsl_cube_lod {
-                        #[cfg(target_arch = "wasm32")]
-                        {
+                    match (self.shader_language.version, self.has_glsl_cube_lod) {
+                        (GlslVersion::Es100, true) => {
                             preamble.push_str("#extension GL_EXT_shader_texture_lod : enable\n");
                             preamble.push_str("vec4 textureCubeLod(samplerCube tex, vec3 dir, float lod) { return textureCubeLodEXT(tex, dir, lod); }\n");
                         }
-                    } else {
-                        #[cfg(not(any(target_os = "macos", target_arch = "wasm32")))]
-                        {
+                        (GlslVersion::Glsl120, false) => {
                             preamble.push_str("vec4 textureCubeLod(samplerCube tex, vec3 dir, float lod) { return textureCube(tex, dir, lod); }\n");
                         }
+                        _ => (),
                     }
                 }
 
@@ -554,11 +1163,24 @@ impl BevyGlContext {
                     }
                 }
 
-                *shader_source = format!("{}\n#line 0 0\n{}", preamble, expanded_shader_source);
+                *shader_source = if raw {
+                    expanded_shader_source
+                } else {
+                    format!("{}\n#line 0 0\n{}", preamble, expanded_shader_source)
+                };
             }
 
-            #[cfg(target_os = "macos")]
-            macos_compat::translate_shader_to_330(&mut vertex, &mut fragment);
+            if self.shader_language.translates() && !(raw_vertex && raw_fragment) {
+                // Translation looks at both stages together for attribute locations, raw stages are put back after.
+                let untranslated = (vertex.clone(), fragment.clone());
+                macos_compat::translate_shader_to_330(&mut vertex, &mut fragment);
+                if raw_vertex {
+                    vertex = untranslated.0;
+                }
+                if raw_fragment {
+                    fragment = untranslated.1;
+                }
+            }
 
             let shader_sources = [
                 ("vertex", glow::VERTEX_SHADER, vertex),
@@ -568,33 +1190,52 @@ impl BevyGlContext {
             let mut shaders = Vec::with_capacity(shader_sources.len());
 
             for (stage_name, shader_type, shader_source) in shader_sources.iter() {
-                let shader = self.gl.create_shader(*shader_type).map_err(Error::msg)?;
+                let shader = self
+                    .gl
+                    .create_shader(*shader_type)
+                    .map_err(|log| ShaderError {
+                        stage: *stage_name,
+                        log,
+                        source: shader_source.clone(),
+                    })?;
 
                 self.gl.shader_source(shader, shader_source);
 
                 self.gl.compile_shader(shader);
 
                 if !self.gl.get_shader_compile_status(shader) {
-                    return Err(anyhow!(
-                        "{stage_name} shader compilation error: {}", //\n\n{shader_source}
-                        self.gl.get_shader_info_log(shader)
-                    ));
+                    return Err(ShaderError {
+                        stage: *stage_name,
+                        log: self.gl.get_shader_info_log(shader),
+                        source: shader_source.clone(),
+                    });
                 }
 
                 self.gl.attach_shader(program, shader);
                 shaders.push(shader);
             }
 
+            if self.program_binary_cache.is_some() {
+                self.gl.program_binary_retrievable_hint(program, true);
+            }
             self.gl.link_program(program);
 
             if !self.gl.get_program_link_status(program) {
-                return Err(anyhow!("{}", self.gl.get_program_info_log(program)));
+                return Err(ShaderError {
+                    stage: "link",
+                    log: self.gl.get_program_info_log(program),
+                    source: format!(
+                        "// vertex\n{}\n// fragment\n{}",
+                        shader_sources[0].2, shader_sources[1].2
+                    ),
+                });
             }
 
             for shader in shaders {
                 self.gl.detach_shader(program, shader);
                 self.gl.delete_shader(shader);
             }
+            self.debug_check_error("compile_shader");
 
             Ok(program)
         }
@@ -622,21 +1263,126 @@ impl BevyGlContext {
             self.gl
                 .buffer_data_u8_slice(glow::ARRAY_BUFFER, data, usage);
             self.gl.bind_buffer(glow::ARRAY_BUFFER, None);
+            self.debug_check_error("gen_vbo");
             vbo
         }
     }
 
     pub fn gen_vbo_element(&self, data: &[u8], usage: u32) -> Buffer {
+        self.invalidate_vertex_bindings();
         unsafe {
+            if self.has_vertex_array_object {
+                // The element array binding is part of the VAO state, don't modify whatever VAO was last bound.
+                self.gl.bind_vertex_array(None);
+            }
             let vbo = self.gl.create_buffer().unwrap();
             self.gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(vbo));
             self.gl
                 .buffer_data_u8_slice(glow::ELEMENT_ARRAY_BUFFER, data, usage);
             self.gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, None);
+            self.debug_check_error("gen_vbo_element");
             vbo
         }
     }
 
+    /// Overwrites the start of an existing buffer. data must fit in the size the buffer was allocated with.
+    pub fn update_vbo(&self, buffer: Buffer, data: &[u8]) {
+        unsafe {
+            self.gl.bind_buffer(glow::ARRAY_BUFFER, Some(buffer));
+            self.gl
+                .buffer_sub_data_u8_slice(glow::ARRAY_BUFFER, 0, data);
+            self.gl.bind_buffer(glow::ARRAY_BUFFER, None);
+        }
+        self.debug_check_error("update_vbo");
+    }
+
+    /// Overwrites the start of an existing element buffer. data must fit in the size the buffer was allocated with.
+    pub fn update_vbo_element(&self, buffer: Buffer, data: &[u8]) {
+        self.invalidate_vertex_bindings();
+        unsafe {
+            if self.has_vertex_array_object {
+                // The element array binding is part of the VAO state, don't modify whatever VAO was last bound.
+                self.gl.bind_vertex_array(None);
+            }
+            self.gl
+                .bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(buffer));
+            self.gl
+                .buffer_sub_data_u8_slice(glow::ELEMENT_ARRAY_BUFFER, 0, data);
+            self.gl.bind_buffer(glow::ELEMENT_ARRAY_BUFFER, None);
+        }
+        self.debug_check_error("update_vbo_element");
+    }
+
+    /// Logs every pending GL error with label and returns true if there were any. glGetError only reports the first
+    /// error per flag since the last call, so call this once before the operation in question to clear older ones.
+    pub fn check_error(&self, label: &str) -> bool {
+        let mut found = false;
+        // Bounded since a lost context can keep reporting errors on some drivers.
+        for _ in 0..16 {
+            let error = unsafe { self.gl.get_error() };
+            if error == glow::NO_ERROR {
+                break;
+            }
+            error!("GL error {} (0x{error:x}) in {label}", gl_error_name(error));
+            found = true;
+        }
+        found
+    }
+
+    /// Names a buffer for graphics debuggers like RenderDoc and apitrace. Does nothing unless debug_labels and
+    /// has_debug_labels are set.
+    pub fn label_buffer(&self, buffer: Buffer, label: &str) {
+        #[cfg(not(target_arch = "wasm32"))]
+        self.label_object(glow::BUFFER, buffer.0.get(), label);
+        #[cfg(target_arch = "wasm32")]
+        let _ = (buffer, label);
+    }
+
+    /// See label_buffer()
+    pub fn label_texture(&self, texture: glow::Texture, label: &str) {
+        #[cfg(not(target_arch = "wasm32"))]
+        self.label_object(glow::TEXTURE, texture.0.get(), label);
+        #[cfg(target_arch = "wasm32")]
+        let _ = (texture, label);
+    }
+
+    fn label_program(&self, program: glow::Program, vertex: &Path, fragment: &Path) {
+        #[cfg(not(target_arch = "wasm32"))]
+        if self.debug_labels && self.has_debug_labels {
+            let label = format!("{} {}", vertex.display(), fragment.display());
+            self.label_object(glow::PROGRAM, program.0.get(), &label);
+        }
+        #[cfg(target_arch = "wasm32")]
+        let _ = (program, vertex, fragment);
+    }
+
+    /// WebGL has no object labels, has_debug_labels is always false there.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn label_object(&self, identifier: u32, name: u32, label: &str) {
+        if self.debug_labels && self.has_debug_labels {
+            unsafe { self.gl.object_label(identifier, name, Some(label)) };
+        }
+    }
+
+    /// check_error() if debug_gl_errors is set, otherwise does nothing.
+    #[inline]
+    pub fn debug_check_error(&self, label: &str) -> bool {
+        self.debug_gl_errors && self.check_error(label)
+    }
+
+    /// Call after changing vertex array, element buffer or vertex attribute bindings with raw gl calls, so GpuMeshes binds
+    /// its buffers again before the next draw. The BevyGlContext methods that change them call this.
+    pub fn invalidate_vertex_bindings(&self) {
+        self.vertex_bindings_generation
+            .set(self.vertex_bindings_generation.get().wrapping_add(1));
+    }
+
+    /// Binds vao, see invalidate_vertex_bindings(). Needs has_vertex_array_object.
+    pub fn bind_vertex_array(&self, vao: Option<glow::VertexArray>) {
+        unsafe { self.gl.bind_vertex_array(vao) };
+        self.invalidate_vertex_bindings();
+    }
+
     pub fn bind_vertex_attrib(
         &self,
         index: u32,
@@ -644,6 +1390,7 @@ impl BevyGlContext {
         ty: AttribType,
         buffer: Buffer,
     ) {
+        self.invalidate_vertex_bindings();
         unsafe {
             self.gl.bind_buffer(glow::ARRAY_BUFFER, Some(buffer));
             self.gl.vertex_attrib_pointer_f32(
@@ -658,10 +1405,44 @@ impl BevyGlContext {
         }
     }
 
-    pub fn clear_color_and_depth(&self, color: Option<Vec4>) {
+    /// Restricts drawing and clears to rect, in physical pixels from the top left of the window like bevy's
+    /// Viewport. None resets the viewport to the full window and disables the scissor test.
+    pub fn set_viewport(&mut self, rect: Option<URect>) {
+        let (x, y, width, height) = match rect {
+            // GL's origin is the bottom left
+            Some(rect) => (
+                rect.min.x,
+                self.window_size.y.saturating_sub(rect.max.y),
+                rect.width(),
+                rect.height(),
+            ),
+            None => (0, 0, self.window_size.x, self.window_size.y),
+        };
+        unsafe {
+            self.gl
+                .viewport(x as i32, y as i32, width as i32, height as i32);
+            self.gl
+                .scissor(x as i32, y as i32, width as i32, height as i32);
+        }
+        set_capability(&self.gl, glow::SCISSOR_TEST, rect.is_some());
+    }
+
+    /// color as it should be written to the window: sRGB, or linear with srgb_framebuffer.
+    pub fn output_color(&self, color: Color) -> Vec4 {
+        if self.srgb_framebuffer {
+            color.to_linear().to_vec4()
+        } else {
+            color.to_srgba().to_vec4()
+        }
+    }
+
+    pub fn clear_color_and_depth(&mut self, color: Option<Vec4>) {
+        self.apply_render_state(RenderState {
+            depth_write: true,
+            color_write: [true; 4],
+            ..self.render_state
+        });
         unsafe {
-            self.gl.depth_mask(true);
-            self.gl.color_mask(true, true, true, true);
             if let Some(color) = color {
                 self.gl.clear_color(color.x, color.y, color.z, color.w);
             } else {
@@ -669,14 +1450,18 @@ impl BevyGlContext {
             }
             self.gl.clear_depth_f32(0.0);
             self.gl
-                .clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT);
+                .clear(glow::COLOR_BUFFER_BIT | glow::DEPTH_BUFFER_BIT | self.stencil_clear_bit());
         };
+        self.restore_stencil_write_mask();
     }
 
-    pub fn clear_color(&self, color: Option<Vec4>) {
+    pub fn clear_color(&mut self, color: Option<Vec4>) {
+        self.apply_render_state(RenderState {
+            depth_write: false,
+            color_write: [true; 4],
+            ..self.render_state
+        });
         unsafe {
-            self.gl.depth_mask(false);
-            self.gl.color_mask(true, true, true, true);
             if let Some(color) = color {
                 self.gl.clear_color(color.x, color.y, color.z, color.w);
             } else {
@@ -686,73 +1471,356 @@ impl BevyGlContext {
         };
     }
 
-    pub fn clear_depth(&self) {
+    pub fn clear_depth(&mut self) {
+        self.apply_render_state(RenderState {
+            depth_write: true,
+            ..self.render_state
+        });
         unsafe {
-            self.gl.depth_mask(true);
             self.gl.clear_depth_f32(0.0);
-            self.gl.clear(glow::DEPTH_BUFFER_BIT);
+            self.gl
+                .clear(glow::DEPTH_BUFFER_BIT | self.stencil_clear_bit());
         };
+        self.restore_stencil_write_mask();
     }
 
-    pub fn start_alpha_blend(&self) {
+    /// STENCIL_BUFFER_BIT if there is a stencil buffer, with the write mask opened up so the whole value is cleared to 0.
+    fn stencil_clear_bit(&self) -> u32 {
+        if !self.has_stencil {
+            return 0;
+        }
         unsafe {
-            self.gl.enable(glow::DEPTH_TEST);
-            self.gl.enable(glow::BLEND);
-            self.gl.depth_func(glow::GEQUAL);
-            self.gl.depth_mask(false);
-            self.gl.color_mask(true, true, true, true);
-            self.gl
-                .blend_func(glow::SRC_ALPHA, glow::ONE_MINUS_SRC_ALPHA);
+            self.gl.clear_stencil(0);
+            self.gl.stencil_mask(!0);
         }
+        glow::STENCIL_BUFFER_BIT
     }
 
-    /// It's not necessary to write depth after a prepass if everything is also included in opaque.
-    pub fn start_opaque(&self, write_depth: bool, depth_equal: bool) {
+    fn restore_stencil_write_mask(&self) {
+        if let Some(state) = self.stencil_state {
+            unsafe { self.gl.stencil_mask(state.write_mask) };
+        }
+    }
+
+    /// Enables the stencil test with state, or disables it with None. Needs a stencil buffer, see has_stencil. The
+    /// start_* functions disable it, so set it after those and back to None after the draws that use it.
+    pub fn set_stencil_state(&mut self, state: Option<StencilState>) {
+        if self.stencil_state == state {
+            return;
+        }
         unsafe {
-            self.gl.enable(glow::DEPTH_TEST);
-            self.gl.disable(glow::BLEND);
-            self.gl.depth_func(if depth_equal {
+            match state {
+                Some(state) => {
+                    self.gl.enable(glow::STENCIL_TEST);
+                    self.gl
+                        .stencil_func(state.func, state.reference, state.read_mask);
+                    self.gl
+                        .stencil_op(state.stencil_fail, state.depth_fail, state.pass);
+                    self.gl.stencil_mask(state.write_mask);
+                }
+                None => {
+                    self.gl.disable(glow::STENCIL_TEST);
+                    self.gl.stencil_mask(!0);
+                }
+            }
+        }
+        self.stencil_state = state;
+    }
+
+    pub fn start_alpha_blend(&mut self) {
+        self.invalidate_vertex_bindings();
+        self.set_stencil_state(None);
+        self.apply_render_state(RenderState {
+            blend: true,
+            depth_test: true,
+            depth_write: false,
+            depth_func: glow::GEQUAL,
+            color_write: [true; 4],
+            ..self.render_state
+        });
+        BlendState::ALPHA.apply(&self.gl);
+    }
+
+    /// It's not necessary to write depth after a prepass if everything is also included in opaque.
+    pub fn start_opaque(&mut self, write_depth: bool, depth_equal: bool) {
+        self.invalidate_vertex_bindings();
+        self.set_stencil_state(None);
+        self.apply_render_state(RenderState {
+            blend: false,
+            depth_test: true,
+            depth_write: write_depth,
+            depth_func: if depth_equal {
                 glow::EQUAL
             } else {
                 glow::GEQUAL
-            });
-            self.gl.depth_mask(write_depth);
-            self.gl.color_mask(true, true, true, false);
-            self.gl.blend_func(glow::ZERO, glow::ONE);
-        }
+            },
+            color_write: [true, true, true, false],
+            ..self.render_state
+        });
+        BlendState::REPLACE_NONE.apply(&self.gl);
+    }
+
+    pub fn start_depth_only(&mut self) {
+        self.invalidate_vertex_bindings();
+        self.set_stencil_state(None);
+        self.apply_render_state(RenderState {
+            blend: false,
+            depth_test: true,
+            depth_write: true,
+            depth_func: glow::GEQUAL,
+            color_write: [false; 4],
+            ..self.render_state
+        });
+        BlendState::REPLACE_NONE.apply(&self.gl);
     }
 
-    pub fn start_depth_only(&self) {
+    pub fn set_cull_mode(&mut self, cull_mode: Option<Face>) {
+        self.apply_render_state(RenderState {
+            cull_mode,
+            ..self.render_state
+        });
+    }
+
+    /// The GL state last set though the start_* functions, set_cull_mode() or apply_render_state().
+    pub fn current_state(&self) -> RenderState {
+        self.render_state
+    }
+
+    /// Only makes GL calls for the parts of the state that differ from the current state.
+    pub fn apply_render_state(&mut self, state: RenderState) {
+        let current = self.render_state;
+        self.render_state = state;
         unsafe {
-            self.gl.enable(glow::DEPTH_TEST);
-            self.gl.disable(glow::BLEND);
-            self.gl.depth_func(glow::GEQUAL);
-            self.gl.depth_mask(true);
-            self.gl.color_mask(false, false, false, false);
-            self.gl.blend_func(glow::ZERO, glow::ONE);
+            if current.cull_mode != state.cull_mode {
+                match state.cull_mode {
+                    Some(face) => {
+                        self.gl.enable(glow::CULL_FACE);
+                        self.gl.cull_face(match face {
+                            Face::Front => glow::FRONT,
+                            Face::Back => glow::BACK,
+                        });
+                    }
+                    None => self.gl.disable(glow::CULL_FACE),
+                }
+            }
+            if current.blend != state.blend {
+                set_capability(&self.gl, glow::BLEND, state.blend);
+            }
+            if current.depth_test != state.depth_test {
+                set_capability(&self.gl, glow::DEPTH_TEST, state.depth_test);
+            }
+            if current.depth_write != state.depth_write {
+                self.gl.depth_mask(state.depth_write);
+            }
+            if current.depth_func != state.depth_func {
+                self.gl.depth_func(state.depth_func);
+            }
+            if current.color_write != state.color_write {
+                let [r, g, b, a] = state.color_write;
+                self.gl.color_mask(r, g, b, a);
+            }
+            #[cfg(not(target_arch = "wasm32"))]
+            if current.polygon_mode != state.polygon_mode {
+                self.gl
+                    .polygon_mode(glow::FRONT_AND_BACK, state.polygon_mode);
+            }
+            if current.polygon_offset.is_some() != state.polygon_offset.is_some() {
+                set_capability(
+                    &self.gl,
+                    glow::POLYGON_OFFSET_FILL,
+                    state.polygon_offset.is_some(),
+                );
+            }
+            if current.polygon_offset != state.polygon_offset
+                && let Some((factor, units)) = state.polygon_offset
+            {
+                self.gl.polygon_offset(factor, units);
+            }
         }
     }
 
-    pub fn set_cull_mode(&mut self, cull_mode: Option<Face>) {
-        if self.last_cull_mode != cull_mode {
-            self.last_cull_mode = cull_mode;
-            unsafe {
-                match cull_mode {
-                    Some(face) => match face {
-                        wgpu_types::Face::Front => {
-                            self.gl.enable(glow::CULL_FACE);
-                            self.gl.cull_face(glow::FRONT);
-                        }
-                        wgpu_types::Face::Back => {
-                            self.gl.enable(glow::CULL_FACE);
-                            self.gl.cull_face(glow::BACK);
-                        }
-                    },
-                    None => {
-                        self.gl.disable(glow::CULL_FACE);
-                    }
+    /// Offsets the depth of filled polygons drawn after this, e.g. for decals on coplanar surfaces or to reduce shadow
+    /// acne. Depth is reversed here (GEQUAL, cleared to 0), so positive values move polygons towards the camera. This
+    /// isn't reset by the start_* functions, call clear_polygon_offset() after the draws that need it or everything
+    /// drawn after is offset too.
+    pub fn set_polygon_offset(&mut self, factor: f32, units: f32) {
+        self.apply_render_state(RenderState {
+            polygon_offset: Some((factor, units)),
+            ..self.render_state
+        });
+    }
+
+    pub fn clear_polygon_offset(&mut self) {
+        self.apply_render_state(RenderState {
+            polygon_offset: None,
+            ..self.render_state
+        });
+    }
+
+    /// Enables blending and sets the blend func and equation. Use after start_alpha_blend() to override its
+    /// standard alpha blending for a custom material. The start_* functions reset the blend state.
+    pub fn set_blend_state(&mut self, blend_state: BlendState) {
+        self.apply_render_state(RenderState {
+            blend: true,
+            ..self.render_state
+        });
+        blend_state.apply(&self.gl);
+    }
+
+    /// glow::FILL, glow::LINE or glow::POINT. Desktop GL only, WebGL has no polygon_mode so this warns and does
+    /// nothing there.
+    pub fn set_polygon_mode(&mut self, polygon_mode: u32) {
+        #[cfg(target_arch = "wasm32")]
+        if polygon_mode != glow::FILL {
+            warn_once!(
+                "polygon_mode is not supported on WebGL, wireframe rendering is desktop only"
+            );
+            return;
+        }
+        let mut state = self.render_state;
+        state.polygon_mode = polygon_mode;
+        self.apply_render_state(state);
+    }
+
+    /// Forgets every cached program and location and reapplies the render state, for when all GL objects were lost
+    /// with a WebGL context. Programs are compiled again the next time they're used.
+    pub fn forget_gl_objects(&mut self) {
+        self.shader_cache.clear();
+        self.shader_cache_map.clear();
+        self.uniform_location_cache.clear();
+        self.attrib_location_cache.clear();
+        self.uniform_slot_map.clear();
+        self.current_program = None;
+        self.current_shader = None;
+        self.current_texture_slot_count = 0;
+        self.invalidate_vertex_bindings();
+        self.restore_render_state();
+    }
+
+    /// Re-issues all the GL calls for the current state. Use after external code (like the egui painter) has changed
+    /// GL state directly so that the tracked state matches again.
+    pub fn restore_render_state(&mut self) {
+        let state = self.render_state;
+        self.render_state = state.inverted();
+        self.apply_render_state(state);
+        let stencil_state = self.stencil_state;
+        self.stencil_state = match stencil_state {
+            Some(_) => None,
+            None => Some(StencilState::write(0)),
+        };
+        self.set_stencil_state(stencil_state);
+    }
+
+    /// Reads RGBA8 pixels from the currently bound framebuffer. rect has its origin at the top left of a framebuffer
+    /// that is framebuffer_height tall. Rows are returned top row first.
+    pub fn read_pixels_rgba8(&self, rect: URect, framebuffer_height: u32) -> Vec<u8> {
+        let size = rect.size();
+        let row_bytes = size.x as usize * 4;
+        let mut pixels = vec![0u8; row_bytes * size.y as usize];
+        if pixels.is_empty() {
+            return pixels;
+        }
+        unsafe {
+            self.gl.read_pixels(
+                rect.min.x as i32,
+                framebuffer_height.saturating_sub(rect.max.y) as i32,
+                size.x as i32,
+                size.y as i32,
+                glow::RGBA,
+                glow::UNSIGNED_BYTE,
+                glow::PixelPackData::Slice(Some(&mut pixels)),
+            );
+        }
+        // GL's origin is at the bottom left
+        pixels
+            .chunks_exact(row_bytes)
+            .rev()
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// Reads level 0 of a TEXTURE_2D back to the CPU. GL ES has no way to query the size of a texture, so it has to be
+    /// passed in. Color textures are returned as Rgba8Unorm. Depth textures are returned as R32Float, but only on
+    /// desktop GL, elsewhere they can't be read. Rows are in texture order, so the first row is the bottom of images
+    /// rendered by GL. Changes the texture bound to the active unit.
+    pub fn read_texture(&self, texture: glow::Texture, target: u32, size: UVec2) -> Option<Image> {
+        if target != glow::TEXTURE_2D || size.min_element() == 0 {
+            return None;
+        }
+        let image = |data, format| {
+            Image::new(
+                Extent3d {
+                    width: size.x,
+                    height: size.y,
+                    depth_or_array_layers: 1,
+                },
+                TextureDimension::D2,
+                data,
+                format,
+                RenderAssetUsages::default(),
+            )
+        };
+        let mut pixels = vec![0u8; size.x as usize * size.y as usize * 4];
+        unsafe {
+            self.gl.pixel_store_i32(glow::PACK_ALIGNMENT, 1);
+            #[cfg(not(target_arch = "wasm32"))]
+            if !self.gl.version().is_embedded {
+                self.check_error("before read_texture");
+                self.gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+                self.gl.get_tex_image(
+                    glow::TEXTURE_2D,
+                    0,
+                    glow::RGBA,
+                    glow::UNSIGNED_BYTE,
+                    glow::PixelPackData::Slice(Some(&mut pixels)),
+                );
+                // Color formats can't be read from depth textures.
+                let format = if self.gl.get_error() == glow::INVALID_OPERATION {
+                    self.gl.get_tex_image(
+                        glow::TEXTURE_2D,
+                        0,
+                        glow::DEPTH_COMPONENT,
+                        glow::FLOAT,
+                        glow::PixelPackData::Slice(Some(&mut pixels)),
+                    );
+                    TextureFormat::R32Float
+                } else {
+                    TextureFormat::Rgba8Unorm
+                };
+                self.gl.bind_texture(glow::TEXTURE_2D, None);
+                if self.check_error("read_texture") {
+                    return None;
                 }
+                return Some(image(pixels, format));
+            }
+
+            let previous = self.gl.get_parameter_framebuffer(glow::FRAMEBUFFER_BINDING);
+            let fbo = self.gl.create_framebuffer().ok()?;
+            self.gl.bind_framebuffer(glow::FRAMEBUFFER, Some(fbo));
+            self.gl.framebuffer_texture_2d(
+                glow::FRAMEBUFFER,
+                glow::COLOR_ATTACHMENT0,
+                glow::TEXTURE_2D,
+                Some(texture),
+                0,
+            );
+            let complete =
+                self.gl.check_framebuffer_status(glow::FRAMEBUFFER) == glow::FRAMEBUFFER_COMPLETE;
+            if complete {
+                self.gl.read_pixels(
+                    0,
+                    0,
+                    size.x as i32,
+                    size.y as i32,
+                    glow::RGBA,
+                    glow::UNSIGNED_BYTE,
+                    glow::PixelPackData::Slice(Some(&mut pixels)),
+                );
             }
+            self.gl.bind_framebuffer(glow::FRAMEBUFFER, previous);
+            self.gl.delete_framebuffer(fbo);
+            complete.then(|| image(pixels, TextureFormat::Rgba8Unorm))
         }
     }
 
@@ -765,6 +1833,259 @@ impl BevyGlContext {
             self.gl_context.as_ref().unwrap(),
         );
     }
+
+    /// Sets whether swap() waits for vertical blank. The window's present mode is applied at creation and whenever it
+    /// changes, see present_mode_vsync(), so this only needs to be called directly without a bevy Window. The driver
+    /// may ignore it.
+    ///
+    /// No-op on WebGL, the browser presents with requestAnimationFrame which is always synced to the display.
+    pub fn set_vsync(&self, vsync: bool) {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            use glutin::surface::{GlSurface, SwapInterval};
+            let (Some(gl_surface), Some(gl_context)) = (&self.gl_surface, &self.gl_context) else {
+                return;
+            };
+            let interval = if vsync {
+                SwapInterval::Wait(std::num::NonZeroU32::new(1).unwrap())
+            } else {
+                SwapInterval::DontWait
+            };
+            if let Err(e) = gl_surface.set_swap_interval(gl_context, interval) {
+                warn!("Couldn't set the swap interval: {e}");
+            }
+        }
+        #[cfg(target_arch = "wasm32")]
+        let _ = vsync;
+    }
+}
+
+/// Whether a present mode waits for vertical blank. GL only has on or off, Mailbox and FifoRelaxed map to the closest.
+pub fn present_mode_vsync(present_mode: bevy::window::PresentMode) -> bool {
+    match present_mode {
+        bevy::window::PresentMode::AutoVsync => true,
+        bevy::window::PresentMode::AutoNoVsync => false,
+        bevy::window::PresentMode::Fifo => true,
+        bevy::window::PresentMode::FifoRelaxed => true,
+        bevy::window::PresentMode::Immediate => false,
+        bevy::window::PresentMode::Mailbox => false,
+    }
+}
+
+/// The first line of source that isn't blank or a // comment, trimmed. See compile_shader().
+fn leading_statement(source: &str) -> &str {
+    source
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with("//"))
+        .unwrap_or("")
+}
+
+/// A shader that failed to compile or link.
+#[derive(Debug, Clone)]
+pub struct ShaderError {
+    /// "vertex", "fragment" or "link"
+    pub stage: &'static str,
+    /// Info log from the driver
+    pub log: String,
+    /// Source that was given to the driver, including the injected preamble and includes.
+    pub source: String,
+}
+
+impl std::fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.stage == "link" {
+            write!(f, "shader link error: {}", self.log)
+        } else {
+            write!(f, "{} shader compilation error: {}", self.stage, self.log)
+        }
+    }
+}
+
+impl std::error::Error for ShaderError {}
+
+/// GL pipeline state tracked by BevyGlContext so redundant state changes can be skipped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderState {
+    pub cull_mode: Option<Face>,
+    pub blend: bool,
+    pub depth_test: bool,
+    pub depth_write: bool,
+    /// glow::GEQUAL, glow::EQUAL, etc...
+    pub depth_func: u32,
+    pub color_write: [bool; 4],
+    /// glow::FILL, glow::LINE or glow::POINT. Always glow::FILL on WebGL.
+    pub polygon_mode: u32,
+    /// glPolygonOffset factor and units for filled polygons, None disables GL_POLYGON_OFFSET_FILL.
+    pub polygon_offset: Option<(f32, f32)>,
+}
+
+impl Default for RenderState {
+    /// The initial state of a new GL context.
+    fn default() -> Self {
+        Self {
+            cull_mode: None,
+            blend: false,
+            depth_test: false,
+            depth_write: true,
+            depth_func: glow::LESS,
+            color_write: [true; 4],
+            polygon_mode: glow::FILL,
+            polygon_offset: None,
+        }
+    }
+}
+
+impl RenderState {
+    /// A state where every field differs from self. Used to force all GL calls in restore_render_state().
+    fn inverted(&self) -> Self {
+        Self {
+            cull_mode: match self.cull_mode {
+                Some(_) => None,
+                None => Some(Face::Back),
+            },
+            blend: !self.blend,
+            depth_test: !self.depth_test,
+            depth_write: !self.depth_write,
+            depth_func: if self.depth_func == glow::NEVER {
+                glow::ALWAYS
+            } else {
+                glow::NEVER
+            },
+            color_write: self.color_write.map(|c| !c),
+            polygon_mode: if self.polygon_mode == glow::FILL {
+                glow::LINE
+            } else {
+                glow::FILL
+            },
+            polygon_offset: match self.polygon_offset {
+                Some(_) => None,
+                None => Some((0.0, 0.0)),
+            },
+        }
+    }
+}
+
+/// glStencilFunc, glStencilOp and glStencilMask, for both faces. See BevyGlContext::set_stencil_state().
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StencilState {
+    /// glow::ALWAYS, glow::EQUAL, glow::NOTEQUAL, etc... Compares reference to the stored value.
+    pub func: u32,
+    pub reference: i32,
+    /// Applied to both reference and the stored value before comparing.
+    pub read_mask: u32,
+    /// glow::KEEP, glow::REPLACE, glow::INCR, etc... when the stencil test fails.
+    pub stencil_fail: u32,
+    /// When the stencil test passes but the depth test fails.
+    pub depth_fail: u32,
+    /// When both pass.
+    pub pass: u32,
+    /// Bits of the stored value that can be written.
+    pub write_mask: u32,
+}
+
+impl StencilState {
+    /// Writes reference wherever something is drawn, e.g. to mask an object for an outline or a portal.
+    pub const fn write(reference: i32) -> Self {
+        Self {
+            func: glow::ALWAYS,
+            reference,
+            read_mask: !0,
+            stencil_fail: glow::KEEP,
+            depth_fail: glow::KEEP,
+            pass: glow::REPLACE,
+            write_mask: !0,
+        }
+    }
+
+    /// Only draws where the stored value is reference, without changing it.
+    pub const fn equal(reference: i32) -> Self {
+        Self {
+            func: glow::EQUAL,
+            pass: glow::KEEP,
+            ..Self::write(reference)
+        }
+    }
+
+    /// Only draws where the stored value isn't reference, without changing it.
+    pub const fn not_equal(reference: i32) -> Self {
+        Self {
+            func: glow::NOTEQUAL,
+            ..Self::equal(reference)
+        }
+    }
+}
+
+/// glBlendFuncSeparate factors and glBlendEquation mode. Only takes effect while blending is enabled, see
+/// BevyGlContext::set_blend_state().
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BlendState {
+    /// glow::ONE, glow::SRC_ALPHA, glow::DST_COLOR, etc...
+    pub src_rgb: u32,
+    pub dst_rgb: u32,
+    pub src_alpha: u32,
+    pub dst_alpha: u32,
+    /// glow::FUNC_ADD, glow::FUNC_SUBTRACT or glow::FUNC_REVERSE_SUBTRACT. glow::MIN and glow::MAX need GL 1.4+,
+    /// WebGL2 or EXT_blend_minmax on WebGL1.
+    pub equation: u32,
+}
+
+impl BlendState {
+    /// Standard non-premultiplied alpha blending. What start_alpha_blend() uses.
+    pub const ALPHA: Self = Self::new(glow::SRC_ALPHA, glow::ONE_MINUS_SRC_ALPHA);
+    /// Color is expected to already be multiplied by alpha in the shader.
+    pub const PREMULTIPLIED_ALPHA: Self = Self::new(glow::ONE, glow::ONE_MINUS_SRC_ALPHA);
+    /// src + dst, ignores alpha.
+    pub const ADDITIVE: Self = Self::new(glow::ONE, glow::ONE);
+    /// src * src_alpha + dst, for additive particles and glows that fade out with alpha.
+    pub const ADDITIVE_ALPHA: Self = Self::new(glow::SRC_ALPHA, glow::ONE);
+    /// dst - src * src_alpha, darkens what is behind.
+    pub const SUBTRACTIVE: Self = Self {
+        equation: glow::FUNC_REVERSE_SUBTRACT,
+        ..Self::ADDITIVE_ALPHA
+    };
+    /// src * dst
+    pub const MULTIPLY: Self = Self::new(glow::DST_COLOR, glow::ZERO);
+    /// Leaves the destination unchanged. What the opaque and depth only passes set.
+    pub const REPLACE_NONE: Self = Self::new(glow::ZERO, glow::ONE);
+
+    /// Same factors for rgb and alpha with glow::FUNC_ADD.
+    pub const fn new(src: u32, dst: u32) -> Self {
+        Self {
+            src_rgb: src,
+            dst_rgb: dst,
+            src_alpha: src,
+            dst_alpha: dst,
+            equation: glow::FUNC_ADD,
+        }
+    }
+
+    pub fn from_alpha_mode(alpha_mode: &AlphaMode) -> Self {
+        match alpha_mode {
+            AlphaMode::Opaque | AlphaMode::Mask(_) => Self::REPLACE_NONE,
+            AlphaMode::Blend | AlphaMode::Premultiplied | AlphaMode::AlphaToCoverage => Self::ALPHA,
+            AlphaMode::Add => Self::ADDITIVE_ALPHA,
+            AlphaMode::Multiply => Self::MULTIPLY,
+        }
+    }
+
+    /// Issues the blend func and equation GL calls. Doesn't enable blending.
+    pub fn apply(&self, gl: &glow::Context) {
+        unsafe {
+            gl.blend_func_separate(self.src_rgb, self.dst_rgb, self.src_alpha, self.dst_alpha);
+            gl.blend_equation(self.equation);
+        }
+    }
+}
+
+fn set_capability(gl: &glow::Context, capability: u32, enabled: bool) {
+    unsafe {
+        if enabled {
+            gl.enable(capability);
+        } else {
+            gl.disable(capability);
+        }
+    }
 }
 
 pub fn flip_cull_mode(cull_mode: Option<Face>, flip: bool) -> Option<Face> {
@@ -866,69 +2187,271 @@ impl AttribType {
     }
 }
 
-pub fn shader_key<'a, I>(
-    vertex: &Path,
-    fragment: &Path,
-    shader_defs: I,
-    bindings: &[&'static [&'static str]],
-) -> u64
-where
-    I: IntoIterator<Item = &'a (&'a str, &'a str)> + Clone,
-{
-    let mut hasher = std::hash::DefaultHasher::new();
-    vertex.hash(&mut hasher);
-    fragment.hash(&mut hasher);
-    shader_defs.into_iter().for_each(|v| v.hash(&mut hasher));
-    bindings.hash(&mut hasher);
-    hasher.finish()
+pub fn shader_key<'a, I>(
+    vertex: &Path,
+    fragment: &Path,
+    shader_defs: I,
+    bindings: &[&'static [&'static str]],
+    shader_language: &ShaderLanguageSettings,
+) -> u64
+where
+    I: IntoIterator<Item = &'a (&'a str, &'a str)> + Clone,
+{
+    // Also part of program_binary_key(), so it has to be stable across builds.
+    let mut hasher = StableHasher::default();
+    shader_language.hash(&mut hasher);
+    vertex.hash(&mut hasher);
+    fragment.hash(&mut hasher);
+    shader_defs.into_iter().for_each(|v| v.hash(&mut hasher));
+    bindings.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Defines past this are dropped from ShaderDefs.
+pub const MAX_ENTITY_SHADER_DEFS: usize = 8;
+
+/// Extra shader defines for a single mesh entity, added to the ones its material's render system uses. Allows
+/// per-entity feature toggles without a separate material type. Supported by the standard material, where
+/// `ShaderDefs::new([("NO_FOG", "")])` excludes an entity from distance fog for example.
+///
+/// Every distinct set results in its own shader program for each combination of the render system's own defines, so
+/// keep the number of different sets small. Draws are sorted by material, not by these defines, so entities with
+/// different sets sharing a material also cause more program changes. Defines are sorted so the order they are
+/// added in doesn't matter, and at most MAX_ENTITY_SHADER_DEFS are kept.
+#[derive(Component, Clone, Default, PartialEq, Eq, Hash, Debug)]
+pub struct ShaderDefs(Vec<(String, String)>);
+
+impl ShaderDefs {
+    pub fn new<N: Into<String>, V: Into<String>>(defs: impl IntoIterator<Item = (N, V)>) -> Self {
+        let mut defs = defs
+            .into_iter()
+            .map(|(name, value)| (name.into(), value.into()))
+            .collect::<Vec<_>>();
+        defs.sort();
+        defs.dedup_by(|a, b| a.0 == b.0);
+        if defs.len() > MAX_ENTITY_SHADER_DEFS {
+            warn_once!(
+                "ShaderDefs has {} defines, only the first {MAX_ENTITY_SHADER_DEFS} are used",
+                defs.len()
+            );
+            defs.truncate(MAX_ENTITY_SHADER_DEFS);
+        }
+        Self(defs)
+    }
+
+    /// The defines in the form shader_cached() takes.
+    pub fn pairs(&self) -> Vec<(&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+pub trait UniformValue: Sized {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation);
+    /// Like load() but uploads at most max_len elements. Only needs to be implemented for arrays. Used to avoid
+    /// uploading past the array length declared in the shader which would result in GL_INVALID_OPERATION.
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, _max_len: usize) {
+        self.load(gl, loc);
+    }
+    // Return false is read raw is not supported
+    fn read_raw(&self, out: &mut StackStack<u32, 16>) -> bool;
+}
+
+/// The first max_len values, for UniformValue::load_array().
+fn clamp_array_len<T>(values: &[T], max_len: usize) -> &[T] {
+    &values[..values.len().min(max_len)]
+}
+
+impl UniformValue for bool {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
+        unsafe { gl.uniform_1_i32(Some(&loc), if *self { 1 } else { 0 }) };
+    }
+    fn read_raw(&self, out: &mut StackStack<u32, 16>) -> bool {
+        out.clear();
+        out.push(if *self { 1 } else { 0 });
+        true
+    }
+}
+
+impl UniformValue for f32 {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
+        unsafe { gl.uniform_1_f32(Some(&loc), *self) };
+    }
+    fn read_raw(&self, out: &mut StackStack<u32, 16>) -> bool {
+        out.clear();
+        out.push(self.to_bits());
+        true
+    }
+}
+
+impl UniformValue for &[f32] {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
+        if !self.is_empty() {
+            unsafe { gl.uniform_1_f32_slice(Some(&loc), &bytemuck::cast_slice(self)) };
+        }
+    }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        clamp_array_len(self, max_len).load(gl, loc);
+    }
+    fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
+        false
+    }
+}
+
+impl UniformValue for Vec<f32> {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
+        if !self.is_empty() {
+            unsafe { gl.uniform_1_f32_slice(Some(&loc), self.as_slice()) };
+        }
+    }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        self.as_slice().load_array(gl, loc, max_len);
+    }
+    fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
+        false
+    }
+}
+
+impl UniformValue for i32 {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
+        unsafe { gl.uniform_1_i32(Some(&loc), *self) };
+    }
+    fn read_raw(&self, out: &mut StackStack<u32, 16>) -> bool {
+        out.clear();
+        out.push(*self as u32);
+        true
+    }
+}
+
+impl UniformValue for IVec2 {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
+        unsafe { gl.uniform_2_i32_slice(Some(&loc), &self.to_array()) };
+    }
+    fn read_raw(&self, out: &mut StackStack<u32, 16>) -> bool {
+        out.clear();
+        self.to_array().iter().for_each(|n| out.push(*n as u32));
+        true
+    }
 }
 
-pub trait UniformValue: Sized {
-    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation);
-    // Return false is read raw is not supported
-    fn read_raw(&self, out: &mut StackStack<u32, 16>) -> bool;
+impl UniformValue for IVec3 {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
+        unsafe { gl.uniform_3_i32_slice(Some(&loc), &self.to_array()) };
+    }
+    fn read_raw(&self, out: &mut StackStack<u32, 16>) -> bool {
+        out.clear();
+        self.to_array().iter().for_each(|n| out.push(*n as u32));
+        true
+    }
 }
 
-impl UniformValue for bool {
+impl UniformValue for IVec4 {
     fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
-        unsafe { gl.uniform_1_i32(Some(&loc), if *self { 1 } else { 0 }) };
+        unsafe { gl.uniform_4_i32_slice(Some(&loc), &self.to_array()) };
     }
     fn read_raw(&self, out: &mut StackStack<u32, 16>) -> bool {
         out.clear();
-        out.push(if *self { 1 } else { 0 });
+        self.to_array().iter().for_each(|n| out.push(*n as u32));
         true
     }
 }
 
-impl UniformValue for f32 {
+/// Unsigned integer uniforms need GL 3.0, GLES 3.0 or WebGL2, and a shader language with uint (GLSL 1.30+ or GLSL ES
+/// 3.00). The u32 and UVec UniformValue impls warn and skip the upload if the context doesn't have them.
+pub fn supports_uint_uniforms(gl: &glow::Context) -> bool {
+    gl.version().major >= 3
+}
+
+fn check_uint_uniforms(gl: &glow::Context) -> bool {
+    let supported = supports_uint_uniforms(gl);
+    if !supported {
+        warn_once!(
+            "u32/UVec uniforms need GL 3.0, GLES 3.0 or WebGL2, they are not uploaded on this context"
+        );
+    }
+    supported
+}
+
+impl UniformValue for u32 {
     fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
-        unsafe { gl.uniform_1_f32(Some(&loc), *self) };
+        if check_uint_uniforms(gl) {
+            unsafe { gl.uniform_1_u32(Some(&loc), *self) };
+        }
     }
     fn read_raw(&self, out: &mut StackStack<u32, 16>) -> bool {
         out.clear();
-        out.push(self.to_bits());
+        out.push(*self);
         true
     }
 }
 
-impl UniformValue for &[f32] {
+impl UniformValue for &[u32] {
     fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
-        if !self.is_empty() {
-            unsafe { gl.uniform_1_f32_slice(Some(&loc), &bytemuck::cast_slice(self)) };
+        if !self.is_empty() && check_uint_uniforms(gl) {
+            unsafe { gl.uniform_1_u32_slice(Some(&loc), self) };
         }
     }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        clamp_array_len(self, max_len).load(gl, loc);
+    }
     fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
         false
     }
 }
 
-impl UniformValue for i32 {
+impl UniformValue for Vec<u32> {
     fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
-        unsafe { gl.uniform_1_i32(Some(&loc), *self) };
+        self.as_slice().load(gl, loc);
+    }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        self.as_slice().load_array(gl, loc, max_len);
+    }
+    fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
+        false
+    }
+}
+
+impl UniformValue for UVec2 {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
+        if check_uint_uniforms(gl) {
+            unsafe { gl.uniform_2_u32_slice(Some(&loc), &self.to_array()) };
+        }
     }
     fn read_raw(&self, out: &mut StackStack<u32, 16>) -> bool {
         out.clear();
-        out.push(*self as u32);
+        self.to_array().iter().for_each(|n| out.push(*n));
+        true
+    }
+}
+
+impl UniformValue for UVec3 {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
+        if check_uint_uniforms(gl) {
+            unsafe { gl.uniform_3_u32_slice(Some(&loc), &self.to_array()) };
+        }
+    }
+    fn read_raw(&self, out: &mut StackStack<u32, 16>) -> bool {
+        out.clear();
+        self.to_array().iter().for_each(|n| out.push(*n));
+        true
+    }
+}
+
+impl UniformValue for UVec4 {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
+        if check_uint_uniforms(gl) {
+            unsafe { gl.uniform_4_u32_slice(Some(&loc), &self.to_array()) };
+        }
+    }
+    fn read_raw(&self, out: &mut StackStack<u32, 16>) -> bool {
+        out.clear();
+        self.to_array().iter().for_each(|n| out.push(*n));
         true
     }
 }
@@ -950,6 +2473,9 @@ impl UniformValue for &[Vec2] {
             unsafe { gl.uniform_2_f32_slice(Some(&loc), &bytemuck::cast_slice(self)) };
         }
     }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        clamp_array_len(self, max_len).load(gl, loc);
+    }
     fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
         false
     }
@@ -961,6 +2487,9 @@ impl UniformValue for Vec<Vec2> {
             unsafe { gl.uniform_2_f32_slice(Some(&loc), &bytemuck::cast_slice(self.as_slice())) };
         }
     }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        self.as_slice().load_array(gl, loc, max_len);
+    }
     fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
         false
     }
@@ -983,6 +2512,9 @@ impl UniformValue for &[Vec3] {
             unsafe { gl.uniform_3_f32_slice(Some(&loc), &bytemuck::cast_slice(self)) };
         }
     }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        clamp_array_len(self, max_len).load(gl, loc);
+    }
     fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
         false
     }
@@ -994,6 +2526,9 @@ impl UniformValue for Vec<Vec3> {
             unsafe { gl.uniform_3_f32_slice(Some(&loc), &bytemuck::cast_slice(self.as_slice())) };
         }
     }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        self.as_slice().load_array(gl, loc, max_len);
+    }
     fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
         false
     }
@@ -1016,6 +2551,9 @@ impl UniformValue for &[Vec4] {
             unsafe { gl.uniform_4_f32_slice(Some(&loc), &bytemuck::cast_slice(self)) };
         }
     }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        clamp_array_len(self, max_len).load(gl, loc);
+    }
     fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
         false
     }
@@ -1027,6 +2565,58 @@ impl UniformValue for Vec<Vec4> {
             unsafe { gl.uniform_4_f32_slice(Some(&loc), &bytemuck::cast_slice(self.as_slice())) };
         }
     }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        self.as_slice().load_array(gl, loc, max_len);
+    }
+    fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
+        false
+    }
+}
+
+impl UniformValue for Mat3 {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
+        unsafe { gl.uniform_matrix_3_f32_slice(Some(&loc), false, &self.to_cols_array()) };
+    }
+    fn read_raw(&self, out: &mut StackStack<u32, 16>) -> bool {
+        out.clear();
+        self.to_cols_array()
+            .iter()
+            .for_each(|n| out.push(n.to_bits()));
+        true
+    }
+}
+
+impl UniformValue for &[Mat3] {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
+        if !self.is_empty() {
+            unsafe {
+                gl.uniform_matrix_3_f32_slice(Some(&loc), false, &bytemuck::cast_slice(self))
+            };
+        }
+    }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        clamp_array_len(self, max_len).load(gl, loc);
+    }
+    fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
+        false
+    }
+}
+
+impl UniformValue for Vec<Mat3> {
+    fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
+        if !self.is_empty() {
+            unsafe {
+                gl.uniform_matrix_3_f32_slice(
+                    Some(&loc),
+                    false,
+                    &bytemuck::cast_slice(self.as_slice()),
+                )
+            };
+        }
+    }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        self.as_slice().load_array(gl, loc, max_len);
+    }
     fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
         false
     }
@@ -1059,6 +2649,9 @@ impl UniformValue for &[Mat4] {
             };
         }
     }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        clamp_array_len(self, max_len).load(gl, loc);
+    }
     fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
         false
     }
@@ -1076,11 +2669,15 @@ impl UniformValue for Vec<Mat4> {
             };
         }
     }
+    fn load_array(&self, gl: &glow::Context, loc: &glow::UniformLocation, max_len: usize) {
+        self.as_slice().load_array(gl, loc, max_len);
+    }
     fn read_raw(&self, _out: &mut StackStack<u32, 16>) -> bool {
         false
     }
 }
 
+/// Uploaded as is, for vec4 uniforms that expect linear color.
 impl UniformValue for LinearRgba {
     fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
         unsafe { gl.uniform_4_f32_slice(Some(&loc), &self.to_vec4().to_array()) };
@@ -1095,6 +2692,8 @@ impl UniformValue for LinearRgba {
     }
 }
 
+/// Converted to LinearRgba on the CPU before uploading, so sRGB colors like Color::srgb() arrive in the shader as
+/// linear vec4. Shaders doing their lighting in linear space shouldn't convert again.
 impl UniformValue for Color {
     fn load(&self, gl: &glow::Context, loc: &glow::UniformLocation) {
         unsafe { gl.uniform_4_f32_slice(Some(&loc), &self.to_linear().to_vec4().to_array()) };
@@ -1133,6 +2732,7 @@ macro_rules! shader_cached {
                 $fragment.as_ref(),
                 $shader_defs,
                 $bindings,
+                &$bevy_gl_context.shader_language,
             );
             if let Some((index, _)) = $bevy_gl_context.shader_cache_map.get(&key) {
                 Some(*index)
@@ -1176,6 +2776,88 @@ pub trait UniformSet {
         slot: &mut SlotData,
         temp_value: &mut StackStack<u32, 16>,
     );
+    /// For `Vec<T>` fields where T is itself a UniformSet, the names and glsl types of T. The index corresponds to the
+    /// order returned from names(). Each element field of an array of structs has its own location.
+    fn struct_array_fields(
+        _index: u32,
+    ) -> Option<(&'static [&'static str], &'static [&'static str])> {
+        None
+    }
+}
+
+/// Used by the UniformSet derive for `Vec<T>` fields where T is a UniformSet. Declares a struct with T's fields, guarded
+/// so it can be declared by more than one binding, followed by the array uniform.
+pub fn struct_array_binding(
+    struct_name: &str,
+    uniform_name: &str,
+    array_max: &str,
+    element_bindings: &[&str],
+) -> &'static str {
+    let mut binding = format!(
+        "#ifndef UNIFORM_STRUCT_{struct_name}\n#define UNIFORM_STRUCT_{struct_name}\nstruct {struct_name} {{\n"
+    );
+    for element_binding in element_bindings {
+        binding.push_str("    ");
+        binding.push_str(element_binding.trim_start_matches("uniform "));
+        binding.push('\n');
+    }
+    binding.push_str(&format!(
+        "}};\n#endif\nuniform {struct_name} {uniform_name}[{array_max}];"
+    ));
+    Box::leak(binding.into_boxed_str())
+}
+
+/// Loads each element's fields into the locations found by map_uniform_set_locations(). Elements beyond the declared
+/// array length are ignored.
+pub fn load_struct_array_if_new<T: UniformSet>(
+    v: &[T],
+    gl: &glow::Context,
+    gpu_images: &GpuImages,
+    slot: &mut SlotData,
+    temp: &mut StackStack<u32, 16>,
+) {
+    match slot {
+        SlotData::StructArray { elements } => {
+            for (value, element_slots) in v.iter().zip(elements) {
+                for (index, slot) in element_slots.iter_mut().enumerate() {
+                    if let Some(slot) = slot {
+                        value.load(gl, gpu_images, index as u32, slot, temp);
+                    }
+                }
+            }
+        }
+        _ => panic!("Expected struct array"),
+    }
+}
+
+/// Used by the UniformSet derive for #[flatten("prefix")] fields. Leaks the prefixed names, which are only created once.
+pub fn prefix_uniform_names(prefix: &str, names: &[&'static str]) -> Vec<&'static str> {
+    if prefix.is_empty() {
+        return names.to_vec();
+    }
+    names
+        .iter()
+        .map(|name| &*Box::leak(format!("{prefix}{name}").into_boxed_str()))
+        .collect()
+}
+
+/// Used by the UniformSet derive for #[flatten("prefix")] fields. Renames the uniform in each binding.
+pub fn prefix_uniform_bindings(
+    prefix: &str,
+    names: &[&'static str],
+    bindings: &[&'static str],
+) -> Vec<&'static str> {
+    if prefix.is_empty() {
+        return bindings.to_vec();
+    }
+    names
+        .iter()
+        .zip(bindings)
+        .map(|(name, binding)| {
+            let binding = binding.replacen(&format!(" {name}"), &format!(" {prefix}{name}"), 1);
+            &*Box::leak(binding.into_boxed_str())
+        })
+        .collect()
 }
 
 #[inline]
@@ -1190,9 +2872,10 @@ pub fn load_if_new<T: UniformValue>(
             init,
             previous,
             location,
+            array_len,
         } => {
             if !v.read_raw(temp) {
-                v.load(&gl, &location);
+                v.load_array(&gl, &location, *array_len);
             } else if !*init || temp != previous {
                 *init = true;
                 std::mem::swap(previous, temp);
@@ -1203,92 +2886,198 @@ pub fn load_if_new<T: UniformValue>(
     }
 }
 
+/// Used by the UniformSet derive for #[texture] fields, the bound is what makes other types a compile error.
+#[inline]
+pub fn texture_field_to_tex<T: Clone + Into<Tex>>(value: &T) -> Tex {
+    value.clone().into()
+}
+
 #[inline]
 pub fn load_tex_if_new(tex: &Tex, gl: &glow::Context, gpu_images: &GpuImages, slot: &mut SlotData) {
+    load_sampled_tex_if_new(tex, None, gl, gpu_images, slot);
+}
+
+/// Used by the UniformSet derive for SampledImage fields. Binds the sampler of sampler_override to the slot's texture
+/// unit, or with has_sampler_objects false sets the override on the texture. Without an override the unit's sampler
+/// is unbound, so the texture's own parameters are used.
+#[inline]
+pub fn load_sampled_tex_if_new(
+    tex: &Tex,
+    sampler_override: Option<SamplerOverride>,
+    gl: &glow::Context,
+    gpu_images: &GpuImages,
+    slot: &mut SlotData,
+) {
     match slot {
         SlotData::Texture {
             target,
             texture_slot,
             previous,
+            sampler,
             location,
         } => {
-            let mut texture = gpu_images.placeholder.unwrap();
-            match tex {
-                Tex::Bevy(image_h) => {
-                    if let Some(image_h) = image_h {
-                        if let Some(t) = gpu_images.bevy_textures.get(&image_h.id()) {
-                            texture = t.0;
-                            *target = t.1;
-                        }
-                    }
-                }
-                Tex::Gl(t) => {
-                    texture = *t;
-                }
-                Tex::Ref(t_ref) => {
-                    if let Some(idx) = t_ref.get() {
-                        let t = gpu_images.raw_textures[idx as usize];
-                        texture = t.0;
-                        *target = t.1;
-                    }
-                }
-            }
+            // target starts out as the one matching the sampler type, see map_uniform_location().
+            let (texture, resolved_target) = gpu_images.resolve(tex, *target);
+            *target = resolved_target;
             unsafe {
                 if let Some(previous) = previous.as_ref() {
-                    if previous == &texture {
+                    if previous == &texture && *sampler == sampler_override {
                         return;
                     }
                 }
-                // TODO needs to use info from the texture to actually setup correctly
                 gl.active_texture(glow::TEXTURE0 + *texture_slot);
                 gl.bind_texture(*target, Some(texture));
                 gl.uniform_1_i32(Some(&location), *texture_slot as i32);
+                if gpu_images.has_samplers() {
+                    // Another program may have left a sampler bound to this unit, so it's also set on the first load.
+                    if previous.is_none() || *sampler != sampler_override {
+                        let sampler = sampler_override.and_then(|o| gpu_images.sampler(o));
+                        gl.bind_sampler(*texture_slot, sampler);
+                    }
+                } else if let Some(sampler_override) = sampler_override {
+                    sampler_override.apply_to_texture(gl, *target);
+                }
                 *previous = Some(texture);
+                *sampler = sampler_override;
             }
         }
         _ => panic!("Expected texture"),
     }
 }
 
+/// Asset path used for debug labels, or the id for assets that weren't loaded from a file.
+pub fn asset_debug_label<A: Asset>(asset_server: Option<&AssetServer>, id: AssetId<A>) -> String {
+    asset_server
+        .and_then(|server| server.get_path(id))
+        .map(|path| path.to_string())
+        .unwrap_or_else(|| id.to_string())
+}
+
+pub fn gl_error_name(error: u32) -> &'static str {
+    match error {
+        glow::INVALID_ENUM => "INVALID_ENUM",
+        glow::INVALID_VALUE => "INVALID_VALUE",
+        glow::INVALID_OPERATION => "INVALID_OPERATION",
+        glow::INVALID_FRAMEBUFFER_OPERATION => "INVALID_FRAMEBUFFER_OPERATION",
+        glow::OUT_OF_MEMORY => "OUT_OF_MEMORY",
+        glow::STACK_UNDERFLOW => "STACK_UNDERFLOW",
+        glow::STACK_OVERFLOW => "STACK_OVERFLOW",
+        glow::CONTEXT_LOST => "CONTEXT_LOST",
+        _ => "unknown",
+    }
+}
+
 impl BevyGlContext {
     pub fn map_uniform_set_locations<T: UniformSet + 'static>(&mut self) {
         let current_program = self
             .current_program
             .expect("Need to run use_cached_program() before map_uniform_set_locations()");
 
+        let array_lens = self.active_uniform_array_lens(current_program);
+
         let locations = T::names()
             .iter()
             .zip(T::glsl_types())
-            .map(|(name, glsl_type)| unsafe {
-                self.gl
-                    .get_uniform_location(current_program, name)
-                    .map(|location| {
-                        if glsl_type.contains("sampler") {
-                            let slot = SlotData::Texture {
-                                target: if *glsl_type == "samplerCube" {
-                                    glow::TEXTURE_CUBE_MAP
-                                } else {
-                                    glow::TEXTURE_2D
-                                },
-                                texture_slot: self.current_texture_slot_count as u32,
-                                previous: None,
-                                location,
-                            };
-                            self.current_texture_slot_count += 1;
-                            slot
-                        } else {
-                            SlotData::Uniform {
-                                init: false,
-                                previous: Default::default(),
-                                location,
-                            }
-                        }
-                    })
+            .enumerate()
+            .map(|(index, (name, glsl_type))| {
+                if let Some((field_names, field_types)) = T::struct_array_fields(index as u32) {
+                    self.map_struct_array_locations(name, field_names, field_types, &array_lens)
+                } else {
+                    self.map_uniform_location(name, glsl_type, &array_lens)
+                }
             })
             .collect::<Vec<_>>();
 
+        if self.warn_missing_uniforms {
+            for (name, location) in T::names().iter().zip(&locations) {
+                if location.is_none()
+                    && self
+                        .missing_uniform_warnings
+                        .insert((current_program, name.to_string()))
+                {
+                    warn!(
+                        "Uniform {name} from {} has no active location in program {current_program:?}",
+                        type_name::<T>()
+                    );
+                }
+            }
+        }
+
         self.uniform_slot_map.insert(TypeId::of::<T>(), locations);
     }
+
+    fn map_uniform_location(
+        &mut self,
+        name: &str,
+        glsl_type: &str,
+        array_lens: &HashMap<String, usize>,
+    ) -> Option<SlotData> {
+        self.get_uniform_location(name).map(|location| {
+            if glsl_type.contains("sampler") {
+                let slot = SlotData::Texture {
+                    target: match glsl_type {
+                        "samplerCube" => glow::TEXTURE_CUBE_MAP,
+                        "sampler2DArray" => glow::TEXTURE_2D_ARRAY,
+                        _ => glow::TEXTURE_2D,
+                    },
+                    texture_slot: self.alloc_texture_slot(),
+                    previous: None,
+                    sampler: None,
+                    location,
+                };
+                slot
+            } else {
+                SlotData::Uniform {
+                    init: false,
+                    previous: Default::default(),
+                    location,
+                    array_len: array_lens.get(name).copied().unwrap_or(1),
+                }
+            }
+        })
+    }
+
+    /// Maps name[i].field for each element until an element has no active fields.
+    fn map_struct_array_locations(
+        &mut self,
+        name: &str,
+        field_names: &[&str],
+        field_types: &[&str],
+        array_lens: &HashMap<String, usize>,
+    ) -> Option<SlotData> {
+        let mut elements = Vec::new();
+        loop {
+            let i = elements.len();
+            let element = field_names
+                .iter()
+                .zip(field_types)
+                .map(|(field_name, field_type)| {
+                    let element_name = format!("{name}[{i}].{field_name}");
+                    self.map_uniform_location(&element_name, field_type, array_lens)
+                })
+                .collect::<Vec<_>>();
+            if element.iter().all(Option::is_none) {
+                break;
+            }
+            elements.push(element);
+        }
+        (!elements.is_empty()).then_some(SlotData::StructArray { elements })
+    }
+
+    /// Declared array length of each active uniform in the program. Arrays are keyed without the [0] suffix.
+    fn active_uniform_array_lens(&self, program: glow::Program) -> HashMap<String, usize> {
+        unsafe {
+            (0..self.gl.get_active_uniforms(program))
+                .filter_map(|index| self.gl.get_active_uniform(program, index))
+                .map(|uniform| {
+                    (
+                        uniform.name.trim_end_matches("[0]").to_string(),
+                        uniform.size.max(1) as usize,
+                    )
+                })
+                .collect()
+        }
+    }
     pub fn bind_uniforms_set<T: UniformSet + 'static>(&mut self, images: &GpuImages, v: &T) {
         for (index, slot) in self
             .uniform_slot_map
@@ -1311,6 +3100,38 @@ impl BevyGlContext {
             }
         }
     }
+
+    /// Reserves the next texture unit of the current program. Units are handed out in order from 0 by load_tex() and
+    /// map_uniform_set_locations(), and start over at use_cached_program().
+    pub fn alloc_texture_slot(&mut self) -> u32 {
+        let slot = self.current_texture_slot_count as u32;
+        self.current_texture_slot_count += 1;
+        slot
+    }
+
+    /// Binds texture_ref to the given texture unit with the target it was created with, and points the sampler
+    /// uniform name at it. For custom pipelines that manage units themselves, use a unit from alloc_texture_slot() so
+    /// it doesn't collide with the automatically assigned ones. Returns false if name isn't an active uniform.
+    pub fn bind_texture_ref(
+        &mut self,
+        images: &GpuImages,
+        name: &str,
+        texture_ref: &TextureRef,
+        unit: u32,
+    ) -> bool {
+        let Some(location) = self.get_uniform_location(name) else {
+            return false;
+        };
+        let (texture, target) = images.resolve(&Tex::Ref(texture_ref.clone()), glow::TEXTURE_2D);
+        unsafe {
+            self.gl.active_texture(glow::TEXTURE0 + unit);
+            self.gl.bind_texture(target, Some(texture));
+            self.gl.uniform_1_i32(Some(&location), unit as i32);
+        }
+        self.unbind_sampler(unit);
+        true
+    }
+
     #[inline]
     /// Loads the texture in the next available slot. Returns the texture slot and location if the location is found.
     /// Call set_tex() to update the texture at this slot.
@@ -1320,44 +3141,29 @@ impl BevyGlContext {
         name: &str,
         tex: &Tex,
     ) -> Option<(u32, glow::UniformLocation)> {
-        let mut texture = images.placeholder.unwrap();
-        let mut target = glow::TEXTURE_2D;
-
         let Some(location) = self.get_uniform_location(name) else {
             return None;
         };
-        let texture_slot = self.current_texture_slot_count as u32;
-        self.current_texture_slot_count += 1;
-
-        match tex {
-            Tex::Bevy(image_h) => {
-                if let Some(image_h) = image_h {
-                    if let Some(t) = images.bevy_textures.get(&image_h.id()) {
-                        texture = t.0;
-                        target = t.1;
-                    }
-                }
-            }
-            Tex::Gl(t) => {
-                texture = *t;
-            }
-            Tex::Ref(t_ref) => {
-                if let Some(idx) = t_ref.get() {
-                    let t = images.raw_textures[idx as usize];
-                    texture = t.0;
-                    target = t.1;
-                }
-            }
-        }
+        let texture_slot = self.alloc_texture_slot();
+        // Tex::Gl doesn't know its target, it's assumed to be TEXTURE_2D.
+        let (texture, target) = images.resolve(tex, glow::TEXTURE_2D);
         unsafe {
-            // TODO needs to use info from the texture to actually setup correctly
             self.gl.active_texture(glow::TEXTURE0 + texture_slot);
             self.gl.bind_texture(target, Some(texture));
             self.gl.uniform_1_i32(Some(&location), texture_slot as i32);
         }
+        self.unbind_sampler(texture_slot);
         Some((texture_slot, location))
     }
 
+    /// Units can be left with a sampler bound by a SampledImage of another program, which would take precedence over
+    /// the texture's own parameters.
+    pub(crate) fn unbind_sampler(&self, unit: u32) {
+        if self.has_sampler_objects {
+            unsafe { self.gl.bind_sampler(unit, None) };
+        }
+    }
+
     #[inline]
     pub fn set_tex(
         &self,
@@ -1365,30 +3171,8 @@ impl BevyGlContext {
         images: &GpuImages,
         slot_location: (u32, glow::UniformLocation),
     ) {
-        let mut texture = images.placeholder.unwrap();
-        let mut target = glow::TEXTURE_2D;
-        match tex {
-            Tex::Bevy(image_h) => {
-                if let Some(image_h) = image_h {
-                    if let Some(t) = images.bevy_textures.get(&image_h.id()) {
-                        texture = t.0;
-                        target = t.1;
-                    }
-                }
-            }
-            Tex::Gl(t) => {
-                texture = *t;
-            }
-            Tex::Ref(t_ref) => {
-                if let Some(idx) = t_ref.get() {
-                    let t = images.raw_textures[idx as usize];
-                    texture = t.0;
-                    target = t.1;
-                }
-            }
-        }
+        let (texture, target) = images.resolve(tex, glow::TEXTURE_2D);
         unsafe {
-            // TODO needs to use info from the texture to actually setup correctly
             self.gl.active_texture(glow::TEXTURE0 + slot_location.0);
             self.gl.bind_texture(target, Some(texture));
             self.gl
@@ -1402,11 +3186,19 @@ pub enum SlotData {
         init: bool,
         previous: StackStack<u32, 16>,
         location: glow::UniformLocation,
+        /// Array length declared in the shader, 1 for non-array uniforms.
+        array_len: usize,
+    },
+    /// Slots for each field of each element of an array of structs, see UniformSet::struct_array_fields()
+    StructArray {
+        elements: Vec<Vec<Option<SlotData>>>,
     },
     Texture {
         target: u32,
         texture_slot: u32,
         previous: Option<glow::Texture>,
+        /// The SamplerOverride of the previous load, see load_sampled_tex_if_new().
+        sampler: Option<SamplerOverride>,
         location: glow::UniformLocation,
     },
 }
@@ -1481,3 +3273,106 @@ pub fn clone3<T0: Clone, T1: Clone, T2: Clone>(
 ) -> Option<(T0, T1, T2)> {
     opt.map(|(t0, t1, t2)| (t0.clone(), t1.clone(), t2.clone()))
 }
+
+/// The cached location of name, calling query on the first lookup. Misses are cached too.
+fn cached_location<L: Clone>(
+    cache: &mut HashMap<String, Option<L>>,
+    name: &str,
+    query: impl FnOnce() -> Option<L>,
+) -> Option<L> {
+    if let Some(location) = cache.get(name) {
+        return location.clone();
+    }
+    let location = query();
+    cache.insert(name.to_string(), location.clone());
+    location
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cached_location_queries_once() {
+        let mut cache = HashMap::new();
+        let mut queries = 0;
+        for _ in 0..3 {
+            let found = cached_location(&mut cache, "found", || {
+                queries += 1;
+                Some(7u32)
+            });
+            assert_eq!(found, Some(7));
+        }
+        for _ in 0..3 {
+            let missing = cached_location(&mut cache, "missing", || {
+                queries += 1;
+                None
+            });
+            assert_eq!(missing, None);
+        }
+        assert_eq!(queries, 2);
+
+        // A hot reload removes the shader's entries, the next lookup queries again.
+        cache.clear();
+        cached_location(&mut cache, "found", || {
+            queries += 1;
+            Some(7u32)
+        });
+        assert_eq!(queries, 3);
+    }
+
+    #[test]
+    fn test_clamp_array_len() {
+        let values = [1.0, 2.0, 3.0, 4.0];
+        assert_eq!(clamp_array_len(&values, 0), &[] as &[f32]);
+        assert_eq!(clamp_array_len(&values, 2), &[1.0, 2.0]);
+        assert_eq!(clamp_array_len(&values, 4), &values);
+        assert_eq!(clamp_array_len(&values, 5), &values);
+        assert_eq!(clamp_array_len(&values, usize::MAX), &values);
+    }
+
+    #[test]
+    fn test_mat3_read_raw() {
+        let m = Mat3::from_cols_array(&[1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0]);
+        let mut out = StackStack::<u32, 16>::default();
+        assert!(m.read_raw(&mut out));
+        assert_eq!(out.len(), 9);
+        assert_eq!(
+            out.as_slice(),
+            m.to_cols_array().map(|n| n.to_bits()).as_slice()
+        );
+    }
+
+    #[test]
+    fn test_leading_statement() {
+        let ported = "\n// Ported as is\n  #version 300 es\nprecision highp float;\n";
+        assert!(leading_statement(ported).starts_with("#version"));
+        assert!(
+            leading_statement("precision mediump float;\nvoid main() {}").starts_with("precision")
+        );
+        assert_eq!(
+            leading_statement("#include std::math\n"),
+            "#include std::math"
+        );
+        assert_eq!(leading_statement("\n  \n"), "");
+    }
+
+    #[test]
+    fn test_render_state_inverted_differs() {
+        let state = RenderState::default();
+        let inverted = state.inverted();
+        assert_ne!(state.cull_mode, inverted.cull_mode);
+        assert_ne!(state.blend, inverted.blend);
+        assert_ne!(state.depth_test, inverted.depth_test);
+        assert_ne!(state.depth_write, inverted.depth_write);
+        assert_ne!(state.depth_func, inverted.depth_func);
+        assert_ne!(state.polygon_offset, inverted.polygon_offset);
+        assert!(
+            state
+                .color_write
+                .iter()
+                .zip(inverted.color_write)
+                .all(|(a, b)| *a != b)
+        );
+    }
+}
@@ -1,6 +1,7 @@
 use bevy::platform::collections::HashMap;
 use fancy_regex::{Captures, Regex};
 
+/// Also used for GLSL ES 3.00 on WebGL 2, which has the same in/out, layout and texture function syntax.
 pub fn translate_shader_to_330(vertex: &mut String, fragment: &mut String) {
     let mut map: HashMap<String, usize> = HashMap::new();
     let mut next_location: usize = 0;
@@ -18,12 +19,70 @@ pub fn translate_shader_to_330(vertex: &mut String, fragment: &mut String) {
         .replace("void main(", "out vec4 _FragColor;\nvoid main(");
 
     for shader in [vertex, fragment] {
-        *shader = shader
-            .replace("texture2D(", "texture(")
-            .replace("textureCubeLod(", "textureLod(");
+        *shader = rewrite_texture_functions(shader);
     }
 }
 
+/// Renames the GLSL 1.x sampler specific texture functions to their overloaded GLSL 3 equivalents. Only whole
+/// identifiers directly followed by `(` are matched, so already translated calls and names like
+/// `textureCubeLodEXT` are left alone. See wrap_shadow_lookups() for the `shadow2D` family.
+fn rewrite_texture_functions(src: &str) -> String {
+    let src = &wrap_shadow_lookups(src);
+    let re = Regex::new(
+        r#"\b(texture2DArrayLod|texture2DArray|texture2DProjLod|texture2DProj|texture2DLod|texture2D|texture3DProjLod|texture3DProj|texture3DLod|texture3D|textureCubeLod|textureCube|shadow2DProjLod|shadow2DProj|shadow2DLod|shadow2D)(\s*)\("#,
+    )
+    .unwrap();
+
+    re.replace_all(src, |caps: &Captures| {
+        let name = &caps[1];
+        let translated = if name.ends_with("ProjLod") {
+            "textureProjLod"
+        } else if name.ends_with("Proj") {
+            "textureProj"
+        } else if name.ends_with("Lod") {
+            "textureLod"
+        } else {
+            "texture"
+        };
+        format!("{}{}(", translated, &caps[2])
+    })
+    .to_string()
+}
+
+/// The sampler2DShadow overloads of the GLSL 3 texture functions return a float where `shadow2D` returned a vec4, so
+/// calls are wrapped in vec4() to keep swizzles like `.r` valid. Lookups nested in another lookup's arguments aren't
+/// wrapped.
+fn wrap_shadow_lookups(src: &str) -> String {
+    let re = Regex::new(r#"\b(shadow2DProjLod|shadow2DProj|shadow2DLod|shadow2D)\s*\("#).unwrap();
+    let mut out = String::with_capacity(src.len());
+    let mut copied = 0;
+    for m in re.find_iter(src).flatten() {
+        if m.start() < copied {
+            continue;
+        }
+        // Find the closing parenthesis of the call.
+        let mut depth = 0;
+        let close = src[m.end() - 1..].char_indices().find_map(|(i, c)| {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => (),
+            }
+            (depth == 0).then_some(m.end() - 1 + i)
+        });
+        let Some(close) = close else {
+            continue;
+        };
+        out.push_str(&src[copied..m.start()]);
+        out.push_str("vec4(");
+        out.push_str(&src[m.start()..=close]);
+        out.push(')');
+        copied = close + 1;
+    }
+    out.push_str(&src[copied..]);
+    out
+}
+
 fn extract_attributes(shader: &str, map: &mut HashMap<String, usize>, next_location: &mut usize) {
     // Match:
     //   attribute [lowp|mediump|highp]? <type> <name>[...optional array...]
@@ -58,3 +117,121 @@ fn rewrite_attributes(src: &str, map: &HashMap<String, usize>) -> String {
     })
     .to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate_shader_to_330() {
+        let mut vertex = "attribute vec3 Vertex_Position;\n\
+            attribute vec2 Vertex_Uv;\n\
+            varying vec2 uv;\n\
+            void main() { uv = Vertex_Uv; gl_Position = vec4(Vertex_Position, 1.0); }\n"
+            .to_string();
+        let mut fragment = "varying vec2 uv;\n\
+            uniform sampler2D tex;\n\
+            void main() { gl_FragColor = texture2D(tex, uv); }\n"
+            .to_string();
+
+        translate_shader_to_330(&mut vertex, &mut fragment);
+
+        assert!(vertex.contains("layout (location = 0) in vec3 Vertex_Position;"));
+        assert!(vertex.contains("layout (location = 1) in vec2 Vertex_Uv;"));
+        assert!(vertex.contains("out vec2 uv;"));
+        assert!(!vertex.contains("attribute "));
+        assert!(!vertex.contains("varying "));
+
+        assert!(fragment.contains("in vec2 uv;"));
+        assert!(fragment.contains("out vec4 _FragColor;\nvoid main("));
+        assert!(fragment.contains("_FragColor = texture(tex, uv);"));
+        assert!(!fragment.contains("gl_FragColor"));
+    }
+
+    fn translate_fragment(body: &str) -> String {
+        let mut vertex = String::new();
+        let mut fragment = body.to_string();
+        translate_shader_to_330(&mut vertex, &mut fragment);
+        fragment
+    }
+
+    #[test]
+    fn test_translate_texture2d() {
+        assert!(translate_fragment("c = texture2D(tex, uv);").contains("c = texture(tex, uv);"));
+        assert!(
+            translate_fragment("c = texture2DLod(tex, uv, 0.0);")
+                .contains("c = textureLod(tex, uv, 0.0);")
+        );
+        assert!(
+            translate_fragment("c = texture2DProj(tex, p);").contains("c = textureProj(tex, p);")
+        );
+        assert!(
+            translate_fragment("c = texture2DProjLod(tex, p, 1.0);")
+                .contains("c = textureProjLod(tex, p, 1.0);")
+        );
+    }
+
+    #[test]
+    fn test_translate_texture_cube() {
+        assert!(
+            translate_fragment("c = textureCube(cube, dir);").contains("c = texture(cube, dir);")
+        );
+        assert!(
+            translate_fragment("c = textureCubeLod(cube, dir, 2.0);")
+                .contains("c = textureLod(cube, dir, 2.0);")
+        );
+        assert!(
+            translate_fragment("c = textureCubeLodEXT(cube, dir, 2.0);")
+                .contains("c = textureCubeLodEXT(cube, dir, 2.0);")
+        );
+    }
+
+    #[test]
+    fn test_translate_texture3d() {
+        assert!(translate_fragment("c = texture3D(vol, p);").contains("c = texture(vol, p);"));
+        assert!(
+            translate_fragment("c = texture3DLod(vol, p, 0.0);")
+                .contains("c = textureLod(vol, p, 0.0);")
+        );
+        assert!(
+            translate_fragment("c = texture3DProj(vol, p);").contains("c = textureProj(vol, p);")
+        );
+    }
+
+    #[test]
+    fn test_translate_texture2d_array() {
+        assert!(
+            translate_fragment("c = texture2DArray(atlas, p);").contains("c = texture(atlas, p);")
+        );
+        assert!(
+            translate_fragment("c = texture2DArrayLod(atlas, p, 1.0);")
+                .contains("c = textureLod(atlas, p, 1.0);")
+        );
+    }
+
+    #[test]
+    fn test_translate_shadow2d() {
+        assert!(
+            translate_fragment("s = shadow2D(shadow, p).r;")
+                .contains("s = vec4(texture(shadow, p)).r;")
+        );
+        assert!(
+            translate_fragment("s = shadow2DProj(shadow, p).r;")
+                .contains("s = vec4(textureProj(shadow, p)).r;")
+        );
+        assert!(
+            translate_fragment("return shadow2D(shadow_tex, vec3(uv, z + bias)).r;")
+                .contains("return vec4(texture(shadow_tex, vec3(uv, z + bias))).r;")
+        );
+        let once = translate_fragment("s = shadow2D(shadow, p).r;");
+        assert_eq!(translate_fragment(&once), once);
+    }
+
+    #[test]
+    fn test_translate_leaves_translated_calls() {
+        let src = "c = texture(tex, uv) + textureLod(cube, dir, 1.0) + my_texture2D(uv);";
+        assert_eq!(translate_fragment(src), src);
+        let once = translate_fragment("c = texture2D(tex, uv);");
+        assert_eq!(translate_fragment(&once), once);
+    }
+}
@@ -1,5 +1,6 @@
 use bevy::{
-    mesh::{Indices, MeshVertexAttributeId, VertexAttributeValues},
+    mesh::{Indices, MeshVertexAttributeId, PrimitiveTopology, VertexAttributeValues},
+    platform::collections::HashMap,
     prelude::*,
 };
 
@@ -59,6 +60,42 @@ pub fn get_mesh_indices_u32(mesh: &Mesh, index_buffer_data: &mut Vec<u32>, offse
     }
 }
 
+/// Splits a point, line or triangle list into chunks that each reference fewer than max_vertices unique vertices.
+/// primitive_size is the number of indices per primitive (1, 2 or 3). Returns (vertices, indices) for each chunk, where
+/// vertices index into the original vertex data and indices are local to the chunk.
+pub fn split_primitive_list(
+    indices: &[u32],
+    primitive_size: usize,
+    max_vertices: usize,
+) -> Vec<(Vec<u32>, Vec<u32>)> {
+    let mut chunks = Vec::new();
+    let mut remap: HashMap<u32, u32> = HashMap::new();
+    let mut vertices = Vec::new();
+    let mut local_indices = Vec::new();
+    for primitive in indices.chunks_exact(primitive_size) {
+        let new_vertices = primitive.iter().filter(|i| !remap.contains_key(*i)).count();
+        if vertices.len() + new_vertices >= max_vertices {
+            chunks.push((
+                std::mem::take(&mut vertices),
+                std::mem::take(&mut local_indices),
+            ));
+            remap.clear();
+        }
+        for i in primitive {
+            let next = vertices.len() as u32;
+            let local = *remap.entry(*i).or_insert_with(|| {
+                vertices.push(*i);
+                next
+            });
+            local_indices.push(local);
+        }
+    }
+    if !local_indices.is_empty() {
+        chunks.push((vertices, local_indices));
+    }
+    chunks
+}
+
 pub fn get_attribute_f32x2(
     mesh: &Mesh,
     id: impl Into<MeshVertexAttributeId>,
@@ -96,6 +133,71 @@ pub fn get_attribute_f32x4(
     Some(data)
 }
 
+/// Per vertex tangents for normal mapping, for meshes without Mesh::ATTRIBUTE_TANGENT. The UV derivatives of each
+/// triangle are summed at its vertices and orthogonalized against the normal. w is the bitangent sign, with
+/// bitangent = cross(normal, tangent.xyz) * w like glTF. Simpler than mikktspace, so tangents can differ slightly from
+/// ones baked by modeling tools along UV seams. Needs a TriangleList with positions, normals and UV_0.
+pub fn generate_tangents(mesh: &Mesh) -> Option<Vec<[f32; 4]>> {
+    if mesh.primitive_topology() != PrimitiveTopology::TriangleList {
+        return None;
+    }
+    let positions = get_attribute_f32x3(mesh, Mesh::ATTRIBUTE_POSITION)?;
+    let normals = get_attribute_f32x3(mesh, Mesh::ATTRIBUTE_NORMAL)?;
+    let uvs = get_attribute_f32x2(mesh, Mesh::ATTRIBUTE_UV_0)?;
+    let vertex_count = positions.len();
+    let indices = match mesh.indices() {
+        Some(indices) => indices.iter().collect::<Vec<_>>(),
+        None => (0..vertex_count).collect(),
+    };
+
+    let mut tangents = vec![Vec3::ZERO; vertex_count];
+    let mut bitangents = vec![Vec3::ZERO; vertex_count];
+    for triangle in indices.chunks_exact(3) {
+        let [a, b, c] = [triangle[0], triangle[1], triangle[2]];
+        if a.max(b).max(c) >= vertex_count {
+            continue;
+        }
+        let p0 = Vec3::from(positions[a]);
+        let (e1, e2) = (Vec3::from(positions[b]) - p0, Vec3::from(positions[c]) - p0);
+        let uv0 = Vec2::from(uvs[a]);
+        let (d1, d2) = (Vec2::from(uvs[b]) - uv0, Vec2::from(uvs[c]) - uv0);
+        let det = d1.perp_dot(d2);
+        // Triangles without UV area have no tangent direction.
+        if det.abs() <= f32::EPSILON {
+            continue;
+        }
+        let tangent = (e1 * d2.y - e2 * d1.y) / det;
+        let bitangent = (e2 * d1.x - e1 * d2.x) / det;
+        for i in [a, b, c] {
+            tangents[i] += tangent;
+            bitangents[i] += bitangent;
+        }
+    }
+
+    Some(
+        (0..vertex_count)
+            .map(|i| {
+                let normal = Vec3::from(normals[i]).normalize_or_zero();
+                let mut tangent =
+                    (tangents[i] - normal * normal.dot(tangents[i])).normalize_or_zero();
+                if tangent == Vec3::ZERO {
+                    tangent = if normal == Vec3::ZERO {
+                        Vec3::X
+                    } else {
+                        normal.any_orthonormal_vector()
+                    };
+                }
+                let w = if normal.cross(tangent).dot(bitangents[i]) < 0.0 {
+                    -1.0
+                } else {
+                    1.0
+                };
+                tangent.extend(w).to_array()
+            })
+            .collect(),
+    )
+}
+
 // https://jcgt.org/published/0003/02/01/paper.pdf
 
 /// Encodes normals or unit direction vectors as octahedral coordinates.
@@ -191,3 +293,61 @@ pub fn u16x4_to_u32(arr: &[u16; 4]) -> u32 {
 
     (byte1 << 24) | (byte2 << 16) | (byte3 << 8) | byte4
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_primitive_list() {
+        // Two quads that share no vertices, 4 vertices each.
+        let indices = [0, 1, 2, 0, 2, 3, 4, 5, 6, 4, 6, 7];
+        let chunks = split_primitive_list(&indices, 3, 5);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0], (vec![0, 1, 2, 3], vec![0, 1, 2, 0, 2, 3]));
+        assert_eq!(chunks[1], (vec![4, 5, 6, 7], vec![0, 1, 2, 0, 2, 3]));
+        assert_eq!(split_primitive_list(&indices, 3, 100).len(), 1);
+        // As a line list, a new chunk is started once 3 unique vertices are used.
+        assert_eq!(split_primitive_list(&[0, 1, 1, 2, 2, 3], 2, 3).len(), 2);
+    }
+
+    #[test]
+    fn test_generate_tangents_cube() {
+        let mesh = Cuboid::new(2.0, 2.0, 2.0).mesh().build();
+        assert!(!mesh.contains_attribute(Mesh::ATTRIBUTE_TANGENT));
+        let tangents = generate_tangents(&mesh).unwrap();
+        let positions = get_attribute_f32x3(&mesh, Mesh::ATTRIBUTE_POSITION).unwrap();
+        let normals = get_attribute_f32x3(&mesh, Mesh::ATTRIBUTE_NORMAL).unwrap();
+        let uvs = get_attribute_f32x2(&mesh, Mesh::ATTRIBUTE_UV_0).unwrap();
+        assert_eq!(tangents.len(), positions.len());
+
+        let indices = mesh.indices().unwrap().iter().collect::<Vec<_>>();
+        for triangle in indices.chunks_exact(3) {
+            let [a, b, c] = [triangle[0], triangle[1], triangle[2]];
+            let p0 = Vec3::from(positions[a]);
+            let (e1, e2) = (Vec3::from(positions[b]) - p0, Vec3::from(positions[c]) - p0);
+            let uv0 = Vec2::from(uvs[a]);
+            let (d1, d2) = (Vec2::from(uvs[b]) - uv0, Vec2::from(uvs[c]) - uv0);
+            let det = d1.perp_dot(d2);
+            // Moving along the tangent increases u, and along the bitangent increases v, on every face.
+            let expected_tangent = ((e1 * d2.y - e2 * d1.y) / det).normalize();
+            let expected_bitangent = ((e2 * d1.x - e1 * d2.x) / det).normalize();
+            for i in [a, b, c] {
+                let tangent = Vec4::from(tangents[i]);
+                let normal = Vec3::from(normals[i]);
+                assert!(tangent.truncate().abs_diff_eq(expected_tangent, 1e-5));
+                assert!(tangent.truncate().dot(normal).abs() < 1e-5);
+                assert!(
+                    (normal.cross(tangent.truncate()) * tangent.w)
+                        .abs_diff_eq(expected_bitangent, 1e-5)
+                );
+            }
+        }
+
+        // The front face has u along +x and v along +y in bevy's Cuboid.
+        let front = (0..positions.len())
+            .find(|i| normals[*i] == [0.0, 0.0, 1.0])
+            .unwrap();
+        assert!(Vec4::from(tangents[front]).abs_diff_eq(vec4(1.0, 0.0, 0.0, 1.0), 1e-5));
+    }
+}
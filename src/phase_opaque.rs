@@ -2,8 +2,11 @@ use bevy::{core_pipeline::prepass::DepthPrepass, prelude::*};
 
 use crate::{
     command_encoder::CommandEncoder,
-    plane_reflect::{ReflectionPlane, copy_reflection_texture},
-    render::{RenderPhase, RenderRunner, RenderSet},
+    phase_transparent::{DeferredAlphaBlendDraws, render_transparent},
+    plane_reflect::{ReflectionPlane, bind_reflection_framebuffer, copy_reflection_texture},
+    prepass::{begin_prepass_depth, end_prepass_depth},
+    render::{RenderCamera, RenderPhase, RenderRunner, RenderSet, sorted_cameras},
+    ssao::{end_ssao, render_ssao},
 };
 
 pub struct OpaquePhasePlugin;
@@ -22,11 +25,31 @@ impl Plugin for OpaquePhasePlugin {
     }
 }
 
+/// Should accompany a Camera. Controls what's cleared before the camera draws, both are cleared without it. With
+/// clear_color false the color is kept even if Camera::clear_color is set, e.g. to draw over what an earlier camera or
+/// external GL code rendered. Keeping the previous frame, for trails and accumulation, also depends on the platform
+/// preserving the window contents across swaps, which isn't guaranteed.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ClearSettings {
+    pub clear_color: bool,
+    pub clear_depth: bool,
+}
+
+impl Default for ClearSettings {
+    fn default() -> Self {
+        Self {
+            clear_color: true,
+            clear_depth: true,
+        }
+    }
+}
+
 fn render_reflect_opaque(world: &mut World) {
     let mut planes = world.query::<&ReflectionPlane>();
     if planes.iter(world).len() == 0 {
         return;
     }
+    bind_reflection_framebuffer(world);
     clear_color_and_depth(world);
     let mut query = world.query::<(&Camera3d, &DepthPrepass)>();
     let depth_prepass_enabled = query.iter(world).len() > 0;
@@ -38,16 +61,72 @@ fn render_reflect_opaque(world: &mut World) {
     opaque(world, false, !depth_prepass_enabled, depth_prepass_enabled);
 }
 
-fn render_opaque(world: &mut World) {
-    clear_color_and_depth(world);
-    let mut query = world.query::<(&Camera3d, &DepthPrepass)>();
-    let depth_prepass_enabled = query.iter(world).len() > 0;
-    if depth_prepass_enabled {
-        *world.get_resource_mut::<RenderPhase>().unwrap() = RenderPhase::DepthPrepass;
-        opaque(world, true, true, false)
+/// Runs the opaque and transparent phases for each active camera in order of Camera::order. The transparent phase has
+/// to run before the next camera draws over the window.
+pub(crate) fn render_opaque(world: &mut World) {
+    let mut query = world.query::<(Entity, &Camera)>();
+    let cameras = sorted_cameras(query.iter(world));
+    for (i, camera_entity) in cameras.iter().enumerate() {
+        world.insert_resource(RenderCamera(*camera_entity));
+        let camera = world.get::<Camera>(*camera_entity).unwrap();
+        let clear = world
+            .get::<ClearSettings>(*camera_entity)
+            .copied()
+            .unwrap_or_default();
+        let clear_color = match camera.clear_color {
+            _ if !clear.clear_color => None,
+            ClearColorConfig::Default => Some(world.resource::<ClearColor>().0),
+            ClearColorConfig::Custom(color) => Some(color),
+            ClearColorConfig::None => None,
+        };
+        let viewport = camera_viewport(camera);
+        let mut cmd = world.resource_mut::<CommandEncoder>();
+        // The first camera clears the whole window, later ones only clear inside their viewport.
+        if i > 0 {
+            cmd.set_viewport(viewport);
+        }
+        match (clear_color, clear.clear_depth) {
+            // Seems faster to clear these together
+            (Some(color), true) => cmd.record(move |ctx, _world| {
+                ctx.clear_color_and_depth(Some(ctx.output_color(color)));
+            }),
+            (Some(color), false) => cmd.record(move |ctx, _world| {
+                ctx.clear_color(Some(ctx.output_color(color)));
+            }),
+            (None, true) => cmd.clear_depth(),
+            (None, false) => (),
+        }
+        if i == 0 {
+            cmd.set_viewport(viewport);
+        }
+
+        let depth_prepass_enabled = world.get::<DepthPrepass>(*camera_entity).is_some();
+        // With PrepassTexturesPlugin the prepass goes to its own depth texture, so opaque has to fill the scene depth
+        // itself.
+        let mut prepass_texture = false;
+        if depth_prepass_enabled {
+            prepass_texture = begin_prepass_depth(world, viewport);
+            *world.get_resource_mut::<RenderPhase>().unwrap() = RenderPhase::DepthPrepass;
+            opaque(world, true, true, false);
+            if prepass_texture {
+                end_prepass_depth(world, viewport);
+                render_ssao(world, viewport);
+            }
+        }
+        let depth_equal = depth_prepass_enabled && !prepass_texture;
+        *world.get_resource_mut::<RenderPhase>().unwrap() = RenderPhase::Opaque;
+        opaque(world, false, !depth_equal, depth_equal);
+        if prepass_texture {
+            end_ssao(world);
+        }
+        if world.contains_resource::<DeferredAlphaBlendDraws>() {
+            render_transparent(world, viewport);
+        }
+    }
+    // Systems after this (debug, ui) see the first camera, same as shadows and reflections.
+    if let Some(camera_entity) = cameras.first() {
+        world.insert_resource(RenderCamera(*camera_entity));
     }
-    *world.get_resource_mut::<RenderPhase>().unwrap() = RenderPhase::Opaque;
-    opaque(world, false, !depth_prepass_enabled, depth_prepass_enabled);
 }
 
 // During the opaque pass the registered systems also write any transparent items to the DeferredAlphaBlendDraws.
@@ -76,9 +155,20 @@ fn opaque(world: &mut World, depth_prepass: bool, write_depth: bool, depth_equal
     world.insert_resource(runner);
 }
 
+/// The physical viewport of the camera, if it has one. The viewport is reset to the full window in present().
+pub(crate) fn camera_viewport(camera: &Camera) -> Option<URect> {
+    let viewport = camera.viewport.as_ref()?;
+    Some(URect::from_corners(
+        viewport.physical_position,
+        viewport.physical_position + viewport.physical_size,
+    ))
+}
+
 fn clear_color_and_depth(world: &mut World) {
     // Seems faster to clear these together
-    let color = world.resource::<ClearColor>().clone();
+    let color = world.resource::<ClearColor>().0;
     let mut cmd = world.resource_mut::<CommandEncoder>();
-    cmd.clear_color_and_depth(Some(color.to_srgba().to_vec4()));
+    cmd.record(move |ctx, _world| {
+        ctx.clear_color_and_depth(Some(ctx.output_color(color)));
+    });
 }
@@ -0,0 +1,258 @@
+use bevy::prelude::*;
+use glow::{HasContext, PixelUnpackData};
+
+use crate::{
+    BevyGlContext,
+    command_encoder::{
+        CommandEncoder, remove_render_resource_on_restore, remove_resource_on_restore,
+    },
+    framebuffer::Framebuffer,
+    phase_shadow::{ShadowMapSettings, ShadowView},
+    prepare_image::{GpuImages, TextureRef},
+    render::{RenderPhase, RenderRunner, RenderSet},
+};
+
+/// Cube map shadows for the first PointLight with shadows_enabled. This is expensive: the whole scene is rendered six
+/// times per frame without any culling, so only a single shadow casting point light is supported and the default
+/// resolution is kept low. Needs framebuffer objects, the cube map faces can't be copied from the window.
+pub struct PointShadowPhasePlugin;
+
+impl Plugin for PointShadowPhasePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<ShadowMapSettings>();
+        app.add_systems(
+            PostUpdate,
+            (
+                remove_resource_on_restore::<PointLightShadow>,
+                remove_render_resource_on_restore::<PointShadowFramebuffer>,
+            )
+                .in_set(RenderSet::Acquire),
+        );
+        app.add_systems(
+            PostUpdate,
+            update_point_shadow_tex.in_set(RenderSet::Prepare),
+        );
+        app.add_systems(
+            PostUpdate,
+            render_point_shadow.in_set(RenderSet::RenderShadow),
+        );
+    }
+}
+
+/// Default for ShadowMapSettings::point_resolution
+pub const DEFAULT_POINT_SHADOW_RESOLUTION: u32 = 512;
+
+#[derive(Resource, Clone)]
+pub struct PointLightShadow {
+    /// RGBA packed depth cube map
+    pub texture: TextureRef,
+    pub position: Vec3,
+    /// From PointLight::shadow_map_near_z
+    pub near: f32,
+    pub depth_bias: f32,
+    pub normal_bias: f32,
+    /// Size of each cube face
+    pub size: u32,
+}
+
+impl PointLightShadow {
+    /// Faces in GL order, starting with TEXTURE_CUBE_MAP_POSITIVE_X.
+    pub fn face_views(&self) -> [ShadowView; 6] {
+        let clip_from_view =
+            Mat4::perspective_infinite_reverse_rh(std::f32::consts::FRAC_PI_2, 1.0, self.near);
+        [
+            (Vec3::X, Vec3::NEG_Y),
+            (Vec3::NEG_X, Vec3::NEG_Y),
+            (Vec3::Y, Vec3::Z),
+            (Vec3::NEG_Y, Vec3::NEG_Z),
+            (Vec3::Z, Vec3::NEG_Y),
+            (Vec3::NEG_Z, Vec3::NEG_Y),
+        ]
+        .map(|(dir, up)| ShadowView {
+            view_from_world: Mat4::look_to_rh(self.position, dir, up),
+            clip_from_view,
+            position: self.position,
+        })
+    }
+}
+
+fn update_point_shadow_tex(
+    mut commands: Commands,
+    shadow_tex: Option<ResMut<PointLightShadow>>,
+    point_lights: Query<(&PointLight, &GlobalTransform)>,
+    settings: Res<ShadowMapSettings>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    let caster = point_lights.iter().find(|(light, _)| light.shadows_enabled);
+
+    let Some((light, trans)) = caster else {
+        if let Some(shadow_tex) = shadow_tex {
+            enc.delete_texture_ref(shadow_tex.texture.clone());
+            enc.record(|ctx, world| set_point_shadow_framebuffer(ctx, world, None));
+            commands.remove_resource::<PointLightShadow>();
+        }
+        return;
+    };
+
+    let size = settings.point_resolution.max(1);
+    if let Some(mut shadow_tex) = shadow_tex {
+        shadow_tex.position = trans.translation();
+        shadow_tex.near = light.shadow_map_near_z;
+        shadow_tex.depth_bias = light.shadow_depth_bias;
+        shadow_tex.normal_bias = light.shadow_normal_bias;
+        if shadow_tex.size != size {
+            shadow_tex.size = size;
+            let texture_ref = shadow_tex.texture.clone();
+            enc.record(move |ctx, world| unsafe {
+                if let Some((tex, _target)) = world
+                    .resource_mut::<GpuImages>()
+                    .texture_from_ref(&texture_ref)
+                {
+                    ctx.gl.delete_texture(tex);
+                    PointLightShadow::init(ctx, world, &texture_ref, size);
+                }
+            });
+        }
+    } else {
+        let texture_ref = TextureRef::new();
+        commands.insert_resource(PointLightShadow {
+            texture: texture_ref.clone(),
+            position: trans.translation(),
+            near: light.shadow_map_near_z,
+            depth_bias: light.shadow_depth_bias,
+            normal_bias: light.shadow_normal_bias,
+            size,
+        });
+        enc.record(move |ctx, world| PointLightShadow::init(ctx, world, &texture_ref, size));
+    }
+}
+
+fn render_point_shadow(world: &mut World) {
+    let Some(shadow) = world.get_resource::<PointLightShadow>().cloned() else {
+        return;
+    };
+    let Some(runner) = world.remove_resource::<RenderRunner>() else {
+        return;
+    };
+
+    *world.get_resource_mut::<RenderPhase>().unwrap() = RenderPhase::Shadow;
+    world
+        .resource_mut::<CommandEncoder>()
+        .start_opaque(true, false);
+
+    for (face, view) in shadow.face_views().into_iter().enumerate() {
+        let texture_ref = shadow.texture.clone();
+        world
+            .resource_mut::<CommandEncoder>()
+            .record(move |ctx, world| {
+                if !world.contains_resource::<PointShadowFramebuffer>() {
+                    return;
+                }
+                let Some((texture, _target)) = world
+                    .resource_mut::<GpuImages>()
+                    .texture_from_ref(&texture_ref)
+                else {
+                    return;
+                };
+                let framebuffer = &world.resource::<PointShadowFramebuffer>().0;
+                framebuffer.set_color_target(
+                    ctx,
+                    glow::TEXTURE_CUBE_MAP_POSITIVE_X + face as u32,
+                    texture,
+                );
+                framebuffer.bind(ctx);
+                ctx.clear_color_and_depth(None);
+            });
+        world.insert_resource(view);
+
+        for system in &runner.prepare_registry {
+            let _ = world.run_system(*system);
+        }
+
+        for (_type_id, system) in &runner.render_registry {
+            let _ = world.run_system(*system);
+        }
+    }
+
+    world.insert_resource(runner);
+
+    world
+        .resource_mut::<CommandEncoder>()
+        .record(move |ctx, world| {
+            if world.contains_resource::<PointShadowFramebuffer>() {
+                Framebuffer::unbind(ctx);
+            }
+        });
+}
+
+/// Render world resource. Point light shadows are only sampled if this exists.
+#[derive(Resource)]
+struct PointShadowFramebuffer(Framebuffer);
+
+/// For use in the render world. False if the point light shadow couldn't be rendered, see
+/// StandardLightingUniforms::shader_defs()
+pub fn point_shadow_available(world: &World) -> bool {
+    world.contains_resource::<PointShadowFramebuffer>()
+}
+
+fn set_point_shadow_framebuffer(
+    ctx: &BevyGlContext,
+    world: &mut World,
+    framebuffer: Option<Framebuffer>,
+) {
+    if let Some(old) = world.remove_resource::<PointShadowFramebuffer>() {
+        old.0.delete(&ctx.gl);
+    }
+    if let Some(framebuffer) = framebuffer {
+        world.insert_resource(PointShadowFramebuffer(framebuffer));
+    }
+}
+
+impl PointLightShadow {
+    fn init(ctx: &mut BevyGlContext, world: &mut World, texture_ref: &TextureRef, size: u32) {
+        unsafe {
+            let texture = ctx.gl.create_texture().unwrap();
+            world.resource_mut::<GpuImages>().add_texture_set_ref(
+                texture,
+                glow::TEXTURE_CUBE_MAP,
+                &texture_ref,
+            );
+            ctx.gl.bind_texture(glow::TEXTURE_CUBE_MAP, Some(texture));
+            ctx.label_texture(texture, "point light shadow");
+            for (param, value) in [
+                (glow::TEXTURE_MIN_FILTER, glow::NEAREST),
+                (glow::TEXTURE_MAG_FILTER, glow::NEAREST),
+                (glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE),
+                (glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE),
+            ] {
+                ctx.gl
+                    .tex_parameter_i32(glow::TEXTURE_CUBE_MAP, param, value as i32);
+            }
+            for face in 0..6 {
+                ctx.gl.tex_image_2d(
+                    glow::TEXTURE_CUBE_MAP_POSITIVE_X + face,
+                    0,
+                    glow::RGBA as i32,
+                    size as i32,
+                    size as i32,
+                    0,
+                    glow::RGBA,
+                    glow::UNSIGNED_BYTE,
+                    PixelUnpackData::Slice(None),
+                );
+            }
+            let framebuffer = Framebuffer::new_with_target(
+                ctx,
+                glow::TEXTURE_CUBE_MAP_POSITIVE_X,
+                texture,
+                true,
+                size,
+                size,
+            );
+            if framebuffer.is_none() {
+                warn!("Point light shadows need framebuffer objects");
+            }
+            set_point_shadow_framebuffer(ctx, world, framebuffer);
+        }
+    }
+}
@@ -1,17 +1,40 @@
-use bevy::prelude::*;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use bevy::{
+    light::{CascadeShadowConfig, Cascades},
+    prelude::*,
+};
 use glow::{HasContext, PixelUnpackData};
 
 use crate::{
     BevyGlContext,
-    command_encoder::CommandEncoder,
+    command_encoder::{
+        CommandEncoder, remove_render_resource_on_restore, remove_resource_on_restore,
+    },
+    framebuffer::Framebuffer,
+    phase_point_shadow::DEFAULT_POINT_SHADOW_RESOLUTION,
     prepare_image::{GpuImages, TextureRef},
-    render::{RenderPhase, RenderRunner, RenderSet},
+    render::{RenderCamera, RenderPhase, RenderRunner, RenderSet},
 };
 
 pub struct ShadowPhasePlugin;
 
 impl Plugin for ShadowPhasePlugin {
     fn build(&self, app: &mut App) {
+        app.init_resource::<ShadowFilter>();
+        app.init_resource::<ShadowMapSettings>();
+        app.init_resource::<ShadowFramebufferSupport>();
+        app.add_systems(
+            PostUpdate,
+            (
+                remove_resource_on_restore::<DirectionalLightShadow>,
+                remove_render_resource_on_restore::<ShadowFramebuffer>,
+            )
+                .in_set(RenderSet::Acquire),
+        );
         app.add_systems(PostUpdate, update_shadow_tex.in_set(RenderSet::Prepare));
         app.add_systems(PostUpdate, render_shadow.in_set(RenderSet::RenderShadow));
     }
@@ -20,50 +43,96 @@ impl Plugin for ShadowPhasePlugin {
 fn update_shadow_tex(
     mut commands: Commands,
     bevy_window: Single<&Window>,
+    render_camera: Option<Res<RenderCamera>>,
+    cameras: Query<&GlobalTransform, With<Camera>>,
     shadow_tex: Option<ResMut<DirectionalLightShadow>>,
-    directional_lights: Query<(&DirectionalLight, &GlobalTransform, Option<&ShadowBounds>)>,
+    directional_lights: Query<(
+        &DirectionalLight,
+        &GlobalTransform,
+        Option<&ShadowBounds>,
+        Option<(&Cascades, &CascadeShadowConfig)>,
+    )>,
+    settings: Res<ShadowMapSettings>,
+    framebuffer_support: Res<ShadowFramebufferSupport>,
     mut enc: ResMut<CommandEncoder>,
 ) {
-    // Keep shadow texture size up to date.
-    let mut view_from_world = Default::default();
-    let mut clip_from_view = Default::default();
-    let mut light_trans = Default::default();
-    let mut enabled = false;
-    if let Some((directional_light, trans, shadow_bounds)) = directional_lights.iter().next() {
-        let shadow_bounds = shadow_bounds.cloned().unwrap_or_default();
-        if directional_light.shadows_enabled {
-            light_trans = *trans;
+    // Shadows are only rendered for the first camera.
+    let Some(camera_entity) = render_camera.map(|c| **c) else {
+        return;
+    };
+    let Ok(camera_trans) = cameras.get(camera_entity) else {
+        return;
+    };
+    let mut cascades = Vec::new();
+    let mut light_trans = GlobalTransform::default();
+    if let Some((directional_light, trans, shadow_bounds, cascade_config)) =
+        directional_lights.iter().next()
+        && directional_light.shadows_enabled
+    {
+        light_trans = *trans;
+        if let Some((light_cascades, config)) = cascade_config
+            && shadow_bounds.is_none()
+        {
+            if let Some(view_cascades) = light_cascades.cascades.get(&camera_entity) {
+                for (cascade, far_bound) in view_cascades
+                    .iter()
+                    .zip(&config.bounds)
+                    .take(DEFAULT_MAX_CASCADES)
+                {
+                    cascades.push(ShadowCascade {
+                        view_from_world: cascade.world_from_cascade.inverse(),
+                        clip_from_view: extend_cascade_near(
+                            cascade.clip_from_cascade,
+                            CASCADE_CASTER_DISTANCE,
+                        ),
+                        far_bound: *far_bound,
+                    });
+                }
+            }
+        } else {
+            let shadow_bounds = shadow_bounds.cloned().unwrap_or_default();
             let dir = light_trans
                 .to_matrix()
                 .transform_vector3(vec3(0.0, 0.0, -1.0));
             let position = light_trans.translation() - dir * shadow_bounds.depth * 0.5;
             let z_far = shadow_bounds.depth * 0.5;
-            let shadow_view_from_world = Mat4::look_to_lh(position, dir, Vec3::Y);
-            let shadow_clip_from_view = Mat4::orthographic_lh(
-                -shadow_bounds.width * 0.5,
-                shadow_bounds.width * 0.5,
-                -shadow_bounds.height * 0.5,
-                shadow_bounds.height * 0.5,
-                z_far,
-                0.0,
-            );
-            view_from_world = shadow_view_from_world;
-            clip_from_view = shadow_clip_from_view;
-            enabled = true;
+            cascades.push(ShadowCascade {
+                view_from_world: Mat4::look_to_lh(position, dir, Vec3::Y),
+                clip_from_view: Mat4::orthographic_lh(
+                    -shadow_bounds.width * 0.5,
+                    shadow_bounds.width * 0.5,
+                    -shadow_bounds.height * 0.5,
+                    shadow_bounds.height * 0.5,
+                    z_far,
+                    0.0,
+                ),
+                far_bound: f32::MAX,
+            });
         }
     }
-    let width = bevy_window.physical_width().max(1);
-    let height = bevy_window.physical_height().max(1);
+    let enabled = !cascades.is_empty();
+    let view_z_from_world = camera_trans.to_matrix().inverse().row(2);
+
+    let size = if framebuffer_support.0.load(Ordering::Relaxed) {
+        UVec2::splat(settings.resolution.max(1))
+    } else {
+        // Rendered to the window and copied, so it can't be larger than the window.
+        bevy_window.physical_size().max(UVec2::ONE)
+    };
+    let support = framebuffer_support.0.clone();
+    let (width, height) = (size.x, size.y);
+    let atlas_size = size * atlas_tiles(cascades.len());
     if let Some(mut shadow_tex) = shadow_tex {
         if enabled {
-            shadow_tex.view_from_world = view_from_world;
-            shadow_tex.clip_from_view = clip_from_view;
+            let old_atlas_size = shadow_tex.atlas_size();
+            shadow_tex.cascades = cascades;
+            shadow_tex.view_z_from_world = view_z_from_world;
             shadow_tex.light_position = light_trans.translation();
-            if shadow_tex.width != width || shadow_tex.height != height {
+            shadow_tex.width = width;
+            shadow_tex.height = height;
+            if old_atlas_size != atlas_size {
                 let texture_ref = shadow_tex.texture.clone();
-                shadow_tex.width = width;
-                shadow_tex.height = height;
-
+                let support = support.clone();
                 enc.record(move |ctx, world| unsafe {
                     if let Some((tex, _target)) = world
                         .resource_mut::<GpuImages>()
@@ -72,16 +141,18 @@ fn update_shadow_tex(
                         ctx.gl.delete_texture(tex);
                         DirectionalLightShadow::init(
                             ctx,
-                            &mut world.resource_mut::<GpuImages>(),
+                            world,
                             &texture_ref,
-                            width,
-                            height,
-                        )
+                            atlas_size.x,
+                            atlas_size.y,
+                            &support,
+                        );
                     }
                 });
             }
         } else {
             enc.delete_texture_ref(shadow_tex.texture.clone());
+            enc.record(|ctx, world| set_shadow_framebuffer(ctx, world, None));
             commands.remove_resource::<DirectionalLightShadow>();
         }
     } else {
@@ -90,24 +161,46 @@ fn update_shadow_tex(
             commands.insert_resource(DirectionalLightShadow {
                 texture: texture_ref.clone(),
                 light_position: light_trans.translation(),
-                view_from_world,
-                clip_from_view,
+                cascades,
+                view_z_from_world,
                 width,
                 height,
             });
             enc.record(move |ctx, world| {
                 DirectionalLightShadow::init(
                     ctx,
-                    &mut world.resource_mut::<GpuImages>(),
+                    world,
                     &texture_ref,
-                    width,
-                    height,
+                    atlas_size.x,
+                    atlas_size.y,
+                    &support,
                 )
             });
         }
     }
 }
 
+/// Bevy fits the near plane of each cascade tightly around the view frustum and relies on depth clamping to keep casters
+/// that are outside of it. There's no depth clamping in GL 2.1 / WebGL1 so the near plane is moved towards the light
+/// instead.
+fn extend_cascade_near(clip_from_cascade: Mat4, distance: f32) -> Mat4 {
+    // Bevy uses reverse z: z_clip = z / depth + 1.0
+    let depth = clip_from_cascade.z_axis.z.recip();
+    let extended_depth = depth + distance;
+    let mut clip_from_cascade = clip_from_cascade;
+    clip_from_cascade.z_axis.z = extended_depth.recip();
+    clip_from_cascade.w_axis.z = depth / extended_depth;
+    clip_from_cascade
+}
+
+/// Cascades are stored side by side in a single texture so they only take one texture unit. Returns columns, rows.
+fn atlas_tiles(cascade_count: usize) -> UVec2 {
+    let cascade_count = cascade_count.max(1) as u32;
+    uvec2(cascade_count.min(2), cascade_count.div_ceil(2))
+}
+
+/// Should accompany a DirectionalLight. Uses a single shadow map covering these bounds around the light's position
+/// instead of the cascades from the light's CascadeShadowConfig.
 #[derive(Component, Clone, Copy)]
 pub struct ShadowBounds {
     pub width: f32,
@@ -125,6 +218,58 @@ impl ShadowBounds {
     }
 }
 
+/// How the directional light shadow map is filtered when sampled.
+#[derive(Resource, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum ShadowFilter {
+    /// Single comparison, aliased edges.
+    Hard,
+    /// Bilinear filtered comparisons covering 3x3 texels.
+    #[default]
+    Pcf3x3,
+    /// Castaño's 13 tap kernel covering 5x5 texels.
+    Pcf5x5,
+}
+
+impl ShadowFilter {
+    pub fn shader_def(&self) -> (&'static str, &'static str) {
+        match self {
+            ShadowFilter::Hard => ("SHADOW_FILTER_HARD", ""),
+            ShadowFilter::Pcf3x3 => ("SHADOW_FILTER_PCF3X3", ""),
+            ShadowFilter::Pcf5x5 => ("SHADOW_FILTER_PCF5X5", ""),
+        }
+    }
+}
+
+/// Shadow map resolutions.
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct ShadowMapSettings {
+    /// Width and height of each directional light cascade. Only used if framebuffer objects are supported, without
+    /// them the shadow map is copied from the window and matches its size.
+    pub resolution: u32,
+    /// Size of each point light cube face.
+    pub point_resolution: u32,
+}
+
+impl Default for ShadowMapSettings {
+    fn default() -> Self {
+        Self {
+            resolution: 2048,
+            point_resolution: DEFAULT_POINT_SHADOW_RESOLUTION,
+        }
+    }
+}
+
+/// Whether the render world could create a ShadowFramebuffer. Assumed until the first shadow map is created, if that
+/// fails the shadow map falls back to the window size.
+#[derive(Resource)]
+struct ShadowFramebufferSupport(Arc<AtomicBool>);
+
+impl Default for ShadowFramebufferSupport {
+    fn default() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+}
+
 impl Default for ShadowBounds {
     fn default() -> Self {
         Self {
@@ -136,25 +281,84 @@ impl Default for ShadowBounds {
 }
 
 fn render_shadow(world: &mut World) {
-    let Some(shadow_texture) = world.get_resource::<DirectionalLightShadow>().cloned() else {
+    let Some(shadow) = world.get_resource::<DirectionalLightShadow>().cloned() else {
+        return;
+    };
+    let Some(runner) = world.remove_resource::<RenderRunner>() else {
         return;
     };
     let mut cmd = world.resource_mut::<CommandEncoder>();
-    cmd.start_opaque(true, false); // Reading from depth not supported so we need to write depth to color
-    cmd.clear_color_and_depth(None);
+    // Without depth textures, reading from depth is not supported so we need to write depth to color
+    cmd.start_opaque(true, false);
+    cmd.record(|ctx, world| {
+        if let Some(shadow_framebuffer) = world.get_resource::<ShadowFramebuffer>() {
+            shadow_framebuffer.framebuffer.bind(ctx);
+            ctx.clear_color_and_depth(None);
+        }
+    });
 
     *world.get_resource_mut::<RenderPhase>().unwrap() = RenderPhase::Shadow;
 
-    let Some(runner) = world.remove_resource::<RenderRunner>() else {
-        return;
-    };
+    for cascade_index in 0..shadow.cascades.len() {
+        let cascade = &shadow.cascades[cascade_index];
+        world.insert_resource(ShadowView {
+            view_from_world: cascade.view_from_world,
+            clip_from_view: cascade.clip_from_view,
+            position: shadow.light_position,
+        });
+        let offset = shadow.tile_offset(cascade_index);
+        let (width, height) = (shadow.width, shadow.height);
+        world
+            .resource_mut::<CommandEncoder>()
+            .record(move |ctx, world| unsafe {
+                if world.contains_resource::<ShadowFramebuffer>() {
+                    ctx.gl.viewport(
+                        offset.x as i32,
+                        offset.y as i32,
+                        width as i32,
+                        height as i32,
+                    );
+                } else {
+                    ctx.clear_color_and_depth(None);
+                }
+            });
 
-    for system in &runner.prepare_registry {
-        let _ = world.run_system(*system);
-    }
+        for system in &runner.prepare_registry {
+            let _ = world.run_system(*system);
+        }
+
+        for (_type_id, system) in &runner.render_registry {
+            let _ = world.run_system(*system);
+        }
 
-    for (_type_id, system) in &runner.render_registry {
-        let _ = world.run_system(*system);
+        let texture_ref = shadow.texture.clone();
+        world
+            .resource_mut::<CommandEncoder>()
+            .record(move |ctx, world| {
+                if world.contains_resource::<ShadowFramebuffer>() {
+                    return;
+                }
+                // No framebuffer object, copy from the window into this cascade's tile instead.
+                if let Some((texture, target)) = world
+                    .resource_mut::<GpuImages>()
+                    .texture_from_ref(&texture_ref)
+                {
+                    let size = ctx.window_size.min(uvec2(width, height));
+                    unsafe {
+                        ctx.gl.bind_texture(target, Some(texture));
+                        ctx.gl.copy_tex_sub_image_2d(
+                            target,
+                            0,
+                            offset.x as i32,
+                            offset.y as i32,
+                            0,
+                            0,
+                            size.x as i32,
+                            size.y as i32,
+                        );
+                    };
+                }
+            });
     }
 
     world.insert_resource(runner);
@@ -162,69 +366,198 @@ fn render_shadow(world: &mut World) {
     world
         .resource_mut::<CommandEncoder>()
         .record(move |ctx, world| {
-            if let Some((texture, target)) = world
-                .resource_mut::<GpuImages>()
-                .texture_from_ref(&shadow_texture.texture)
-            {
-                unsafe {
-                    ctx.gl.bind_texture(target, Some(texture));
-                    ctx.gl.copy_tex_image_2d(
-                        target,
-                        0,
-                        glow::RGBA,
-                        0,
-                        0,
-                        shadow_texture.width as i32,
-                        shadow_texture.height as i32,
-                        0,
-                    );
-                };
+            if world.contains_resource::<ShadowFramebuffer>() {
+                Framebuffer::unbind(ctx);
             }
         });
 }
 
+/// Max number of cascades that are rendered. Shaders need a matching MAX_CASCADES def, see DEFAULT_MAX_CASCADES_DEF.
+pub const DEFAULT_MAX_CASCADES: usize = 4;
+pub const DEFAULT_MAX_CASCADES_DEF: (&str, &str) = ("MAX_CASCADES", "4");
+
+/// How far the near plane of each cascade is moved towards the light to catch casters outside of the view frustum.
+pub const CASCADE_CASTER_DISTANCE: f32 = 100.0;
+
+/// The view the shadow phase is currently rendering from. Inserted before the prepare systems run for each directional
+/// light cascade or point light cube face.
 #[derive(Resource, Clone)]
-pub struct DirectionalLightShadow {
-    pub texture: TextureRef,
+pub struct ShadowView {
     pub view_from_world: Mat4,
     pub clip_from_view: Mat4,
+    pub position: Vec3,
+}
+
+#[derive(Clone)]
+pub struct ShadowCascade {
+    pub view_from_world: Mat4,
+    pub clip_from_view: Mat4,
+    /// View space distance from the camera where this cascade ends.
+    pub far_bound: f32,
+}
+
+#[derive(Resource, Clone)]
+pub struct DirectionalLightShadow {
+    /// Contains all cascades, see tile_offset()
+    pub texture: TextureRef,
+    pub cascades: Vec<ShadowCascade>,
+    /// Camera view space z from world space. Used to select the cascade.
+    pub view_z_from_world: Vec4,
     pub light_position: Vec3,
+    /// Size of each cascade
     pub width: u32,
     pub height: u32,
 }
 
+impl DirectionalLightShadow {
+    /// Columns and rows of cascades in the texture.
+    pub fn atlas_tiles(&self) -> UVec2 {
+        atlas_tiles(self.cascades.len())
+    }
+
+    pub fn atlas_size(&self) -> UVec2 {
+        uvec2(self.width, self.height) * self.atlas_tiles()
+    }
+
+    /// Pixel offset of the cascade in the texture.
+    pub fn tile_offset(&self, cascade_index: usize) -> UVec2 {
+        let columns = self.atlas_tiles().x;
+        let cascade_index = cascade_index as u32;
+        uvec2(cascade_index % columns, cascade_index / columns) * uvec2(self.width, self.height)
+    }
+}
+
+/// Render world resource. Only exists if the shadow map can be rendered to directly.
+#[derive(Resource)]
+struct ShadowFramebuffer {
+    framebuffer: Framebuffer,
+    /// The shadow map is a DEPTH_COMPONENT texture sampled with sampler2DShadow instead of depth packed into RGBA.
+    depth_texture: bool,
+}
+
+/// For use in the render world. True if the shadow map is a depth texture, see StandardLightingUniforms::shader_defs()
+pub fn shadow_depth_texture(world: &World) -> bool {
+    world
+        .get_resource::<ShadowFramebuffer>()
+        .is_some_and(|framebuffer| framebuffer.depth_texture)
+}
+
+fn set_shadow_framebuffer(
+    ctx: &BevyGlContext,
+    world: &mut World,
+    framebuffer: Option<ShadowFramebuffer>,
+) {
+    if let Some(old) = world.remove_resource::<ShadowFramebuffer>() {
+        old.framebuffer.delete(&ctx.gl);
+    }
+    if let Some(framebuffer) = framebuffer {
+        world.insert_resource(framebuffer);
+    }
+}
+
 impl DirectionalLightShadow {
     fn init(
         ctx: &mut BevyGlContext,
-        images: &mut GpuImages,
+        world: &mut World,
         texture_ref: &TextureRef,
         width: u32,
         height: u32,
+        support: &AtomicBool,
     ) {
         unsafe {
-            let texture = ctx.gl.create_texture().unwrap();
-            images.add_texture_set_ref(texture, glow::TEXTURE_2D, &texture_ref);
-            ctx.gl.bind_texture(glow::TEXTURE_2D, Some(texture));
-            ctx.gl.tex_parameter_i32(
+            if ctx.has_depth_texture {
+                let texture = create_shadow_texture(ctx, true, width, height);
+                if let Some(framebuffer) = Framebuffer::new_depth(ctx, texture, width, height) {
+                    world.resource_mut::<GpuImages>().add_texture_set_ref(
+                        texture,
+                        glow::TEXTURE_2D,
+                        &texture_ref,
+                    );
+                    let framebuffer = ShadowFramebuffer {
+                        framebuffer,
+                        depth_texture: true,
+                    };
+                    set_shadow_framebuffer(ctx, world, Some(framebuffer));
+                    support.store(true, Ordering::Relaxed);
+                    return;
+                }
+                ctx.gl.delete_texture(texture);
+            }
+
+            // Depth packed into RGBA
+            let texture = create_shadow_texture(ctx, false, width, height);
+            world.resource_mut::<GpuImages>().add_texture_set_ref(
+                texture,
                 glow::TEXTURE_2D,
-                glow::TEXTURE_MIN_FILTER,
-                glow::NEAREST as i32,
+                &texture_ref,
             );
+            let framebuffer =
+                Framebuffer::new(ctx, texture, true, width, height).map(|framebuffer| {
+                    ShadowFramebuffer {
+                        framebuffer,
+                        depth_texture: false,
+                    }
+                });
+            support.store(framebuffer.is_some(), Ordering::Relaxed);
+            set_shadow_framebuffer(ctx, world, framebuffer);
+        }
+    }
+}
+
+unsafe fn create_shadow_texture(
+    ctx: &BevyGlContext,
+    depth_texture: bool,
+    width: u32,
+    height: u32,
+) -> glow::Texture {
+    unsafe {
+        let texture = ctx.gl.create_texture().unwrap();
+        ctx.gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+        ctx.label_texture(texture, "shadow map");
+        // Packed depth can't be interpolated. Depth textures get hardware filtered comparisons with LINEAR.
+        let filter = if depth_texture {
+            glow::LINEAR
+        } else {
+            glow::NEAREST
+        };
+        ctx.gl
+            .tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MIN_FILTER, filter as i32);
+        ctx.gl
+            .tex_parameter_i32(glow::TEXTURE_2D, glow::TEXTURE_MAG_FILTER, filter as i32);
+        ctx.gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_WRAP_S,
+            glow::CLAMP_TO_EDGE as i32,
+        );
+        ctx.gl.tex_parameter_i32(
+            glow::TEXTURE_2D,
+            glow::TEXTURE_WRAP_T,
+            glow::CLAMP_TO_EDGE as i32,
+        );
+        if depth_texture {
             ctx.gl.tex_parameter_i32(
                 glow::TEXTURE_2D,
-                glow::TEXTURE_MAG_FILTER,
-                glow::NEAREST as i32,
+                glow::TEXTURE_COMPARE_MODE,
+                glow::COMPARE_REF_TO_TEXTURE as i32,
             );
+            // Reverse z, lit if the receiver is at least as close to the light as the occluder.
             ctx.gl.tex_parameter_i32(
                 glow::TEXTURE_2D,
-                glow::TEXTURE_WRAP_S,
-                glow::CLAMP_TO_EDGE as i32,
+                glow::TEXTURE_COMPARE_FUNC,
+                glow::GEQUAL as i32,
             );
-            ctx.gl.tex_parameter_i32(
+            ctx.gl.tex_image_2d(
                 glow::TEXTURE_2D,
-                glow::TEXTURE_WRAP_T,
-                glow::CLAMP_TO_EDGE as i32,
+                0,
+                glow::DEPTH_COMPONENT24 as i32,
+                width as i32,
+                height as i32,
+                0,
+                glow::DEPTH_COMPONENT,
+                glow::UNSIGNED_INT,
+                PixelUnpackData::Slice(None),
             );
+        } else {
             ctx.gl.tex_image_2d(
                 glow::TEXTURE_2D,
                 0,
@@ -237,5 +570,6 @@ impl DirectionalLightShadow {
                 PixelUnpackData::Slice(None),
             );
         }
+        texture
     }
 }
@@ -1,32 +1,100 @@
 use bevy::{camera::primitives::Aabb, prelude::*};
-use std::any::TypeId;
+use std::{
+    any::TypeId,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 
+use bytemuck::cast_slice;
 use glow::HasContext;
 
 use crate::{
-    command_encoder::CommandEncoder,
+    AttribType, BevyGlContext, BlendState, RenderState,
+    command_encoder::{CommandEncoder, remove_render_resource_on_restore},
+    framebuffer::{ColorTargetFormat, Framebuffer},
     plane_reflect::ReflectionPlane,
     render::{RenderPhase, RenderRunner, RenderSet},
+    shader_cached,
 };
 
 pub struct TransparentPhasePlugin;
 impl Plugin for TransparentPhasePlugin {
     fn build(&self, app: &mut App) {
         app.init_resource::<DeferredAlphaBlendDraws>();
+        app.init_resource::<TransparencyMode>();
+        app.init_resource::<OitPass>();
+        app.init_resource::<WeightedBlendedSupport>();
         app.add_systems(
             PostUpdate,
-            clear_alpha_blend_draws.in_set(RenderSet::Prepare),
+            remove_render_resource_on_restore::<WeightedBlendedTargets>.in_set(RenderSet::Acquire),
         );
         app.add_systems(
             PostUpdate,
-            (
-                render_reflect_transparent.in_set(RenderSet::RenderReflectTransparent),
-                render_transparent.in_set(RenderSet::RenderTransparent),
-            ),
+            (clear_alpha_blend_draws, prepare_weighted_blended_targets).in_set(RenderSet::Prepare),
+        );
+        app.add_systems(
+            PostUpdate,
+            render_reflect_transparent.in_set(RenderSet::RenderReflectTransparent),
         );
     }
 }
 
+/// How the alpha blend draws of RenderPhase::Transparent are combined. Reflections are always sorted.
+#[derive(Resource, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum TransparencyMode {
+    /// Draws are sorted back to front by the nearest point of their bounding sphere and blended with the blend state
+    /// of their AlphaMode. Intersecting or overlapping draws can blend in the wrong order.
+    #[default]
+    Sorted,
+    /// Weighted blended order independent transparency. The draws are rendered twice without sorting, once summing
+    /// weighted color into a half float target and once multiplying the revealage, then composited over the opaque
+    /// image. The result doesn't depend on draw order but only approximates blending, with weights from alpha and view
+    /// depth. Every AlphaMode blends like AlphaMode::Blend.
+    ///
+    /// Needs BevyGlContext::has_float_render_target, stays sorted without it. The two passes don't need multiple
+    /// render targets, so each transparent draw is issued twice. Materials need to add OitPass::shader_defs() and
+    /// use OitPass::blend_state(), the standard material does.
+    WeightedBlended,
+}
+
+/// Which pass over the transparent draws is being rendered, see TransparencyMode::WeightedBlended.
+#[derive(Resource, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum OitPass {
+    /// Not in a weighted blended pass, draws blend as usual.
+    #[default]
+    None,
+    /// Output color * alpha * weight and alpha * weight, summed into a half float target.
+    Accumulate,
+    /// Output alpha, the target is multiplied by 1 - alpha.
+    Revealage,
+}
+
+impl OitPass {
+    /// OIT_ACCUMULATE or OIT_REVEALAGE, for the std::oit shader include.
+    pub fn shader_defs(&self) -> [(&'static str, &'static str); 1] {
+        [match self {
+            OitPass::None => ("", ""),
+            OitPass::Accumulate => ("OIT_ACCUMULATE", ""),
+            OitPass::Revealage => ("OIT_REVEALAGE", ""),
+        }]
+    }
+
+    /// The blend state to use in place of the material's, if in a weighted blended pass.
+    pub fn blend_state(&self) -> Option<BlendState> {
+        match self {
+            OitPass::None => None,
+            OitPass::Accumulate => Some(BlendState::ADDITIVE),
+            OitPass::Revealage => Some(BlendState::new(glow::ZERO, glow::ONE_MINUS_SRC_COLOR)),
+        }
+    }
+}
+
+/// Whether the render world managed to create WeightedBlendedTargets. Until it has, the transparent phase is sorted.
+#[derive(Resource, Default)]
+struct WeightedBlendedSupport(Arc<AtomicBool>);
+
 #[derive(Resource, Default)]
 pub struct DeferredAlphaBlendDraws {
     pub deferred: Vec<(f32, Entity, TypeId)>,
@@ -89,9 +157,21 @@ fn render_reflect_transparent(world: &mut World) {
     transparent(world);
 }
 
-fn render_transparent(world: &mut World) {
+/// Runs from render_opaque() for each camera, RenderSet::RenderTransparent is kept for ordering user systems.
+/// viewport is the camera's, see TransparencyMode::WeightedBlended.
+pub(crate) fn render_transparent(world: &mut World, viewport: Option<URect>) {
     *world.get_resource_mut::<RenderPhase>().unwrap() = RenderPhase::Transparent;
-    transparent(world);
+    let weighted_blended = *world.resource::<TransparencyMode>()
+        == TransparencyMode::WeightedBlended
+        && world
+            .resource::<WeightedBlendedSupport>()
+            .0
+            .load(Ordering::Relaxed);
+    if weighted_blended {
+        weighted_blended_transparent(world, viewport);
+    } else {
+        transparent(world);
+    }
 }
 
 fn transparent(world: &mut World) {
@@ -107,14 +187,67 @@ fn transparent(world: &mut World) {
         let _ = world.run_system(*system);
     }
 
-    {
+    world
+        .get_resource_mut::<DeferredAlphaBlendDraws>()
+        .unwrap()
+        .deferred
+        .sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+
+    draw_deferred(world, &runner);
+    finish_transparent(world, runner);
+}
+
+fn weighted_blended_transparent(world: &mut World, viewport: Option<URect>) {
+    let Some(runner) = world.remove_resource::<RenderRunner>() else {
+        return;
+    };
+    for system in &runner.prepare_registry {
+        let _ = world.run_system(*system);
+    }
+
+    // Order doesn't matter, group by type so each render system runs once per pass.
+    let deferred = {
         let mut draws = world.get_resource_mut::<DeferredAlphaBlendDraws>().unwrap();
-        draws
-            .deferred
-            .sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
-        draws.next.clear();
+        draws.deferred.sort_by_key(|(_, _, type_id)| *type_id);
+        draws.deferred.clone()
+    };
+    if deferred.is_empty() {
+        finish_transparent(world, runner);
+        return;
     }
 
+    for pass in [OitPass::Accumulate, OitPass::Revealage] {
+        *world.resource_mut::<OitPass>() = pass;
+        world.resource_mut::<DeferredAlphaBlendDraws>().deferred = deferred.clone();
+        world
+            .resource_mut::<CommandEncoder>()
+            .record(move |ctx, world| {
+                if let Some(mut targets) = world.get_resource_mut::<WeightedBlendedTargets>() {
+                    targets.begin_pass(ctx, pass);
+                }
+            });
+        draw_deferred(world, &runner);
+    }
+    *world.resource_mut::<OitPass>() = OitPass::None;
+
+    world
+        .resource_mut::<CommandEncoder>()
+        .record(move |ctx, world| {
+            if let Some(targets) = world.get_resource::<WeightedBlendedTargets>() {
+                targets.composite(ctx, viewport);
+            }
+        });
+    finish_transparent(world, runner);
+}
+
+/// Runs the render systems for the draws in DeferredAlphaBlendDraws::deferred, popping from the end.
+fn draw_deferred(world: &mut World, runner: &RenderRunner) {
+    world
+        .get_resource_mut::<DeferredAlphaBlendDraws>()
+        .unwrap()
+        .next
+        .clear();
+
     let mut last = false;
     // Draw deferred transparent
     loop {
@@ -151,11 +284,15 @@ fn transparent(world: &mut World) {
             break;
         }
     }
+}
 
+fn finish_transparent(world: &mut World, runner: RenderRunner) {
     world
         .resource_mut::<CommandEncoder>()
         .record(move |ctx, _world| {
-            unsafe { ctx.gl.bind_vertex_array(None) };
+            ctx.bind_vertex_array(None);
+            // The camera's mesh phases are done, later passes draw filled even with WireframeSettings.
+            ctx.set_polygon_mode(glow::FILL);
         });
 
     world.insert_resource(runner);
@@ -166,3 +303,239 @@ fn transparent(world: &mut World) {
         .deferred
         .clear();
 }
+
+/// Creates or resizes the render world's WeightedBlendedTargets while TransparencyMode::WeightedBlended is used, and
+/// reports whether that worked back to render_transparent().
+fn prepare_weighted_blended_targets(
+    mode: Res<TransparencyMode>,
+    support: Res<WeightedBlendedSupport>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    if *mode != TransparencyMode::WeightedBlended {
+        return;
+    }
+    let support = support.0.clone();
+    enc.record(move |ctx, world| {
+        let size = ctx.window_size;
+        let current_size = world
+            .get_resource::<WeightedBlendedTargets>()
+            .map(|targets| uvec2(targets.framebuffer.width, targets.framebuffer.height));
+        if current_size == Some(size) {
+            return;
+        }
+        if let Some(targets) = world.remove_resource::<WeightedBlendedTargets>() {
+            targets.delete(&ctx.gl);
+        }
+        if !ctx.has_float_render_target || size.x == 0 || size.y == 0 {
+            if !ctx.has_float_render_target {
+                warn_once!(
+                    "TransparencyMode::WeightedBlended needs half float render targets, using sorted transparency"
+                );
+            }
+            support.store(false, Ordering::Relaxed);
+            return;
+        }
+        let targets = WeightedBlendedTargets::new(ctx, size);
+        if targets.is_none() {
+            warn_once!(
+                "Couldn't create the weighted blended transparency targets, using sorted transparency"
+            );
+        }
+        support.store(targets.is_some(), Ordering::Relaxed);
+        if let Some(targets) = targets {
+            world.insert_resource(targets);
+        }
+    });
+}
+
+/// Window sized accumulation and revealage textures for TransparencyMode::WeightedBlended. They share one framebuffer
+/// with a depth buffer in the same format as the one being rendered to, so the opaque depth can be copied over with
+/// glBlitFramebuffer.
+#[derive(Resource)]
+struct WeightedBlendedTargets {
+    framebuffer: Framebuffer,
+    /// RGBA16F
+    accumulation: glow::Texture,
+    /// RGBA8, the revealage is in r.
+    revealage: glow::Texture,
+    triangle: glow::Buffer,
+    vertex_array: Option<glow::VertexArray>,
+    /// The framebuffer that was bound when the accumulation pass started, None for the window.
+    previous: Option<glow::Framebuffer>,
+}
+
+impl WeightedBlendedTargets {
+    fn new(ctx: &BevyGlContext, size: UVec2) -> Option<Self> {
+        unsafe {
+            let target = ctx.gl.get_parameter_framebuffer(glow::FRAMEBUFFER_BINDING);
+            let depth_format = bound_depth_format(ctx, target.is_some());
+            let accumulation = ColorTargetFormat::Rgba16F.create_texture(ctx, size);
+            let revealage = ColorTargetFormat::Rgba8.create_texture(ctx, size);
+            let framebuffer = Framebuffer::new_with_depth_format(
+                ctx,
+                glow::TEXTURE_2D,
+                accumulation,
+                Some(depth_format),
+                size.x,
+                size.y,
+            );
+            ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, target);
+            let Some(framebuffer) = framebuffer else {
+                ctx.gl.delete_texture(accumulation);
+                ctx.gl.delete_texture(revealage);
+                return None;
+            };
+            ctx.label_texture(accumulation, "oit_accumulation");
+            ctx.label_texture(revealage, "oit_revealage");
+            // One triangle covering the screen
+            let triangle = ctx.gen_vbo(
+                cast_slice(&[-1.0f32, -1.0, 3.0, -1.0, -1.0, 3.0]),
+                glow::STATIC_DRAW,
+            );
+            let vertex_array = ctx
+                .has_vertex_array_object
+                .then(|| ctx.gl.create_vertex_array().unwrap());
+            Some(Self {
+                framebuffer,
+                accumulation,
+                revealage,
+                triangle,
+                vertex_array,
+                previous: None,
+            })
+        }
+    }
+
+    /// Switches rendering to the target of pass, copying depth from the framebuffer being rendered to first. Keeps the
+    /// viewport, the targets are the same size as the window.
+    fn begin_pass(&mut self, ctx: &mut BevyGlContext, pass: OitPass) {
+        let (texture, clear) = match pass {
+            OitPass::Accumulate => (self.accumulation, Vec4::ZERO),
+            OitPass::Revealage => (self.revealage, Vec4::ONE),
+            OitPass::None => return,
+        };
+        unsafe {
+            if pass == OitPass::Accumulate {
+                self.previous = ctx.gl.get_parameter_framebuffer(glow::FRAMEBUFFER_BINDING);
+                let (width, height) = (
+                    self.framebuffer.width as i32,
+                    self.framebuffer.height as i32,
+                );
+                ctx.gl
+                    .bind_framebuffer(glow::READ_FRAMEBUFFER, self.previous);
+                ctx.gl
+                    .bind_framebuffer(glow::DRAW_FRAMEBUFFER, Some(self.framebuffer.fbo));
+                ctx.gl.blit_framebuffer(
+                    0,
+                    0,
+                    width,
+                    height,
+                    0,
+                    0,
+                    width,
+                    height,
+                    glow::DEPTH_BUFFER_BIT,
+                    glow::NEAREST,
+                );
+                ctx.debug_check_error("weighted blended depth copy");
+            }
+        }
+        self.framebuffer
+            .set_color_target(ctx, glow::TEXTURE_2D, texture);
+        ctx.clear_color(Some(clear));
+        ctx.start_alpha_blend();
+    }
+
+    /// Binds the framebuffer from before begin_pass() again and blends the accumulated transparency over it.
+    fn composite(&self, ctx: &mut BevyGlContext, viewport: Option<URect>) {
+        unsafe { ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, self.previous) };
+        ctx.set_viewport(viewport);
+        let Some(shader_index) = shader_cached!(
+            ctx,
+            "shaders/oit_composite.vert",
+            "shaders/oit_composite.frag",
+            &[],
+            &[]
+        ) else {
+            return;
+        };
+        ctx.use_cached_program(shader_index);
+        ctx.apply_render_state(RenderState {
+            cull_mode: None,
+            blend: true,
+            depth_test: false,
+            depth_write: false,
+            polygon_mode: glow::FILL,
+            ..ctx.render_state
+        });
+        BlendState::ALPHA.apply(&ctx.gl);
+        unsafe {
+            ctx.gl.active_texture(glow::TEXTURE0);
+            ctx.gl
+                .bind_texture(glow::TEXTURE_2D, Some(self.accumulation));
+            ctx.gl.active_texture(glow::TEXTURE1);
+            ctx.gl.bind_texture(glow::TEXTURE_2D, Some(self.revealage));
+        }
+        ctx.load("accumulation_texture", 0i32);
+        ctx.load("revealage_texture", 1i32);
+        ctx.load(
+            "target_size",
+            vec2(
+                self.framebuffer.width as f32,
+                self.framebuffer.height as f32,
+            ),
+        );
+        let Some(position) = ctx.cached_attrib_location(shader_index, "position") else {
+            return;
+        };
+        unsafe {
+            if self.vertex_array.is_some() {
+                ctx.bind_vertex_array(self.vertex_array);
+            }
+            ctx.bind_vertex_attrib(position, 2, AttribType::Float, self.triangle);
+            ctx.gl.draw_arrays(glow::TRIANGLES, 0, 3);
+            ctx.gl.disable_vertex_attrib_array(position);
+        }
+    }
+
+    fn delete(&self, gl: &glow::Context) {
+        self.framebuffer.delete(gl);
+        unsafe {
+            gl.delete_texture(self.accumulation);
+            gl.delete_texture(self.revealage);
+            gl.delete_buffer(self.triangle);
+            if let Some(vertex_array) = self.vertex_array {
+                gl.delete_vertex_array(vertex_array);
+            }
+        }
+    }
+}
+
+/// The depth renderbuffer format glBlitFramebuffer needs to copy from the bound framebuffer. The default framebuffer's
+/// depends on BevyGlContextConfig, framebuffer objects from Framebuffer::new() use DEPTH_COMPONENT16.
+pub(crate) fn bound_depth_format(ctx: &BevyGlContext, framebuffer_object: bool) -> u32 {
+    if framebuffer_object {
+        return glow::DEPTH_COMPONENT16;
+    }
+    let (depth_bits, stencil_bits) = unsafe {
+        (
+            ctx.gl.get_framebuffer_attachment_parameter_i32(
+                glow::FRAMEBUFFER,
+                glow::DEPTH,
+                glow::FRAMEBUFFER_ATTACHMENT_DEPTH_SIZE,
+            ),
+            ctx.gl.get_framebuffer_attachment_parameter_i32(
+                glow::FRAMEBUFFER,
+                glow::STENCIL,
+                glow::FRAMEBUFFER_ATTACHMENT_STENCIL_SIZE,
+            ),
+        )
+    };
+    match (depth_bits, stencil_bits > 0) {
+        (32, true) => glow::DEPTH32F_STENCIL8,
+        (32, false) => glow::DEPTH_COMPONENT32F,
+        (16, false) => glow::DEPTH_COMPONENT16,
+        (_, true) => glow::DEPTH24_STENCIL8,
+        (_, false) => glow::DEPTH_COMPONENT24,
+    }
+}
@@ -4,7 +4,10 @@ use uniform_set_derive::UniformSet;
 
 use crate::{
     BevyGlContext,
-    command_encoder::CommandEncoder,
+    command_encoder::{
+        CommandEncoder, remove_render_resource_on_restore, remove_resource_on_restore,
+    },
+    framebuffer::Framebuffer,
     prepare_image::{GpuImages, TextureRef},
     render::RenderSet,
 };
@@ -13,6 +16,14 @@ pub struct PlaneReflectPlugin;
 
 impl Plugin for PlaneReflectPlugin {
     fn build(&self, app: &mut App) {
+        app.add_systems(
+            PostUpdate,
+            (
+                remove_resource_on_restore::<PlaneReflectionTexture>,
+                remove_render_resource_on_restore::<ReflectionFramebuffer>,
+            )
+                .in_set(RenderSet::Acquire),
+        );
         app.add_systems(PostUpdate, update_reflect_tex.in_set(RenderSet::Prepare));
     }
 }
@@ -24,6 +35,14 @@ pub struct ReflectionUniforms {
     reflect_texture: TextureRef,
 }
 
+impl ReflectionUniforms {
+    /// The reflection plane as (normal, -normal · position). Positive on the side that is reflected.
+    pub fn plane(&self) -> Vec4 {
+        let n = self.reflection_plane_normal.normalize_or_zero();
+        n.extend(-n.dot(self.reflection_plane_position))
+    }
+}
+
 fn update_reflect_tex(
     mut commands: Commands,
     bevy_window: Single<&Window>,
@@ -49,6 +68,12 @@ fn update_reflect_tex(
 
     if let Some(shadow_tex) = plane_tex {
         if plane_reflection.is_some() {
+            // Keep the plane up to date for clipping even if the texture doesn't change.
+            commands.insert_resource(ReflectionUniforms {
+                reflection_plane_position: translation,
+                reflection_plane_normal: normal,
+                reflect_texture: shadow_tex.texture.clone(),
+            });
             if shadow_tex.width != width || shadow_tex.height != height {
                 let texture_ref = shadow_tex.texture.clone();
                 commands.insert_resource(PlaneReflectionTexture {
@@ -56,11 +81,6 @@ fn update_reflect_tex(
                     width,
                     height,
                 });
-                commands.insert_resource(ReflectionUniforms {
-                    reflection_plane_position: translation,
-                    reflection_plane_normal: normal,
-                    reflect_texture: texture_ref.clone(),
-                });
                 enc.record(move |ctx, world| {
                     unsafe {
                         if let Some((tex, _target)) = world
@@ -69,18 +89,13 @@ fn update_reflect_tex(
                         {
                             ctx.gl.delete_texture(tex);
                         }
-                        PlaneReflectionTexture::init(
-                            ctx,
-                            &mut world.resource_mut::<GpuImages>(),
-                            &texture_ref,
-                            width,
-                            height,
-                        );
+                        PlaneReflectionTexture::init(ctx, world, &texture_ref, width, height);
                     };
                 });
             }
         } else {
             enc.delete_texture_ref(shadow_tex.texture.clone());
+            enc.record(|ctx, world| set_reflection_framebuffer(ctx, world, None));
             commands.remove_resource::<PlaneReflectionTexture>();
             commands.remove_resource::<ReflectionUniforms>();
         }
@@ -98,13 +113,7 @@ fn update_reflect_tex(
                 height,
             });
             enc.record(move |ctx, world| {
-                PlaneReflectionTexture::init(
-                    ctx,
-                    &mut world.resource_mut::<GpuImages>(),
-                    &texture_ref,
-                    width,
-                    height,
-                );
+                PlaneReflectionTexture::init(ctx, world, &texture_ref, width, height);
             });
         } else {
             return;
@@ -123,18 +132,40 @@ pub struct PlaneReflectionTexture {
     pub height: u32,
 }
 
+/// Render world resource. Only exists if the reflection texture can be rendered to directly.
+#[derive(Resource)]
+struct ReflectionFramebuffer(Framebuffer);
+
+fn set_reflection_framebuffer(
+    ctx: &BevyGlContext,
+    world: &mut World,
+    framebuffer: Option<Framebuffer>,
+) {
+    if let Some(old) = world.remove_resource::<ReflectionFramebuffer>() {
+        old.0.delete(&ctx.gl);
+    }
+    if let Some(framebuffer) = framebuffer {
+        world.insert_resource(ReflectionFramebuffer(framebuffer));
+    }
+}
+
 impl PlaneReflectionTexture {
     fn init(
         ctx: &mut BevyGlContext,
-        images: &mut GpuImages,
+        world: &mut World,
         texture_ref: &TextureRef,
         width: u32,
         height: u32,
     ) {
         unsafe {
             let texture = ctx.gl.create_texture().unwrap();
-            images.add_texture_set_ref(texture, glow::TEXTURE_2D, &texture_ref);
+            world.resource_mut::<GpuImages>().add_texture_set_ref(
+                texture,
+                glow::TEXTURE_2D,
+                &texture_ref,
+            );
             ctx.gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+            ctx.label_texture(texture, "plane reflection");
             ctx.gl.tex_parameter_i32(
                 glow::TEXTURE_2D,
                 glow::TEXTURE_MIN_FILTER,
@@ -166,6 +197,8 @@ impl PlaneReflectionTexture {
                 glow::UNSIGNED_BYTE,
                 PixelUnpackData::Slice(None),
             );
+            let framebuffer = Framebuffer::new(ctx, texture, true, width, height);
+            set_reflection_framebuffer(ctx, world, framebuffer);
         }
     }
 }
@@ -183,6 +216,39 @@ pub fn reflection_plane_matrix(p0: Vec3, normal: Vec3) -> Mat4 {
     )
 }
 
+/// Lengyel's oblique near plane clipping, adapted for bevy's reverse z projections in GL clip space: the near plane
+/// (z = w) is replaced with view_plane so everything on its negative side is clipped. The far plane (z = -w) is tilted
+/// to pass through the frustum corner furthest in front of view_plane.
+/// https://terathon.com/lengyel/Lengyel-Oblique.pdf
+pub fn oblique_near_plane(clip_from_view: Mat4, view_plane: Vec4) -> Mat4 {
+    // Reverse z, ndc z = 0 is the far plane.
+    let far_corner =
+        clip_from_view.inverse() * vec4(view_plane.x.signum(), view_plane.y.signum(), 0.0, 1.0);
+    let plane_dot_corner = view_plane.dot(far_corner);
+    if plane_dot_corner <= 0.0 {
+        // The whole frustum is behind the plane
+        return clip_from_view;
+    }
+    let w_row = clip_from_view.row(3);
+    let scale = 2.0 * w_row.dot(far_corner) / plane_dot_corner;
+    let mut rows = clip_from_view.transpose();
+    rows.z_axis = w_row - view_plane * scale;
+    rows.transpose()
+}
+
+/// Called before the reflection phases. If the reflection texture has a framebuffer, the reflection is rendered
+/// directly into it.
+pub fn bind_reflection_framebuffer(world: &mut World) {
+    if !world.contains_resource::<PlaneReflectionTexture>() {
+        return;
+    }
+    world.resource_mut::<CommandEncoder>().record(|ctx, world| {
+        if let Some(framebuffer) = world.get_resource::<ReflectionFramebuffer>() {
+            framebuffer.0.bind(ctx);
+        }
+    });
+}
+
 // Currently called in opaque phase
 pub fn copy_reflection_texture(world: &mut World) {
     let Some(plane_reflection_texture) = world.get_resource::<PlaneReflectionTexture>().cloned()
@@ -192,6 +258,11 @@ pub fn copy_reflection_texture(world: &mut World) {
     world
         .resource_mut::<CommandEncoder>()
         .record(move |ctx, world| {
+            if world.contains_resource::<ReflectionFramebuffer>() {
+                Framebuffer::unbind(ctx);
+                return;
+            }
+            // No framebuffer object, copy from the window instead.
             unsafe {
                 if let Some((tex, _target)) = &mut world
                     .resource_mut::<GpuImages>()
@@ -0,0 +1,618 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use bevy::{core_pipeline::tonemapping::Tonemapping, prelude::*};
+use bytemuck::cast_slice;
+use glow::HasContext;
+
+use crate::{
+    AttribType, BevyGlContext, RenderState, ShaderIndex,
+    command_encoder::{CommandEncoder, remove_render_resource_on_restore},
+    framebuffer::{ColorTargetFormat, Framebuffer},
+    phase_opaque::render_opaque,
+    phase_transparent::bound_depth_format,
+    plane_reflect::copy_reflection_texture,
+    prepare_image::{GpuImages, TextureRef},
+    prepare_view::{ViewUniforms, tonemapping_shader_def},
+    render::RenderSet,
+    shader_cached,
+};
+
+/// Renders the cameras into a window sized target instead of the window while PostProcessStack has enabled passes or
+/// PostProcessStack::hdr is set, then runs the passes in RenderSet::PostProcess. The last pass draws to the window, so debug lines and UI are drawn
+/// over the processed image. Needs framebuffer objects, without them the passes are skipped.
+///
+/// The scene target isn't multisampled, so the window's MSAA (BevyGlContextConfig::msaa_samples) doesn't apply while
+/// the stack is active. The scene depth is copied to the window for the draws after post processing where
+/// glBlitFramebuffer is available, but not to a multisampled window, which a blit can't write to from a single sample
+/// target. Those draws aren't depth tested against the scene then.
+///
+/// Pass fragment shaders get the previous target as `uniform sampler2D source_texture`, its size as
+/// `uniform vec2 source_size` and `varying vec2 uv`. Their vertex shader can be `#include std::post_process_vertex`.
+pub struct PostProcessPlugin;
+
+impl Plugin for PostProcessPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PostProcessStack>()
+            .init_resource::<PostProcessHdr>()
+            .init_resource::<PostProcessHdrSupport>()
+            .add_systems(
+                Startup,
+                init_post_process_include.in_set(RenderSet::Pipeline),
+            )
+            .add_systems(
+                PostUpdate,
+                (
+                    remove_render_resource_on_restore::<PostProcessTargets>
+                        .in_set(RenderSet::Acquire),
+                    update_post_process_hdr.in_set(RenderSet::Prepare),
+                    begin_post_process
+                        .in_set(RenderSet::RenderOpaque)
+                        .after(copy_reflection_texture)
+                        .before(render_opaque),
+                    run_post_process.in_set(RenderSet::PostProcess),
+                ),
+            );
+    }
+}
+
+/// Fullscreen passes run in order after the transparent phase, see PostProcessPlugin.
+#[derive(Resource)]
+pub struct PostProcessStack {
+    pub passes: Vec<PostProcessPass>,
+    /// Render the scene as linear HDR and tonemap it when presenting, even when no pass has hdr_input. A tonemapping
+    /// pass is run first in that case. Unset to let the materials tonemap and skip the scene target when there are no
+    /// passes.
+    pub hdr: bool,
+    /// Window sized targets. The scene is rendered into the first, then each pass reads one and writes the other.
+    /// RGBA8, except the first is a float format from ColorTargetFormat::hdr() while PostProcessHdr is set. Unset until
+    /// the first frame with enabled passes.
+    pub targets: [TextureRef; 2],
+}
+
+impl Default for PostProcessStack {
+    fn default() -> Self {
+        Self {
+            passes: Vec::new(),
+            hdr: true,
+            targets: Default::default(),
+        }
+    }
+}
+
+impl PostProcessStack {
+    pub fn push(&mut self, pass: PostProcessPass) -> &mut Self {
+        self.passes.push(pass);
+        self
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut PostProcessPass> {
+        self.passes.iter_mut().find(|pass| pass.name == name)
+    }
+
+    fn active(&self) -> bool {
+        self.hdr || self.passes.iter().any(|pass| pass.enabled)
+    }
+
+    fn wants_hdr(&self) -> bool {
+        self.hdr
+            || self
+                .passes
+                .iter()
+                .find(|pass| pass.enabled)
+                .is_some_and(|pass| pass.hdr_input)
+    }
+
+    /// The enabled passes, after a tonemapping pass if the scene is HDR and the first doesn't take HDR input.
+    fn enabled_passes(&self, hdr: bool) -> Vec<PostProcessPass> {
+        let mut passes = self
+            .passes
+            .iter()
+            .filter(|pass| pass.enabled)
+            .cloned()
+            .collect::<Vec<_>>();
+        if hdr && !passes.first().is_some_and(|pass| pass.hdr_input) {
+            passes.insert(0, PostProcessPass::tonemap());
+        }
+        passes
+    }
+}
+
+/// Whether the cameras render linear HDR into a float target for the first PostProcessPass, which then tonemaps.
+/// Set when PostProcessStack::hdr is or that pass has hdr_input, and the render world could create a float target, see
+/// ViewUniforms::hdr_output.
+#[derive(Resource, Default, Clone, Copy, Deref)]
+pub struct PostProcessHdr(pub bool);
+
+/// Whether the render world could create a float scene target. Assumed until the first attempt.
+#[derive(Resource)]
+struct PostProcessHdrSupport(Arc<AtomicBool>);
+
+impl Default for PostProcessHdrSupport {
+    fn default() -> Self {
+        Self(Arc::new(AtomicBool::new(true)))
+    }
+}
+
+pub type PostProcessProgram = fn(&mut BevyGlContext, &mut World) -> Option<ShaderIndex>;
+pub type PostProcessPrepare =
+    Arc<dyn Fn(&mut BevyGlContext, &mut World, &PostProcessSource) + Send + Sync>;
+pub type PostProcessUniforms = Arc<dyn Fn(&mut BevyGlContext, &mut World) + Send + Sync>;
+
+#[derive(Clone)]
+pub struct PostProcessPass {
+    pub name: &'static str,
+    pub enabled: bool,
+    /// The pass reads linear HDR and tonemaps it. Only used for the first enabled pass, see PostProcessHdr.
+    pub hdr_input: bool,
+    /// Gets the pass's program on the render thread, usually with shader_cached!. A pass that fails to compile is
+    /// skipped.
+    pub program: PostProcessProgram,
+    /// Runs before the pass draws, e.g. to render intermediate textures from the source. The pass's target is bound
+    /// and the render state is set up again afterwards.
+    pub prepare: Option<PostProcessPrepare>,
+    /// Loads the pass's own uniforms, after source_texture and source_size. Texture unit 0 is source_texture.
+    pub uniforms: PostProcessUniforms,
+}
+
+/// What a pass reads, for PostProcessPass::prepare.
+pub struct PostProcessSource<'a> {
+    pub texture: glow::Texture,
+    pub size: UVec2,
+    /// The texture is the float scene target with linear HDR, see PostProcessHdr.
+    pub hdr: bool,
+    pub triangle: &'a FullscreenTriangle,
+}
+
+impl PostProcessPass {
+    pub fn new(name: &'static str, program: PostProcessProgram) -> Self {
+        Self {
+            name,
+            enabled: true,
+            hdr_input: false,
+            program,
+            prepare: None,
+            uniforms: Arc::new(|_, _| {}),
+        }
+    }
+
+    pub fn with_prepare(
+        mut self,
+        prepare: impl Fn(&mut BevyGlContext, &mut World, &PostProcessSource) + Send + Sync + 'static,
+    ) -> Self {
+        self.prepare = Some(Arc::new(prepare));
+        self
+    }
+
+    pub fn with_uniforms(
+        mut self,
+        uniforms: impl Fn(&mut BevyGlContext, &mut World) + Send + Sync + 'static,
+    ) -> Self {
+        self.uniforms = Arc::new(uniforms);
+        self
+    }
+
+    /// Tonemaps HDR input with the view's Tonemapping. Added by PostProcessStack::hdr, needs
+    /// OpenGLStandardMaterialPlugin for the std::tonemapping shader include.
+    pub fn tonemap() -> Self {
+        let mut pass = Self::new("tonemap", |ctx, world| {
+            shader_cached!(
+                ctx,
+                "shaders/post_process.vert",
+                "shaders/post_process_tonemap.frag",
+                [view_tonemapping_def(world)].iter(),
+                &[]
+            )
+        });
+        pass.hdr_input = true;
+        pass
+    }
+
+    /// Darkens the image towards the edges. strength is how dark the corners get, 0 to 1. radius is where darkening
+    /// starts, in units of the window height from the center.
+    pub fn vignette(strength: f32, radius: f32) -> Self {
+        Self::new("vignette", |ctx, _world| {
+            shader_cached!(
+                ctx,
+                "shaders/post_process.vert",
+                "shaders/vignette.frag",
+                &[],
+                &[]
+            )
+        })
+        .with_uniforms(move |ctx, _world| {
+            ctx.load("vignette_strength", strength);
+            ctx.load("vignette_radius", radius);
+        })
+    }
+}
+
+/// One triangle covering the viewport, for shaders with `attribute vec2 position` like std::post_process_vertex.
+pub struct FullscreenTriangle {
+    buffer: glow::Buffer,
+    vertex_array: Option<glow::VertexArray>,
+}
+
+impl FullscreenTriangle {
+    pub fn new(ctx: &BevyGlContext) -> Self {
+        Self {
+            buffer: ctx.gen_vbo(
+                cast_slice(&[-1.0f32, -1.0, 3.0, -1.0, -1.0, 3.0]),
+                glow::STATIC_DRAW,
+            ),
+            vertex_array: ctx
+                .has_vertex_array_object
+                .then(|| unsafe { ctx.gl.create_vertex_array().unwrap() }),
+        }
+    }
+
+    /// Draws with program, which must be in use.
+    pub fn draw(&self, ctx: &mut BevyGlContext, program: ShaderIndex) {
+        let Some(position) = ctx.cached_attrib_location(program, "position") else {
+            return;
+        };
+        unsafe {
+            if self.vertex_array.is_some() {
+                ctx.bind_vertex_array(self.vertex_array);
+            }
+            ctx.bind_vertex_attrib(position, 2, AttribType::Float, self.buffer);
+            ctx.gl.draw_arrays(glow::TRIANGLES, 0, 3);
+            ctx.gl.disable_vertex_attrib_array(position);
+        }
+    }
+
+    pub fn delete(&self, gl: &glow::Context) {
+        unsafe {
+            gl.delete_buffer(self.buffer);
+            if let Some(vertex_array) = self.vertex_array {
+                gl.delete_vertex_array(vertex_array);
+            }
+        }
+    }
+}
+
+/// Binds a TEXTURE_2D to unit for sampling, without a sampler object overriding its parameters.
+pub fn bind_post_process_texture(ctx: &BevyGlContext, unit: u32, texture: glow::Texture) {
+    ctx.unbind_sampler(unit);
+    unsafe {
+        ctx.gl.active_texture(glow::TEXTURE0 + unit);
+        ctx.gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+    }
+}
+
+/// The tonemapping def for passes that tonemap HDR input, from the render world ViewUniforms. TonyMcMapface falls back
+/// to AgX as passes don't bind its LUT.
+pub fn view_tonemapping_def(world: &World) -> (&'static str, &'static str) {
+    let tonemapping = world
+        .get_resource::<ViewUniforms>()
+        .map(|view| view.tonemapping)
+        .unwrap_or(Tonemapping::AgX);
+    tonemapping_shader_def(tonemapping, false)
+}
+
+/// Passes sample between texels when resampling, e.g. for blurs.
+pub(crate) fn set_linear_filter(ctx: &BevyGlContext, texture: glow::Texture) {
+    unsafe {
+        ctx.gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+        for param in [glow::TEXTURE_MIN_FILTER, glow::TEXTURE_MAG_FILTER] {
+            ctx.gl
+                .tex_parameter_i32(glow::TEXTURE_2D, param, glow::LINEAR as i32);
+        }
+        ctx.gl.bind_texture(glow::TEXTURE_2D, None);
+    }
+}
+
+fn init_post_process_include(mut enc: ResMut<CommandEncoder>) {
+    enc.record(|ctx, _world| {
+        ctx.add_shader_include(
+            "std::post_process_vertex",
+            include_str!("shaders/post_process.vert"),
+        );
+    });
+}
+
+/// The target index each of pass_count passes reads and the one it writes, None for the window.
+fn pass_targets(pass_count: usize) -> impl Iterator<Item = (usize, Option<usize>)> {
+    (0..pass_count).map(move |i| (i % 2, (i + 1 < pass_count).then_some((i + 1) % 2)))
+}
+
+pub(crate) fn update_post_process_hdr(
+    stack: Res<PostProcessStack>,
+    support: Res<PostProcessHdrSupport>,
+    mut hdr: ResMut<PostProcessHdr>,
+) {
+    let wants_hdr = stack.wants_hdr() && support.0.load(Ordering::Relaxed);
+    if **hdr != wants_hdr {
+        hdr.0 = wants_hdr;
+    }
+}
+
+fn begin_post_process(
+    stack: Res<PostProcessStack>,
+    hdr: Res<PostProcessHdr>,
+    support: Res<PostProcessHdrSupport>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    if !stack.active() {
+        return;
+    }
+    let texture_refs = stack.targets.clone();
+    let hdr = **hdr;
+    let support = support.0.clone();
+    enc.record(move |ctx, world| {
+        let size = ctx.window_size;
+        let current = world
+            .get_resource::<PostProcessTargets>()
+            .map(|targets| (targets.size, targets.hdr));
+        if current != Some((size, hdr)) {
+            if let Some(targets) = world.remove_resource::<PostProcessTargets>() {
+                targets.delete(&ctx.gl);
+            }
+            let format = if hdr {
+                ColorTargetFormat::hdr(ctx, false)
+            } else {
+                ColorTargetFormat::Rgba8
+            };
+            if hdr && !format.is_float() {
+                support.store(false, Ordering::Relaxed);
+            }
+            let Some(targets) = PostProcessTargets::new(ctx, size, format) else {
+                warn_once!("Couldn't create the post processing targets, passes are skipped");
+                return;
+            };
+            let mut gpu_images = world.resource_mut::<GpuImages>();
+            for (texture_ref, texture) in texture_refs.iter().zip(targets.textures) {
+                match texture_ref.get() {
+                    Some(idx) => {
+                        gpu_images.raw_textures[idx as usize] = (texture, glow::TEXTURE_2D)
+                    }
+                    None => {
+                        texture_ref.set(gpu_images.raw_textures.len() as u32);
+                        gpu_images.raw_textures.push((texture, glow::TEXTURE_2D));
+                    }
+                }
+            }
+            world.insert_resource(targets);
+        }
+        let mut targets = world.resource_mut::<PostProcessTargets>();
+        targets.previous = unsafe { ctx.gl.get_parameter_framebuffer(glow::FRAMEBUFFER_BINDING) };
+        targets.framebuffers[0].bind(ctx);
+        targets.active = true;
+    });
+}
+
+fn run_post_process(
+    stack: Res<PostProcessStack>,
+    hdr: Res<PostProcessHdr>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    if !stack.active() {
+        return;
+    }
+    let passes = stack.enabled_passes(**hdr);
+    enc.record(move |ctx, world| {
+        let Some(mut targets) = world.remove_resource::<PostProcessTargets>() else {
+            return;
+        };
+        if std::mem::take(&mut targets.active) {
+            targets.run(ctx, world, &passes);
+        }
+        world.insert_resource(targets);
+    });
+}
+
+/// Render world ping-pong targets, see PostProcessStack::targets.
+#[derive(Resource)]
+struct PostProcessTargets {
+    size: UVec2,
+    /// The first texture has a float format.
+    hdr: bool,
+    textures: [glow::Texture; 2],
+    /// Only the first has a depth buffer, in the window's format so it can be copied back for the draws after post
+    /// processing.
+    framebuffers: [Framebuffer; 2],
+    triangle: FullscreenTriangle,
+    /// The framebuffer that was bound when the scene target was, None for the window.
+    previous: Option<glow::Framebuffer>,
+    /// The scene target was bound this frame.
+    active: bool,
+}
+
+impl PostProcessTargets {
+    /// format is for the scene target, the other is RGBA8.
+    fn new(ctx: &BevyGlContext, size: UVec2, format: ColorTargetFormat) -> Option<Self> {
+        if !ctx.has_framebuffer_object || size.x == 0 || size.y == 0 {
+            return None;
+        }
+        // glBlitFramebuffer needs GL 3, before that a smaller depth buffer is more likely to be supported.
+        let depth_format = if ctx.capabilities.major_version >= 3 {
+            bound_depth_format(ctx, false)
+        } else {
+            glow::DEPTH_COMPONENT16
+        };
+        let textures = [0, 1].map(|i| {
+            let texture = if i == 0 {
+                format
+            } else {
+                ColorTargetFormat::Rgba8
+            }
+            .create_texture(ctx, size);
+            ctx.label_texture(texture, &format!("post_process_{i}"));
+            set_linear_filter(ctx, texture);
+            texture
+        });
+        let previous = unsafe { ctx.gl.get_parameter_framebuffer(glow::FRAMEBUFFER_BINDING) };
+        let scene = Framebuffer::new_with_depth_format(
+            ctx,
+            glow::TEXTURE_2D,
+            textures[0],
+            Some(depth_format),
+            size.x,
+            size.y,
+        );
+        let other = Framebuffer::new(ctx, textures[1], false, size.x, size.y);
+        unsafe { ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, previous) };
+        let (Some(scene), Some(other)) = (scene, other) else {
+            unsafe {
+                ctx.gl.delete_texture(textures[0]);
+                ctx.gl.delete_texture(textures[1]);
+            }
+            return None;
+        };
+        Some(Self {
+            size,
+            hdr: format.is_float(),
+            textures,
+            framebuffers: [scene, other],
+            triangle: FullscreenTriangle::new(ctx),
+            previous: None,
+            active: false,
+        })
+    }
+
+    fn run(&self, ctx: &mut BevyGlContext, world: &mut World, passes: &[PostProcessPass]) {
+        let mut programs = passes
+            .iter()
+            .filter_map(|pass| Some(((pass.program)(ctx, world)?, Some(pass))))
+            .collect::<Vec<_>>();
+        // The scene still has to reach the window.
+        if programs.is_empty()
+            && let Some(copy) = shader_cached!(
+                ctx,
+                "shaders/post_process.vert",
+                "shaders/post_process_copy.frag",
+                &[],
+                &[]
+            )
+        {
+            programs.push((copy, None));
+        }
+
+        let previous_state = ctx.render_state;
+        let post_process_state = RenderState {
+            cull_mode: None,
+            blend: false,
+            depth_test: false,
+            depth_write: false,
+            color_write: [true; 4],
+            polygon_mode: glow::FILL,
+            ..previous_state
+        };
+        ctx.set_stencil_state(None);
+        ctx.set_viewport(None);
+        if programs.is_empty() {
+            self.bind_previous(ctx);
+        }
+        for ((source, destination), (program, pass)) in pass_targets(programs.len()).zip(programs) {
+            if let Some(prepare) = pass.and_then(|pass| pass.prepare.as_ref()) {
+                ctx.apply_render_state(post_process_state);
+                prepare(
+                    ctx,
+                    world,
+                    &PostProcessSource {
+                        texture: self.textures[source],
+                        size: self.size,
+                        hdr: self.hdr && source == 0,
+                        triangle: &self.triangle,
+                    },
+                );
+            }
+            match destination {
+                Some(destination) => self.framebuffers[destination].bind(ctx),
+                None => self.bind_previous(ctx),
+            }
+            ctx.apply_render_state(post_process_state);
+            ctx.use_cached_program(program);
+            bind_post_process_texture(ctx, 0, self.textures[source]);
+            ctx.load("source_texture", 0i32);
+            ctx.load("source_size", self.size.as_vec2());
+            if let Some(pass) = pass {
+                (pass.uniforms)(ctx, world);
+            }
+            self.triangle.draw(ctx, program);
+        }
+        ctx.apply_render_state(previous_state);
+    }
+
+    /// Binds the framebuffer from before the scene target and copies the scene depth into it where glBlitFramebuffer
+    /// is available and it isn't multisampled, see PostProcessPlugin.
+    fn bind_previous(&self, ctx: &mut BevyGlContext) {
+        unsafe {
+            ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, self.previous);
+            // Render targets are never multisampled, only the window can be.
+            if self.previous.is_none() && ctx.capabilities.window_samples > 0 {
+                warn_once!(
+                    "The window is multisampled, post processing renders the scene without MSAA and doesn't copy its depth back"
+                );
+            } else if ctx.capabilities.major_version >= 3 {
+                let (width, height) = (self.size.x as i32, self.size.y as i32);
+                ctx.gl
+                    .bind_framebuffer(glow::READ_FRAMEBUFFER, Some(self.framebuffers[0].fbo));
+                ctx.gl.blit_framebuffer(
+                    0,
+                    0,
+                    width,
+                    height,
+                    0,
+                    0,
+                    width,
+                    height,
+                    glow::DEPTH_BUFFER_BIT,
+                    glow::NEAREST,
+                );
+                ctx.debug_check_error("post process depth copy");
+                ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, self.previous);
+            }
+        }
+        ctx.set_viewport(None);
+    }
+
+    /// The textures are deleted along with the framebuffers, their TextureRefs are pointed at the new ones.
+    fn delete(&self, gl: &glow::Context) {
+        unsafe {
+            for (framebuffer, texture) in self.framebuffers.iter().zip(self.textures) {
+                framebuffer.delete(gl);
+                gl.delete_texture(texture);
+            }
+        }
+        self.triangle.delete(gl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pass_targets() {
+        assert_eq!(pass_targets(1).collect::<Vec<_>>(), [(0, None)]);
+        assert_eq!(
+            pass_targets(3).collect::<Vec<_>>(),
+            [(0, Some(1)), (1, Some(0)), (0, None)]
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_vignette_darkens_corners() {
+        let Some((mut ctx, target)) = BevyGlContext::new_headless(64, 64) else {
+            return;
+        };
+        let mut targets =
+            PostProcessTargets::new(&ctx, uvec2(64, 64), ColorTargetFormat::Rgba8).unwrap();
+        targets.framebuffers[0].bind(&ctx);
+        ctx.clear_color(Some(Vec4::ONE));
+        targets.previous = Some(target.framebuffer.fbo);
+        targets.run(
+            &mut ctx,
+            &mut World::new(),
+            &[PostProcessPass::vignette(1.0, 0.1)],
+        );
+        let pixels = target.read_pixels(&ctx);
+        let red = |x: usize, y: usize| pixels[(y * 64 + x) * 4];
+        assert!(red(32, 32) > 200);
+        assert!(red(0, 0) < red(32, 32) / 2);
+    }
+}
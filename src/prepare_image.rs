@@ -1,4 +1,5 @@
 use std::{
+    borrow::Cow,
     sync::{
         Arc,
         atomic::{AtomicU32, Ordering},
@@ -7,17 +8,20 @@ use std::{
 };
 
 use bevy::{
+    asset::RenderAssetUsages,
     image::{ImageAddressMode, ImageFilterMode, ImageSampler, ImageSamplerDescriptor},
     platform::collections::{HashMap, HashSet},
     prelude::*,
-    render::render_resource::TextureFormat,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
 };
 
 use glow::{HasContext, PixelUnpackData};
 use shared_exponent_formats::rgb9e5::rgb9e5_to_vec3;
-use wgpu_types::TextureViewDimension;
+use wgpu_types::{TextureDataOrder, TextureViewDimension};
 
-use crate::{BevyGlContext, command_encoder::CommandEncoder, render::RenderSet};
+use crate::{
+    BevyGlContext, Tex, asset_debug_label, command_encoder::CommandEncoder, render::RenderSet,
+};
 
 /// Handles uploading bevy Image assets to the GPU
 pub struct PrepareImagePlugin;
@@ -25,6 +29,26 @@ pub struct PrepareImagePlugin;
 #[derive(Resource, Deref)]
 pub struct DefaultSampler(ImageSamplerDescriptor);
 
+/// Texture upload quality settings. Can be inserted before PrepareImagePlugin is added. Changes only apply to
+/// textures uploaded after the change, already uploaded textures are not updated.
+#[derive(Resource, Clone, Copy)]
+pub struct TextureQualitySettings {
+    /// Clamped to the driver's MAX_TEXTURE_MAX_ANISOTROPY_EXT. Set to 1 to disable anisotropic filtering.
+    pub max_anisotropy: u32,
+    /// Added to the mip level of mipmapped textures. Negative values sharpen distant textures at the cost of more
+    /// shimmer, positive values soften them. Desktop GL only, GL ES and WebGL have no TEXTURE_LOD_BIAS.
+    pub lod_bias: f32,
+}
+
+impl Default for TextureQualitySettings {
+    fn default() -> Self {
+        Self {
+            max_anisotropy: 16,
+            lod_bias: 0.0,
+        }
+    }
+}
+
 impl Plugin for PrepareImagePlugin {
     fn build(&self, app: &mut App) {
         // TODO figure out when best to delete GL textures on render thread on app quit.
@@ -35,6 +59,8 @@ impl Plugin for PrepareImagePlugin {
             warn!("No ImagePlugin found. Try adding PrepareImagePlugin after DefaultPlugins");
         }
 
+        app.init_resource::<TextureQualitySettings>();
+
         app.world_mut()
             .resource_mut::<CommandEncoder>()
             .record(|_ctx, world| {
@@ -49,9 +75,120 @@ impl Plugin for PrepareImagePlugin {
 pub struct GpuImages {
     // u32 is target glow::TEXTURE_2D or glow::TEXTURE_CUBE_MAP
     pub bevy_textures: HashMap<AssetId<Image>, (glow::Texture, u32)>,
+    /// White 1x1 TEXTURE_2D, bound in place of textures that aren't available (yet).
     pub placeholder: Option<glow::Texture>,
+    /// White 1x1 TEXTURE_CUBE_MAP, the placeholder for samplerCube uniforms.
+    pub placeholder_cube: Option<glow::Texture>,
     /// Textures without a corresponding AssetId<Image>. u32 is target
     pub raw_textures: Vec<(glow::Texture, u32)>,
+    /// A sampler object for each SamplerOverride, see SamplerOverride::index(). Empty without
+    /// BevyGlContext::has_sampler_objects.
+    pub samplers: Vec<glow::Sampler>,
+}
+
+/// Filtering for SamplerOverride. Mipmapped textures are also filtered between mips, Nearest picks the nearest mip.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SamplerFilter {
+    Nearest,
+    #[default]
+    Linear,
+}
+
+/// Sampling to use for a SampledImage instead of the sampler the image was uploaded with, so the same texture can be
+/// sampled differently by different materials, e.g. a sprite sheet drawn pixelated by one and smooth by another.
+///
+/// With BevyGlContext::has_sampler_objects (GL 3.3, GLES 3.0, WebGL 2) a shared sampler object is bound to the
+/// texture unit, and the texture itself is unchanged. These don't use anisotropic filtering. Otherwise the mag filter
+/// and address mode are set on the texture when it's bound, so they also apply to later uses of that texture without
+/// an override. The min filter is kept there since textures without mips would be incomplete with a mipmapped one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct SamplerOverride {
+    pub filter: SamplerFilter,
+    pub address_mode: SamplerAddressMode,
+}
+
+/// Address mode for SamplerOverride, applied to all axes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SamplerAddressMode {
+    #[default]
+    ClampToEdge,
+    Repeat,
+    MirrorRepeat,
+}
+
+impl SamplerOverride {
+    pub const NEAREST: Self = Self {
+        filter: SamplerFilter::Nearest,
+        address_mode: SamplerAddressMode::ClampToEdge,
+    };
+    pub const LINEAR: Self = Self {
+        filter: SamplerFilter::Linear,
+        address_mode: SamplerAddressMode::ClampToEdge,
+    };
+    const COUNT: usize = 6;
+
+    fn index(&self) -> usize {
+        self.filter as usize * 3 + self.address_mode as usize
+    }
+
+    fn from_index(index: usize) -> Self {
+        Self {
+            filter: [SamplerFilter::Nearest, SamplerFilter::Linear][index / 3],
+            address_mode: [
+                SamplerAddressMode::ClampToEdge,
+                SamplerAddressMode::Repeat,
+                SamplerAddressMode::MirrorRepeat,
+            ][index % 3],
+        }
+    }
+
+    fn gl_filters(&self) -> (u32, u32) {
+        match self.filter {
+            SamplerFilter::Nearest => (glow::NEAREST_MIPMAP_NEAREST, glow::NEAREST),
+            SamplerFilter::Linear => (glow::LINEAR_MIPMAP_LINEAR, glow::LINEAR),
+        }
+    }
+
+    fn gl_address_mode(&self) -> u32 {
+        match self.address_mode {
+            SamplerAddressMode::ClampToEdge => glow::CLAMP_TO_EDGE,
+            SamplerAddressMode::Repeat => glow::REPEAT,
+            SamplerAddressMode::MirrorRepeat => glow::MIRRORED_REPEAT,
+        }
+    }
+
+    /// Fallback without sampler objects, the texture must be bound to target.
+    pub(crate) fn apply_to_texture(&self, gl: &glow::Context, target: u32) {
+        let mode = self.gl_address_mode() as i32;
+        unsafe {
+            gl.tex_parameter_i32(target, glow::TEXTURE_MAG_FILTER, self.gl_filters().1 as i32);
+            gl.tex_parameter_i32(target, glow::TEXTURE_WRAP_S, mode);
+            gl.tex_parameter_i32(target, glow::TEXTURE_WRAP_T, mode);
+            // Without sampler objects this is GL 2.1 or WebGL 1, which has no WRAP_R.
+            if cfg!(not(target_arch = "wasm32"))
+                && matches!(target, glow::TEXTURE_3D | glow::TEXTURE_CUBE_MAP)
+            {
+                gl.tex_parameter_i32(target, glow::TEXTURE_WRAP_R, mode);
+            }
+        }
+    }
+}
+
+/// A texture field for UniformSet that can be sampled with a SamplerOverride. Without an override it's sampled like an
+/// `Option<Handle<Image>>` field.
+#[derive(Clone, Default, Debug)]
+pub struct SampledImage {
+    pub image: Option<Handle<Image>>,
+    pub sampler: Option<SamplerOverride>,
+}
+
+impl SampledImage {
+    pub fn new(image: Handle<Image>, sampler: SamplerOverride) -> Self {
+        Self {
+            image: Some(image),
+            sampler: Some(sampler),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -82,9 +219,11 @@ impl GpuImages {
         &mut self,
         ctx: &BevyGlContext,
         default_sampler: Option<ImageSamplerDescriptor>,
+        settings: &TextureQualitySettings,
         bevy_image: &Image,
     ) -> Option<u32> {
-        let Some((texture, target)) = bevy_image_to_gl_texture(ctx, default_sampler, bevy_image)
+        let Some((texture, target)) =
+            bevy_image_to_gl_texture(ctx, default_sampler, settings, bevy_image)
         else {
             return None;
         };
@@ -96,16 +235,33 @@ impl GpuImages {
         &mut self,
         ctx: &BevyGlContext,
         default_sampler: Option<ImageSamplerDescriptor>,
+        settings: &TextureQualitySettings,
         bevy_image: &Image,
         texture_ref: &TextureRef,
     ) -> Option<u32> {
-        let Some(idx) = self.add_bevy_image(ctx, default_sampler, bevy_image) else {
+        let Some(idx) = self.add_bevy_image(ctx, default_sampler, settings, bevy_image) else {
             return None;
         };
         texture_ref.set(idx);
         Some(idx)
     }
 
+    /// Creates a 2D texture from tightly packed rows of pixels, top row first, without mips or wrapping. format must
+    /// be R8Unorm, Rg8Unorm, Rgba8Unorm or Rgba8UnormSrgb. Returns index into raw_textures, or None if the data doesn't
+    /// match the size and format.
+    pub fn add_raw_texture(
+        &mut self,
+        ctx: &BevyGlContext,
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+        data: Vec<u8>,
+        filter: ImageFilterMode,
+    ) -> Option<u32> {
+        let (texture, target) = create_raw_texture(ctx, width, height, format, data, filter)?;
+        Some(self.add_texture(texture, target))
+    }
+
     /// returns index into raw_textures
     pub fn add_texture(&mut self, texture: glow::Texture, target: u32) -> u32 {
         let idx = self.raw_textures.len() as u32;
@@ -131,32 +287,119 @@ impl GpuImages {
         };
         Some(self.raw_textures[idx as usize])
     }
+
+    /// Whether samplers were created, see BevyGlContext::has_sampler_objects.
+    pub fn has_samplers(&self) -> bool {
+        !self.samplers.is_empty()
+    }
+
+    /// The shared sampler object for sampler_override, None without sampler objects.
+    pub fn sampler(&self, sampler_override: SamplerOverride) -> Option<glow::Sampler> {
+        self.samplers.get(sampler_override.index()).copied()
+    }
+
+    /// Forgets the bevy image textures, placeholders and samplers after their GL objects were lost with a WebGL
+    /// context, see GlContextRestored. raw_textures are kept so existing TextureRefs stay in bounds, but no longer
+    /// refer to valid textures.
+    pub fn forget_gl_objects(&mut self) {
+        self.bevy_textures.clear();
+        self.placeholder = None;
+        self.placeholder_cube = None;
+        self.samplers.clear();
+    }
+
+    /// The placeholder that can be bound to target. Only cube maps have their own, anything else gets the TEXTURE_2D
+    /// placeholder.
+    pub fn placeholder_for(&self, target: u32) -> glow::Texture {
+        match self.placeholder_cube {
+            Some(texture) if target == glow::TEXTURE_CUBE_MAP => texture,
+            _ => self.placeholder.unwrap(),
+        }
+    }
+
+    /// The texture and the target it was created with. target is used for Tex::Gl, which has no target info, and
+    /// to pick the placeholder when the texture isn't available.
+    pub fn resolve(&self, tex: &Tex, target: u32) -> (glow::Texture, u32) {
+        let found = match tex {
+            Tex::Bevy(image_h) => image_h
+                .as_ref()
+                .and_then(|image_h| self.bevy_textures.get(&image_h.id()).copied()),
+            Tex::Gl(texture) => Some((*texture, target)),
+            Tex::Ref(texture_ref) => texture_ref.get().map(|idx| self.raw_textures[idx as usize]),
+        };
+        found.unwrap_or_else(|| (self.placeholder_for(target), target))
+    }
+}
+
+/// Upload targets of each cube map face, in layer order.
+const CUBE_MAP_FACES: [u32; 6] = [
+    glow::TEXTURE_CUBE_MAP_POSITIVE_X,
+    glow::TEXTURE_CUBE_MAP_NEGATIVE_X,
+    glow::TEXTURE_CUBE_MAP_POSITIVE_Y,
+    glow::TEXTURE_CUBE_MAP_NEGATIVE_Y,
+    glow::TEXTURE_CUBE_MAP_POSITIVE_Z,
+    glow::TEXTURE_CUBE_MAP_NEGATIVE_Z,
+];
+
+/// White 1x1 texture with each of faces filled.
+fn create_placeholder(ctx: &BevyGlContext, target: u32, faces: &[u32]) -> glow::Texture {
+    unsafe {
+        let texture = ctx.gl.create_texture().unwrap();
+        ctx.gl.bind_texture(target, Some(texture));
+        // Without mips the default NEAREST_MIPMAP_LINEAR would leave the texture incomplete.
+        ctx.gl
+            .tex_parameter_i32(target, glow::TEXTURE_MIN_FILTER, glow::NEAREST as i32);
+        for face in faces {
+            ctx.gl.tex_image_2d(
+                *face,
+                0,
+                glow::RGBA as i32,
+                1,
+                1,
+                0,
+                glow::RGBA,
+                glow::UNSIGNED_BYTE,
+                PixelUnpackData::Slice(Some(&[255, 255, 255, 255])),
+            );
+        }
+        ctx.gl.bind_texture(target, None);
+        texture
+    }
 }
 
 pub fn send_images_to_gpu(
     images: Res<Assets<Image>>,
     mut image_events: MessageReader<AssetEvent<Image>>,
     default_sampler: Res<DefaultSampler>,
+    settings: Res<TextureQualitySettings>,
+    asset_server: Option<Res<AssetServer>>,
     mut enc: ResMut<CommandEncoder>,
 ) {
+    if settings.is_changed() {
+        // Also make the settings available on the render thread for images uploaded with CommandEncoder::bevy_image()
+        let settings = *settings;
+        enc.record(move |_ctx, world| {
+            world.insert_resource(settings);
+        });
+    }
+
     enc.record(|ctx, world| {
         let mut image = world.resource_mut::<GpuImages>();
         if image.placeholder.is_none() {
-            unsafe {
-                let texture = ctx.gl.create_texture().unwrap();
-                ctx.gl.bind_texture(glow::TEXTURE_2D, Some(texture));
-                ctx.gl.tex_image_2d(
-                    glow::TEXTURE_2D,
-                    0,
-                    glow::RGBA as i32,
-                    1,
-                    1,
-                    0,
-                    glow::RGBA,
-                    glow::UNSIGNED_BYTE,
-                    PixelUnpackData::Slice(Some(&[255, 255, 255, 255])),
-                );
-                image.placeholder = Some(texture);
+            image.placeholder = Some(create_placeholder(
+                ctx,
+                glow::TEXTURE_2D,
+                &[glow::TEXTURE_2D],
+            ));
+            image.placeholder_cube = Some(create_placeholder(
+                ctx,
+                glow::TEXTURE_CUBE_MAP,
+                &CUBE_MAP_FACES,
+            ));
+            if ctx.has_sampler_objects {
+                image.samplers = (0..SamplerOverride::COUNT)
+                    .map(|i| create_sampler(ctx, SamplerOverride::from_index(i)))
+                    .collect();
             }
         }
     });
@@ -187,13 +430,16 @@ pub fn send_images_to_gpu(
             }
 
             let default_sampler = default_sampler.clone();
+            let settings = *settings;
+            let label = asset_debug_label(asset_server.as_deref(), handle);
             enc.record(move |ctx, world| {
                 let mut image = world.resource_mut::<GpuImages>();
                 let Some((texture, target)) =
-                    bevy_image_to_gl_texture(&ctx, Some(default_sampler), &bevy_image)
+                    bevy_image_to_gl_texture(&ctx, Some(default_sampler), &settings, &bevy_image)
                 else {
                     return;
                 };
+                ctx.label_texture(texture, &label);
 
                 if let Some(old) = image.bevy_textures.insert(handle, (texture, target)) {
                     unsafe { ctx.gl.delete_texture(old.0) };
@@ -203,15 +449,69 @@ pub fn send_images_to_gpu(
     }
 }
 
+/// Creates the texture of GpuImages::add_raw_texture() without adding it to raw_textures, for replacing the texture in
+/// an existing slot. Returns the texture and its target.
+pub fn create_raw_texture(
+    ctx: &BevyGlContext,
+    width: u32,
+    height: u32,
+    format: TextureFormat,
+    data: Vec<u8>,
+    filter: ImageFilterMode,
+) -> Option<(glow::Texture, u32)> {
+    if !matches!(
+        format,
+        TextureFormat::R8Unorm
+            | TextureFormat::Rg8Unorm
+            | TextureFormat::Rgba8Unorm
+            | TextureFormat::Rgba8UnormSrgb
+    ) {
+        warn!("Unsupported raw texture format {format:?}");
+        return None;
+    }
+    let expected_len = (width * height * format.block_copy_size(None).unwrap_or(4)) as usize;
+    if data.len() != expected_len {
+        warn!(
+            "Raw texture data is {} bytes, expected {expected_len} for {width}x{height} {format:?}",
+            data.len()
+        );
+        return None;
+    }
+    let mut image = Image::new(
+        Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        format,
+        RenderAssetUsages::default(),
+    );
+    image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        mag_filter: filter,
+        min_filter: filter,
+        ..default()
+    });
+    bevy_image_to_gl_texture(ctx, None, &TextureQualitySettings::default(), &image)
+}
+
 /// Returns texture handle and target
 pub fn bevy_image_to_gl_texture(
     ctx: &BevyGlContext,
     default_sampler: Option<ImageSamplerDescriptor>,
+    settings: &TextureQualitySettings,
     bevy_image: &Image,
 ) -> Option<(glow::Texture, u32)> {
-    let Some(target) = get_dimension_target(bevy_image) else {
+    let Some(target) = get_dimension_target(ctx, bevy_image) else {
         return None;
     };
+    let format = bevy_image.texture_descriptor.format;
+    if format.is_compressed() && compressed_gl_format(&ctx.gl, format).is_none() {
+        // GpuImages::placeholder is used instead
+        warn!("Compressed format {format:?} isn't supported by this context");
+        return None;
+    }
     unsafe {
         let texture = ctx.gl.create_texture().unwrap();
 
@@ -253,24 +553,49 @@ pub fn bevy_image_to_gl_texture(
             let c2e = glow::CLAMP_TO_EDGE as i32;
             ctx.gl.tex_parameter_i32(target, glow::TEXTURE_WRAP_S, c2e);
             ctx.gl.tex_parameter_i32(target, glow::TEXTURE_WRAP_T, c2e);
-            //ctx.gl.tex_parameter_i32(target, glow::TEXTURE_WRAP_R, c2e); // only applies to TEXTURE_3D and TEXTURE_2D_ARRAY
+            if has_wrap_r(ctx) {
+                ctx.gl.tex_parameter_i32(target, glow::TEXTURE_WRAP_R, c2e);
+            }
         } else {
+            let border_clamp = has_texture_border_clamp(&ctx.gl);
             let set_mode = |dir, address_mode| {
                 let mode = match address_mode {
                     ImageAddressMode::ClampToEdge => glow::CLAMP_TO_EDGE,
                     ImageAddressMode::Repeat => glow::REPEAT,
                     ImageAddressMode::MirrorRepeat => glow::MIRRORED_REPEAT,
-                    ImageAddressMode::ClampToBorder => glow::CLAMP_TO_EDGE, // glow::CLAMP_TO_BORDER not supported
+                    ImageAddressMode::ClampToBorder if border_clamp => glow::CLAMP_TO_BORDER,
+                    ImageAddressMode::ClampToBorder => {
+                        warn_once!(
+                            "ClampToBorder is not supported by this context, using ClampToEdge"
+                        );
+                        glow::CLAMP_TO_EDGE
+                    }
                 };
                 ctx.gl.tex_parameter_i32(target, dir, mode as i32);
             };
             set_mode(glow::TEXTURE_WRAP_S, sampler.address_mode_u);
             set_mode(glow::TEXTURE_WRAP_T, sampler.address_mode_v);
-            //set_mode(glow::TEXTURE_WRAP_R, sampler.address_mode_w); // only applies to TEXTURE_3D and TEXTURE_2D_ARRAY
+            if matches!(target, glow::TEXTURE_3D | glow::TEXTURE_CUBE_MAP) && has_wrap_r(ctx) {
+                set_mode(glow::TEXTURE_WRAP_R, sampler.address_mode_w);
+            }
+
+            #[cfg(not(target_arch = "wasm32"))]
+            if border_clamp && let Some(border_color) = sampler.border_color {
+                use bevy::image::ImageSamplerBorderColor;
+                let color = match border_color {
+                    ImageSamplerBorderColor::TransparentBlack | ImageSamplerBorderColor::Zero => {
+                        [0.0, 0.0, 0.0, 0.0]
+                    }
+                    ImageSamplerBorderColor::OpaqueBlack => [0.0, 0.0, 0.0, 1.0],
+                    ImageSamplerBorderColor::OpaqueWhite => [1.0, 1.0, 1.0, 1.0],
+                };
+                ctx.gl
+                    .tex_parameter_f32_slice(target, glow::TEXTURE_BORDER_COLOR, &color);
+            }
         }
 
-        #[cfg(not(target_arch = "wasm32"))]
-        {
+        // WebGL 1 doesn't have these, the mip chain always goes down to 1x1 there.
+        if cfg!(not(target_arch = "wasm32")) || ctx.webgl2 {
             ctx.gl
                 .tex_parameter_i32(target, glow::TEXTURE_BASE_LEVEL, 0);
             ctx.gl.tex_parameter_i32(
@@ -282,26 +607,55 @@ pub fn bevy_image_to_gl_texture(
 
         transfer_image_data(bevy_image, target, ctx);
 
-        // TODO make configurable
         if sampler.mag_filter == ImageFilterMode::Nearest || mip_level_count == 1 {
-            set_anisotropy(&ctx.gl, target, 1);
+            set_anisotropy(ctx, target, 1);
         } else {
-            set_anisotropy(&ctx.gl, target, 16);
+            set_anisotropy(ctx, target, settings.max_anisotropy);
+        }
+        if settings.lod_bias != 0.0 && mip_level_count > 1 {
+            set_lod_bias(&ctx.gl, target, settings.lod_bias);
         }
         Some((texture, target))
     }
 }
 
-fn get_dimension_target(image: &Image) -> Option<u32> {
+fn get_dimension_target(ctx: &BevyGlContext, image: &Image) -> Option<u32> {
     let view = image.texture_view_descriptor.clone().unwrap_or_default();
-    let dimension = view.dimension.unwrap_or_default();
+    let layers = image.texture_descriptor.array_layer_count();
+    // Like wgpu, the default view of a 2D image with multiple layers is an array
+    let dimension = view
+        .dimension
+        .unwrap_or(match image.texture_descriptor.dimension {
+            wgpu_types::TextureDimension::D3 => TextureViewDimension::D3,
+            wgpu_types::TextureDimension::D2 if layers > 1 => TextureViewDimension::D2Array,
+            _ => TextureViewDimension::D2,
+        });
     let target = match dimension {
         TextureViewDimension::D1 => return None,
         TextureViewDimension::D2 => glow::TEXTURE_2D,
-        TextureViewDimension::D2Array => return None,
-        TextureViewDimension::Cube => glow::TEXTURE_CUBE_MAP,
+        TextureViewDimension::D2Array => {
+            if !ctx.has_texture_array {
+                warn!("Array textures aren't supported by this context");
+                return None;
+            }
+            glow::TEXTURE_2D_ARRAY
+        }
+        TextureViewDimension::Cube => {
+            // Each face is uploaded to TEXTURE_CUBE_MAP_POSITIVE_X + layer. Cube arrays are not supported.
+            if layers != 6 {
+                warn!("Cube map images must have exactly 6 array layers, found {layers}");
+                return None;
+            }
+            glow::TEXTURE_CUBE_MAP
+        }
         TextureViewDimension::CubeArray => return None,
-        TextureViewDimension::D3 => return None,
+        TextureViewDimension::D3 => {
+            if !ctx.has_texture_3d {
+                warn!("3D textures aren't supported by this context");
+                return None;
+            }
+            glow::TEXTURE_3D
+        }
     };
     Some(target)
 }
@@ -318,29 +672,34 @@ fn transfer_image_data(image: &bevy::prelude::Image, target: u32, ctx: &BevyGlCo
     let mut block_size = format.block_copy_size(None).unwrap_or(4);
     let (block_width, block_height) = format.block_dimensions();
 
-    let mut binary_offset = 0;
-
     let size3d = (
         image.texture_descriptor.size.width,
         image.texture_descriptor.size.height,
         image.texture_descriptor.size.depth_or_array_layers,
     );
 
-    let cube_targets = [
-        glow::TEXTURE_CUBE_MAP_POSITIVE_X,
-        glow::TEXTURE_CUBE_MAP_NEGATIVE_X,
-        glow::TEXTURE_CUBE_MAP_POSITIVE_Y,
-        glow::TEXTURE_CUBE_MAP_NEGATIVE_Y,
-        glow::TEXTURE_CUBE_MAP_POSITIVE_Z,
-        glow::TEXTURE_CUBE_MAP_NEGATIVE_Z,
-    ];
-
     #[cfg(not(target_arch = "wasm32"))]
     let rgb_format = glow::RGBA8;
     #[cfg(target_arch = "wasm32")]
     let rgb_format = glow::RGBA;
 
+    let compressed_format = compressed_gl_format(&ctx.gl, format);
+
+    // R8 and RG8 are expanded to RGBA if the context doesn't have them.
+    let expand_to_rgba =
+        matches!(format, TextureFormat::R8Unorm | TextureFormat::Rg8Unorm) && !ctx.has_texture_rg;
+
     let internal_format = match image.texture_descriptor.format {
+        _ if expand_to_rgba => rgb_format,
+        format if format.is_compressed() => {
+            let Some(compressed_format) = compressed_format else {
+                warn!("unsupported compressed format {format:?}");
+                return;
+            };
+            compressed_format
+        }
+        TextureFormat::R8Unorm => glow::R8,
+        TextureFormat::Rg8Unorm => glow::RG8,
         TextureFormat::Rgba8Unorm => rgb_format,
         TextureFormat::Rgba8UnormSrgb => rgb_format,
         // rgb9e5 not supported by WebGL1 or some OpenGL2 drivers so we convert to RGBE
@@ -354,6 +713,11 @@ fn transfer_image_data(image: &bevy::prelude::Image, target: u32, ctx: &BevyGlCo
     };
 
     let pixel_format = match image.texture_descriptor.format {
+        _ if expand_to_rgba => glow::RGBA,
+        // Not used by compressed_tex_image_2d
+        format if format.is_compressed() => 0,
+        TextureFormat::R8Unorm => glow::RED,
+        TextureFormat::Rg8Unorm => glow::RG,
         TextureFormat::Rgba8Unorm => glow::RGBA,
         TextureFormat::Rgba8UnormSrgb => glow::RGBA,
         // rgb9e5 not supported by WebGL1 or some OpenGL2 drivers so we convert to RGBE
@@ -367,6 +731,8 @@ fn transfer_image_data(image: &bevy::prelude::Image, target: u32, ctx: &BevyGlCo
     };
 
     let pixel_type = match image.texture_descriptor.format {
+        format if format.is_compressed() => 0,
+        TextureFormat::R8Unorm | TextureFormat::Rg8Unorm => glow::UNSIGNED_BYTE,
         TextureFormat::Rgba8Unorm => glow::UNSIGNED_BYTE,
         TextureFormat::Rgba8UnormSrgb => glow::UNSIGNED_BYTE,
         // rgb9e5 not supported by WebGL1 or some OpenGL2 drivers so we convert to RGBE
@@ -404,64 +770,165 @@ fn transfer_image_data(image: &bevy::prelude::Image, target: u32, ctx: &BevyGlCo
         None
     };
 
+    let expanded_rgba = if expand_to_rgba {
+        let channels = block_size as usize;
+        block_size = 4;
+        Some(
+            image_data
+                .chunks_exact(channels)
+                .flat_map(|c| [c[0], if channels > 1 { c[1] } else { 0 }, 0, 255])
+                .collect::<Vec<u8>>(),
+        )
+    } else {
+        None
+    };
+
     let image_data = if let Some(converted_rgbe) = &converted_rgbe {
         bytemuck::cast_slice::<u32, u8>(converted_rgbe)
+    } else if let Some(expanded_rgba) = &expanded_rgba {
+        expanded_rgba
     } else {
         image_data
     };
 
-    // https://github.com/gfx-rs/wgpu/blob/17fcb194258b05205d21001e8473762141ebda26/wgpu/src/util/device.rs#L15
-    for mip_level in 0..mip_level_count as usize {
-        if mip_level > 0 {
-            #[cfg(target_arch = "wasm32")]
-            unsafe {
-                // TODO wasm seems to have issues when the mips are manually set.
-                // Here we just do the first and let the driver generate the rest.
-                // This may have unexpected results if the user was putting different data in each mip.
-                ctx.gl.generate_mipmap(target);
-                return;
-            }
-        }
-        for array_layer in 0..array_layer_count {
-            // https://github.com/bevyengine/bevy/blob/160bcc787c9b2f8dacafbf9dca7d7a6b2349386a/crates/bevy_render/src/texture/dds.rs#L318
-            let mip_size = mip_level_size(size3d, mip_level, dim);
-            // When uploading mips of compressed textures and the mip is supposed to be
-            // a size that isn't a multiple of the block size, the mip needs to be uploaded
-            // as its "physical size" which is the size rounded up to the nearest block size.
-            let mip_physical = physical_size(mip_size, format);
+    // Rows of one and two channel textures aren't always a multiple of the default 4 byte unpack alignment.
+    let unpack_alignment = if block_size < 4 { 1 } else { 4 };
+    unsafe {
+        ctx.gl
+            .pixel_store_i32(glow::UNPACK_ALIGNMENT, unpack_alignment)
+    };
+
+    // Size in bytes of one layer at mip_level. For 3D textures this includes every slice.
+    let level_size = |mip_level: usize| {
+        // https://github.com/bevyengine/bevy/blob/160bcc787c9b2f8dacafbf9dca7d7a6b2349386a/crates/bevy_render/src/texture/dds.rs#L318
+        let mip_size = mip_level_size(size3d, mip_level, dim);
+        // When uploading mips of compressed textures and the mip is supposed to be
+        // a size that isn't a multiple of the block size, the mip needs to be uploaded
+        // as its "physical size" which is the size rounded up to the nearest block size.
+        let mip_physical = physical_size(mip_size, format);
 
-            // All these calculations are performed on the physical size as that's the
-            // data that exists in the buffer.
-            let width_blocks = mip_physical.0 / block_width;
-            let height_blocks = mip_physical.1 / block_height;
+        // All these calculations are performed on the physical size as that's the
+        // data that exists in the buffer.
+        let width_blocks = mip_physical.0 / block_width;
+        let height_blocks = mip_physical.1 / block_height;
 
-            let bytes_per_row = width_blocks * block_size;
+        let bytes_per_row = width_blocks * block_size;
 
-            // TODO: this also had `* mip_size.depth;` but this seemed incorrect with multilayer which seemed layer major
-            let data_size = bytes_per_row * height_blocks;
+        let slices = if dim == 3 { mip_physical.2 } else { 1 };
 
-            let end_offset = binary_offset + data_size as usize;
+        (bytes_per_row * height_blocks * slices) as usize
+    };
+    let layer_size = (0..mip_level_count as usize).map(level_size).sum::<usize>();
+    // Offset of mip_level within a layer
+    let mut mip_offset = 0;
 
-            // https://github.com/gfx-rs/wgpu/blob/6f16ea460ab437173e14d2f5f3584ca7e1c9841d/wgpu-hal/src/vulkan/command.rs#L24
-            let block_size = image
-                .texture_descriptor
-                .format
-                .block_copy_size(Some(bevy::render::render_resource::TextureAspect::All))
-                .unwrap();
-            let _buffer_row_length = block_width * (bytes_per_row / block_size);
+    // https://github.com/gfx-rs/wgpu/blob/17fcb194258b05205d21001e8473762141ebda26/wgpu/src/util/device.rs#L15
+    for mip_level in 0..mip_level_count as usize {
+        // Mipmaps can't be generated for compressed textures, the ones in the image are always uploaded.
+        #[cfg(target_arch = "wasm32")]
+        if mip_level > 0 && compressed_format.is_none() && !ctx.webgl2 {
+            // TODO WebGL 1 seems to have issues when the mips are manually set.
+            // Here we just do the first and let the driver generate the rest.
+            // This may have unexpected results if the user was putting different data in each mip, like prefiltered
+            // environment maps. WebGL 2 uploads every mip like desktop.
+            unsafe { ctx.gl.generate_mipmap(target) };
+            break;
+        }
+        let mip_size = mip_level_size(size3d, mip_level, dim);
+        let data_size = level_size(mip_level);
+        let mip_start = mip_offset;
+        mip_offset += data_size;
+        let layer_offset = move |array_layer: usize| match image.data_order {
+            TextureDataOrder::MipMajor => {
+                mip_start * array_layer_count as usize + array_layer * data_size
+            }
+            TextureDataOrder::LayerMajor => array_layer * layer_size + mip_start,
+        };
+
+        if target == glow::TEXTURE_2D_ARRAY || target == glow::TEXTURE_3D {
+            // All the layers or slices of a mip are uploaded at once, gather layers if they aren't contiguous. 3D
+            // textures only have one layer.
+            let depth = if target == glow::TEXTURE_3D {
+                mip_size.2
+            } else {
+                array_layer_count
+            };
+            let start = layer_offset(0);
+            let mip_data = match image.data_order {
+                TextureDataOrder::MipMajor => Cow::Borrowed(
+                    &image_data[start..start + data_size * array_layer_count as usize],
+                ),
+                TextureDataOrder::LayerMajor => Cow::Owned(
+                    (0..array_layer_count as usize)
+                        .flat_map(|layer| {
+                            &image_data[layer_offset(layer)..layer_offset(layer) + data_size]
+                        })
+                        .copied()
+                        .collect::<Vec<u8>>(),
+                ),
+            };
+            unsafe {
+                if compressed_format.is_some() {
+                    ctx.gl.compressed_tex_image_3d(
+                        target,
+                        mip_level as i32,
+                        internal_format as i32,
+                        mip_size.0 as i32,
+                        mip_size.1 as i32,
+                        depth as i32,
+                        0,
+                        mip_data.len() as i32,
+                        &mip_data,
+                    );
+                } else {
+                    ctx.gl.tex_image_3d(
+                        target,
+                        mip_level as i32,
+                        internal_format as i32,
+                        mip_size.0 as i32,
+                        mip_size.1 as i32,
+                        depth as i32,
+                        0,
+                        pixel_format,
+                        pixel_type,
+                        PixelUnpackData::Slice(Some(&mip_data)),
+                    );
+                }
+            }
+            continue;
+        }
 
+        for array_layer in 0..array_layer_count {
+            // Layers past the first are only used by cube maps, TEXTURE_2D only has one.
             if target != glow::TEXTURE_CUBE_MAP && array_layer != 0 {
-                binary_offset = end_offset;
+                break;
+            }
+            let binary_offset = layer_offset(array_layer as usize);
+            let end_offset = binary_offset + data_size;
+
+            let face_target = if target == glow::TEXTURE_CUBE_MAP {
+                CUBE_MAP_FACES[array_layer as usize]
+            } else {
+                glow::TEXTURE_2D
+            };
+            if compressed_format.is_some() {
+                unsafe {
+                    ctx.gl.compressed_tex_image_2d(
+                        face_target,
+                        mip_level as i32,
+                        internal_format as i32,
+                        mip_size.0 as i32,
+                        mip_size.1 as i32,
+                        0,
+                        data_size as i32,
+                        &image_data[binary_offset..end_offset],
+                    );
+                };
                 continue;
             }
-            // Only the first array layer is supported
             unsafe {
                 ctx.gl.tex_image_2d(
-                    if target == glow::TEXTURE_CUBE_MAP {
-                        cube_targets[array_layer as usize]
-                    } else {
-                        glow::TEXTURE_2D
-                    },
+                    face_target,
                     mip_level as i32,
                     internal_format as i32,
                     mip_size.0 as i32,
@@ -472,10 +939,93 @@ fn transfer_image_data(image: &bevy::prelude::Image, target: u32, ctx: &BevyGlCo
                     PixelUnpackData::Slice(Some(&image_data[binary_offset..end_offset])),
                 );
             };
-
-            binary_offset = end_offset;
         }
     }
+    if unpack_alignment != 4 {
+        unsafe { ctx.gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 4) };
+    }
+    ctx.debug_check_error("transfer_image_data");
+}
+
+/// TEXTURE_WRAP_R is in desktop GL and WebGL 2, but not WebGL 1.
+fn has_wrap_r(ctx: &BevyGlContext) -> bool {
+    cfg!(not(target_arch = "wasm32")) || ctx.webgl2
+}
+
+/// CLAMP_TO_BORDER is core in desktop GL and GLES 3.2, earlier GLES needs an extension and WebGL doesn't have it.
+fn has_texture_border_clamp(gl: &glow::Context) -> bool {
+    if cfg!(target_arch = "wasm32") {
+        return false;
+    }
+    let version = gl.version();
+    let extensions = gl.supported_extensions();
+    !version.is_embedded
+        || (version.major, version.minor) >= (3, 2)
+        || extensions.contains("GL_EXT_texture_border_clamp")
+        || extensions.contains("GL_OES_texture_border_clamp")
+}
+
+/// GL internal format for a block compressed format, if the context supports it. sRGB variants map to the linear
+/// formats, like Rgba8UnormSrgb they are decoded in the shader.
+pub fn compressed_gl_format(gl: &glow::Context, format: TextureFormat) -> Option<u32> {
+    let version = gl.version();
+    let extensions = gl.supported_extensions();
+    let has_any = |names: &[&str]| names.iter().any(|name| extensions.contains(*name));
+    let desktop_version = |major: u32, minor: u32| {
+        !version.is_embedded && (version.major, version.minor) >= (major, minor)
+    };
+
+    let s3tc = has_any(&[
+        "GL_EXT_texture_compression_s3tc",
+        "WEBGL_compressed_texture_s3tc",
+    ]);
+    let rgtc = desktop_version(3, 0)
+        || has_any(&[
+            "GL_ARB_texture_compression_rgtc",
+            "GL_EXT_texture_compression_rgtc",
+            "EXT_texture_compression_rgtc",
+        ]);
+    let bptc = desktop_version(4, 2)
+        || has_any(&[
+            "GL_ARB_texture_compression_bptc",
+            "GL_EXT_texture_compression_bptc",
+            "EXT_texture_compression_bptc",
+        ]);
+    // ETC2 is core in GLES 3.0 but not in WebGL 2, which reports itself as embedded 3.0.
+    let etc2 = (cfg!(not(target_arch = "wasm32")) && version.is_embedded && version.major >= 3)
+        || desktop_version(4, 3)
+        || has_any(&["GL_ARB_ES3_compatibility", "WEBGL_compressed_texture_etc"]);
+
+    let (supported, gl_format) = match format {
+        TextureFormat::Bc1RgbaUnorm | TextureFormat::Bc1RgbaUnormSrgb => {
+            (s3tc, glow::COMPRESSED_RGBA_S3TC_DXT1_EXT)
+        }
+        TextureFormat::Bc2RgbaUnorm | TextureFormat::Bc2RgbaUnormSrgb => {
+            (s3tc, glow::COMPRESSED_RGBA_S3TC_DXT3_EXT)
+        }
+        TextureFormat::Bc3RgbaUnorm | TextureFormat::Bc3RgbaUnormSrgb => {
+            (s3tc, glow::COMPRESSED_RGBA_S3TC_DXT5_EXT)
+        }
+        TextureFormat::Bc4RUnorm => (rgtc, glow::COMPRESSED_RED_RGTC1),
+        TextureFormat::Bc5RgUnorm => (rgtc, glow::COMPRESSED_RG_RGTC2),
+        TextureFormat::Bc6hRgbUfloat => (bptc, glow::COMPRESSED_RGB_BPTC_UNSIGNED_FLOAT),
+        TextureFormat::Bc7RgbaUnorm | TextureFormat::Bc7RgbaUnormSrgb => {
+            (bptc, glow::COMPRESSED_RGBA_BPTC_UNORM)
+        }
+        TextureFormat::Etc2Rgb8Unorm | TextureFormat::Etc2Rgb8UnormSrgb => {
+            (etc2, glow::COMPRESSED_RGB8_ETC2)
+        }
+        TextureFormat::Etc2Rgb8A1Unorm | TextureFormat::Etc2Rgb8A1UnormSrgb => {
+            (etc2, glow::COMPRESSED_RGB8_PUNCHTHROUGH_ALPHA1_ETC2)
+        }
+        TextureFormat::Etc2Rgba8Unorm | TextureFormat::Etc2Rgba8UnormSrgb => {
+            (etc2, glow::COMPRESSED_RGBA8_ETC2_EAC)
+        }
+        TextureFormat::EacR11Unorm => (etc2, glow::COMPRESSED_R11_EAC),
+        TextureFormat::EacRg11Unorm => (etc2, glow::COMPRESSED_RG11_EAC),
+        _ => return None,
+    };
+    supported.then_some(gl_format)
 }
 
 /// Calculates the extent at a given mip level.
@@ -517,19 +1067,47 @@ pub fn physical_size(extent: (u32, u32, u32), format: TextureFormat) -> (u32, u3
     (width, height, extent.2)
 }
 
-fn set_anisotropy(gl: &glow::Context, target: u32, requested: u32) {
-    unsafe {
-        let ext = gl.supported_extensions();
-        let supported = ext.contains("GL_EXT_texture_filter_anisotropic")
-            || ext.contains("EXT_texture_filter_anisotropic");
-        if supported {
-            let max = gl.get_parameter_f32(glow::MAX_TEXTURE_MAX_ANISOTROPY_EXT);
-            gl.tex_parameter_f32(
+fn set_anisotropy(ctx: &BevyGlContext, target: u32, requested: u32) {
+    let max = ctx.capabilities.max_anisotropy;
+    if max > 1.0 {
+        unsafe {
+            ctx.gl.tex_parameter_f32(
                 target,
                 glow::TEXTURE_MAX_ANISOTROPY_EXT,
                 (requested as f32).clamp(1.0, max),
-            );
+            )
+        };
+    }
+}
+
+fn create_sampler(ctx: &BevyGlContext, sampler_override: SamplerOverride) -> glow::Sampler {
+    let (min_filter, mag_filter) = sampler_override.gl_filters();
+    let mode = sampler_override.gl_address_mode() as i32;
+    unsafe {
+        let sampler = ctx.gl.create_sampler().unwrap();
+        ctx.gl
+            .sampler_parameter_i32(sampler, glow::TEXTURE_MIN_FILTER, min_filter as i32);
+        ctx.gl
+            .sampler_parameter_i32(sampler, glow::TEXTURE_MAG_FILTER, mag_filter as i32);
+        for wrap in [
+            glow::TEXTURE_WRAP_S,
+            glow::TEXTURE_WRAP_T,
+            glow::TEXTURE_WRAP_R,
+        ] {
+            ctx.gl.sampler_parameter_i32(sampler, wrap, mode);
         }
+        sampler
+    }
+}
+
+fn set_lod_bias(gl: &glow::Context, target: u32, bias: f32) {
+    if cfg!(target_arch = "wasm32") || gl.version().is_embedded {
+        warn_once!("TextureQualitySettings::lod_bias isn't supported on GL ES or WebGL");
+        return;
+    }
+    unsafe {
+        let max = gl.get_parameter_f32(glow::MAX_TEXTURE_LOD_BIAS);
+        gl.tex_parameter_f32(target, glow::TEXTURE_LOD_BIAS, bias.clamp(-max, max));
     }
 }
 
@@ -1,3 +1,4 @@
+use bevy::mesh::{MeshVertexAttribute, PrimitiveTopology, morph::MorphAttributes};
 use bevy::{
     platform::collections::{HashMap, HashSet},
     prelude::*,
@@ -9,15 +10,25 @@ use std::hash::Hasher;
 use wgpu_types::VertexFormat;
 
 use crate::{
-    AttribType, BevyGlContext, BufferRef, GpuMeshBufferSet, ShaderIndex,
+    AttribType, BevyGlContext, BufferRef, GpuMeshBufferSet, ShaderIndex, asset_debug_label,
     command_encoder::CommandEncoder,
-    mesh_util::{get_attribute_f32x3, get_mesh_indices_u16, get_mesh_indices_u32},
+    mesh_util::{
+        get_attribute_f32x3, get_mesh_indices_u16, get_mesh_indices_u32, split_primitive_list,
+    },
     render::RenderSet,
 };
 
 /// Handles uploading bevy mesh assets to the GPU
 pub struct PrepareMeshPlugin;
 
+/// Meshes in this set are given their own buffer set created with DYNAMIC_DRAW, which is updated in place when the
+/// mesh is modified instead of being recreated. Use for meshes that change often like procedural geometry or debug
+/// lines.
+#[derive(Resource, Default)]
+pub struct DynamicMeshes {
+    pub ids: HashSet<AssetId<Mesh>>,
+}
+
 impl Plugin for PrepareMeshPlugin {
     fn build(&self, app: &mut App) {
         // TODO figure out when best to delete GL buffers on render thread on app quit.
@@ -26,6 +37,7 @@ impl Plugin for PrepareMeshPlugin {
             .record(|_ctx, world| {
                 world.init_resource::<GpuMeshes>();
             });
+        app.init_resource::<DynamicMeshes>();
         app.add_systems(
             PostUpdate,
             (send_standard_meshes_to_gpu)
@@ -38,91 +50,496 @@ impl Plugin for PrepareMeshPlugin {
 #[derive(Default, Resource)]
 pub struct GpuMeshes {
     pub last_bind: Option<(ShaderIndex, usize)>, //shader_index, buffer_index
+    /// BevyGlContext::vertex_bindings_generation when last_bind was bound.
+    last_bind_generation: u32,
     pub buffers: Vec<Option<(GpuMeshBufferSet, HashSet<AssetId<Mesh>>)>>,
-    pub map: HashMap<AssetId<Mesh>, BufferRef>,
+    /// Meshes too large to fit in a single buffer set are split into several parts, each with its own BufferRef.
+    pub map: HashMap<AssetId<Mesh>, Vec<BufferRef>>,
+    /// Only used if BevyGlContext::has_vertex_array_object. The program is stored so the VAO can be rebuilt if the
+    /// shader at this index was hot reloaded.
+    pub vao_cache: HashMap<(ShaderIndex, usize), (glow::Program, glow::VertexArray)>, //shader_index, buffer_index
+    /// Per instance data for draw_mesh_instanced(), respecified on each call.
+    pub instance_buffer: Option<glow::Buffer>,
+}
+
+/// A per instance vertex attribute for GpuMeshes::draw_mesh_instanced(). Components are f32. Attributes with more than
+/// 4 components use consecutive locations, a mat4 has 16 and uses 4.
+#[derive(Clone, Copy, Debug)]
+pub struct InstanceAttribute {
+    pub name: &'static str,
+    pub components: u32,
+}
+
+impl InstanceAttribute {
+    pub const fn new(name: &'static str, components: u32) -> Self {
+        Self { name, components }
+    }
 }
 
 impl GpuMeshes {
-    /// Call before using bind() or draw_mesh()
+    /// Forgets the last bound buffer set. Only needed after changing vertex bindings with raw gl calls without
+    /// BevyGlContext::invalidate_vertex_bindings(), the cache is otherwise kept across render systems within a phase.
     pub fn reset_mesh_bind_cache(&mut self) {
         self.last_bind = None;
     }
 
-    /// Make sure to call reset_mesh_bind_cache() before the first iteration of bind(). It doesn't know about whatever random
-    /// opengl state came before.
-    pub fn bind_mesh(
+    /// Whether the buffer set from the last bind is still bound, so drawing it again with the same shader doesn't
+    /// rebind anything.
+    pub fn bind_cache_valid(&self, ctx: &BevyGlContext) -> bool {
+        self.last_bind.is_some()
+            && self.last_bind_generation == ctx.vertex_bindings_generation.get()
+    }
+
+    /// Deletes the buffer set at buffer_index along with any VAOs that reference it.
+    pub fn delete_buffer_set(&mut self, gl: &glow::Context, buffer_index: usize) {
+        if let Some((old_buffer, _)) = self.buffers[buffer_index].take() {
+            old_buffer.delete(gl);
+        }
+        self.vao_cache.retain(|(_, vao_buffer_index), (_, vao)| {
+            if *vao_buffer_index == buffer_index {
+                unsafe { gl.delete_vertex_array(*vao) };
+                false
+            } else {
+                true
+            }
+        });
+        if self.last_bind.is_some_and(|(_, i)| i == buffer_index) {
+            self.last_bind = None;
+        }
+    }
+
+    /// Maps mesh to buffer_refs. If the mesh was already mapped it is removed from its old buffer sets, and any that are
+    /// left with zero references are deleted.
+    pub fn set_mesh_buffer_refs(
+        &mut self,
+        gl: &glow::Context,
+        mesh: AssetId<Mesh>,
+        buffer_refs: Vec<BufferRef>,
+    ) {
+        self.remove_mesh(gl, mesh);
+        self.map.insert(mesh, buffer_refs);
+    }
+
+    /// Removes the mapping for mesh. Buffer sets that are left with zero references are deleted.
+    pub fn remove_mesh(&mut self, gl: &glow::Context, mesh: AssetId<Mesh>) {
+        let Some(buffer_refs) = self.map.remove(&mesh) else {
+            return;
+        };
+        for buffer_ref in buffer_refs {
+            let mut buffer_unused = false;
+            if let Some(Some((_old_buffer, set))) = self.buffers.get_mut(buffer_ref.buffer_index) {
+                set.remove(&mesh);
+                buffer_unused = set.is_empty();
+            }
+            if buffer_unused {
+                self.delete_buffer_set(gl, buffer_ref.buffer_index);
+            }
+        }
+    }
+
+    /// Updates the buffers of a dynamic mesh in place. Returns false if the mesh doesn't have a dynamic buffer set yet,
+    /// or if the new data doesn't fit in the one it has.
+    fn update_dynamic_mesh(
+        &mut self,
+        ctx: &BevyGlContext,
+        mesh_h: AssetId<Mesh>,
+        mesh: &Mesh,
+        index_count: usize,
+        index_data: &[u8],
+        scratch_floats: &mut Vec<f32>,
+    ) -> bool {
+        let Some([buffer_ref]) = self.map.get_mut(&mesh_h).map(|r| r.as_mut_slice()) else {
+            return false;
+        };
+        let Some((buffer_set, _)) = &self.buffers[buffer_ref.buffer_index] else {
+            return false;
+        };
+        let vertex_count = mesh.count_vertices();
+        let same_layout = buffer_set
+            .buffers
+            .iter()
+            .map(|(att, _)| (att.id, att.format))
+            .eq(mesh
+                .attributes()
+                .map(|(att, _)| (att.id, supported_vertex_format(att.format))));
+        if buffer_set.usage != glow::DYNAMIC_DRAW
+            || !same_layout
+            || vertex_count > buffer_set.vertex_capacity
+            || index_count > buffer_set.index_capacity
+        {
+            return false;
+        }
+        for ((mesh_attribute, data), (_, buffer)) in mesh.attributes().zip(&buffer_set.buffers) {
+            let data =
+                convert_attribute_data(mesh_attribute.format, data.get_bytes(), scratch_floats);
+            ctx.update_vbo(*buffer, data);
+        }
+        ctx.update_vbo_element(buffer_set.index, index_data);
+        buffer_ref.indices_count = index_count;
+        // The topology can change along with the data, strips included since each dynamic mesh has its own buffer set.
+        buffer_ref.mode = gl_primitive_mode(mesh.primitive_topology());
+        // update_vbo_element unbinds the current VAO.
+        self.last_bind = None;
+        true
+    }
+
+    /// Binds the buffer set of buffer_ref for shader_index unless it's still bound, see bind_cache_valid(). Returns false
+    /// if the buffer set no longer exists.
+    pub fn bind_buffer_ref(
         &mut self,
         ctx: &mut BevyGlContext,
-        mesh: &AssetId<Mesh>,
+        buffer_ref: &BufferRef,
         shader_index: u32,
-    ) -> Option<BufferRef> {
-        if let Some(buffer_ref) = self.map.get(mesh) {
-            if let Some((buffers, _)) = &self.buffers[buffer_ref.buffer_index] {
-                let this_bind_set = Some((shader_index, buffer_ref.buffer_index));
-                if this_bind_set == self.last_bind {
-                    return Some(*buffer_ref);
+    ) -> bool {
+        let Some((buffers, _)) = &self.buffers[buffer_ref.buffer_index] else {
+            return false;
+        };
+        let this_bind_set = Some((shader_index, buffer_ref.buffer_index));
+        if this_bind_set == self.last_bind && self.bind_cache_valid(ctx) {
+            return true;
+        }
+        self.last_bind = this_bind_set;
+        if ctx.has_vertex_array_object {
+            let program = ctx.shader_cache[shader_index as usize];
+            let key = (shader_index, buffer_ref.buffer_index);
+            if let Some((vao_program, vao)) = self.vao_cache.get(&key)
+                && *vao_program == program
+            {
+                unsafe { ctx.gl.bind_vertex_array(Some(*vao)) };
+                self.last_bind_generation = ctx.vertex_bindings_generation.get();
+                return true;
+            }
+            // Cache miss, record the attribute setup below into a new VAO.
+            unsafe {
+                let vao = ctx.gl.create_vertex_array().unwrap();
+                ctx.gl.bind_vertex_array(Some(vao));
+                if let Some((_, old_vao)) = self.vao_cache.insert(key, (program, vao)) {
+                    ctx.gl.delete_vertex_array(old_vao);
                 }
-                self.last_bind = this_bind_set;
+            }
+        }
+        unsafe {
+            ctx.gl
+                .bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(buffers.index));
+        };
+        for (att, buffer) in &buffers.buffers {
+            if let Some(loc) = ctx.cached_attrib_location(shader_index, att.name) {
+                let attrib_type = AttribType::from_bevy_vertex_format(att.format);
+                ctx.bind_vertex_attrib(
+                    loc,
+                    att.format.size() as u32 / attrib_type.gl_type_bytes(),
+                    attrib_type,
+                    *buffer,
+                );
+            }
+        }
+        // bind_vertex_attrib() bumps the generation, so this is the state it left.
+        self.last_bind_generation = ctx.vertex_bindings_generation.get();
+        true
+    }
+
+    /// Draws every part of mesh with the program at shader_index, which must be in use.
+    pub fn draw_mesh(&mut self, ctx: &mut BevyGlContext, mesh: AssetId<Mesh>, shader_index: u32) {
+        let part_count = self.map.get(&mesh).map_or(0, Vec::len);
+        for part in 0..part_count {
+            let buffer_ref = self.map[&mesh][part];
+            if self.bind_buffer_ref(ctx, &buffer_ref, shader_index) {
                 unsafe {
-                    ctx.gl
-                        .bind_buffer(glow::ELEMENT_ARRAY_BUFFER, Some(buffers.index));
+                    ctx.gl.draw_elements(
+                        buffer_ref.mode,
+                        buffer_ref.indices_count as i32,
+                        buffer_ref.index_element_type,
+                        buffer_ref.bytes_offset,
+                    );
                 };
-                for (att, buffer) in &buffers.buffers {
-                    // TODO use caching to avoid looking up from the name here
-                    if let Some(loc) = ctx.get_attrib_location(shader_index, att.name) {
-                        let attrib_type = AttribType::from_bevy_vertex_format(att.format);
-                        ctx.bind_vertex_attrib(
-                            loc,
-                            att.format.size() as u32 / attrib_type.gl_type_bytes(),
-                            attrib_type,
-                            *buffer,
+                ctx.debug_check_error("draw_mesh");
+            }
+        }
+    }
+
+    /// Draws mesh once for each instance in data, which holds the attributes of each instance interleaved in the order
+    /// of attributes. Uses one draw_elements_instanced per mesh part if BevyGlContext::has_instancing. Otherwise the
+    /// mesh is drawn once per instance with the attributes set as constant vertex attributes, so the same shader works
+    /// either way.
+    pub fn draw_mesh_instanced(
+        &mut self,
+        ctx: &mut BevyGlContext,
+        mesh: AssetId<Mesh>,
+        shader_index: u32,
+        attributes: &[InstanceAttribute],
+        data: &[f32],
+    ) {
+        let stride = attributes
+            .iter()
+            .map(|a| a.components as usize)
+            .sum::<usize>();
+        if stride == 0 || data.len() < stride {
+            return;
+        }
+        let instance_count = data.len() / stride;
+        let slots = instance_attribute_slots(ctx, shader_index, attributes);
+
+        let instance_buffer = if ctx.has_instancing {
+            let buffer = *self
+                .instance_buffer
+                .get_or_insert_with(|| unsafe { ctx.gl.create_buffer().unwrap() });
+            unsafe {
+                ctx.gl.bind_buffer(glow::ARRAY_BUFFER, Some(buffer));
+                ctx.gl.buffer_data_u8_slice(
+                    glow::ARRAY_BUFFER,
+                    cast_slice(&data[..instance_count * stride]),
+                    glow::STREAM_DRAW,
+                );
+            }
+            Some(buffer)
+        } else {
+            None
+        };
+
+        let part_count = self.map.get(&mesh).map_or(0, Vec::len);
+        for part in 0..part_count {
+            let buffer_ref = self.map[&mesh][part];
+            if !self.bind_buffer_ref(ctx, &buffer_ref, shader_index) {
+                continue;
+            }
+            unsafe {
+                if let Some(instance_buffer) = instance_buffer {
+                    ctx.gl
+                        .bind_buffer(glow::ARRAY_BUFFER, Some(instance_buffer));
+                    for &(location, offset, components) in &slots {
+                        ctx.gl.vertex_attrib_pointer_f32(
+                            location,
+                            components as i32,
+                            glow::FLOAT,
+                            false,
+                            (stride * 4) as i32,
+                            (offset * 4) as i32,
+                        );
+                        ctx.gl.enable_vertex_attrib_array(location);
+                        ctx.gl.vertex_attrib_divisor(location, 1);
+                    }
+                    ctx.gl.draw_elements_instanced(
+                        buffer_ref.mode,
+                        buffer_ref.indices_count as i32,
+                        buffer_ref.index_element_type,
+                        buffer_ref.bytes_offset,
+                        instance_count as i32,
+                    );
+                    // Leave the cached VAO (or the global attribute state without VAOs) as bind_buffer_ref() set it up.
+                    for &(location, _, _) in &slots {
+                        ctx.gl.vertex_attrib_divisor(location, 0);
+                        ctx.gl.disable_vertex_attrib_array(location);
+                    }
+                } else {
+                    for &(location, _, _) in &slots {
+                        ctx.gl.disable_vertex_attrib_array(location);
+                    }
+                    for instance in data.chunks_exact(stride) {
+                        for &(location, offset, components) in &slots {
+                            let mut value = [0.0, 0.0, 0.0, 1.0];
+                            value[..components]
+                                .copy_from_slice(&instance[offset..offset + components]);
+                            ctx.gl.vertex_attrib_4_f32_slice(location, &value);
+                        }
+                        ctx.gl.draw_elements(
+                            buffer_ref.mode,
+                            buffer_ref.indices_count as i32,
+                            buffer_ref.index_element_type,
+                            buffer_ref.bytes_offset,
                         );
                     }
                 }
-                return Some(*buffer_ref);
             }
+            ctx.debug_check_error("draw_mesh_instanced");
         }
-        None
     }
+}
 
-    /// Make sure to call reset_mesh_bind_cache() before the first iteration of bind(). It doesn't know about whatever random
-    /// opengl state came before.
-    pub fn draw_mesh(&mut self, ctx: &mut BevyGlContext, mesh: AssetId<Mesh>, shader_index: u32) {
-        // Extremely slow temporary workaround for initially testing macos
-        #[cfg(target_os = "macos")]
-        self.reset_mesh_bind_cache();
-        #[cfg(target_os = "macos")]
-        let vao = unsafe {
-            let vao = ctx.gl.create_vertex_array().unwrap();
-            ctx.gl.bind_vertex_array(Some(vao));
-            vao
-        };
-        if let Some(buffer_ref) = self.bind_mesh(ctx, &mesh, shader_index) {
-            unsafe {
-                ctx.gl.draw_elements(
-                    glow::TRIANGLES,
-                    buffer_ref.indices_count as i32,
-                    buffer_ref.index_element_type,
-                    buffer_ref.bytes_offset,
-                );
-            };
+/// Attribute location, offset into the instance in floats, and component count for each location used by attributes.
+/// Attributes that aren't active in the shader are skipped.
+fn instance_attribute_slots(
+    ctx: &mut BevyGlContext,
+    shader_index: u32,
+    attributes: &[InstanceAttribute],
+) -> Vec<(u32, usize, usize)> {
+    let mut slots = Vec::new();
+    let mut offset = 0;
+    for attribute in attributes {
+        let components = attribute.components as usize;
+        if let Some(location) = ctx.cached_attrib_location(shader_index, attribute.name) {
+            for (i, start) in (0..components).step_by(4).enumerate() {
+                slots.push((
+                    location + i as u32,
+                    offset + start,
+                    (components - start).min(4),
+                ));
+            }
         }
-        #[cfg(target_os = "macos")]
-        unsafe {
-            ctx.gl.bind_vertex_array(None);
-            ctx.gl.delete_vertex_array(vao);
+        offset += components;
+    }
+    slots
+}
+
+/// GL primitive mode used to draw meshes with this topology.
+pub fn gl_primitive_mode(topology: PrimitiveTopology) -> u32 {
+    match topology {
+        PrimitiveTopology::PointList => glow::POINTS,
+        PrimitiveTopology::LineList => glow::LINES,
+        PrimitiveTopology::LineStrip => glow::LINE_STRIP,
+        PrimitiveTopology::TriangleList => glow::TRIANGLES,
+        PrimitiveTopology::TriangleStrip => glow::TRIANGLE_STRIP,
+    }
+}
+
+/// The format the attribute data is uploaded as.
+fn supported_vertex_format(format: VertexFormat) -> VertexFormat {
+    match format {
+        // Vertex_JointIndex uses Uint16x4 but this type is not supported so Float32x4 is used instead
+        VertexFormat::Uint16x4 => VertexFormat::Float32x4,
+        _ => format,
+    }
+}
+
+/// Converts data to supported_vertex_format(format), using scratch_floats if a conversion is needed.
+fn convert_attribute_data<'a>(
+    format: VertexFormat,
+    data: &'a [u8],
+    scratch_floats: &'a mut Vec<f32>,
+) -> &'a [u8] {
+    match format {
+        VertexFormat::Uint16x4 => {
+            scratch_floats.clear();
+            scratch_floats.extend(cast_slice::<u8, u16>(data).iter().map(|v| *v as f32));
+            cast_slice::<f32, u8>(scratch_floats)
+        }
+        _ => data,
+    }
+}
+
+/// Creates a vertex buffer for each attribute. Formats that aren't supported are converted. Each buffer is labeled
+/// with label and the attribute name.
+fn gen_attribute_buffers<'a>(
+    ctx: &BevyGlContext,
+    attributes: impl Iterator<Item = (MeshVertexAttribute, &'a [u8])>,
+    usage: u32,
+    scratch_floats: &mut Vec<f32>,
+    label: &str,
+) -> Vec<(MeshVertexAttribute, glow::Buffer)> {
+    attributes
+        .map(|(mut mesh_attribute, data)| {
+            let converted_data =
+                convert_attribute_data(mesh_attribute.format, data, scratch_floats);
+            let buffer = ctx.gen_vbo(converted_data, usage);
+            ctx.label_buffer(buffer, &format!("{label} {}", mesh_attribute.name));
+            mesh_attribute.format = supported_vertex_format(mesh_attribute.format);
+            (mesh_attribute, buffer)
+        })
+        .collect()
+}
+
+/// Morph targets are uploaded as vertex attributes, which are limited. GL 2.1 only guarantees 16 and the standard
+/// material already uses 6. Only the position and normal deltas of the first MAX_MORPH_TARGETS targets of a mesh are
+/// used, tangents aren't morphed.
+pub const MAX_MORPH_TARGETS: usize = 4;
+
+pub const ATTRIBUTE_MORPH_POSITION: [MeshVertexAttribute; MAX_MORPH_TARGETS] = [
+    MeshVertexAttribute::new(
+        "Vertex_MorphPosition0",
+        3_872_141_200,
+        VertexFormat::Float32x3,
+    ),
+    MeshVertexAttribute::new(
+        "Vertex_MorphPosition1",
+        3_872_141_201,
+        VertexFormat::Float32x3,
+    ),
+    MeshVertexAttribute::new(
+        "Vertex_MorphPosition2",
+        3_872_141_202,
+        VertexFormat::Float32x3,
+    ),
+    MeshVertexAttribute::new(
+        "Vertex_MorphPosition3",
+        3_872_141_203,
+        VertexFormat::Float32x3,
+    ),
+];
+
+pub const ATTRIBUTE_MORPH_NORMAL: [MeshVertexAttribute; MAX_MORPH_TARGETS] = [
+    MeshVertexAttribute::new(
+        "Vertex_MorphNormal0",
+        3_872_141_210,
+        VertexFormat::Float32x3,
+    ),
+    MeshVertexAttribute::new(
+        "Vertex_MorphNormal1",
+        3_872_141_211,
+        VertexFormat::Float32x3,
+    ),
+    MeshVertexAttribute::new(
+        "Vertex_MorphNormal2",
+        3_872_141_212,
+        VertexFormat::Float32x3,
+    ),
+    MeshVertexAttribute::new(
+        "Vertex_MorphNormal3",
+        3_872_141_213,
+        VertexFormat::Float32x3,
+    ),
+];
+
+/// Copies the position and normal deltas out of the mesh's morph target image (see bevy's MorphTargetImage) into
+/// ATTRIBUTE_MORPH_POSITION and ATTRIBUTE_MORPH_NORMAL vertex attributes.
+fn insert_morph_attributes(mesh: &mut Mesh, images: &Assets<Image>) {
+    let Some(image) = mesh.morph_targets().and_then(|h| images.get(h)) else {
+        return;
+    };
+    let Some(data) = &image.data else {
+        return;
+    };
+    // The image data isn't necessarily aligned to f32.
+    let data: Vec<f32> = bytemuck::pod_collect_to_vec(data);
+    let size = image.texture_descriptor.size;
+    let layer_len = (size.width * size.height) as usize;
+    let target_count = size.depth_or_array_layers as usize;
+    if target_count > MAX_MORPH_TARGETS {
+        warn_once!(
+            "Mesh has {target_count} morph targets, only the first {MAX_MORPH_TARGETS} are used"
+        );
+    }
+    let vertex_count = mesh.count_vertices();
+    let has_normals = mesh.contains_attribute(Mesh::ATTRIBUTE_NORMAL);
+    for target in 0..target_count.min(MAX_MORPH_TARGETS) {
+        let start = target * layer_len;
+        let Some(layer) = data.get(start..start + vertex_count * MorphAttributes::COMPONENT_COUNT)
+        else {
+            return;
+        };
+        let (positions, normals): (Vec<[f32; 3]>, Vec<[f32; 3]>) = layer
+            .chunks_exact(MorphAttributes::COMPONENT_COUNT)
+            .map(|v| ([v[0], v[1], v[2]], [v[3], v[4], v[5]]))
+            .unzip();
+        mesh.insert_attribute(ATTRIBUTE_MORPH_POSITION[target], positions);
+        if has_normals {
+            mesh.insert_attribute(ATTRIBUTE_MORPH_NORMAL[target], normals);
         }
     }
 }
 
 pub fn send_standard_meshes_to_gpu(
     bevy_meshes: Res<Assets<Mesh>>,
+    images: Res<Assets<Image>>,
     mut mesh_events: MessageReader<AssetEvent<Mesh>>,
+    dynamic_meshes: Res<DynamicMeshes>,
+    asset_server: Option<Res<AssetServer>>,
     mut enc: ResMut<CommandEncoder>,
 ) {
     // key is hash of vertex attribute props
     let mut meshes_by_attr: HashMap<u64, Vec<AssetId<Mesh>>> = HashMap::new();
     let mut meshes = HashMap::new();
+    // Debug labels for the GL buffers, see BevyGlContext::debug_labels
+    let mut labels = HashMap::new();
+    let mut dynamic_mesh_handles = Vec::new();
 
     for event in mesh_events.read() {
         let mesh_h = match event {
@@ -132,25 +549,7 @@ pub fn send_standard_meshes_to_gpu(
             AssetEvent::Removed { id } => {
                 let id = *id;
                 enc.record(move |ctx, world| {
-                    let mut meshes = world.resource_mut::<GpuMeshes>();
-                    if let Some(buffer_ref) = meshes.map.remove(&id) {
-                        // after removing mapping, also remove it from the old set
-                        // If the old set now has zero references, remove the buffer.
-                        let mut buffer_unused = false;
-                        if let Some((_old_buffer, set)) =
-                            &mut meshes.buffers[buffer_ref.buffer_index]
-                        {
-                            set.remove(&id);
-                            buffer_unused = set.is_empty();
-                        }
-                        if buffer_unused {
-                            if let Some((old_buffer, _)) =
-                                meshes.buffers[buffer_ref.buffer_index].take()
-                            {
-                                old_buffer.delete(&ctx.gl);
-                            }
-                        }
-                    }
+                    world.resource_mut::<GpuMeshes>().remove_mesh(&ctx.gl, id);
                 });
                 continue;
             }
@@ -161,7 +560,18 @@ pub fn send_standard_meshes_to_gpu(
             continue;
         };
 
-        meshes.insert(*mesh_h, mesh.clone());
+        let mut mesh = mesh.clone();
+        if mesh.has_morph_targets() {
+            insert_morph_attributes(&mut mesh, &images);
+        }
+        meshes.insert(*mesh_h, mesh);
+        let mesh = &meshes[mesh_h];
+        labels.insert(*mesh_h, asset_debug_label(asset_server.as_deref(), *mesh_h));
+
+        if dynamic_meshes.ids.contains(mesh_h) {
+            dynamic_mesh_handles.push(*mesh_h);
+            continue;
+        }
 
         let mut hasher = std::hash::DefaultHasher::new();
 
@@ -171,6 +581,8 @@ pub fn send_standard_meshes_to_gpu(
             a.id.hash(&mut hasher);
             a.format.hash(&mut hasher);
         }
+        // Only meshes with the same topology can be drawn together.
+        mesh.primitive_topology().hash(&mut hasher);
         let attr_hash = hasher.finish();
 
         // See if there's other meshes that were added this frame that this one could be packed with.
@@ -187,16 +599,7 @@ pub fn send_standard_meshes_to_gpu(
         let mut index_buffer_data_u32 = Vec::new();
         let mut scratch_floats = Vec::new();
 
-        let es_or_webgl = unsafe {
-            ctx.gl
-                .get_parameter_string(glow::SHADING_LANGUAGE_VERSION)
-                .contains(" ES ")
-        };
-        let u16_indices = es_or_webgl
-            && !ctx
-                .gl
-                .supported_extensions()
-                .contains("OES_element_index_uint");
+        let u16_indices = !ctx.capabilities.uint_indices;
         let element_type = if u16_indices {
             glow::UNSIGNED_SHORT
         } else {
@@ -210,6 +613,8 @@ pub fn send_standard_meshes_to_gpu(
 
         // Groups of meshes to be combined.
         let mut mesh_groups: Vec<Vec<AssetId<Mesh>>> = Vec::new();
+        // Meshes with too many vertices to fit in a single buffer set. These are split into multiple parts.
+        let mut oversized_meshes: Vec<AssetId<Mesh>> = Vec::new();
 
         // Go though meshes_by_attr and create groups that can fit in the index space available (which might only be u16::MAX)
         for (_, mesh_handles) in meshes_by_attr.drain() {
@@ -223,20 +628,32 @@ pub fn send_standard_meshes_to_gpu(
                 let positions_count = get_attribute_f32x3(mesh, Mesh::ATTRIBUTE_POSITION)
                     .expect("Meshes vertex positions are required")
                     .len();
+                if positions_count >= max_verts_per_buffer {
+                    oversized_meshes.push(mesh_h);
+                    continue;
+                }
+                if matches!(
+                    mesh.primitive_topology(),
+                    PrimitiveTopology::LineStrip | PrimitiveTopology::TriangleStrip
+                ) {
+                    // There's no primitive restart in GL 2.1 / WebGL 1, so strips can't be concatenated.
+                    mesh_groups.push(vec![mesh_h]);
+                    continue;
+                }
+                let index_count = mesh.indices().map_or(positions_count, |ind| ind.len());
                 accum_positions += positions_count;
-                accum_indices += mesh.indices().map_or(positions_count, |ind| ind.len());
+                accum_indices += index_count;
                 // The math for accum_indices is because draw_elements offset is an i32 that uses bytes. Doesn't matter that
                 // i16 would only be 2 bytes since if this was over it would also easily already be over for u16 in general.
-                if accum_positions < max_verts_per_buffer && accum_indices * 4 < i32::MAX as usize {
-                    // If a single mesh goes over, it ends up being skipped here. TODO break into multiple meshes.
-                    mesh_group.push(mesh_h);
-                } else {
-                    accum_positions = 0;
-                    accum_indices = 0;
-                    let mut new_group = Vec::new();
-                    std::mem::swap(&mut mesh_group, &mut new_group);
-                    mesh_groups.push(new_group);
+                if accum_positions >= max_verts_per_buffer || accum_indices * 4 >= i32::MAX as usize
+                {
+                    if !mesh_group.is_empty() {
+                        mesh_groups.push(std::mem::take(&mut mesh_group));
+                    }
+                    accum_positions = positions_count;
+                    accum_indices = index_count;
                 }
+                mesh_group.push(mesh_h);
             }
             if !mesh_group.is_empty() {
                 mesh_groups.push(mesh_group);
@@ -257,6 +674,11 @@ pub fn send_standard_meshes_to_gpu(
             };
 
             let count = first_mesh.attributes().count();
+            let label = if mesh_handles.len() > 1 {
+                format!("{} +{} meshes", labels[first_mesh_h], mesh_handles.len() - 1)
+            } else {
+                labels[first_mesh_h].clone()
+            };
 
             let mut buffer_data: Vec<Vec<u8>> = vec![Vec::new(); count];
 
@@ -272,15 +694,8 @@ pub fn send_standard_meshes_to_gpu(
 
                 let vertex_count = positions.len();
 
+                // Groups are built so that the vertex count always fits in the index type.
                 let index_count = if u16_indices {
-                    if (vertex_count + vertex_offset) >= u16::MAX as usize {
-                        warn!(
-                            "Too many vertices. Base OpenGL ES 2.0 and WebGL 1.0 with OES_element_index_uint only support GL_UNSIGNED_BYTE or GL_UNSIGNED_SHORT"
-                        );
-                        // Could split up mesh data and then issue multiple calls, but if a platform doesn't have
-                        // OES_element_index_uint it might also struggle with so many tris.
-                        continue;
-                    }
                     get_mesh_indices_u16(mesh, &mut index_buffer_data_u16, vertex_offset as u16)
                 } else {
                     get_mesh_indices_u32(mesh, &mut index_buffer_data_u32, vertex_offset as u32)
@@ -295,6 +710,7 @@ pub fn send_standard_meshes_to_gpu(
 
                 let buffer_ref = BufferRef {
                     buffer_index: next_buffer_set_index,
+                    mode: gl_primitive_mode(mesh.primitive_topology()),
                     indices_start: index_offset,
                     indices_count: index_count,
                     index_element_type: element_type,
@@ -302,23 +718,7 @@ pub fn send_standard_meshes_to_gpu(
                 };
 
                 // Add mapping from mesh handle to buffer. If this handle already had a mapping, remove it from the old set.
-                // If the old set now has zero references, remove the buffer.
-                if let Some(old_buffer_ref) = gpu_meshes.map.insert(mesh_h.clone(), buffer_ref) {
-                    let mut buffer_unused = false;
-                    if let Some(b) = gpu_meshes.buffers.get_mut(old_buffer_ref.buffer_index) {
-                        if let Some((_old_buffer, set)) = b {
-                            set.remove(mesh_h);
-                            buffer_unused = set.is_empty();
-                        }
-                    }
-                    if buffer_unused {
-                        if let Some((old_buffer, _)) =
-                            gpu_meshes.buffers[old_buffer_ref.buffer_index].take()
-                        {
-                            old_buffer.delete(&ctx.gl);
-                        }
-                    }
-                }
+                gpu_meshes.set_mesh_buffer_refs(&ctx.gl, *mesh_h, vec![buffer_ref]);
 
                 index_offset += index_count;
                 vertex_offset += vertex_count;
@@ -333,40 +733,180 @@ pub fn send_standard_meshes_to_gpu(
                 },
                 glow::STATIC_DRAW,
             );
+            ctx.label_buffer(index_buffer, &format!("{label} indices"));
 
             // Create combined vertex attribute buffers
-            let buffers = first_mesh
-                .attributes()
-                .zip(buffer_data.iter_mut())
-                .map(|((mesh_attribute, _), data)| {
-                    let mut mesh_attribute = *mesh_attribute;
-                    let converted_data = match mesh_attribute.format {
-                        // Vertex_JointIndex uses Uint16x4 but this type is not supported so Float32x4 is used instead
-                        VertexFormat::Uint16x4 => {
-                            scratch_floats.clear();
-                            scratch_floats
-                                .extend(cast_slice::<u8, u16>(data).iter().map(|v| *v as f32));
-                            mesh_attribute.format = VertexFormat::Float32x4;
-                            cast_slice::<f32, u8>(&scratch_floats)
-                        }
-                        _ => data,
-                    };
-
-                    (
-                        mesh_attribute,
-                        ctx.gen_vbo(converted_data, glow::STATIC_DRAW),
-                    )
-                })
-                .collect();
+            let buffers = gen_attribute_buffers(
+                ctx,
+                first_mesh
+                    .attributes()
+                    .zip(buffer_data.iter())
+                    .map(|((mesh_attribute, _), data)| (*mesh_attribute, data.as_slice())),
+                glow::STATIC_DRAW,
+                &mut scratch_floats,
+                &label,
+            );
 
             gpu_meshes.buffers.push(Some((
                 GpuMeshBufferSet {
                     buffers,
                     index: index_buffer,
                     index_element_type: element_type,
+                    usage: glow::STATIC_DRAW,
+                    vertex_capacity: vertex_offset,
+                    index_capacity: index_offset,
                 },
                 HashSet::from_iter(mesh_handles),
             )));
         }
+
+        // Dynamic meshes each get their own buffer set that is updated in place if the new data fits.
+        for mesh_h in dynamic_mesh_handles {
+            let Some(mesh) = meshes.get(&mesh_h) else {
+                continue;
+            };
+            let vertex_count = mesh.count_vertices();
+            if vertex_count >= max_verts_per_buffer {
+                oversized_meshes.push(mesh_h);
+                continue;
+            }
+            index_buffer_data_u16.clear();
+            index_buffer_data_u32.clear();
+            let index_count = if u16_indices {
+                get_mesh_indices_u16(mesh, &mut index_buffer_data_u16, 0)
+            } else {
+                get_mesh_indices_u32(mesh, &mut index_buffer_data_u32, 0)
+            };
+            let index_data: &[u8] = if u16_indices {
+                cast_slice(&index_buffer_data_u16)
+            } else {
+                cast_slice(&index_buffer_data_u32)
+            };
+
+            if gpu_meshes.update_dynamic_mesh(
+                ctx,
+                mesh_h,
+                mesh,
+                index_count,
+                index_data,
+                &mut scratch_floats,
+            ) {
+                continue;
+            }
+
+            // First upload, or the mesh grew beyond the allocated size. Reallocate.
+            let buffers = gen_attribute_buffers(
+                ctx,
+                mesh.attributes()
+                    .map(|(mesh_attribute, data)| (*mesh_attribute, data.get_bytes())),
+                glow::DYNAMIC_DRAW,
+                &mut scratch_floats,
+                &labels[&mesh_h],
+            );
+            let index_buffer = ctx.gen_vbo_element(index_data, glow::DYNAMIC_DRAW);
+            ctx.label_buffer(index_buffer, &format!("{} indices", labels[&mesh_h]));
+            let buffer_ref = BufferRef {
+                buffer_index: gpu_meshes.buffers.len(),
+                mode: gl_primitive_mode(mesh.primitive_topology()),
+                indices_start: 0,
+                indices_count: index_count,
+                index_element_type: element_type,
+                bytes_offset: 0,
+            };
+            gpu_meshes.buffers.push(Some((
+                GpuMeshBufferSet {
+                    buffers,
+                    index: index_buffer,
+                    index_element_type: element_type,
+                    usage: glow::DYNAMIC_DRAW,
+                    vertex_capacity: vertex_count,
+                    index_capacity: index_count,
+                },
+                HashSet::from_iter([mesh_h]),
+            )));
+            gpu_meshes.set_mesh_buffer_refs(&ctx.gl, mesh_h, vec![buffer_ref]);
+        }
+
+        // Split each oversized mesh into parts that fit in the index space available. Each part gets its own buffer set.
+        for mesh_h in oversized_meshes {
+            let Some(mesh) = meshes.get(&mesh_h) else {
+                continue;
+            };
+            let primitive_size = match mesh.primitive_topology() {
+                PrimitiveTopology::PointList => 1,
+                PrimitiveTopology::LineList => 2,
+                PrimitiveTopology::TriangleList => 3,
+                PrimitiveTopology::LineStrip | PrimitiveTopology::TriangleStrip => {
+                    warn!(
+                        "Strip mesh {mesh_h:?} has too many vertices for the available index type and can't be split"
+                    );
+                    continue;
+                }
+            };
+            let mode = gl_primitive_mode(mesh.primitive_topology());
+            let mut mesh_indices = Vec::new();
+            get_mesh_indices_u32(mesh, &mut mesh_indices, 0);
+
+            let mut buffer_refs = Vec::new();
+            for (part, (vertices, part_indices)) in
+                split_primitive_list(&mesh_indices, primitive_size, max_verts_per_buffer)
+                    .into_iter()
+                    .enumerate()
+            {
+                let label = format!("{} part {part}", labels[&mesh_h]);
+                let part_data: Vec<Vec<u8>> = mesh
+                    .attributes()
+                    .map(|(mesh_attribute, data)| {
+                        let data = data.get_bytes();
+                        let size = mesh_attribute.format.size() as usize;
+                        vertices
+                            .iter()
+                            .flat_map(|v| &data[*v as usize * size..(*v as usize + 1) * size])
+                            .copied()
+                            .collect()
+                    })
+                    .collect();
+
+                let buffers = gen_attribute_buffers(
+                    ctx,
+                    mesh.attributes()
+                        .zip(part_data.iter())
+                        .map(|((mesh_attribute, _), data)| (*mesh_attribute, data.as_slice())),
+                    glow::STATIC_DRAW,
+                    &mut scratch_floats,
+                    &label,
+                );
+
+                let index_buffer = if u16_indices {
+                    index_buffer_data_u16.clear();
+                    index_buffer_data_u16.extend(part_indices.iter().map(|i| *i as u16));
+                    ctx.gen_vbo_element(cast_slice(&index_buffer_data_u16), glow::STATIC_DRAW)
+                } else {
+                    ctx.gen_vbo_element(cast_slice(&part_indices), glow::STATIC_DRAW)
+                };
+                ctx.label_buffer(index_buffer, &format!("{label} indices"));
+
+                buffer_refs.push(BufferRef {
+                    buffer_index: gpu_meshes.buffers.len(),
+                    mode,
+                    indices_start: 0,
+                    indices_count: part_indices.len(),
+                    index_element_type: element_type,
+                    bytes_offset: 0,
+                });
+                gpu_meshes.buffers.push(Some((
+                    GpuMeshBufferSet {
+                        buffers,
+                        index: index_buffer,
+                        index_element_type: element_type,
+                        usage: glow::STATIC_DRAW,
+                        vertex_capacity: vertices.len(),
+                        index_capacity: part_indices.len(),
+                    },
+                    HashSet::from_iter([mesh_h]),
+                )));
+            }
+            gpu_meshes.set_mesh_buffer_refs(&ctx.gl, mesh_h, buffer_refs);
+        }
     });
 }
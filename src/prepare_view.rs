@@ -0,0 +1,187 @@
+use bevy::{
+    camera::Exposure, core_pipeline::tonemapping::Tonemapping, diagnostic::FrameCount, prelude::*,
+};
+use uniform_set_derive::UniformSet;
+
+use crate::{
+    UniformSet, UniformValue,
+    command_encoder::CommandEncoder,
+    exposure::{AutoExposureState, ExposureSettings, update_auto_exposure},
+    phase_shadow::ShadowView,
+    plane_reflect::{ReflectionPlane, ReflectionUniforms, oblique_near_plane},
+    post_process::PostProcessHdr,
+    render::{RenderCamera, RenderPhase, RenderSet, WireframeSettings, register_prepare_system},
+    taa::TaaJitter,
+};
+
+/// Computes ViewUniforms for each RenderPhase. Custom materials that only need the view matrices can depend on this
+/// without pulling in OpenGLStandardMaterialPlugin, which also adds it.
+pub struct ViewUniformsPlugin;
+
+impl Plugin for ViewUniformsPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<AutoExposureState>()
+            .add_systems(PostUpdate, update_auto_exposure.in_set(RenderSet::Prepare));
+        register_prepare_system(app.world_mut(), prepare_view);
+    }
+}
+
+#[derive(UniformSet, Component, Resource, Clone)]
+#[uniform_set(prefix = "ub_")]
+pub struct ViewUniforms {
+    pub world_from_view: Mat4,
+    pub view_from_world: Mat4,
+    pub clip_from_world: Mat4,
+    pub view_position: Vec3,
+    pub view_resolution: Vec2,
+    pub view_exposure: f32,
+    pub frame: f32,
+    pub time: f32,
+    /// From the camera, see tonemapping_shader_def()
+    #[exclude]
+    pub tonemapping: Tonemapping,
+    /// The view renders linear HDR for post processing to tonemap, see PostProcessHdr. Only the camera phases,
+    /// reflections are still tonemapped.
+    #[exclude]
+    pub hdr_output: bool,
+}
+
+/// For use with std::tonemapping. AgX, TonyMcMapface, Reinhard, ReinhardLuminance, and None are supported, the others
+/// fall back to AgX. TonyMcMapface also falls back to AgX until its LUT is uploaded, see TonyMcMapfaceLut.
+pub fn tonemapping_shader_def(
+    tonemapping: Tonemapping,
+    tony_mc_mapface_lut: bool,
+) -> (&'static str, &'static str) {
+    match tonemapping {
+        Tonemapping::None => ("TONEMAP_NONE", ""),
+        Tonemapping::Reinhard => ("TONEMAP_REINHARD", ""),
+        Tonemapping::ReinhardLuminance => ("TONEMAP_REINHARD_LUMINANCE", ""),
+        Tonemapping::TonyMcMapface if tony_mc_mapface_lut => ("TONEMAP_TONY", ""),
+        _ => ("TONEMAP_AGX", ""),
+    }
+}
+
+/// Selects TONEMAP_HDR_OUTPUT in std::tonemapping, which outputs linear HDR without clamping.
+pub const HDR_OUTPUT_DEF: (&str, &str) = ("TONEMAP_HDR_OUTPUT", "");
+
+impl ViewUniforms {
+    /// tonemapping_shader_def() for the view, or HDR_OUTPUT_DEF if hdr_output is set.
+    pub fn tonemapping_shader_def(
+        &self,
+        tony_mc_mapface_lut: bool,
+    ) -> (&'static str, &'static str) {
+        if self.hdr_output {
+            HDR_OUTPUT_DEF
+        } else {
+            tonemapping_shader_def(self.tonemapping, tony_mc_mapface_lut)
+        }
+    }
+}
+
+/// Runs at each view transition: Before shadows, before reflections, for each camera, etc.. Inserts ViewUniforms
+/// for the RenderCamera on the camera entity, as a resource, and as a resource in the render world.
+pub fn prepare_view(
+    mut commands: Commands,
+    phase: Res<RenderPhase>,
+    render_camera: Option<Res<RenderCamera>>,
+    cameras: Query<
+        (
+            &GlobalTransform,
+            &Projection,
+            Option<&Exposure>,
+            Option<&Tonemapping>,
+        ),
+        With<Camera>,
+    >,
+    exposure_settings: Option<Res<ExposureSettings>>,
+    auto_exposure: Res<AutoExposureState>,
+    shadow_view: Option<Res<ShadowView>>,
+    reflect: Option<Single<&ReflectionPlane>>,
+    reflect_uniforms: Option<Res<ReflectionUniforms>>,
+    bevy_window: Single<&Window>,
+    mut enc: ResMut<CommandEncoder>,
+    frame: Res<FrameCount>,
+    time: Res<Time>,
+    wireframe: Res<WireframeSettings>,
+    post_process_hdr: Option<Res<PostProcessHdr>>,
+    taa_jitter: Option<Res<TaaJitter>>,
+) {
+    // Shadows are always filled so that lighting stays the same with wireframe on.
+    enc.set_polygon_mode(if wireframe.enabled && *phase != RenderPhase::Shadow {
+        glow::LINE
+    } else {
+        glow::FILL
+    });
+
+    let Some(camera_entity) = render_camera.map(|c| **c) else {
+        return;
+    };
+    let Ok((cam_global_trans, cam_proj, exposure, tonemapping)) = cameras.get(camera_entity) else {
+        return;
+    };
+    // Reflections and shadows are rendered for this frame only, the post processing only sees the camera phases.
+    let camera_phase = matches!(
+        *phase,
+        RenderPhase::DepthPrepass | RenderPhase::Opaque | RenderPhase::Transparent
+    );
+    let view_resolution = vec2(
+        bevy_window.physical_width() as f32,
+        bevy_window.physical_height() as f32,
+    );
+
+    let view_position;
+    let mut world_from_view;
+    let view_from_world;
+    let clip_from_world;
+
+    if *phase == RenderPhase::Shadow {
+        if let Some(shadow_view) = &shadow_view {
+            view_position = shadow_view.position;
+            view_from_world = shadow_view.view_from_world;
+            world_from_view = shadow_view.view_from_world.inverse();
+            clip_from_world = shadow_view.clip_from_view * shadow_view.view_from_world;
+        } else {
+            return;
+        }
+    } else {
+        view_position = cam_global_trans.translation();
+        let mut clip_from_view = cam_proj.get_clip_from_view();
+        world_from_view = cam_global_trans.to_matrix();
+        if let Some(reflect) = reflect
+            && phase.reflection()
+        {
+            world_from_view = reflect.0 * world_from_view;
+            // Clip everything behind the mirror so it doesn't show up in the reflection.
+            if let Some(reflect_uniforms) = &reflect_uniforms {
+                let view_plane = world_from_view.transpose() * reflect_uniforms.plane();
+                clip_from_view = oblique_near_plane(clip_from_view, view_plane);
+            }
+        }
+        if camera_phase && let Some(jitter) = &taa_jitter {
+            clip_from_view = Mat4::from_translation(jitter.extend(0.0)) * clip_from_view;
+        }
+        view_from_world = world_from_view.inverse();
+        clip_from_world = clip_from_view * view_from_world;
+    }
+
+    let view_uniforms = ViewUniforms {
+        world_from_view,
+        view_from_world,
+        clip_from_world,
+        view_position,
+        view_resolution,
+        view_exposure: auto_exposure
+            .exposure(exposure_settings.as_deref())
+            .or_else(|| exposure.map(|e| e.exposure()))
+            .unwrap_or_else(|| Exposure::default().exposure()),
+        frame: frame.0 as f32,
+        time: time.elapsed_secs(),
+        tonemapping: tonemapping.copied().unwrap_or(Tonemapping::AgX),
+        hdr_output: post_process_hdr.is_some_and(|hdr| **hdr) && camera_phase,
+    };
+    commands.entity(camera_entity).insert(view_uniforms.clone());
+    commands.insert_resource(view_uniforms.clone());
+    enc.record(move |_ctx, world| {
+        world.insert_resource(view_uniforms.clone());
+    });
+}
@@ -0,0 +1,195 @@
+use bevy::prelude::*;
+use glow::{HasContext, PixelUnpackData};
+
+use crate::{
+    BevyGlContext,
+    command_encoder::{CommandEncoder, remove_render_resource_on_restore},
+    framebuffer::Framebuffer,
+    prepare_image::{GpuImages, TextureRef},
+    render::RenderSet,
+};
+
+/// Renders the depth prepass of cameras with DepthPrepass into a window sized depth texture instead of the scene
+/// target, so it can be sampled, see PrepassTextures. The opaque phase then writes the scene depth itself for those
+/// cameras instead of only testing against the prepass. Needs framebuffer objects and depth textures, without them the
+/// prepass renders as usual and PrepassTextures::depth stays unset.
+pub struct PrepassTexturesPlugin;
+
+impl Plugin for PrepassTexturesPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PrepassTextures>()
+            .add_systems(Startup, send_prepass_textures.in_set(RenderSet::Pipeline))
+            .add_systems(
+                PostUpdate,
+                remove_render_resource_on_restore::<PrepassDepthTarget>.in_set(RenderSet::Acquire),
+            );
+    }
+}
+
+/// In the main and render world. Textures filled by the depth prepass, see PrepassTexturesPlugin.
+///
+/// depth holds the prepass of the last camera with DepthPrepass, from after its prepass until the next one's. Sample it
+/// at `gl_FragCoord.xy / ub_view_resolution`, or the window uv in fullscreen passes. It's reverse z: 1 is the near
+/// plane and 0 is where nothing was drawn, which is infinitely far with bevy's default perspective projection. The
+/// view space position is reconstructed with the inverse of the camera's projection, from ViewUniforms:
+///
+/// ```glsl
+/// float depth = texture2D(prepass_depth, window_uv).r;
+/// vec4 ndc = vec4(viewport_uv * 2.0 - 1.0, depth * 2.0 - 1.0, 1.0);
+/// vec4 view = view_from_clip * ndc; // inverse(clip_from_world * world_from_view)
+/// vec3 view_position = view.xyz / view.w;
+/// vec3 world_position = (ub_world_from_view * vec4(view_position, 1.0)).xyz;
+/// ```
+///
+/// viewport_uv is across the camera's viewport and window_uv across the window, they're the same without a viewport.
+/// Linear view depth is -view_position.z. The depth texture isn't compared, use sampler2D.
+#[derive(Resource, Clone, Default)]
+pub struct PrepassTextures {
+    pub depth: TextureRef,
+}
+
+fn send_prepass_textures(textures: Res<PrepassTextures>, mut enc: ResMut<CommandEncoder>) {
+    let textures = textures.clone();
+    enc.record(move |_ctx, world| {
+        world.insert_resource(textures.clone());
+    });
+}
+
+/// For use in the render world. The texture PrepassTextures::depth refers to, once it's been created.
+pub fn prepass_depth_texture(world: &World) -> Option<glow::Texture> {
+    world
+        .get_resource::<PrepassDepthTarget>()
+        .map(|target| target.texture)
+}
+
+/// Called before the depth prepass of a camera with DepthPrepass. Binds the depth texture target if
+/// PrepassTexturesPlugin was added, returns whether it was.
+pub(crate) fn begin_prepass_depth(world: &mut World, viewport: Option<URect>) -> bool {
+    let Some(textures) = world.get_resource::<PrepassTextures>().cloned() else {
+        return false;
+    };
+    world
+        .resource_mut::<CommandEncoder>()
+        .record(move |ctx, world| {
+            let size = ctx.window_size;
+            let current = world
+                .get_resource::<PrepassDepthTarget>()
+                .map(|target| target.size);
+            if current != Some(size) {
+                if let Some(target) = world.remove_resource::<PrepassDepthTarget>() {
+                    target.delete(&ctx.gl);
+                }
+                let Some(target) = PrepassDepthTarget::new(ctx, size) else {
+                    warn_once!("Couldn't create the prepass depth texture, the prepass renders to the scene target");
+                    return;
+                };
+                let mut gpu_images = world.resource_mut::<GpuImages>();
+                match textures.depth.get() {
+                    Some(idx) => {
+                        gpu_images.raw_textures[idx as usize] = (target.texture, glow::TEXTURE_2D)
+                    }
+                    None => {
+                        gpu_images.add_texture_set_ref(
+                            target.texture,
+                            glow::TEXTURE_2D,
+                            &textures.depth,
+                        );
+                    }
+                }
+                world.insert_resource(target);
+            }
+            let mut target = world.resource_mut::<PrepassDepthTarget>();
+            target.previous =
+                unsafe { ctx.gl.get_parameter_framebuffer(glow::FRAMEBUFFER_BINDING) };
+            target.framebuffer.bind(ctx);
+            ctx.set_viewport(viewport);
+            ctx.clear_depth();
+            target.bound = true;
+        });
+    true
+}
+
+/// Called after the depth prepass if begin_prepass_depth() returned true. Binds the target from before it again.
+pub(crate) fn end_prepass_depth(world: &mut World, viewport: Option<URect>) {
+    world
+        .resource_mut::<CommandEncoder>()
+        .record(move |ctx, world| {
+            if let Some(mut target) = world.get_resource_mut::<PrepassDepthTarget>()
+                && std::mem::take(&mut target.bound)
+            {
+                unsafe { ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, target.previous) };
+                ctx.set_viewport(viewport);
+            }
+        });
+}
+
+/// Render world depth texture target, see PrepassTexturesPlugin.
+#[derive(Resource)]
+struct PrepassDepthTarget {
+    size: UVec2,
+    texture: glow::Texture,
+    framebuffer: Framebuffer,
+    /// The framebuffer that was bound when this one was, None for the window.
+    previous: Option<glow::Framebuffer>,
+    /// Bound for the current camera's prepass.
+    bound: bool,
+}
+
+impl PrepassDepthTarget {
+    fn new(ctx: &BevyGlContext, size: UVec2) -> Option<Self> {
+        if !ctx.has_framebuffer_object || !ctx.has_depth_texture || size.min_element() == 0 {
+            return None;
+        }
+        let previous = unsafe { ctx.gl.get_parameter_framebuffer(glow::FRAMEBUFFER_BINDING) };
+        let texture = unsafe { create_depth_texture(ctx, size) };
+        let framebuffer = Framebuffer::new_depth(ctx, texture, size.x, size.y);
+        unsafe { ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, previous) };
+        let Some(framebuffer) = framebuffer else {
+            unsafe { ctx.gl.delete_texture(texture) };
+            return None;
+        };
+        Some(Self {
+            size,
+            texture,
+            framebuffer,
+            previous: None,
+            bound: false,
+        })
+    }
+
+    fn delete(&self, gl: &glow::Context) {
+        self.framebuffer.delete(gl);
+        unsafe { gl.delete_texture(self.texture) };
+    }
+}
+
+/// A DEPTH_COMPONENT texture sampled without comparison.
+unsafe fn create_depth_texture(ctx: &BevyGlContext, size: UVec2) -> glow::Texture {
+    unsafe {
+        let texture = ctx.gl.create_texture().unwrap();
+        ctx.gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+        ctx.label_texture(texture, "prepass_depth");
+        for (param, value) in [
+            (glow::TEXTURE_MIN_FILTER, glow::NEAREST),
+            (glow::TEXTURE_MAG_FILTER, glow::NEAREST),
+            (glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE),
+            (glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE),
+        ] {
+            ctx.gl
+                .tex_parameter_i32(glow::TEXTURE_2D, param, value as i32);
+        }
+        ctx.gl.tex_image_2d(
+            glow::TEXTURE_2D,
+            0,
+            glow::DEPTH_COMPONENT24 as i32,
+            size.x as i32,
+            size.y as i32,
+            0,
+            glow::DEPTH_COMPONENT,
+            glow::UNSIGNED_INT,
+            PixelUnpackData::Slice(None),
+        );
+        ctx.gl.bind_texture(glow::TEXTURE_2D, None);
+        texture
+    }
+}
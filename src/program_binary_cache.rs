@@ -0,0 +1,147 @@
+use std::{
+    hash::{Hash, Hasher},
+    path::PathBuf,
+};
+
+use bevy::prelude::*;
+use glow::HasContext;
+
+/// Persists linked program binaries to disk so shaders don't need to be compiled from source on the next run. Only
+/// available on desktop GL with GL_ARB_get_program_binary, WebGL has no equivalent. Only the shader_cached! path that
+/// reads shaders from disk uses it, with bundle_shaders (and on wasm) programs are always compiled from source.
+pub struct ProgramBinaryCache {
+    dir: PathBuf,
+    /// Hash of the GL vendor, renderer and version. Binaries are only valid for the driver that created them.
+    driver_hash: u64,
+}
+
+const EXTENSION: &str = "glbin";
+
+/// 64 bit FNV-1a. The cache file names have to be the same from one build to the next, which std's DefaultHasher
+/// doesn't guarantee.
+pub(crate) struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl ProgramBinaryCache {
+    /// Returns None if program binaries are not supported. Binaries in dir from a different driver are deleted.
+    pub fn new(gl: &glow::Context, dir: impl Into<PathBuf>) -> Option<Self> {
+        if cfg!(target_arch = "wasm32") {
+            return None;
+        }
+        let supported = gl.version().major > 4
+            || (gl.version().major == 4 && gl.version().minor >= 1)
+            || gl
+                .supported_extensions()
+                .contains("GL_ARB_get_program_binary");
+        if !supported || unsafe { gl.get_parameter_i32(glow::NUM_PROGRAM_BINARY_FORMATS) } == 0 {
+            return None;
+        }
+
+        let mut hasher = StableHasher::default();
+        unsafe {
+            gl.get_parameter_string(glow::VENDOR).hash(&mut hasher);
+            gl.get_parameter_string(glow::RENDERER).hash(&mut hasher);
+            gl.get_parameter_string(glow::VERSION).hash(&mut hasher);
+        }
+        let cache = ProgramBinaryCache {
+            dir: dir.into(),
+            driver_hash: hasher.finish(),
+        };
+
+        if let Err(e) = std::fs::create_dir_all(&cache.dir) {
+            warn!(
+                "Couldn't create program binary cache dir {:?}: {e}",
+                cache.dir
+            );
+            return None;
+        }
+        let prefix = format!("{:016x}_", cache.driver_hash);
+        if let Ok(entries) = std::fs::read_dir(&cache.dir) {
+            for path in entries.flatten().map(|entry| entry.path()) {
+                let stale = path.extension().is_some_and(|ext| ext == EXTENSION)
+                    && !path
+                        .file_name()
+                        .is_some_and(|name| name.to_string_lossy().starts_with(&prefix));
+                if stale {
+                    let _ = std::fs::remove_file(path);
+                }
+            }
+        }
+        Some(cache)
+    }
+
+    fn path(&self, key: u64) -> PathBuf {
+        self.dir
+            .join(format!("{:016x}_{key:016x}.{EXTENSION}", self.driver_hash))
+    }
+
+    /// Creates a program from the binary stored for key. Returns None if there isn't one or the driver rejects it.
+    pub fn load(&self, gl: &glow::Context, key: u64) -> Option<glow::Program> {
+        let data = std::fs::read(self.path(key)).ok()?;
+        if data.len() < 4 {
+            return None;
+        }
+        let binary = glow::ProgramBinary {
+            format: u32::from_le_bytes(data[..4].try_into().unwrap()),
+            buffer: data[4..].to_vec(),
+        };
+        unsafe {
+            let program = gl.create_program().ok()?;
+            gl.program_binary(program, &binary);
+            if gl.get_program_link_status(program) {
+                Some(program)
+            } else {
+                gl.delete_program(program);
+                None
+            }
+        }
+    }
+
+    /// Writes the binary of a linked program. The program should have been linked with
+    /// PROGRAM_BINARY_RETRIEVABLE_HINT set.
+    pub fn store(&self, gl: &glow::Context, key: u64, program: glow::Program) {
+        let Some(binary) = (unsafe { gl.get_program_binary(program) }) else {
+            return;
+        };
+        let mut data = Vec::with_capacity(binary.buffer.len() + 4);
+        data.extend(binary.format.to_le_bytes());
+        data.extend(binary.buffer);
+        if let Err(e) = std::fs::write(self.path(key), data) {
+            warn!("Couldn't write program binary: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stable_hasher() {
+        let hash = |bytes: &[u8]| {
+            let mut hasher = StableHasher::default();
+            hasher.write(bytes);
+            hasher.finish()
+        };
+        // Reference FNV-1a values, file names from earlier runs depend on these.
+        assert_eq!(hash(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+}
@@ -1,13 +1,15 @@
-use std::any::TypeId;
+use std::{any::TypeId, marker::PhantomData, path::PathBuf};
 
 use bevy::{
+    camera::primitives::{Aabb, Frustum},
+    diagnostic::FrameCount,
     ecs::system::{SystemId, SystemState},
     image::{CompressedImageFormatSupport, CompressedImageFormats},
     light::SimulationLightSystems,
     platform::collections::HashMap,
     prelude::*,
     render::{RenderPlugin, settings::WgpuSettings},
-    window::WindowResized,
+    window::{PresentMode, WindowResized},
     winit::WINIT_WINDOWS,
 };
 use glow::HasContext;
@@ -22,15 +24,23 @@ use raw_window_handle::{HasDisplayHandle, HasWindowHandle};
 use winit::platform::web::WindowExtWebSys;
 
 use crate::{
-    BevyGlContext, WindowInitData,
-    command_encoder::{CommandEncoder, CommandEncoderPlugin, CommandEncoderSender},
+    BevyGlContextConfig, BlendState, WindowInitData,
+    capabilities::GlCapabilitiesPlugin,
+    command_encoder::{
+        CommandEncoder, CommandEncoderPlugin, CommandEncoderSender, GlContextRestored,
+        gl_initialized,
+    },
     phase_opaque::OpaquePhasePlugin,
+    phase_point_shadow::PointShadowPhasePlugin,
     phase_shadow::ShadowPhasePlugin,
-    phase_transparent::TransparentPhasePlugin,
+    phase_transparent::{DeferredAlphaBlendDraws, TransparentPhasePlugin},
     plane_reflect::PlaneReflectPlugin,
     prepare_image::PrepareImagePlugin,
     prepare_joints::PrepareJointsPlugin,
     prepare_mesh::PrepareMeshPlugin,
+    prepare_view::ViewUniforms,
+    present_mode_vsync,
+    watchers::ShaderHotReload,
 };
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone, SystemSet)]
@@ -44,6 +54,7 @@ pub enum RenderSet {
     RenderReflectTransparent,
     RenderOpaque,
     RenderTransparent,
+    PostProcess,
     RenderDebug,
     RenderUi,
     Present,
@@ -58,6 +69,7 @@ impl Plugin for OpenGLRenderPlugins {
             CommandEncoderPlugin,
             OpenGLMinimalRenderPlugin,
             ShadowPhasePlugin,
+            PointShadowPhasePlugin,
             OpaquePhasePlugin,
             TransparentPhasePlugin,
             PlaneReflectPlugin,
@@ -72,56 +84,183 @@ impl Plugin for OpenGLMinimalRenderPlugin {
         app.insert_resource(CompressedImageFormatSupport(CompressedImageFormats::BC)) // TODO query?
             .init_resource::<RenderRunner>()
             .init_resource::<RenderPhase>()
-            .add_plugins((PrepareMeshPlugin, PrepareImagePlugin, PrepareJointsPlugin));
+            .init_resource::<WireframeSettings>()
+            .add_plugins((
+                GlCapabilitiesPlugin,
+                PrepareMeshPlugin,
+                PrepareImagePlugin,
+                PrepareJointsPlugin,
+            ));
 
-        // TODO reference: https://github.com/bevyengine/bevy/pull/22144
-        app.configure_sets(Startup, (RenderSet::Init, RenderSet::Pipeline).chain());
-        app.configure_sets(
-            PostUpdate,
-            (
-                RenderSet::Init,
-                RenderSet::Pipeline,
-                RenderSet::Acquire,
-                RenderSet::Prepare,
-                RenderSet::RenderShadow,
-                RenderSet::RenderReflectOpaque,
-                RenderSet::RenderReflectTransparent,
-                RenderSet::RenderOpaque,
-                RenderSet::RenderTransparent,
-                RenderSet::RenderDebug,
-                RenderSet::RenderUi,
-                RenderSet::Present,
-                RenderSet::SubmitEncoder,
-            )
-                .chain()
-                .after(TransformSystems::Propagate)
-                .after(SimulationLightSystems::UpdateDirectionalLightCascades),
-        );
+        configure_render_sets(app);
 
         app.add_systems(Startup, init_gl.in_set(RenderSet::Init));
+        app.add_systems(
+            PostUpdate,
+            init_gl.in_set(RenderSet::Init).run_if(not(gl_initialized)),
+        );
+        app.add_systems(
+            PostUpdate,
+            (update_render_camera, reupload_assets).in_set(RenderSet::Acquire),
+        );
         app.add_systems(PostUpdate, present.in_set(RenderSet::Present));
+        app.add_systems(
+            PostUpdate,
+            reload_shader_includes.in_set(RenderSet::Pipeline),
+        );
+    }
+}
+
+/// Orders the render sets and only runs the ones that record GL commands once the context exists.
+pub(crate) fn configure_render_sets(app: &mut App) {
+    // TODO reference: https://github.com/bevyengine/bevy/pull/22144
+    app.configure_sets(Startup, (RenderSet::Init, RenderSet::Pipeline).chain());
+    app.configure_sets(
+        PostUpdate,
+        (
+            RenderSet::Init,
+            RenderSet::Pipeline,
+            RenderSet::Acquire,
+            RenderSet::Prepare,
+            RenderSet::RenderShadow,
+            RenderSet::RenderReflectOpaque,
+            RenderSet::RenderReflectTransparent,
+            RenderSet::RenderOpaque,
+            RenderSet::RenderTransparent,
+            RenderSet::PostProcess,
+            RenderSet::RenderDebug,
+            RenderSet::RenderUi,
+            RenderSet::Present,
+            RenderSet::SubmitEncoder,
+        )
+            .chain()
+            .after(TransformSystems::Propagate)
+            .after(SimulationLightSystems::UpdateDirectionalLightCascades),
+    );
+
+    // Nothing is prepared or drawn until there's a window to create the context with, which some platforms only have
+    // after startup. Otherwise per-frame commands would pile up in the CommandEncoder while there's no render thread
+    // to send them to. Commands recorded in Startup stay queued, and reupload_assets() uploads every Image and Mesh
+    // once the context exists.
+    app.configure_sets(Update, RenderSet::Prepare.run_if(gl_initialized));
+    app.configure_sets(
+        PostUpdate,
+        (
+            RenderSet::Pipeline,
+            RenderSet::Acquire,
+            RenderSet::Prepare,
+            RenderSet::RenderShadow,
+            RenderSet::RenderReflectOpaque,
+            RenderSet::RenderReflectTransparent,
+            RenderSet::RenderOpaque,
+            RenderSet::RenderTransparent,
+            RenderSet::PostProcess,
+            RenderSet::RenderDebug,
+            RenderSet::RenderUi,
+            RenderSet::Present,
+        )
+            .run_if(gl_initialized),
+    );
+}
+
+/// The camera currently being rendered. During the opaque and transparent phases this is set to each active camera in
+/// turn, ordered by Camera::order. Otherwise it's the first of those, which shadows and reflections are rendered for.
+#[derive(Resource, Clone, Copy, Deref, PartialEq, Eq, Debug)]
+pub struct RenderCamera(pub Entity);
+
+/// Active cameras sorted by Camera::order, lowest first. Cameras with the same order are in query order.
+pub fn sorted_cameras<'a>(cameras: impl Iterator<Item = (Entity, &'a Camera)>) -> Vec<Entity> {
+    let mut cameras = cameras
+        .filter(|(_, camera)| camera.is_active)
+        .map(|(entity, camera)| (camera.order, entity))
+        .collect::<Vec<_>>();
+    cameras.sort_by_key(|(order, _)| *order);
+    cameras.into_iter().map(|(_, entity)| entity).collect()
+}
+
+fn update_render_camera(mut commands: Commands, cameras: Query<(Entity, &Camera)>) {
+    if let Some(camera) = sorted_cameras(cameras.iter()).first() {
+        commands.insert_resource(RenderCamera(*camera));
+    } else {
+        commands.remove_resource::<RenderCamera>();
+    }
+}
+
+/// Sends every Image and Mesh as modified after GlContextRestored, so the Prepare systems upload them again. Also done
+/// the first time this runs if the context was only created after the first frame, since the Prepare systems didn't
+/// run before then and missed the events for assets added in the meantime.
+fn reupload_assets(
+    mut restored: MessageReader<GlContextRestored>,
+    mut ran: Local<bool>,
+    frame: Res<FrameCount>,
+    images: Res<Assets<Image>>,
+    meshes: Res<Assets<Mesh>>,
+    mut image_events: MessageWriter<AssetEvent<Image>>,
+    mut mesh_events: MessageWriter<AssetEvent<Mesh>>,
+) {
+    let delayed_init = !std::mem::replace(&mut *ran, true) && frame.0 > 0;
+    if restored.is_empty() && !delayed_init {
+        return;
     }
+    restored.clear();
+    image_events.write_batch(images.ids().map(|id| AssetEvent::Modified { id }));
+    mesh_events.write_batch(meshes.ids().map(|id| AssetEvent::Modified { id }));
+}
+
+fn reload_shader_includes(mut enc: ResMut<CommandEncoder>) {
+    enc.record(|ctx, world| {
+        if let Some(hot_reload) = world.get_resource::<ShaderHotReload>() {
+            hot_reload.reload_if_changed(ctx);
+        }
+    });
 }
 
 fn present(
     mut enc: ResMut<CommandEncoder>,
     resized: MessageReader<WindowResized>,
     mut bevy_window: Single<(Entity, &mut Window)>,
+    mut present_mode: Local<Option<PresentMode>>,
 ) {
     #[allow(unused)]
     let (bevy_window_entity, bevy_window) = &mut *bevy_window;
     let width = bevy_window.physical_width().max(1);
     let height = bevy_window.physical_height().max(1);
     let resized = resized.len() > 0;
+    // The context applies the present mode it's created with.
+    let vsync = match present_mode.replace(bevy_window.present_mode) {
+        Some(previous) if previous != bevy_window.present_mode => {
+            Some(present_mode_vsync(bevy_window.present_mode))
+        }
+        _ => None,
+    };
     #[cfg(target_arch = "wasm32")]
     let bevy_window_entity = *bevy_window_entity;
+    // Only the window is presented, a target left bound would get the reads and the viewport reset.
+    if enc.target.is_some() {
+        enc.unbind_target();
+    }
+    let framebuffer_reads = std::mem::take(&mut enc.framebuffer_reads);
     enc.record(move |ctx, _world| {
+        // Undo the camera viewport set in the opaque phase.
+        ctx.set_viewport(None);
+        if !framebuffer_reads.is_empty() {
+            unsafe { ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, None) };
+            for (rect, callback) in framebuffer_reads {
+                let window_rect = URect::new(0, 0, width, height);
+                let mut rect = rect.map_or(window_rect, |rect| rect.intersect(window_rect));
+                rect.max = rect.max.max(rect.min);
+                callback(ctx.read_pixels_rgba8(rect, height), rect.size());
+            }
+        }
         ctx.swap();
+        if let Some(vsync) = vsync {
+            ctx.set_vsync(vsync);
+        }
         if resized {
+            ctx.window_size = uvec2(width, height);
             #[cfg(not(target_arch = "wasm32"))]
             {
                 use std::num::NonZeroU32;
-                //let present_mode = bevy_window.present_mode; // TODO update
                 unsafe { ctx.gl.viewport(0, 0, width as i32, height as i32) };
                 unsafe { ctx.gl.scissor(0, 0, width as i32, height as i32) };
                 ctx.gl_surface.as_ref().unwrap().resize(
@@ -148,6 +287,13 @@ fn present(
     });
 }
 
+/// Draw geometry as lines for debugging. Render systems read this when they set up a view. Desktop GL only, WebGL
+/// doesn't support polygon_mode.
+#[derive(Resource, Default, Clone, Copy)]
+pub struct WireframeSettings {
+    pub enabled: bool,
+}
+
 #[derive(Resource, Default, PartialEq, Eq, Clone, Copy)]
 pub enum RenderPhase {
     Shadow,
@@ -161,15 +307,15 @@ pub enum RenderPhase {
 }
 
 impl RenderPhase {
+    /// Whether the camera's ViewVisibility applies. Other phases are culled against their own view, see
+    /// DrawCollector::cull_or_defer().
     pub fn can_use_camera_frustum_cull(&self) -> bool {
         match self {
-            RenderPhase::Shadow | RenderPhase::ReflectOpaque | RenderPhase::ReflectTransparent => {
-                false
-            }
-            RenderPhase::ReflectDepthPrepass
-            | RenderPhase::DepthPrepass
-            | RenderPhase::Opaque
-            | RenderPhase::Transparent => true,
+            RenderPhase::Shadow
+            | RenderPhase::ReflectDepthPrepass
+            | RenderPhase::ReflectOpaque
+            | RenderPhase::ReflectTransparent => false,
+            RenderPhase::DepthPrepass | RenderPhase::Opaque | RenderPhase::Transparent => true,
         }
     }
     pub fn reflection(&self) -> bool {
@@ -254,23 +400,39 @@ impl RenderRunner {
     }
 }
 
+/// Starts the render thread with a context for the primary window. Runs at startup and then each frame until it
+/// succeeds, since the winit window may not exist yet.
 pub fn init_gl(world: &mut World, params: &mut SystemState<Query<(Entity, &mut Window)>>) {
-    if world.contains_non_send::<BevyGlContext>() {
+    #[cfg(not(target_arch = "wasm32"))]
+    if world.contains_resource::<CommandEncoderSender>() {
         return;
     }
+    #[cfg(target_arch = "wasm32")]
+    if world.contains_non_send::<CommandEncoderSender>() {
+        return;
+    }
+    let config = world
+        .get_resource::<BevyGlContextConfig>()
+        .copied()
+        .unwrap_or_default();
     WINIT_WINDOWS.with_borrow(|winit_windows| {
         let mut windows = params.get_mut(world);
 
-        let (bevy_window_entity, bevy_window) = windows.single_mut().unwrap();
+        let Ok((bevy_window_entity, bevy_window)) = windows.single_mut() else {
+            return;
+        };
         let Some(winit_window) = winit_windows.get_window(bevy_window_entity) else {
-            warn!("No Window Found");
+            debug!("No winit window yet, retrying OpenGL context creation next frame");
             return;
         };
 
         let window_init_data = WindowInitData {
             #[cfg(not(target_arch = "wasm32"))]
             attrs: winit_window
-                .build_surface_attributes(Default::default())
+                .build_surface_attributes(
+                    glutin::surface::SurfaceAttributesBuilder::new()
+                        .with_srgb(config.srgb_framebuffer.then_some(true)),
+                )
                 .unwrap()
                 .clone(),
             #[cfg(target_arch = "wasm32")]
@@ -280,6 +442,7 @@ pub fn init_gl(world: &mut World, params: &mut SystemState<Query<(Entity, &mut W
             present_mode: bevy_window.present_mode,
             width: bevy_window.physical_size().x as u32,
             height: bevy_window.physical_size().y as u32,
+            config,
         };
 
         let sender = CommandEncoderSender::new(window_init_data);
@@ -314,6 +477,75 @@ pub fn register_prepare_system<M>(world: &mut World, system: impl IntoSystem<(),
         .register_prepare(system_id);
 }
 
+/// Registers the render system of material type M, see register_render_system(). Use the returned
+/// MaterialRegistration to also add a prepare system and the shader includes the material's shaders use.
+///
+/// Ordering:
+/// - Shader includes are added in Startup in RenderSet::Pipeline, so they exist before any render system runs.
+///   Watched includes are hot reloaded in PostUpdate in RenderSet::Pipeline, before any RenderPhase.
+/// - In each RenderPhase every prepare system runs before any render system. There's no order between prepare
+///   systems, or between the render systems of different materials.
+/// - Alpha blend draws deferred to DeferredAlphaBlendDraws are drawn back to front across all materials.
+/// - There is one render system per material type, registering M again replaces the previous one.
+pub fn register_material<M: 'static, Marker>(
+    app: &mut App,
+    render_system: impl IntoSystem<(), (), Marker> + 'static,
+) -> MaterialRegistration<'_> {
+    register_render_system::<M, _>(app.world_mut(), render_system);
+    MaterialRegistration { app }
+}
+
+pub struct MaterialRegistration<'a> {
+    app: &'a mut App,
+}
+
+impl MaterialRegistration<'_> {
+    /// Run at the start of each RenderPhase, see register_prepare_system().
+    pub fn prepare_system<Marker>(self, system: impl IntoSystem<(), (), Marker> + 'static) -> Self {
+        register_prepare_system(self.app.world_mut(), system);
+        self
+    }
+
+    /// Makes `src` available to shaders as `#include name`.
+    pub fn shader_include(self, name: &'static str, src: &'static str) -> Self {
+        self.add_shader_include(name, src, None)
+    }
+
+    /// Like shader_include() but also hot reloads the include from `path` when it changes on disk.
+    pub fn watched_shader_include(
+        self,
+        name: &'static str,
+        src: &'static str,
+        path: impl Into<PathBuf>,
+    ) -> Self {
+        self.add_shader_include(name, src, Some(path.into()))
+    }
+
+    fn add_shader_include(
+        self,
+        name: &'static str,
+        src: &'static str,
+        path: Option<PathBuf>,
+    ) -> Self {
+        self.app.add_systems(
+            Startup,
+            (move |mut enc: ResMut<CommandEncoder>| {
+                let path = path.clone();
+                enc.record(move |ctx, world| {
+                    ctx.add_shader_include(name, src);
+                    if let Some(path) = path {
+                        world
+                            .get_resource_or_init::<ShaderHotReload>()
+                            .watch_include(name, path);
+                    }
+                });
+            })
+            .in_set(RenderSet::Pipeline),
+        );
+        self
+    }
+}
+
 pub fn default_plugins_no_render_backend() -> bevy::app::PluginGroupBuilder {
     DefaultPlugins.set(RenderPlugin {
         render_creation: WgpuSettings {
@@ -337,16 +569,127 @@ pub fn transparent_draw_from_alpha_mode(alpha_mode: &AlphaMode) -> bool {
     }
 }
 
+/// Sets the blend func and equation for the AlphaMode, see BlendState::from_alpha_mode(). Doesn't enable blending.
 pub fn set_blend_func_from_alpha_mode(gl: &glow::Context, alpha_mode: &AlphaMode) {
-    unsafe {
-        match alpha_mode {
-            AlphaMode::Opaque => gl.blend_func(glow::ZERO, glow::ONE),
-            AlphaMode::Mask(_) => gl.blend_func(glow::ZERO, glow::ONE),
-            AlphaMode::Blend => gl.blend_func(glow::SRC_ALPHA, glow::ONE_MINUS_SRC_ALPHA),
-            AlphaMode::Premultiplied => gl.blend_func(glow::SRC_ALPHA, glow::ONE_MINUS_SRC_ALPHA),
-            AlphaMode::AlphaToCoverage => gl.blend_func(glow::SRC_ALPHA, glow::ONE_MINUS_SRC_ALPHA),
-            AlphaMode::Add => gl.blend_func(glow::SRC_ALPHA, glow::ONE),
-            AlphaMode::Multiply => gl.blend_func(glow::DST_COLOR, glow::ZERO),
+    BlendState::from_alpha_mode(alpha_mode).apply(gl);
+}
+
+/// A draw collected by DrawCollector. `data` holds anything else the render system needs per draw.
+pub struct CollectedDraw<D> {
+    pub world_from_local: Mat4,
+    pub mesh: AssetId<Mesh>,
+    /// Index into the materials returned by DrawCollector::finish()
+    pub material_idx: u32,
+    pub data: D,
+}
+
+/// Builds the draw list of a render system for the current RenderPhase.
+/// - In the transparent phases entities() returns the alpha blend draws deferred during the opaque phases.
+/// - cull_or_defer() frustum culls, with the camera's ViewVisibility or against the shadow / reflection view, and
+///   defers alpha blend draws while in an opaque phase.
+/// - push() only stores a new material when the key differs from the previous draw, so iterate entities sorted by
+///   material to get the fewest material binds.
+///
+/// T is the type the render system was registered with in register_render_system(). K is the material key (an
+/// AssetId, Entity, etc...) and M is the material data moved into the CommandEncoder.
+pub struct DrawCollector<'a, T: 'static, K, M, D = ()> {
+    pub phase: RenderPhase,
+    view_from_world: Mat4,
+    /// For phases that can't use the camera's ViewVisibility.
+    frustum: Option<Frustum>,
+    transparent_draws: &'a mut DeferredAlphaBlendDraws,
+    last_material: Option<K>,
+    materials: Vec<M>,
+    draws: Vec<CollectedDraw<D>>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<'a, T: 'static, K: PartialEq, M, D> DrawCollector<'a, T, K, M, D> {
+    /// view is the ViewUniforms of the view being rendered, see prepare_view().
+    pub fn new(
+        phase: RenderPhase,
+        view: &ViewUniforms,
+        transparent_draws: &'a mut DeferredAlphaBlendDraws,
+    ) -> Self {
+        Self {
+            phase,
+            view_from_world: view.view_from_world,
+            frustum: (!phase.can_use_camera_frustum_cull())
+                .then(|| Frustum::from_clip_from_world(&view.clip_from_world)),
+            transparent_draws,
+            last_material: None,
+            materials: Vec::new(),
+            draws: Vec::new(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// The entities to iterate in this phase. The deferred alpha blend draws, already sorted back to front, in the
+    /// transparent phases, otherwise `entities`.
+    pub fn entities(&mut self, entities: impl IntoIterator<Item = Entity>) -> Vec<Entity> {
+        if self.phase.transparent() {
+            self.transparent_draws.take()
+        } else {
+            entities.into_iter().collect()
+        }
+    }
+
+    /// Returns world_from_local if the entity should be drawn in this phase.
+    pub fn cull_or_defer(
+        &mut self,
+        entity: Entity,
+        view_visibility: &ViewVisibility,
+        transform: &GlobalTransform,
+        aabb: &Aabb,
+        transparent: bool,
+    ) -> Option<Mat4> {
+        if self.phase.can_use_camera_frustum_cull() && !view_visibility.get() {
+            return None;
+        }
+        if let Some(frustum) = &self.frustum {
+            // oblique_near_plane() skews the far plane of reflections, GL doesn't actually clip against it.
+            let intersect_far = !self.phase.reflection();
+            if !frustum.intersects_obb(aabb, &transform.affine(), true, intersect_far) {
+                return None;
+            }
+        }
+        let world_from_local = transform.to_matrix();
+        self.transparent_draws
+            .maybe_defer::<T>(
+                transparent,
+                self.phase,
+                entity,
+                transform,
+                aabb,
+                &self.view_from_world,
+                &world_from_local,
+            )
+            .then_some(world_from_local)
+    }
+
+    /// `material` is only called if `material_key` differs from the previous draw.
+    pub fn push(
+        &mut self,
+        material_key: K,
+        material: impl FnOnce() -> M,
+        mesh: AssetId<Mesh>,
+        world_from_local: Mat4,
+        data: D,
+    ) {
+        if self.last_material.as_ref() != Some(&material_key) {
+            self.materials.push(material());
+            self.last_material = Some(material_key);
         }
+        self.draws.push(CollectedDraw {
+            world_from_local,
+            mesh,
+            material_idx: self.materials.len() as u32 - 1,
+            data,
+        });
+    }
+
+    /// The materials and draws, in draw order.
+    pub fn finish(self) -> (Vec<M>, Vec<CollectedDraw<D>>) {
+        (self.materials, self.draws)
     }
 }
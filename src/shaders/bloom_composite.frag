@@ -0,0 +1,22 @@
+#include std::math
+#include std::agx
+#include std::tonemapping
+
+// Adds the bloom to the linear scene and tonemaps, see BloomPlugin.
+
+uniform sampler2D source_texture;
+uniform sampler2D bloom_texture;
+uniform float bloom_intensity;
+// False when the scene was already tonemapped because half float targets aren't supported.
+uniform bool bloom_tonemap;
+
+varying vec2 uv;
+
+void main() {
+    vec4 scene = texture2D(source_texture, uv);
+    vec3 color = scene.rgb + texture2D(bloom_texture, uv).rgb * bloom_intensity;
+    if (bloom_tonemap) {
+        color = tonemap(color);
+    }
+    gl_FragColor = clamp(vec4(color, scene.a), vec4(0.0), vec4(1.0));
+}
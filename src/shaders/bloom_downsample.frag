@@ -0,0 +1,26 @@
+// Dual filter downsample, see BloomPlugin. With BLOOM_PREFILTER only the part above bloom_threshold is kept.
+
+uniform sampler2D source_texture;
+uniform vec2 source_size;
+uniform float bloom_threshold;
+
+varying vec2 uv;
+
+vec3 bloom_sample(vec2 sample_uv) {
+    vec3 color = texture2D(source_texture, sample_uv).rgb;
+    #ifdef BLOOM_PREFILTER
+    float brightness = max(color.r, max(color.g, color.b));
+    color *= max(brightness - bloom_threshold, 0.0) / max(brightness, 1e-5);
+    #endif
+    return color;
+}
+
+void main() {
+    vec2 texel = 1.0 / source_size;
+    vec3 sum = bloom_sample(uv) * 4.0;
+    sum += bloom_sample(uv - texel);
+    sum += bloom_sample(uv + texel);
+    sum += bloom_sample(uv + vec2(texel.x, -texel.y));
+    sum += bloom_sample(uv - vec2(texel.x, -texel.y));
+    gl_FragColor = vec4(sum / 8.0, 1.0);
+}
@@ -0,0 +1,19 @@
+// Dual filter upsample, added onto the next larger mip, see BloomPlugin.
+
+uniform sampler2D source_texture;
+uniform vec2 source_size;
+
+varying vec2 uv;
+
+void main() {
+    vec2 texel = 1.0 / source_size;
+    vec3 sum = texture2D(source_texture, uv + vec2(-texel.x * 2.0, 0.0)).rgb;
+    sum += texture2D(source_texture, uv + vec2(texel.x * 2.0, 0.0)).rgb;
+    sum += texture2D(source_texture, uv + vec2(0.0, -texel.y * 2.0)).rgb;
+    sum += texture2D(source_texture, uv + vec2(0.0, texel.y * 2.0)).rgb;
+    sum += texture2D(source_texture, uv + vec2(-texel.x, texel.y)).rgb * 2.0;
+    sum += texture2D(source_texture, uv + vec2(texel.x, texel.y)).rgb * 2.0;
+    sum += texture2D(source_texture, uv + vec2(texel.x, -texel.y)).rgb * 2.0;
+    sum += texture2D(source_texture, uv + vec2(-texel.x, -texel.y)).rgb * 2.0;
+    gl_FragColor = vec4(sum / 12.0, 1.0);
+}
@@ -0,0 +1,10 @@
+// Drawn after tonemapping, the colors are already sRGB, see DebugLines.
+
+varying vec4 line_color;
+
+void main() {
+    gl_FragColor = line_color;
+#ifdef SRGB_FRAMEBUFFER
+    gl_FragColor.rgb = pow(gl_FragColor.rgb, vec3(2.2));
+#endif
+}
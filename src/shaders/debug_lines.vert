@@ -0,0 +1,9 @@
+attribute vec3 position;
+attribute vec4 color;
+
+varying vec4 line_color;
+
+void main() {
+    gl_Position = ub_clip_from_world * vec4(position, 1.0);
+    line_color = color;
+}
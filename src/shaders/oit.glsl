@@ -0,0 +1,19 @@
+// Weighted blended order independent transparency (McGuire and Bavoil 2013), see TransparencyMode::WeightedBlended.
+// OIT_ACCUMULATE and OIT_REVEALAGE are set by OitPass::shader_defs() for the two passes over the transparent draws.
+
+float oit_weight(float alpha, float view_depth) {
+    // Equation 10 from the paper, with view_depth in world units.
+    float a = view_depth / 5.0;
+    float b = view_depth / 200.0;
+    return alpha * clamp(10.0 / (1e-5 + a * a + b * b * b * b * b * b), 1e-2, 3e3);
+}
+
+// color is the blended output of the material, not premultiplied.
+vec4 oit_output(vec4 color, float view_depth) {
+    #ifdef OIT_ACCUMULATE
+    float weight = oit_weight(color.a, view_depth);
+    return vec4(color.rgb * color.a * weight, color.a * weight);
+    #else
+    return vec4(color.a);
+    #endif
+}
@@ -0,0 +1,16 @@
+// Blended over the opaque image with standard alpha blending, see TransparencyMode::WeightedBlended.
+
+uniform sampler2D accumulation_texture;
+uniform sampler2D revealage_texture;
+// The targets are window sized, so camera viewports sample their own part of them.
+uniform vec2 target_size;
+
+void main() {
+    vec2 uv = gl_FragCoord.xy / target_size;
+    float revealage = texture2D(revealage_texture, uv).r;
+    if (revealage >= 1.0) {
+        discard;
+    }
+    vec4 accumulation = texture2D(accumulation_texture, uv);
+    gl_FragColor = vec4(accumulation.rgb / max(accumulation.a, 1e-5), 1.0 - revealage);
+}
@@ -3,7 +3,7 @@
 // http://www.mikktspace.com/
 vec3 apply_normal_mapping(sampler2D normal_tex, vec3 ws_normal, vec4 ws_tangent, vec2 uv, bool flip_normal_map_y, bool double_sided) {
     vec3 N = ws_normal;
-    vec3 T = ws_tangent.xyz;
+    vec3 T = normalize(ws_tangent.xyz);
     vec3 B = ws_tangent.w * cross(N, T);
     vec3 Nt = texture2D(normal_tex, uv).rgb * 2.0 - 1.0; // Only supports 3-component normal maps
     if (flip_normal_map_y) {
@@ -16,6 +16,66 @@ vec3 apply_normal_mapping(sampler2D normal_tex, vec3 ws_normal, vec4 ws_tangent,
     return normalize(N);
 }
 
+#ifdef PARALLAX
+// Steep parallax mapping refined with parallax occlusion or, with RELIEF_MAPPING, a binary search. Vt is the vector from
+// the camera to the fragment in tangent space. The loops are capped at MAX_PARALLAX_LAYERS and MAX_RELIEF_STEPS since
+// GLSL ES 1.00 needs constant loop bounds.
+// https://web.archive.org/web/20150419215321/http://sunandblackcat.com/tipFullView.php?l=eng&topicid=28
+vec2 parallaxed_uv(sampler2D depth_map, float depth_scale, float max_layer_count, float max_steps, vec2 original_uv, vec3 Vt) {
+    if (max_layer_count < 1.0) {
+        return original_uv;
+    }
+    vec2 uv = original_uv;
+    float view_steepness = abs(Vt.z);
+    // Fewer layers when looking straight at the surface, at least one to avoid dividing by zero.
+    float layer_count = mix(max_layer_count, 1.0, view_steepness);
+    float layer_depth = 1.0 / layer_count;
+    vec2 delta_uv = depth_scale * layer_depth * Vt.xy * vec2(1.0, -1.0) / view_steepness;
+
+    float current_layer_depth = 0.0;
+    float texture_depth = texture2D(depth_map, uv).r;
+    // Step forward until the ray is below the depth map.
+    for (int i = 0; i <= MAX_PARALLAX_LAYERS; i++) {
+        if (texture_depth <= current_layer_depth || float(i) > layer_count) {
+            break;
+        }
+        current_layer_depth += layer_depth;
+        uv += delta_uv;
+        texture_depth = texture2D(depth_map, uv).r;
+    }
+
+#ifdef RELIEF_MAPPING
+    delta_uv *= 0.5;
+    float delta_depth = 0.5 * layer_depth;
+    uv -= delta_uv;
+    current_layer_depth -= delta_depth;
+    for (int i = 0; i < MAX_RELIEF_STEPS; i++) {
+        if (float(i) >= max_steps) {
+            break;
+        }
+        texture_depth = texture2D(depth_map, uv).r;
+        delta_uv *= 0.5;
+        delta_depth *= 0.5;
+        if (texture_depth > current_layer_depth) {
+            uv += delta_uv;
+            current_layer_depth += delta_depth;
+        } else {
+            uv -= delta_uv;
+            current_layer_depth -= delta_depth;
+        }
+    }
+#else
+    // Interpolate between the last layer above the depth map and the first below it.
+    vec2 previous_uv = uv - delta_uv;
+    float next_depth = texture_depth - current_layer_depth;
+    float previous_depth = texture2D(depth_map, previous_uv).r - current_layer_depth + layer_depth;
+    float weight = next_depth / (next_depth - previous_depth);
+    uv = mix(uv, previous_uv, weight);
+#endif // RELIEF_MAPPING
+    return uv;
+}
+#endif // PARALLAX
+
 float distance_attenuation(float dist, float range) {
     float distanceSquare = dist * dist;
     float inverseRangeSquared = 1.0 / (range * range);
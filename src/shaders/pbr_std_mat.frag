@@ -1,8 +1,10 @@
 #include std::math
 #include std::pbr
 #include std::agx
+#include std::tonemapping
 #include std::shadow_sampling
 #include std::pbr_lighting
+#include std::oit
 
 varying vec4 clip_position;
 varying vec3 ws_position;
@@ -14,12 +16,29 @@ uniform sampler2D reflect_texture;
 uniform bool read_reflection;
 uniform vec3 reflection_plane_position;
 uniform vec3 reflection_plane_normal;
+#ifdef SSAO
+uniform sampler2D ssao_texture;
+#endif // SSAO
 
 void main() {
-    vec4 base_color = ub_base_color * to_linear(texture2D(ub_base_color_texture, uv_0));
+    vec3 V = normalize(ub_view_position - ws_position);
+    vec2 uv = uv_0;
+    #ifdef PARALLAX
+    {
+        vec3 N = normalize(vert_normal);
+        vec3 T = normalize(tangent.xyz);
+        vec3 B = tangent.w * cross(N, T);
+        // Toward the surface, in tangent space.
+        vec3 Vt = -vec3(dot(V, T), dot(V, B), dot(V, N));
+        uv = parallaxed_uv(ub_depth_map, ub_parallax_depth_scale, ub_max_parallax_layer_count,
+                ub_max_relief_mapping_search_steps, uv, Vt);
+    }
+    #endif // PARALLAX
+
+    vec4 base_color = ub_base_color * to_linear(texture2D(ub_base_color_texture, uv));
 
     #ifdef ALPHA_MASK
-    if (!ub_alpha_blend && (base_color.a < 0.5)) {
+    if (!ub_alpha_blend && (base_color.a < ub_alpha_cutoff)) {
         discard;
     }
     #endif //ALPHA_MASK
@@ -36,24 +55,25 @@ void main() {
     gl_FragColor = EncodeFloatRGBA(saturate(ndc_position.z * 0.5 + 0.5));
     #else // RENDER_DEPTH_ONLY
 
-    vec3 V = normalize(ub_view_position - ws_position);
-
-    vec4 metallic_roughness = texture2D(ub_metallic_roughness_texture, uv_0);
+    vec4 metallic_roughness = texture2D(ub_metallic_roughness_texture, uv);
     float perceptual_roughness = metallic_roughness.g * ub_perceptual_roughness;
     float metallic = ub_metallic * metallic_roughness.b;
     vec3 F0 = calculate_F0(base_color.rgb, metallic, ub_reflectance);
     vec3 diffuse_color = base_color.rgb * (1.0 - metallic);
 
     float emissive_exposure_factor = 1000.0; // TODO do something better
-    vec3 emissive = emissive_exposure_factor * ub_emissive.rgb * to_linear(texture2D(ub_emissive_texture, uv_0).rgb);
+    vec3 emissive = emissive_exposure_factor * ub_emissive.rgb * to_linear(texture2D(ub_emissive_texture, uv).rgb);
 
     vec3 normal = vert_normal;
     if (ub_has_normal_map) {
-        normal = apply_normal_mapping(ub_normal_map_texture, vert_normal, tangent, uv_0, ub_flip_normal_map_y, ub_double_sided);
+        normal = apply_normal_mapping(ub_normal_map_texture, vert_normal, tangent, uv, ub_flip_normal_map_y, ub_double_sided);
     }
 
     vec3 output_color = emissive.rgb;
     float env_occ = 1.0;
+    #ifdef SSAO
+    env_occ = texture2D(ssao_texture, gl_FragCoord.xy / ub_view_resolution).r;
+    #endif // SSAO
 
     #ifdef READ_REFLECTION
     if (read_reflection && perceptual_roughness < 0.2) {
@@ -67,13 +87,23 @@ void main() {
             env_occ, ub_diffuse_transmission, screen_uv, ub_view_resolution, ws_position);
 
     gl_FragColor = vec4(ub_view_exposure * output_color, base_color.a);
+    #ifndef NO_FOG
+    float view_depth = -(ub_view_from_world * vec4(ws_position, 1.0)).z;
+    gl_FragColor.rgb = apply_fog(gl_FragColor.rgb, view_depth);
+    #endif // NO_FOG
     #ifdef WRITE_REFLECTION
         gl_FragColor.rgb = reversible_tonemap(gl_FragColor.rgb);
     #else
-        gl_FragColor.rgb = agx_tonemapping(gl_FragColor.rgb); // in: linear, out: srgb
-        //gl_FragColor.rgb = from_linear(gl_FragColor.rgb); // in: linear, out: srgb
+        gl_FragColor.rgb = tonemap(gl_FragColor.rgb); // in: linear, out: srgb
     #endif // WRITE_REFLECTION
+    #ifdef TONEMAP_HDR_OUTPUT
+    gl_FragColor = vec4(max(gl_FragColor.rgb, vec3(0.0)), clamp(gl_FragColor.a, 0.0, 1.0));
+    #else
     gl_FragColor = clamp(gl_FragColor, vec4(0.0), vec4(1.0));
+    #endif
+    #if defined(OIT_ACCUMULATE) || defined(OIT_REVEALAGE)
+    gl_FragColor = oit_output(gl_FragColor, -(ub_view_from_world * vec4(ws_position, 1.0)).z);
+    #endif
 
     #endif // NOT RENDER_DEPTH_ONLY
 }
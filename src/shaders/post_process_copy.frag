@@ -0,0 +1,10 @@
+// Copies the last target to the window when no PostProcessPass could be compiled. Also the TAA pass, which binds its
+// resolved history as source_texture.
+
+uniform sampler2D source_texture;
+
+varying vec2 uv;
+
+void main() {
+    gl_FragColor = texture2D(source_texture, uv);
+}
@@ -0,0 +1,14 @@
+#include std::math
+#include std::agx
+#include std::tonemapping
+
+// Tonemaps the linear HDR scene when no other pass does, see PostProcessStack::hdr.
+
+uniform sampler2D source_texture;
+
+varying vec2 uv;
+
+void main() {
+    vec4 scene = texture2D(source_texture, uv);
+    gl_FragColor = clamp(vec4(tonemap(scene.rgb), scene.a), vec4(0.0), vec4(1.0));
+}
@@ -24,17 +24,28 @@ float bilinear_shadow(sampler2D shadow_tex, vec2 uv, float receiver_z, float bia
     return mix(mix(t00, t10, f.x), mix(t01, t11, f.x), f.y);
 }
 
-float bilinear_shadow2(sampler2D shadow_tex, vec2 uv, float receiver_z, float bias, vec2 shadow_res) {
+// Expects shadow_sampler2D to be defined as sampler2DShadow if SHADOW_DEPTH_TEXTURE is defined, sampler2D otherwise.
+// Comparison of the 2x2 texels around uv, weighted bilinearly.
+float shadow_tap(shadow_sampler2D shadow_tex, vec2 uv, float receiver_z, float bias, vec2 shadow_res) {
+#ifdef SHADOW_DEPTH_TEXTURE
+    // Done in hardware with LINEAR filtering
+    return shadow2D(shadow_tex, vec3(uv, receiver_z + bias)).r;
+#else
+    return bilinear_shadow(shadow_tex, uv, receiver_z, bias, shadow_res);
+#endif
+}
+
+float bilinear_shadow2(shadow_sampler2D shadow_tex, vec2 uv, float receiver_z, float bias, vec2 shadow_res) {
     vec2 step = 1.0 / shadow_res;
 
     vec2 p = (uv * shadow_res - 0.5);
     vec2 pos = floor(p) * step;
     vec2 f = fract(p);
 
-    float t00 = bilinear_shadow(shadow_tex, pos + vec2(0.0, 0.0), receiver_z, bias, shadow_res);
-    float t10 = bilinear_shadow(shadow_tex, pos + vec2(step.x, 0.0), receiver_z, bias, shadow_res);
-    float t01 = bilinear_shadow(shadow_tex, pos + vec2(0.0, step.y), receiver_z, bias, shadow_res);
-    float t11 = bilinear_shadow(shadow_tex, pos + vec2(step.x, step.y), receiver_z, bias, shadow_res);
+    float t00 = shadow_tap(shadow_tex, pos + vec2(0.0, 0.0), receiver_z, bias, shadow_res);
+    float t10 = shadow_tap(shadow_tex, pos + vec2(step.x, 0.0), receiver_z, bias, shadow_res);
+    float t01 = shadow_tap(shadow_tex, pos + vec2(0.0, step.y), receiver_z, bias, shadow_res);
+    float t11 = shadow_tap(shadow_tex, pos + vec2(step.x, step.y), receiver_z, bias, shadow_res);
 
     return mix(mix(t00, t10, f.x), mix(t01, t11, f.x), f.y);
 }
@@ -56,7 +67,7 @@ float bilinear_shadow_cont(sampler2D shadow_tex, vec2 uv, float receiver_z, floa
     return float(receiver_z > result - bias);
 }
 
-float sample_shadow_map_castano_thirteen(sampler2D shadow_tex, vec2 uv, float receiver_z, float bias, vec2 shadow_res) {
+float sample_shadow_map_castano_thirteen(shadow_sampler2D shadow_tex, vec2 uv, float receiver_z, float bias, vec2 shadow_res) {
     vec2 inv_map_size = vec2(1.0) / shadow_res;
 
     uv = uv * shadow_res;
@@ -84,17 +95,51 @@ float sample_shadow_map_castano_thirteen(sampler2D shadow_tex, vec2 uv, float re
 
     float sum = 0.0;
 
-    sum += uw0 * vw0 * bilinear_shadow(shadow_tex, base_uv + vec2(u0, v0) * inv_map_size, receiver_z, bias, shadow_res);
-    sum += uw1 * vw0 * bilinear_shadow(shadow_tex, base_uv + vec2(u1, v0) * inv_map_size, receiver_z, bias, shadow_res);
-    sum += uw2 * vw0 * bilinear_shadow(shadow_tex, base_uv + vec2(u2, v0) * inv_map_size, receiver_z, bias, shadow_res);
+    sum += uw0 * vw0 * shadow_tap(shadow_tex, base_uv + vec2(u0, v0) * inv_map_size, receiver_z, bias, shadow_res);
+    sum += uw1 * vw0 * shadow_tap(shadow_tex, base_uv + vec2(u1, v0) * inv_map_size, receiver_z, bias, shadow_res);
+    sum += uw2 * vw0 * shadow_tap(shadow_tex, base_uv + vec2(u2, v0) * inv_map_size, receiver_z, bias, shadow_res);
 
-    sum += uw0 * vw1 * bilinear_shadow(shadow_tex, base_uv + vec2(u0, v1) * inv_map_size, receiver_z, bias, shadow_res);
-    sum += uw1 * vw1 * bilinear_shadow(shadow_tex, base_uv + vec2(u1, v1) * inv_map_size, receiver_z, bias, shadow_res);
-    sum += uw2 * vw1 * bilinear_shadow(shadow_tex, base_uv + vec2(u2, v1) * inv_map_size, receiver_z, bias, shadow_res);
+    sum += uw0 * vw1 * shadow_tap(shadow_tex, base_uv + vec2(u0, v1) * inv_map_size, receiver_z, bias, shadow_res);
+    sum += uw1 * vw1 * shadow_tap(shadow_tex, base_uv + vec2(u1, v1) * inv_map_size, receiver_z, bias, shadow_res);
+    sum += uw2 * vw1 * shadow_tap(shadow_tex, base_uv + vec2(u2, v1) * inv_map_size, receiver_z, bias, shadow_res);
 
-    sum += uw0 * vw2 * bilinear_shadow(shadow_tex, base_uv + vec2(u0, v2) * inv_map_size, receiver_z, bias, shadow_res);
-    sum += uw1 * vw2 * bilinear_shadow(shadow_tex, base_uv + vec2(u1, v2) * inv_map_size, receiver_z, bias, shadow_res);
-    sum += uw2 * vw2 * bilinear_shadow(shadow_tex, base_uv + vec2(u2, v2) * inv_map_size, receiver_z, bias, shadow_res);
+    sum += uw0 * vw2 * shadow_tap(shadow_tex, base_uv + vec2(u0, v2) * inv_map_size, receiver_z, bias, shadow_res);
+    sum += uw1 * vw2 * shadow_tap(shadow_tex, base_uv + vec2(u1, v2) * inv_map_size, receiver_z, bias, shadow_res);
+    sum += uw2 * vw2 * shadow_tap(shadow_tex, base_uv + vec2(u2, v2) * inv_map_size, receiver_z, bias, shadow_res);
 
     return sum / 144.0;
 }
+
+// Expects one of SHADOW_FILTER_HARD, SHADOW_FILTER_PCF3X3 or SHADOW_FILTER_PCF5X5
+float filtered_shadow(shadow_sampler2D shadow_tex, vec2 uv, float receiver_z, float bias, vec2 shadow_res) {
+#ifdef SHADOW_FILTER_HARD
+#ifdef SHADOW_DEPTH_TEXTURE
+    return shadow2D(shadow_tex, vec3(uv, receiver_z + bias)).r;
+#else
+    return float(receiver_z > DecodeFloatRGBA(texture2D(shadow_tex, uv)) - bias);
+#endif
+#else
+#ifdef SHADOW_FILTER_PCF5X5
+    return hardenedKernel(sample_shadow_map_castano_thirteen(shadow_tex, uv, receiver_z, bias, shadow_res));
+#else
+    return hardenedKernel(bilinear_shadow2(shadow_tex, uv, receiver_z, bias, shadow_res));
+#endif
+#endif
+}
+
+// Cube map shadow from PointLightShadow. light_to_frag is the world space offset from the light to the fragment.
+// params: near, depth_bias, normal_bias, cube face size
+float sample_point_shadow(samplerCube shadow_tex, vec3 light_to_frag, vec3 normal, vec4 params) {
+    vec3 a = abs(light_to_frag);
+    float major = max(a.x, max(a.y, a.z));
+    // Normal bias is in texels at the fragment's distance, depth bias is in world units along the major axis.
+    light_to_frag += normal * params.z * 2.0 * major / params.w;
+    a = abs(light_to_frag);
+    major = max(a.x, max(a.y, a.z));
+    // Faces are rendered with infinite reverse-z, so ndc z = near / view depth.
+    float occluder_ndc = DecodeFloatRGBA(textureCube(shadow_tex, light_to_frag)) * 2.0 - 1.0;
+    if (occluder_ndc <= 0.0) {
+        return 1.0;
+    }
+    return float(major - params.y < params.x / occluder_ndc);
+}
@@ -0,0 +1,25 @@
+#include std::math
+#include std::agx
+#include std::tonemapping
+
+uniform mat4 world_from_clip;
+
+varying vec2 ndc;
+
+void main() {
+    // Reverse z, ndc z = 1 is the near plane.
+    vec4 near_point = world_from_clip * vec4(ndc, 1.0, 1.0);
+    vec3 dir = ub_env_rotation * normalize(near_point.xyz / near_point.w - ub_view_position);
+    vec3 color = rgbe2rgb(textureCube(ub_specular_map, vec3(dir.xy, -dir.z))) * ub_env_intensity;
+    gl_FragColor = vec4(ub_view_exposure * color, 1.0);
+    #ifdef WRITE_REFLECTION
+        gl_FragColor.rgb = reversible_tonemap(gl_FragColor.rgb);
+    #else
+        gl_FragColor.rgb = tonemap(gl_FragColor.rgb); // in: linear, out: srgb
+    #endif // WRITE_REFLECTION
+    #ifdef TONEMAP_HDR_OUTPUT
+    gl_FragColor = vec4(max(gl_FragColor.rgb, vec3(0.0)), clamp(gl_FragColor.a, 0.0, 1.0));
+    #else
+    gl_FragColor = clamp(gl_FragColor, vec4(0.0), vec4(1.0));
+    #endif
+}
@@ -0,0 +1,9 @@
+attribute vec2 position;
+
+varying vec2 ndc;
+
+void main() {
+    ndc = position;
+    // At the far plane, see SkyboxPlugin.
+    gl_Position = vec4(position, 0.0, 1.0);
+}
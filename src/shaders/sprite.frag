@@ -0,0 +1,14 @@
+// Drawn after tonemapping. Sprite textures are uploaded without sRGB decoding, so both the texels and the tint are
+// already sRGB, see Sprite::color.
+
+uniform sampler2D sprite_texture;
+
+varying vec2 sprite_uv;
+varying vec4 sprite_color;
+
+void main() {
+    gl_FragColor = texture2D(sprite_texture, sprite_uv) * sprite_color;
+#ifdef SRGB_FRAMEBUFFER
+    gl_FragColor.rgb = pow(gl_FragColor.rgb, vec3(2.2));
+#endif
+}
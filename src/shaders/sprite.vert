@@ -0,0 +1,15 @@
+attribute vec2 position;
+attribute vec2 uv;
+attribute vec4 color;
+
+// Physical pixels of the window, positions are relative to its center.
+uniform vec2 screen_size;
+
+varying vec2 sprite_uv;
+varying vec4 sprite_color;
+
+void main() {
+    gl_Position = vec4(position * 2.0 / screen_size, 0.0, 1.0);
+    sprite_uv = uv;
+    sprite_color = color;
+}
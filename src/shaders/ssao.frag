@@ -0,0 +1,78 @@
+// See SsaoPlugin. Tests points in the hemisphere around each pixel's normal against the depth prepass. Red is 1 where
+// unoccluded.
+
+// Must match SSAO_SAMPLES in ssao.rs
+#define SSAO_SAMPLES 16
+
+// The prepass depth
+uniform sampler2D source_texture;
+uniform vec2 source_size;
+// x, y, width, height in window pixels, GL's bottom left origin
+uniform vec4 ssao_viewport;
+uniform mat4 clip_from_view;
+uniform mat4 view_from_clip;
+uniform vec3 ssao_kernel[SSAO_SAMPLES];
+uniform float ssao_radius;
+uniform float ssao_bias;
+uniform float ssao_intensity;
+
+// Across the viewport
+varying vec2 uv;
+
+float depth_at(vec2 viewport_uv) {
+    return texture2D(source_texture, (ssao_viewport.xy + viewport_uv * ssao_viewport.zw) / source_size).r;
+}
+
+vec3 view_position(vec2 viewport_uv, float depth) {
+    // Reverse z, 0 is infinitely far with an infinite projection.
+    vec4 view = view_from_clip * vec4(viewport_uv * 2.0 - 1.0, max(depth, 1e-7) * 2.0 - 1.0, 1.0);
+    return view.xyz / view.w;
+}
+
+vec3 view_position_at(vec2 viewport_uv) {
+    return view_position(viewport_uv, depth_at(viewport_uv));
+}
+
+void main() {
+    float depth = depth_at(uv);
+    if (depth <= 0.0) {
+        // Nothing was drawn here.
+        gl_FragColor = vec4(1.0);
+        return;
+    }
+    vec3 position = view_position(uv, depth);
+
+    // The normal from the neighbors on the same surface, the closer of each pair so edges don't smear.
+    vec2 texel = 1.0 / ssao_viewport.zw;
+    vec3 right = view_position_at(uv + vec2(texel.x, 0.0)) - position;
+    vec3 left = position - view_position_at(uv - vec2(texel.x, 0.0));
+    vec3 up = view_position_at(uv + vec2(0.0, texel.y)) - position;
+    vec3 down = position - view_position_at(uv - vec2(0.0, texel.y));
+    vec3 dx = abs(right.z) < abs(left.z) ? right : left;
+    vec3 dy = abs(up.z) < abs(down.z) ? up : down;
+    vec3 normal = normalize(cross(dx, dy));
+    // Towards the camera
+    if (dot(normal, position) > 0.0) {
+        normal = -normal;
+    }
+
+    // A rotation that repeats every 4x4 pixels, which the blur averages out.
+    vec2 cell = mod(floor(gl_FragCoord.xy), 4.0);
+    float angle = fract(sin(dot(cell, vec2(12.9898, 78.233))) * 43758.5453) * 6.2831853;
+    vec3 random = vec3(cos(angle), sin(angle), 0.0);
+    vec3 tangent = normalize(random - normal * dot(random, normal));
+    mat3 tbn = mat3(tangent, cross(normal, tangent), normal);
+
+    float occlusion = 0.0;
+    for (int i = 0; i < SSAO_SAMPLES; i++) {
+        vec3 sample_position = position + tbn * ssao_kernel[i] * ssao_radius;
+        vec4 clip = clip_from_view * vec4(sample_position, 1.0);
+        vec2 sample_uv = clip.xy / clip.w * 0.5 + 0.5;
+        float scene_z = view_position_at(sample_uv).z;
+        // Geometry much closer to the camera than the pixel doesn't occlude it.
+        float range = smoothstep(0.0, 1.0, ssao_radius / abs(position.z - scene_z));
+        occlusion += (scene_z >= sample_position.z + ssao_bias ? 1.0 : 0.0) * range;
+    }
+    float ao = pow(1.0 - occlusion / float(SSAO_SAMPLES), ssao_intensity);
+    gl_FragColor = vec4(vec3(ao), 1.0);
+}
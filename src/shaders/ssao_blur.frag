@@ -0,0 +1,16 @@
+// See SsaoPlugin. Averages the 4x4 pixels the occlusion's rotation pattern repeats over.
+
+uniform sampler2D source_texture;
+uniform vec2 source_size;
+
+void main() {
+    vec2 texel = 1.0 / source_size;
+    vec2 center = gl_FragCoord.xy * texel;
+    float ao = 0.0;
+    for (int y = -2; y < 2; y++) {
+        for (int x = -2; x < 2; x++) {
+            ao += texture2D(source_texture, center + vec2(float(x), float(y)) * texel).r;
+        }
+    }
+    gl_FragColor = vec4(vec3(ao / 16.0), 1.0);
+}
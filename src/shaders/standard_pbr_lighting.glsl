@@ -1,3 +1,33 @@
+#ifndef NO_POINT
+// Light i of the point light arrays, point or spot.
+vec3 apply_point_light(int i, vec3 V, vec3 diffuse_color, vec3 F0, vec3 normal, float roughness,
+        float diffuse_transmission, vec3 ws_position, float point_shadow) {
+    vec4 light_position_range = ub_point_light_position_range[i];
+    vec3 to_light = light_position_range.xyz - ws_position;
+    if (length(to_light) >= light_position_range.w) {
+        return vec3(0.0);
+    }
+    vec4 light_color_radius = ub_point_light_color_radius[i];
+    vec4 dos = ub_spot_light_dir_offset_scale[i];
+    vec3 spot_dir = octahedral_decode(dos.xy);
+    // Point lights come first. An offset of 1 and scale of 0 turns off the cone.
+    bool is_spot = i >= ub_point_light_count;
+    float spot_offset = is_spot ? dos.z : 1.0;
+    float spot_scale = is_spot ? dos.w : 0.0;
+    vec3 light = point_light(V, diffuse_color, F0, normal, roughness, diffuse_transmission, to_light,
+            light_position_range.w, light_color_radius.rgb, spot_dir, spot_offset, spot_scale);
+    if (i == ub_point_shadow_index) {
+        light *= point_shadow;
+    }
+    return light;
+}
+#endif // NO_POINT
+
+#ifdef CLUSTERED_LIGHTING
+// Must match MAX_LIGHTS_PER_CLUSTER / 4 in clustered_lighting.rs
+#define CLUSTER_TEXELS 4
+#endif // CLUSTERED_LIGHTING
+
 vec3 apply_pbr_lighting(vec3 V, vec3 diffuse_color, vec3 F0, vec3 vert_normal, vec3 normal, float perceptual_roughness,
     float environment_occlusion, float diffuse_transmission, vec2 screen_uv, vec2 view_resolution, vec3 ws_position) {
     float roughness = perceptual_roughness * perceptual_roughness;
@@ -7,14 +37,29 @@ vec3 apply_pbr_lighting(vec3 V, vec3 diffuse_color, vec3 F0, vec3 vert_normal, v
     float dir_shadow = 1.0;
     #ifdef SAMPLE_SHADOW
     float bias = 0.002;
-    float normal_bias = 0.05;
-    vec4 shadow_clip = ub_shadow_clip_from_world * vec4(ws_position + vert_normal * normal_bias, 1.0);
+    // Select the first cascade that covers the view space depth
+    float view_depth = -dot(ub_shadow_view_z_from_world, vec4(ws_position, 1.0));
+    float cascade = -1.0;
+    mat4 shadow_clip_from_world = ub_shadow_clip_from_world[0];
+    for (int i = 0; i < MAX_CASCADES; i++) {
+        if (cascade < 0.0 && i < ub_shadow_cascade_count && view_depth < ub_shadow_cascade_far_bounds[i]) {
+            cascade = float(i);
+            shadow_clip_from_world = ub_shadow_clip_from_world[i];
+        }
+    }
+    // Same units as bevy: normal bias is in shadow map texels, depth bias is in world units towards the light.
+    vec2 shadow_tile_res = ub_shadow_resolution / ub_shadow_atlas_tiles;
+    float texel_size = 2.0 / (length(vec3(shadow_clip_from_world[0][0], shadow_clip_from_world[1][0], shadow_clip_from_world[2][0])) * shadow_tile_res.x);
+    vec3 shadow_offset = vert_normal * ub_shadow_normal_bias * texel_size - ub_directional_light_dir * ub_shadow_depth_bias;
+    vec4 shadow_clip = shadow_clip_from_world * vec4(ws_position + shadow_offset, 1.0);
     vec3 shadow_uvz = (shadow_clip.xyz / shadow_clip.w) * 0.5 + 0.5;
 
-    if (shadow_uvz.x > 0.0 && shadow_uvz.x < 1.0 && shadow_uvz.y > 0.0 && shadow_uvz.y < 1.0 && shadow_uvz.z > 0.0 && shadow_uvz.z < 1.0) {
-        dir_shadow *= bilinear_shadow2(ub_shadow_texture, shadow_uvz.xy, shadow_uvz.z, bias, view_resolution);
-        //dir_shadow *= sample_shadow_map_castano_thirteen(ub_shadow_texture, shadow_uvz.xy, shadow_uvz.z, bias, view_resolution);
-        dir_shadow = hardenedKernel(dir_shadow);
+    if (cascade >= 0.0 && shadow_uvz.x > 0.0 && shadow_uvz.x < 1.0 && shadow_uvz.y > 0.0 && shadow_uvz.y < 1.0 && shadow_uvz.z > 0.0 && shadow_uvz.z < 1.0) {
+        // Cascades are tiled in the shadow texture. Keep the filter footprint inside the tile.
+        vec2 tile = vec2(mod(cascade, ub_shadow_atlas_tiles.x), floor(cascade / ub_shadow_atlas_tiles.x));
+        vec2 tile_margin = 3.0 * ub_shadow_atlas_tiles / ub_shadow_resolution;
+        vec2 shadow_uv = (clamp(shadow_uvz.xy, tile_margin, 1.0 - tile_margin) + tile) / ub_shadow_atlas_tiles;
+        dir_shadow *= filtered_shadow(ub_shadow_texture, shadow_uv, shadow_uvz.z, bias, ub_shadow_resolution);
     }
     #endif // SAMPLE_SHADOW
 
@@ -26,28 +71,70 @@ vec3 apply_pbr_lighting(vec3 V, vec3 diffuse_color, vec3 F0, vec3 vert_normal, v
         // Environment map
         float NoV = abs(dot(normal, V)) + 1e-5;
         float mip_levels = 8.0; // TODO put in uniform
-        vec3 dir = reflect(-V, normal);
-        vec3 env_diffuse = rgbe2rgb(textureCubeLod(ub_diffuse_map, vec3(normal.xy, -normal.z), 0.0)) * ub_env_intensity;
+        vec3 env_normal = ub_env_rotation * normal;
+        vec3 dir = ub_env_rotation * reflect(-V, normal);
+        vec3 env_diffuse = rgbe2rgb(textureCubeLod(ub_diffuse_map, vec3(env_normal.xy, -env_normal.z), 0.0)) * ub_env_intensity;
         vec3 env_specular = rgbe2rgb(textureCubeLod(ub_specular_map, vec3(dir.xy, -dir.z), perceptual_roughness * mip_levels)) * ub_env_intensity;
         output_color += environment_light(NoV, F0, perceptual_roughness, diffuse_color, env_diffuse, env_specular) * environment_occlusion;
+    #else
+        // Flat ambient fill light
+        output_color += diffuse_color * ub_ambient_color * ub_ambient_brightness * environment_occlusion;
     #endif // NO_ENV
 
     #ifndef NO_POINT
+    float point_shadow = 1.0;
+    #ifdef SAMPLE_POINT_SHADOW
+    // Sampled outside the loop to keep the texture lookup out of non-uniform control flow.
+    point_shadow = sample_point_shadow(ub_point_shadow_texture, ws_position - ub_point_shadow_position, vert_normal,
+            ub_point_shadow_params);
+    #endif // SAMPLE_POINT_SHADOW
+
+    #ifdef CLUSTERED_LIGHTING
+    // Only the lights assigned to this fragment's cluster, see ClusteredLightingPlugin. Each texel holds 4 light
+    // indices, 255 is empty.
+    float cluster_depth = -dot(ub_cluster_view_z_from_world, vec4(ws_position, 1.0));
+    vec2 cluster_tile = clamp(floor(screen_uv * ub_cluster_grid.xy), vec2(0.0), ub_cluster_grid.xy - 1.0);
+    float cluster_slice = clamp(floor(log(max(cluster_depth, 1e-4)) * ub_cluster_z_params.x + ub_cluster_z_params.y),
+            0.0, ub_cluster_grid.z - 1.0);
+    vec2 cluster_texel = vec2(cluster_tile.x * float(CLUSTER_TEXELS), cluster_tile.y + cluster_slice * ub_cluster_grid.y);
+    for (int t = 0; t < CLUSTER_TEXELS; t++) {
+        vec2 cluster_uv = (cluster_texel + vec2(float(t) + 0.5, 0.5)) / ub_cluster_z_params.zw;
+        vec4 indices = floor(texture2D(ub_cluster_texture, cluster_uv) * 255.0 + 0.5);
+        for (int c = 0; c < 4; c++) {
+            if (indices[c] < 255.0) {
+                output_color += apply_point_light(int(indices[c]), V, diffuse_color, F0, normal, roughness,
+                        diffuse_transmission, ws_position, point_shadow);
+            }
+        }
+    }
+    #else
     // Point Lights
     for (int i = 0; i < MAX_POINT_LIGHTS; i++) {
         if (i < ub_light_count) {
-            vec4 light_position_range = ub_point_light_position_range[i];
-            vec3 to_light = light_position_range.xyz - ws_position;
-            if (length(to_light) < light_position_range.w) {
-                vec4 light_color_radius = ub_point_light_color_radius[i];
-                vec4 dos = ub_spot_light_dir_offset_scale[i];
-                vec3 spot_dir = octahedral_decode(dos.xy);
-                output_color += point_light(V, diffuse_color, F0, normal, roughness, diffuse_transmission, to_light,
-                        light_position_range.w, light_color_radius.rgb, spot_dir, dos.z, dos.w);
-            }
+            output_color += apply_point_light(i, V, diffuse_color, F0, normal, roughness, diffuse_transmission,
+                    ws_position, point_shadow);
         }
     }
+    #endif // CLUSTERED_LIGHTING
     #endif // NO_POINT
 
     return output_color;
 }
+
+#ifndef NO_FOG
+// view_depth is the positive view space depth. Expects one of FOG_LINEAR, FOG_EXPONENTIAL, FOG_EXPONENTIAL_SQUARED.
+vec3 apply_fog(vec3 color, float view_depth) {
+    float fog = 0.0;
+    #ifdef FOG_LINEAR
+    fog = saturate((view_depth - ub_fog_params.x) / max(ub_fog_params.y - ub_fog_params.x, 1e-4));
+    #endif // FOG_LINEAR
+    #ifdef FOG_EXPONENTIAL
+    fog = 1.0 - exp(-view_depth * ub_fog_params.z);
+    #endif // FOG_EXPONENTIAL
+    #ifdef FOG_EXPONENTIAL_SQUARED
+    float fog_depth = view_depth * ub_fog_params.z;
+    fog = 1.0 - exp(-fog_depth * fog_depth);
+    #endif // FOG_EXPONENTIAL_SQUARED
+    return mix(color, ub_fog_color.rgb, fog * ub_fog_color.a);
+}
+#endif // NO_FOG
@@ -6,8 +6,41 @@ attribute vec2 Vertex_Uv;
 attribute vec4 Vertex_JointWeight;
 attribute vec4 Vertex_JointIndex;
 
+#ifdef HAS_MORPH_TARGETS
+// Position and normal deltas of the first 4 morph targets, see prepare_mesh::MAX_MORPH_TARGETS
+attribute vec3 Vertex_MorphPosition0;
+attribute vec3 Vertex_MorphPosition1;
+attribute vec3 Vertex_MorphPosition2;
+attribute vec3 Vertex_MorphPosition3;
+attribute vec3 Vertex_MorphNormal0;
+attribute vec3 Vertex_MorphNormal1;
+attribute vec3 Vertex_MorphNormal2;
+attribute vec3 Vertex_MorphNormal3;
+uniform vec4 morph_weights;
+#endif
+
 uniform mat4 world_from_local;
+#ifdef JOINT_AFFINE
+// The top 3 rows of each joint matrix, see JointEncoding::Affine3x4
+uniform vec4 joint_data[MAX_JOINTS * 3];
+
+mat4 joint_matrix(int i) {
+    vec4 r0 = joint_data[i * 3];
+    vec4 r1 = joint_data[i * 3 + 1];
+    vec4 r2 = joint_data[i * 3 + 2];
+    return mat4(
+        vec4(r0.x, r1.x, r2.x, 0.0),
+        vec4(r0.y, r1.y, r2.y, 0.0),
+        vec4(r0.z, r1.z, r2.z, 0.0),
+        vec4(r0.w, r1.w, r2.w, 1.0));
+}
+#else
 uniform mat4 joint_data[MAX_JOINTS];
+
+mat4 joint_matrix(int i) {
+    return joint_data[i];
+}
+#endif
 uniform bool has_joint_data;
 
 varying vec4 clip_position;
@@ -18,19 +51,33 @@ varying vec2 uv_0;
 
 void main() {
     mat4 world_from_local = world_from_local;
+    vec3 position = Vertex_Position;
+    vec3 normal = Vertex_Normal;
+
+#ifdef HAS_MORPH_TARGETS
+    // Meshes without morph targets are drawn with zero weights.
+    position += morph_weights.x * Vertex_MorphPosition0 +
+            morph_weights.y * Vertex_MorphPosition1 +
+            morph_weights.z * Vertex_MorphPosition2 +
+            morph_weights.w * Vertex_MorphPosition3;
+    normal += morph_weights.x * Vertex_MorphNormal0 +
+            morph_weights.y * Vertex_MorphNormal1 +
+            morph_weights.z * Vertex_MorphNormal2 +
+            morph_weights.w * Vertex_MorphNormal3;
+#endif
 
     if (has_joint_data) {
         ivec4 indices = ivec4(Vertex_JointIndex);
-        world_from_local = Vertex_JointWeight.x * joint_data[indices.x] +
-                Vertex_JointWeight.y * joint_data[indices.y] +
-                Vertex_JointWeight.z * joint_data[indices.z] +
-                Vertex_JointWeight.w * joint_data[indices.w];
+        world_from_local = Vertex_JointWeight.x * joint_matrix(indices.x) +
+                Vertex_JointWeight.y * joint_matrix(indices.y) +
+                Vertex_JointWeight.z * joint_matrix(indices.z) +
+                Vertex_JointWeight.w * joint_matrix(indices.w);
     }
 
-    clip_position = (ub_clip_from_world * world_from_local) * vec4(Vertex_Position, 1.0);
+    clip_position = (ub_clip_from_world * world_from_local) * vec4(position, 1.0);
     gl_Position = clip_position;
-    vert_normal = (world_from_local * vec4(Vertex_Normal, 0.0)).xyz;
-    ws_position = (world_from_local * vec4(Vertex_Position, 1.0)).xyz;
+    vert_normal = (world_from_local * vec4(normal, 0.0)).xyz;
+    ws_position = (world_from_local * vec4(position, 1.0)).xyz;
     uv_0 = Vertex_Uv;
-    tangent = Vertex_Tangent;
+    tangent = vec4((world_from_local * vec4(Vertex_Tangent.xyz, 0.0)).xyz, Vertex_Tangent.w);
 }
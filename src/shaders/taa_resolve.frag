@@ -0,0 +1,54 @@
+// See TaaPlugin. Blends the current frame into the history, reprojected to where each pixel was in the previous frame
+// and first clamped to the range of colors around the pixel so that whatever moved away doesn't linger.
+
+uniform sampler2D source_texture;
+uniform vec2 source_size;
+uniform sampler2D history_texture;
+uniform float taa_blend;
+// x, y, width, height in window pixels, GL's bottom left origin
+uniform vec4 taa_viewport;
+// The previous frame's clip space from this frame's, both without the jitter.
+uniform mat4 history_from_clip;
+
+#ifdef TAA_DEPTH
+// The prepass depth, reverse z.
+uniform sampler2D depth_texture;
+#endif
+
+varying vec2 uv;
+
+// Where this pixel was in the history, in window uv.
+vec2 history_uv() {
+    vec2 viewport_uv = (uv * source_size - taa_viewport.xy) / taa_viewport.zw;
+    if (any(lessThan(viewport_uv, vec2(0.0))) || any(greaterThan(viewport_uv, vec2(1.0)))) {
+        return uv;
+    }
+#ifdef TAA_DEPTH
+    float depth = texture2D(depth_texture, uv).r;
+#else
+    // Without a prepass everything is taken to be infinitely far, which only follows the camera's rotation.
+    float depth = 0.0;
+#endif
+    vec4 previous = history_from_clip * vec4(viewport_uv * 2.0 - 1.0, max(depth, 1e-7) * 2.0 - 1.0, 1.0);
+    vec2 previous_uv = previous.xy / previous.w * 0.5 + 0.5;
+    return (taa_viewport.xy + previous_uv * taa_viewport.zw) / source_size;
+}
+
+void main() {
+    vec2 texel = 1.0 / source_size;
+    vec4 current = texture2D(source_texture, uv);
+    vec3 lo = current.rgb;
+    vec3 hi = current.rgb;
+    for (int y = -1; y <= 1; y++) {
+        for (int x = -1; x <= 1; x++) {
+            vec3 neighbor = texture2D(source_texture, uv + vec2(float(x), float(y)) * texel).rgb;
+            lo = min(lo, neighbor);
+            hi = max(hi, neighbor);
+        }
+    }
+    vec2 previous_uv = history_uv();
+    // Pixels that were off screen have no history.
+    float blend = any(lessThan(previous_uv, vec2(0.0))) || any(greaterThan(previous_uv, vec2(1.0))) ? 1.0 : taa_blend;
+    vec3 history = clamp(texture2D(history_texture, previous_uv).rgb, lo, hi);
+    gl_FragColor = vec4(mix(history, current.rgb, blend), current.a);
+}
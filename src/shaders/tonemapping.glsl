@@ -0,0 +1,62 @@
+// Expects std::math and std::agx to be included first, and StandardLightingUniforms for TONEMAP_TONY.
+// Selected with TONEMAP_NONE, TONEMAP_REINHARD, TONEMAP_REINHARD_LUMINANCE, TONEMAP_TONY, or TONEMAP_AGX. Defaults to
+// AgX. TONEMAP_HDR_OUTPUT leaves the color linear for a post process pass to tonemap, see ViewUniforms::hdr_output.
+
+float tonemapping_luminance(vec3 v) {
+    return dot(v, vec3(0.2126, 0.7152, 0.0722));
+}
+
+vec3 tonemapping_reinhard(vec3 color) {
+    return color / (1.0 + color);
+}
+
+vec3 tonemapping_reinhard_luminance(vec3 color) {
+    float l_old = tonemapping_luminance(color);
+    float l_new = l_old / (1.0 + l_old);
+    return color * (l_new / max(l_old, 1e-5));
+}
+
+#ifdef TONEMAP_TONY
+// https://github.com/h3r2tic/tony-mc-mapface, sampled the same way as bevy. The LUT is already gamma encoded, see
+// tony_mc_mapface_lut_image().
+vec3 tonemapping_tony_mc_mapface(vec3 stimulus) {
+    const float lut_size = 48.0;
+    vec3 uvw = (stimulus / (stimulus + 1.0)) * ((lut_size - 1.0) / lut_size) + 0.5 / lut_size;
+    #ifdef TONEMAPPING_LUT_3D
+    return texture3D(ub_tonemapping_lut, uvw).rgb;
+    #else
+    // Slices are tiled left to right, blend between the two nearest.
+    float slice = uvw.z * lut_size - 0.5;
+    float slice0 = clamp(floor(slice), 0.0, lut_size - 1.0);
+    float slice1 = min(slice0 + 1.0, lut_size - 1.0);
+    vec3 color0 = texture2D(ub_tonemapping_lut, vec2((uvw.x + slice0) / lut_size, uvw.y)).rgb;
+    vec3 color1 = texture2D(ub_tonemapping_lut, vec2((uvw.x + slice1) / lut_size, uvw.y)).rgb;
+    return mix(color0, color1, saturate(slice - slice0));
+    #endif // TONEMAPPING_LUT_3D
+}
+#endif // TONEMAP_TONY
+
+vec3 tonemap_to_srgb(vec3 color) {
+    #if defined(TONEMAP_NONE)
+    return from_linear(saturate(color));
+    #elif defined(TONEMAP_REINHARD)
+    return from_linear(tonemapping_reinhard(color));
+    #elif defined(TONEMAP_REINHARD_LUMINANCE)
+    return from_linear(saturate(tonemapping_reinhard_luminance(color)));
+    #elif defined(TONEMAP_TONY)
+    return tonemapping_tony_mc_mapface(color);
+    #else
+    return agx_tonemapping(color);
+    #endif
+}
+
+vec3 tonemap(vec3 color) { // in: linear, out: srgb, or linear with SRGB_FRAMEBUFFER
+    #if defined(TONEMAP_HDR_OUTPUT)
+    return color;
+    #elif defined(SRGB_FRAMEBUFFER)
+    // The window encodes to sRGB on write, see BevyGlContextConfig::srgb_framebuffer.
+    return to_linear(tonemap_to_srgb(color));
+    #else
+    return tonemap_to_srgb(color);
+    #endif
+}
@@ -0,0 +1,16 @@
+// See PostProcessPass::vignette()
+
+uniform sampler2D source_texture;
+uniform vec2 source_size;
+uniform float vignette_strength;
+uniform float vignette_radius;
+
+varying vec2 uv;
+
+void main() {
+    vec4 color = texture2D(source_texture, uv);
+    // Round regardless of the aspect ratio, 0.5 is the top and bottom edge.
+    vec2 centered = (uv - 0.5) * vec2(source_size.x / source_size.y, 1.0);
+    float falloff = smoothstep(vignette_radius, vignette_radius + 0.5, length(centered));
+    gl_FragColor = vec4(color.rgb * (1.0 - vignette_strength * falloff), color.a);
+}
@@ -0,0 +1,134 @@
+use bevy::prelude::*;
+use bytemuck::cast_slice;
+use glow::{Buffer, HasContext, VertexArray};
+
+use crate::{
+    AttribType, RenderState,
+    bevy_standard_lighting::StandardLightingUniforms,
+    command_encoder::{CommandEncoder, remove_render_resource_on_restore},
+    phase_shadow::DEFAULT_MAX_CASCADES_DEF,
+    prepare_image::GpuImages,
+    prepare_view::ViewUniforms,
+    render::{RenderCamera, RenderPhase, RenderSet, register_render_system},
+    shader_cached,
+};
+
+/// Draws the specular map of the camera's EnvironmentMapLight behind the scene, in the opaque phase of the camera and
+/// of plane reflections. Uses the cube map already bound for lighting, see StandardLightingUniforms::specular_map, so
+/// needs OpenGLStandardLightingPlugin and OpenGLStandardMaterialPlugin for the shader includes.
+///
+/// The sky is a fullscreen triangle at the far plane, which with reverse z only passes where nothing closer has been
+/// drawn. It doesn't write depth, so it composites the same whether it's drawn before or after opaque draws.
+pub struct SkyboxPlugin;
+
+impl Plugin for SkyboxPlugin {
+    fn build(&self, app: &mut App) {
+        register_render_system::<SkyboxPlugin, _>(app.world_mut(), render_skybox);
+        app.add_systems(
+            PostUpdate,
+            remove_render_resource_on_restore::<SkyboxTriangle>.in_set(RenderSet::Acquire),
+        );
+    }
+}
+
+/// Render world fullscreen triangle.
+#[derive(Resource)]
+struct SkyboxTriangle {
+    triangle: Buffer,
+    vertex_array: Option<VertexArray>,
+}
+
+fn render_skybox(
+    phase: Res<RenderPhase>,
+    render_camera: Option<Res<RenderCamera>>,
+    env_maps: Query<(), With<EnvironmentMapLight>>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    if !matches!(*phase, RenderPhase::Opaque | RenderPhase::ReflectOpaque) {
+        return;
+    }
+    if !render_camera.is_some_and(|c| env_maps.contains(**c)) {
+        return;
+    }
+    let phase = *phase;
+
+    enc.record(move |ctx, world| {
+        let lighting = world.resource::<StandardLightingUniforms>().clone();
+        let Some(specular_map) = &lighting.specular_map else {
+            return;
+        };
+        // Until the cube map is uploaded the placeholder would be drawn as a white sky.
+        if !world
+            .resource::<GpuImages>()
+            .bevy_textures
+            .contains_key(&specular_map.id())
+        {
+            return;
+        }
+        let view_uniforms = world.resource::<ViewUniforms>().clone();
+        let Some(shader_index) = shader_cached!(
+            ctx,
+            "shaders/skybox.vert",
+            "shaders/skybox.frag",
+            [
+                lighting.max_lights_def(),
+                DEFAULT_MAX_CASCADES_DEF,
+                view_uniforms.tonemapping_shader_def(lighting.tonemapping_lut.get().is_some()),
+            ]
+            .iter()
+            .chain(lighting.shader_defs(false, false, &phase).iter())
+            .chain(phase.shader_defs().iter()),
+            &[
+                ViewUniforms::bindings(),
+                StandardLightingUniforms::bindings()
+            ]
+        ) else {
+            return;
+        };
+        ctx.use_cached_program(shader_index);
+        ctx.map_uniform_set_locations::<ViewUniforms>();
+        ctx.bind_uniforms_set(world.resource::<GpuImages>(), &view_uniforms);
+        ctx.map_uniform_set_locations::<StandardLightingUniforms>();
+        ctx.bind_uniforms_set(world.resource::<GpuImages>(), &lighting);
+        ctx.load("world_from_clip", view_uniforms.clip_from_world.inverse());
+
+        // Render systems after this one continue with the state the phase was started with.
+        let previous_state = ctx.render_state;
+        ctx.apply_render_state(RenderState {
+            cull_mode: None,
+            blend: false,
+            depth_test: true,
+            depth_write: false,
+            depth_func: glow::GEQUAL,
+            color_write: [true; 4],
+            polygon_mode: glow::FILL,
+            polygon_offset: None,
+        });
+
+        if !world.contains_resource::<SkyboxTriangle>() {
+            let triangle = ctx.gen_vbo(
+                cast_slice(&[-1.0f32, -1.0, 3.0, -1.0, -1.0, 3.0]),
+                glow::STATIC_DRAW,
+            );
+            let vertex_array = ctx
+                .has_vertex_array_object
+                .then(|| unsafe { ctx.gl.create_vertex_array().unwrap() });
+            world.insert_resource(SkyboxTriangle {
+                triangle,
+                vertex_array,
+            });
+        }
+        let sky = world.resource::<SkyboxTriangle>();
+        if let Some(position) = ctx.cached_attrib_location(shader_index, "position") {
+            unsafe {
+                if sky.vertex_array.is_some() {
+                    ctx.bind_vertex_array(sky.vertex_array);
+                }
+                ctx.bind_vertex_attrib(position, 2, AttribType::Float, sky.triangle);
+                ctx.gl.draw_arrays(glow::TRIANGLES, 0, 3);
+                ctx.gl.disable_vertex_attrib_array(position);
+            }
+        }
+        ctx.apply_render_state(previous_state);
+    });
+}
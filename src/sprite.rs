@@ -0,0 +1,325 @@
+use bevy::{math::Affine3A, prelude::*, window::PrimaryWindow};
+use bytemuck::cast_slice;
+use glow::{Buffer, HasContext, VertexArray};
+
+use crate::{
+    AttribType, BevyGlContext, RenderState, Tex,
+    command_encoder::{CommandEncoder, remove_render_resource_on_restore},
+    post_process::bind_post_process_texture,
+    prepare_image::GpuImages,
+    render::RenderSet,
+    shader_cached,
+};
+
+/// Draws Sprite entities as screen space quads over the whole window, after the 3D transparent phase and post
+/// processing. Sprites are batched into one vertex buffer and drawn with a draw call per run of sprites sharing a
+/// texture. SpriteQuads are drawn after them. Textures come from GpuImages, so they need to be uploaded like any other
+/// image.
+pub struct SpritePlugin;
+
+impl Plugin for SpritePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SpriteQuads>().add_systems(
+            PostUpdate,
+            (
+                remove_render_resource_on_restore::<SpriteBuffers>.in_set(RenderSet::Acquire),
+                render_sprites.in_set(RenderSet::RenderUi),
+            ),
+        );
+    }
+}
+
+/// A colored or textured quad in screen space. The GlobalTransform translation is in logical pixels from the center
+/// of the window with y up, the quad is centered on it. Rotation and scale around z apply, translation z only orders
+/// sprites, higher is drawn on top.
+///
+/// bevy's Sprite needs the bevy_sprite feature, this is the subset of it that's drawn here.
+#[derive(Component, Clone, Debug)]
+#[require(Transform, Visibility)]
+pub struct Sprite {
+    /// Drawn untextured if None. Sprites with an image aren't drawn until it's loaded.
+    pub image: Option<Handle<Image>>,
+    /// Multiplied with the texture. Drawn after tonemapping, so this shows up as specified.
+    pub color: Color,
+    /// Size in logical pixels. Defaults to the size of rect, or of the image.
+    pub custom_size: Option<Vec2>,
+    /// Region of the image to draw, in pixels.
+    pub rect: Option<Rect>,
+    pub flip_x: bool,
+    pub flip_y: bool,
+}
+
+impl Default for Sprite {
+    fn default() -> Self {
+        Self {
+            image: None,
+            color: Color::WHITE,
+            custom_size: None,
+            rect: None,
+            flip_x: false,
+            flip_y: false,
+        }
+    }
+}
+
+impl Sprite {
+    pub fn from_image(image: Handle<Image>) -> Self {
+        Self {
+            image: Some(image),
+            ..default()
+        }
+    }
+
+    pub fn from_color(color: impl Into<Color>, size: Vec2) -> Self {
+        Self {
+            color: color.into(),
+            custom_size: Some(size),
+            ..default()
+        }
+    }
+
+    /// The quad this sprite is drawn with, None while its image isn't loaded.
+    pub fn quad(&self, transform: &GlobalTransform, images: &Assets<Image>) -> Option<SpriteQuad> {
+        let image_size = match &self.image {
+            Some(handle) => Some(images.get(handle)?.size_f32()),
+            None => None,
+        };
+        let rect = self
+            .rect
+            .or_else(|| image_size.map(|size| Rect::from_corners(Vec2::ZERO, size)));
+        let size = self
+            .custom_size
+            .or_else(|| rect.map(|rect| rect.size()))
+            .unwrap_or(Vec2::ONE);
+
+        let (mut uv_min, mut uv_max) = match (rect, image_size) {
+            (Some(rect), Some(image_size)) => (rect.min / image_size, rect.max / image_size),
+            _ => (Vec2::ZERO, Vec2::ONE),
+        };
+        if self.flip_x {
+            std::mem::swap(&mut uv_min.x, &mut uv_max.x);
+        }
+        // Image rows go down, screen y goes up.
+        if !self.flip_y {
+            std::mem::swap(&mut uv_min.y, &mut uv_max.y);
+        }
+
+        Some(SpriteQuad {
+            image: self.image.clone(),
+            transform: transform.affine() * Affine3A::from_scale(size.extend(1.0)),
+            uv_min,
+            uv_max,
+            color: self.color,
+        })
+    }
+}
+
+/// A quad as drawn by SpritePlugin.
+#[derive(Clone, Debug)]
+pub struct SpriteQuad {
+    pub image: Option<Handle<Image>>,
+    /// Maps the unit square centered on the origin to logical pixels from the center of the window, y up.
+    pub transform: Affine3A,
+    /// uv at the bottom left corner of the quad.
+    pub uv_min: Vec2,
+    /// uv at the top right corner of the quad.
+    pub uv_max: Vec2,
+    pub color: Color,
+}
+
+/// Immediate mode quads. Everything pushed is drawn once after the Sprite entities, in the order pushed, and then
+/// cleared.
+#[derive(Resource, Default)]
+pub struct SpriteQuads {
+    quads: Vec<SpriteQuad>,
+}
+
+impl SpriteQuads {
+    pub fn push(&mut self, quad: SpriteQuad) {
+        self.quads.push(quad);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.quads.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.quads.clear();
+    }
+}
+
+const VERTICES_PER_SPRITE: usize = 6;
+
+/// Render world vertex buffers, grown as needed.
+#[derive(Resource)]
+struct SpriteBuffers {
+    positions: Buffer,
+    uvs: Buffer,
+    colors: Buffer,
+    /// In vertices
+    capacity: usize,
+    vertex_array: Option<VertexArray>,
+}
+
+impl SpriteBuffers {
+    fn new(ctx: &BevyGlContext, capacity: usize) -> Self {
+        Self {
+            positions: ctx.gen_vbo(&vec![0; capacity * 8], glow::DYNAMIC_DRAW),
+            uvs: ctx.gen_vbo(&vec![0; capacity * 8], glow::DYNAMIC_DRAW),
+            colors: ctx.gen_vbo(&vec![0; capacity * 16], glow::DYNAMIC_DRAW),
+            capacity,
+            vertex_array: ctx
+                .has_vertex_array_object
+                .then(|| unsafe { ctx.gl.create_vertex_array().unwrap() }),
+        }
+    }
+
+    fn delete(&self, gl: &glow::Context) {
+        unsafe {
+            gl.delete_buffer(self.positions);
+            gl.delete_buffer(self.uvs);
+            gl.delete_buffer(self.colors);
+            if let Some(vertex_array) = self.vertex_array {
+                gl.delete_vertex_array(vertex_array);
+            }
+        }
+    }
+}
+
+/// A run of sprites drawn with the same texture.
+struct SpriteBatch {
+    image: Option<Handle<Image>>,
+    /// In vertices
+    start: usize,
+    count: usize,
+}
+
+fn render_sprites(
+    sprites: Query<(&Sprite, &GlobalTransform, &InheritedVisibility)>,
+    mut sprite_quads: ResMut<SpriteQuads>,
+    window: Option<Single<&Window, With<PrimaryWindow>>>,
+    images: Res<Assets<Image>>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    let immediate = std::mem::take(&mut sprite_quads.quads);
+    let Some(window) = window else {
+        return;
+    };
+    let scale_factor = window.scale_factor();
+
+    let mut sorted = sprites
+        .iter()
+        .filter(|(_, _, visibility)| visibility.get())
+        .filter_map(|(sprite, transform, _)| {
+            Some((transform.translation().z, sprite.quad(transform, &images)?))
+        })
+        .collect::<Vec<_>>();
+    // Stable, so sprites at the same depth keep a consistent order and same textured ones stay together.
+    sorted.sort_by(|(a_z, a), (b_z, b)| {
+        a_z.total_cmp(b_z).then_with(|| {
+            let id = |quad: &SpriteQuad| quad.image.as_ref().map(|image| image.id());
+            id(a).cmp(&id(b))
+        })
+    });
+    let quads = sorted.into_iter().map(|(_, quad)| quad).chain(immediate);
+
+    let mut positions: Vec<Vec2> = Vec::new();
+    let mut uvs: Vec<Vec2> = Vec::new();
+    let mut colors: Vec<Vec4> = Vec::new();
+    let mut batches: Vec<SpriteBatch> = Vec::new();
+    for quad in quads {
+        let corner = |x: f32, y: f32| {
+            quad.transform
+                .transform_point3(vec3(x - 0.5, y - 0.5, 0.0))
+                .truncate()
+                * scale_factor
+        };
+        let uv = |x: f32, y: f32| quad.uv_min + (quad.uv_max - quad.uv_min) * vec2(x, y);
+        let color = quad.color.to_srgba().to_vec4();
+        for (x, y) in [
+            (0.0, 0.0),
+            (1.0, 0.0),
+            (1.0, 1.0),
+            (0.0, 0.0),
+            (1.0, 1.0),
+            (0.0, 1.0),
+        ] {
+            positions.push(corner(x, y));
+            uvs.push(uv(x, y));
+            colors.push(color);
+        }
+
+        let start = positions.len() - VERTICES_PER_SPRITE;
+        match batches.last_mut() {
+            Some(batch) if batch.image == quad.image => batch.count += VERTICES_PER_SPRITE,
+            _ => batches.push(SpriteBatch {
+                image: quad.image,
+                start,
+                count: VERTICES_PER_SPRITE,
+            }),
+        }
+    }
+    if batches.is_empty() {
+        return;
+    }
+
+    enc.record(move |ctx, world| {
+        // Cameras may have left their viewport set.
+        ctx.set_viewport(None);
+        let Some(shader_index) =
+            shader_cached!(ctx, "shaders/sprite.vert", "shaders/sprite.frag", &[], &[])
+        else {
+            return;
+        };
+        ctx.use_cached_program(shader_index);
+        ctx.load("screen_size", ctx.window_size.as_vec2());
+        ctx.load("sprite_texture", 0i32);
+        ctx.start_alpha_blend();
+        ctx.apply_render_state(RenderState {
+            cull_mode: None,
+            depth_test: false,
+            polygon_mode: glow::FILL,
+            ..ctx.render_state
+        });
+
+        let count = positions.len();
+        let buffers = match world.remove_resource::<SpriteBuffers>() {
+            Some(buffers) if buffers.capacity >= count => buffers,
+            old => {
+                if let Some(old) = old {
+                    old.delete(&ctx.gl);
+                }
+                SpriteBuffers::new(ctx, count.next_power_of_two().max(1024))
+            }
+        };
+        ctx.update_vbo(buffers.positions, cast_slice(&positions));
+        ctx.update_vbo(buffers.uvs, cast_slice(&uvs));
+        ctx.update_vbo(buffers.colors, cast_slice(&colors));
+
+        let position = ctx.cached_attrib_location(shader_index, "position");
+        let uv = ctx.cached_attrib_location(shader_index, "uv");
+        let color = ctx.cached_attrib_location(shader_index, "color");
+        if let (Some(position), Some(uv), Some(color)) = (position, uv, color) {
+            let gpu_images = world.resource::<GpuImages>();
+            unsafe {
+                if buffers.vertex_array.is_some() {
+                    ctx.bind_vertex_array(buffers.vertex_array);
+                }
+                ctx.bind_vertex_attrib(position, 2, AttribType::Float, buffers.positions);
+                ctx.bind_vertex_attrib(uv, 2, AttribType::Float, buffers.uvs);
+                ctx.bind_vertex_attrib(color, 4, AttribType::Float, buffers.colors);
+                for batch in &batches {
+                    let (texture, _) =
+                        gpu_images.resolve(&Tex::Bevy(batch.image.clone()), glow::TEXTURE_2D);
+                    bind_post_process_texture(ctx, 0, texture);
+                    ctx.gl
+                        .draw_arrays(glow::TRIANGLES, batch.start as i32, batch.count as i32);
+                }
+                ctx.gl.disable_vertex_attrib_array(position);
+                ctx.gl.disable_vertex_attrib_array(uv);
+                ctx.gl.disable_vertex_attrib_array(color);
+            }
+        }
+        world.insert_resource(buffers);
+    });
+}
@@ -0,0 +1,324 @@
+use bevy::prelude::*;
+use glow::HasContext;
+use uniform_set_derive::UniformSet;
+
+use crate::{
+    BevyGlContext, RenderState,
+    command_encoder::{CommandEncoder, remove_render_resource_on_restore},
+    framebuffer::{ColorTargetFormat, Framebuffer},
+    post_process::{FullscreenTriangle, bind_post_process_texture},
+    prepare_image::{GpuImages, TextureRef},
+    prepare_view::ViewUniforms,
+    prepass::{PrepassTexturesPlugin, prepass_depth_texture},
+    render::RenderSet,
+    shader_cached,
+};
+
+/// Must match SSAO_SAMPLES in ssao.frag
+const SSAO_SAMPLES: usize = 16;
+
+/// Screen space ambient occlusion for cameras with DepthPrepass while SsaoSettings exists. After the prepass the
+/// occlusion is computed from PrepassTextures::depth by testing points in the hemisphere around each pixel's normal,
+/// and blurred. The standard material multiplies it into the environment and ambient light during the opaque phase.
+///
+/// Adds PrepassTexturesPlugin, see there for what that changes about the prepass. Without the prepass depth texture
+/// there's no occlusion.
+pub struct SsaoPlugin;
+
+impl Plugin for SsaoPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<PrepassTexturesPlugin>() {
+            app.add_plugins(PrepassTexturesPlugin);
+        }
+        app.add_systems(
+            PostUpdate,
+            (
+                remove_render_resource_on_restore::<SsaoTargets>.in_set(RenderSet::Acquire),
+                remove_ssao_targets.in_set(RenderSet::Prepare),
+            ),
+        );
+    }
+}
+
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct SsaoSettings {
+    /// Size of the sampled hemisphere in world units. Geometry further away doesn't occlude.
+    pub radius: f32,
+    /// Exponent applied to the occlusion, higher darkens more.
+    pub intensity: f32,
+    /// Depth difference in world units below which samples don't count as occluded, to avoid self occlusion on flat
+    /// surfaces.
+    pub bias: f32,
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        Self {
+            radius: 0.5,
+            intensity: 1.5,
+            bias: 0.025,
+        }
+    }
+}
+
+/// Render world. The blurred occlusion, for materials during the opaque phase when ssao_active() is true. Sampled at
+/// gl_FragCoord.xy / the window size, red is 1 where unoccluded.
+#[derive(UniformSet, Resource, Clone, Default)]
+pub struct SsaoUniforms {
+    ssao_texture: TextureRef,
+}
+
+/// For use in the render world. True during the opaque phase of a camera the occlusion was computed for, see
+/// SsaoUniforms.
+pub fn ssao_active(world: &World) -> bool {
+    world
+        .get_resource::<SsaoTargets>()
+        .is_some_and(|targets| targets.active)
+}
+
+/// The sample offsets, in the hemisphere around +z, denser towards the center.
+fn ssao_kernel() -> [Vec3; SSAO_SAMPLES] {
+    let golden_angle = std::f32::consts::PI * (3.0 - 5.0f32.sqrt());
+    std::array::from_fn(|i| {
+        // Cosine weighted directions on a spiral.
+        let t = (i as f32 + 0.5) / SSAO_SAMPLES as f32;
+        let angle = i as f32 * golden_angle;
+        let direction = vec3(
+            t.sqrt() * angle.cos(),
+            t.sqrt() * angle.sin(),
+            (1.0 - t).sqrt(),
+        );
+        // Lengths in a different order than the directions so they aren't correlated.
+        let s = ((i * 7) % SSAO_SAMPLES) as f32 / SSAO_SAMPLES as f32;
+        direction * (0.1 + 0.9 * s * s)
+    })
+}
+
+/// Called after the depth prepass of a camera with DepthPrepass, with the target from before it bound. Computes the
+/// occlusion from the prepass depth if SsaoSettings exists.
+pub(crate) fn render_ssao(world: &mut World, viewport: Option<URect>) {
+    let Some(settings) = world.get_resource::<SsaoSettings>().copied() else {
+        return;
+    };
+    world
+        .resource_mut::<CommandEncoder>()
+        .record(move |ctx, world| {
+            let Some(depth_texture) = prepass_depth_texture(world) else {
+                return;
+            };
+            let size = ctx.window_size;
+            let current = world
+                .get_resource::<SsaoTargets>()
+                .map(|targets| targets.size);
+            if current != Some(size) {
+                if let Some(targets) = world.remove_resource::<SsaoTargets>() {
+                    targets.delete(&ctx.gl);
+                }
+                let Some(targets) = SsaoTargets::new(ctx, size) else {
+                    warn_once!("Couldn't create the SSAO targets, SSAO is skipped");
+                    return;
+                };
+                let texture_ref = world
+                    .get_resource_or_init::<SsaoUniforms>()
+                    .ssao_texture
+                    .clone();
+                let mut gpu_images = world.resource_mut::<GpuImages>();
+                match texture_ref.get() {
+                    Some(idx) => {
+                        gpu_images.raw_textures[idx as usize] =
+                            (targets.textures[1], glow::TEXTURE_2D)
+                    }
+                    None => {
+                        gpu_images.add_texture_set_ref(
+                            targets.textures[1],
+                            glow::TEXTURE_2D,
+                            &texture_ref,
+                        );
+                    }
+                }
+                world.insert_resource(targets);
+            }
+            let Some(mut targets) = world.remove_resource::<SsaoTargets>() else {
+                return;
+            };
+            let previous = unsafe { ctx.gl.get_parameter_framebuffer(glow::FRAMEBUFFER_BINDING) };
+            targets.active = targets.render(ctx, world, depth_texture, settings, viewport);
+            unsafe { ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, previous) };
+            ctx.set_viewport(viewport);
+            world.insert_resource(targets);
+        });
+}
+
+/// Called after the opaque phase, so later cameras and phases don't sample the occlusion.
+pub(crate) fn end_ssao(world: &mut World) {
+    world
+        .resource_mut::<CommandEncoder>()
+        .record(|_ctx, world| {
+            if let Some(mut targets) = world.get_resource_mut::<SsaoTargets>() {
+                targets.active = false;
+            }
+        });
+}
+
+fn remove_ssao_targets(
+    settings: Option<Res<SsaoSettings>>,
+    mut enc: ResMut<CommandEncoder>,
+    mut enabled: Local<bool>,
+) {
+    if *enabled && settings.is_none() {
+        enc.record(|ctx, world| {
+            if let Some(targets) = world.remove_resource::<SsaoTargets>() {
+                targets.delete(&ctx.gl);
+            }
+        });
+    }
+    *enabled = settings.is_some();
+}
+
+/// Render world window sized targets, see SsaoPlugin.
+#[derive(Resource)]
+struct SsaoTargets {
+    size: UVec2,
+    /// The occlusion, then the blurred occlusion that SsaoUniforms refers to.
+    textures: [glow::Texture; 2],
+    framebuffers: [Framebuffer; 2],
+    triangle: FullscreenTriangle,
+    /// See ssao_active()
+    active: bool,
+}
+
+impl SsaoTargets {
+    fn new(ctx: &BevyGlContext, size: UVec2) -> Option<Self> {
+        let previous = unsafe { ctx.gl.get_parameter_framebuffer(glow::FRAMEBUFFER_BINDING) };
+        let textures = [0, 1].map(|i| {
+            let texture = ColorTargetFormat::Rgba8.create_texture(ctx, size);
+            ctx.label_texture(texture, &format!("ssao_{i}"));
+            texture
+        });
+        let framebuffers =
+            textures.map(|texture| Framebuffer::new(ctx, texture, false, size.x, size.y));
+        unsafe { ctx.gl.bind_framebuffer(glow::FRAMEBUFFER, previous) };
+        match framebuffers {
+            [Some(first), Some(second)] => Some(Self {
+                size,
+                textures,
+                framebuffers: [first, second],
+                triangle: FullscreenTriangle::new(ctx),
+                active: false,
+            }),
+            framebuffers => {
+                for framebuffer in framebuffers.iter().flatten() {
+                    framebuffer.delete(&ctx.gl);
+                }
+                for texture in textures {
+                    unsafe { ctx.gl.delete_texture(texture) };
+                }
+                None
+            }
+        }
+    }
+
+    /// Renders the occlusion and blurs it, returns whether both programs compiled.
+    fn render(
+        &self,
+        ctx: &mut BevyGlContext,
+        world: &World,
+        depth_texture: glow::Texture,
+        settings: SsaoSettings,
+        viewport: Option<URect>,
+    ) -> bool {
+        let (Some(occlusion), Some(blur)) = (
+            shader_cached!(
+                ctx,
+                "shaders/post_process.vert",
+                "shaders/ssao.frag",
+                &[],
+                &[]
+            ),
+            shader_cached!(
+                ctx,
+                "shaders/post_process.vert",
+                "shaders/ssao_blur.frag",
+                &[],
+                &[]
+            ),
+        ) else {
+            return false;
+        };
+        let Some(view) = world.get_resource::<ViewUniforms>() else {
+            return false;
+        };
+        let clip_from_view = view.clip_from_world * view.world_from_view;
+        // In window pixels with GL's bottom left origin, like gl_FragCoord.
+        let viewport_rect = viewport.map_or(
+            vec4(0.0, 0.0, self.size.x as f32, self.size.y as f32),
+            |rect| {
+                vec4(
+                    rect.min.x as f32,
+                    self.size.y.saturating_sub(rect.max.y) as f32,
+                    rect.width() as f32,
+                    rect.height() as f32,
+                )
+            },
+        );
+
+        let previous_state = ctx.render_state;
+        ctx.apply_render_state(RenderState {
+            cull_mode: None,
+            blend: false,
+            depth_test: false,
+            depth_write: false,
+            color_write: [true; 4],
+            polygon_mode: glow::FILL,
+            ..previous_state
+        });
+
+        self.framebuffers[0].bind(ctx);
+        ctx.set_viewport(viewport);
+        ctx.use_cached_program(occlusion);
+        bind_post_process_texture(ctx, 0, depth_texture);
+        ctx.load("source_texture", 0i32);
+        ctx.load("source_size", self.size.as_vec2());
+        ctx.load("ssao_viewport", viewport_rect);
+        ctx.load("clip_from_view", clip_from_view);
+        ctx.load("view_from_clip", clip_from_view.inverse());
+        ctx.load("ssao_kernel", &ssao_kernel()[..]);
+        ctx.load("ssao_radius", settings.radius);
+        ctx.load("ssao_bias", settings.bias);
+        ctx.load("ssao_intensity", settings.intensity);
+        self.triangle.draw(ctx, occlusion);
+
+        self.framebuffers[1].bind(ctx);
+        ctx.set_viewport(viewport);
+        ctx.use_cached_program(blur);
+        bind_post_process_texture(ctx, 0, self.textures[0]);
+        ctx.load("source_texture", 0i32);
+        ctx.load("source_size", self.size.as_vec2());
+        self.triangle.draw(ctx, blur);
+
+        ctx.apply_render_state(previous_state);
+        true
+    }
+
+    fn delete(&self, gl: &glow::Context) {
+        for (framebuffer, texture) in self.framebuffers.iter().zip(self.textures) {
+            framebuffer.delete(gl);
+            unsafe { gl.delete_texture(texture) };
+        }
+        self.triangle.delete(gl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ssao_kernel_in_hemisphere() {
+        for sample in ssao_kernel() {
+            assert!(sample.z > 0.0);
+            assert!(sample.length() <= 1.0 + 1e-5);
+            assert!(sample.length() >= 0.1 - 1e-5);
+        }
+    }
+}
@@ -0,0 +1,458 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
+
+use bevy::{
+    core_pipeline::prepass::DepthPrepass, diagnostic::FrameCount, prelude::*, window::PrimaryWindow,
+};
+use glow::HasContext;
+
+use crate::{
+    BevyGlContext,
+    command_encoder::{CommandEncoder, remove_render_resource_on_restore},
+    framebuffer::{ColorTargetFormat, Framebuffer},
+    phase_opaque::camera_viewport,
+    post_process::{
+        PostProcessPass, PostProcessPlugin, PostProcessSource, PostProcessStack,
+        bind_post_process_texture, update_post_process_hdr,
+    },
+    prepare_view::ViewUniforms,
+    prepass::prepass_depth_texture,
+    render::{RenderSet, sorted_cameras},
+    shader_cached,
+};
+
+const TAA_PASS: &str = "taa";
+/// Length of the jitter sequence, in frames.
+const JITTER_SAMPLES: u64 = 8;
+
+/// Temporal anti-aliasing while TaaSettings exists. The camera projections are offset by a different subpixel amount
+/// each frame, see TaaJitter, and a PostProcessStack pass blends each frame into a history of the previous ones. This
+/// smooths specular and shading aliasing that MSAA doesn't reach.
+///
+/// There's no velocity buffer. The history is reprojected with the camera's previous view, from the prepass depth if
+/// the camera has DepthPrepass and PrepassTexturesPlugin was added, otherwise only following the camera's rotation.
+/// Things that move on their own are handled by clamping the history to the colors around each pixel in the current
+/// frame, which limits ghosting at the cost of some of the smoothing. The history is dropped when the camera, its
+/// viewport or projection changes, or it moves further than TaaSettings::cut_distance in a frame. The pass runs on
+/// the tonemapped image, after any passes that take HDR input like bloom.
+pub struct TaaPlugin;
+
+impl Plugin for TaaPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<PostProcessPlugin>() {
+            app.add_plugins(PostProcessPlugin);
+        }
+        app.init_resource::<TaaJitter>()
+            .init_resource::<TaaActive>()
+            .add_systems(
+                PostUpdate,
+                remove_render_resource_on_restore::<TaaHistory>.in_set(RenderSet::Acquire),
+            )
+            .add_systems(
+                PostUpdate,
+                (sync_taa_pass, update_taa_jitter, send_taa_view)
+                    .chain()
+                    .in_set(RenderSet::Prepare)
+                    .before(update_post_process_hdr),
+            );
+    }
+}
+
+#[derive(Resource, Clone, Copy, Debug)]
+pub struct TaaSettings {
+    /// How much of the current frame is blended into the history, 0 to 1. Lower is smoother but blurs motion more.
+    pub blend: f32,
+    /// The history is dropped when the camera moves further than this in a frame, so that a cut doesn't blend in the
+    /// previous shot.
+    pub cut_distance: f32,
+}
+
+impl Default for TaaSettings {
+    fn default() -> Self {
+        Self {
+            blend: 0.1,
+            cut_distance: 10.0,
+        }
+    }
+}
+
+/// The subpixel offset applied to the camera projections this frame, in normalized device coordinates. Zero without
+/// TaaSettings, and until the render world has created the history targets.
+#[derive(Resource, Default, Clone, Copy, Deref, Debug)]
+pub struct TaaJitter(pub Vec2);
+
+/// Render world. The camera the post processing sees this frame, the last one drawn, see resolve_taa().
+#[derive(Resource, Clone, Copy, Debug)]
+struct TaaView {
+    camera: Entity,
+    viewport: Option<URect>,
+    /// The camera has DepthPrepass, so the prepass depth is this camera's.
+    depth_prepass: bool,
+    jitter: Vec2,
+}
+
+/// Whether the render world has a history to blend into, so that the image isn't jittered without it being resolved.
+#[derive(Resource, Default)]
+struct TaaActive(Arc<AtomicBool>);
+
+fn taa_pass(settings: TaaSettings, active: Arc<AtomicBool>) -> PostProcessPass {
+    // The resolve renders into the history in prepare, the pass copies the result on.
+    PostProcessPass::new(TAA_PASS, |ctx, _world| {
+        shader_cached!(
+            ctx,
+            "shaders/post_process.vert",
+            "shaders/post_process_copy.frag",
+            &[],
+            &[]
+        )
+    })
+    .with_prepare(move |ctx, world, source| {
+        let resolved = resolve_taa(ctx, world, source, settings);
+        active.store(resolved, Ordering::Relaxed);
+    })
+    .with_uniforms(|ctx, world| {
+        let Some(history) = world.get_resource::<TaaHistory>() else {
+            return;
+        };
+        bind_post_process_texture(ctx, 1, history.textures[history.current]);
+        ctx.load("source_texture", 1i32);
+    })
+}
+
+/// Keeps the TAA pass after the passes that take HDR input while TaaSettings exists.
+fn sync_taa_pass(
+    settings: Option<Res<TaaSettings>>,
+    active: Res<TaaActive>,
+    mut stack: ResMut<PostProcessStack>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    let index = stack.passes.iter().position(|pass| pass.name == TAA_PASS);
+    match (settings, index) {
+        (Some(settings), Some(index)) if settings.is_changed() => {
+            stack.passes[index] = taa_pass(*settings, active.0.clone())
+        }
+        (Some(settings), None) => {
+            let index = stack
+                .passes
+                .iter()
+                .take_while(|pass| pass.hdr_input)
+                .count();
+            stack
+                .passes
+                .insert(index, taa_pass(*settings, active.0.clone()));
+        }
+        (None, Some(index)) => {
+            stack.passes.remove(index);
+            active.0.store(false, Ordering::Relaxed);
+            // So the next TaaSettings doesn't start from a stale history.
+            enc.record(|ctx, world| {
+                if let Some(history) = world.remove_resource::<TaaHistory>() {
+                    history.delete(&ctx.gl);
+                }
+            });
+        }
+        _ => (),
+    }
+}
+
+fn update_taa_jitter(
+    settings: Option<Res<TaaSettings>>,
+    active: Res<TaaActive>,
+    frame: Res<FrameCount>,
+    window: Option<Single<&Window, With<PrimaryWindow>>>,
+    mut jitter: ResMut<TaaJitter>,
+) {
+    let size = window.map_or(Vec2::ZERO, |window| {
+        vec2(
+            window.physical_width() as f32,
+            window.physical_height() as f32,
+        )
+    });
+    let offset =
+        if settings.is_some() && active.0.load(Ordering::Relaxed) && size.min_element() > 0.0 {
+            let index = frame.0 % JITTER_SAMPLES + 1;
+            let pixel = vec2(halton(index, 2), halton(index, 3)) - 0.5;
+            pixel * 2.0 / size
+        } else {
+            Vec2::ZERO
+        };
+    if jitter.0 != offset {
+        jitter.0 = offset;
+    }
+}
+
+fn send_taa_view(
+    settings: Option<Res<TaaSettings>>,
+    jitter: Res<TaaJitter>,
+    cameras: Query<(Entity, &Camera, Has<DepthPrepass>)>,
+    mut enc: ResMut<CommandEncoder>,
+) {
+    if settings.is_none() {
+        return;
+    }
+    // The post processing runs after the last camera is drawn.
+    let sorted = sorted_cameras(cameras.iter().map(|(entity, camera, _)| (entity, camera)));
+    let Some((camera, camera_component, depth_prepass)) =
+        sorted.last().and_then(|entity| cameras.get(*entity).ok())
+    else {
+        return;
+    };
+    let view = TaaView {
+        camera,
+        viewport: camera_viewport(camera_component),
+        depth_prepass,
+        jitter: jitter.0,
+    };
+    enc.record(move |_ctx, world| {
+        world.insert_resource(view);
+    });
+}
+
+/// The index'th element of the Halton sequence with base, in 0..1.
+fn halton(mut index: u64, base: u64) -> f32 {
+    let mut result = 0.0;
+    let mut fraction = 1.0;
+    while index > 0 {
+        fraction /= base as f32;
+        result += fraction * (index % base) as f32;
+        index /= base;
+    }
+    result
+}
+
+/// The view a frame of the history was rendered with, without the jitter.
+#[derive(Clone, Copy, Debug)]
+struct TaaFrame {
+    camera: Option<Entity>,
+    viewport: Option<URect>,
+    clip_from_view: Mat4,
+    clip_from_world: Mat4,
+    view_position: Vec3,
+}
+
+impl TaaFrame {
+    fn new(view: &ViewUniforms, taa_view: Option<TaaView>) -> Self {
+        let jitter = taa_view.map_or(Vec2::ZERO, |taa_view| taa_view.jitter);
+        let clip_from_world = Mat4::from_translation(-jitter.extend(0.0)) * view.clip_from_world;
+        Self {
+            camera: taa_view.map(|taa_view| taa_view.camera),
+            viewport: taa_view.and_then(|taa_view| taa_view.viewport),
+            clip_from_view: clip_from_world * view.world_from_view,
+            clip_from_world,
+            view_position: view.view_position,
+        }
+    }
+
+    /// Whether this frame continues from previous, rather than being a cut or from a different view.
+    fn continues(&self, previous: &TaaFrame, cut_distance: f32) -> bool {
+        self.camera == previous.camera
+            && self.viewport == previous.viewport
+            && self
+                .clip_from_view
+                .abs_diff_eq(previous.clip_from_view, 1e-4)
+            && self.view_position.distance(previous.view_position) <= cut_distance
+    }
+}
+
+/// Render world history targets. Each frame the resolve reads one and writes the other.
+#[derive(Resource)]
+struct TaaHistory {
+    size: UVec2,
+    textures: [glow::Texture; 2],
+    framebuffers: [Framebuffer; 2],
+    /// The texture written last.
+    current: usize,
+    /// The view current was rendered with, None until it holds a previous frame.
+    frame: Option<TaaFrame>,
+}
+
+impl TaaHistory {
+    fn new(ctx: &BevyGlContext, size: UVec2) -> Option<Self> {
+        let textures = [0, 1].map(|i| {
+            let texture = ColorTargetFormat::Rgba8.create_texture(ctx, size);
+            ctx.label_texture(texture, &format!("taa_history_{i}"));
+            texture
+        });
+        match textures.map(|texture| Framebuffer::new(ctx, texture, false, size.x, size.y)) {
+            [Some(first), Some(second)] => Some(Self {
+                size,
+                textures,
+                framebuffers: [first, second],
+                current: 0,
+                frame: None,
+            }),
+            framebuffers => {
+                for (framebuffer, texture) in framebuffers.iter().zip(textures) {
+                    if let Some(framebuffer) = framebuffer {
+                        framebuffer.delete(&ctx.gl);
+                    }
+                    unsafe { ctx.gl.delete_texture(texture) };
+                }
+                None
+            }
+        }
+    }
+
+    fn delete(&self, gl: &glow::Context) {
+        for (framebuffer, texture) in self.framebuffers.iter().zip(self.textures) {
+            framebuffer.delete(gl);
+            unsafe { gl.delete_texture(texture) };
+        }
+    }
+}
+
+/// Blends the source into the history, returns whether there is a history to blend into.
+fn resolve_taa(
+    ctx: &mut BevyGlContext,
+    world: &mut World,
+    source: &PostProcessSource,
+    settings: TaaSettings,
+) -> bool {
+    let current = world
+        .get_resource::<TaaHistory>()
+        .map(|history| history.size);
+    if current != Some(source.size) {
+        if let Some(history) = world.remove_resource::<TaaHistory>() {
+            history.delete(&ctx.gl);
+        }
+        let Some(history) = TaaHistory::new(ctx, source.size) else {
+            warn_once!("Couldn't create the TAA history targets, TAA is skipped");
+            return false;
+        };
+        world.insert_resource(history);
+    }
+    let Some(view) = world.get_resource::<ViewUniforms>() else {
+        return false;
+    };
+    let taa_view = world.get_resource::<TaaView>().copied();
+    let frame = TaaFrame::new(view, taa_view);
+    let depth_texture = prepass_depth_texture(world)
+        .filter(|_| taa_view.is_some_and(|taa_view| taa_view.depth_prepass));
+    let shader_defs: &[(&str, &str)] = if depth_texture.is_some() {
+        &[("TAA_DEPTH", "")]
+    } else {
+        &[]
+    };
+    let Some(program) = shader_cached!(
+        ctx,
+        "shaders/post_process.vert",
+        "shaders/taa_resolve.frag",
+        shader_defs,
+        &[]
+    ) else {
+        return false;
+    };
+
+    let mut history = world.resource_mut::<TaaHistory>();
+    // Blending in a history from a different view would smear it over the new one.
+    let previous_frame = history
+        .frame
+        .filter(|previous| frame.continues(previous, settings.cut_distance));
+    let previous = history.current;
+    history.current = 1 - previous;
+    history.framebuffers[history.current].bind(ctx);
+    ctx.use_cached_program(program);
+    bind_post_process_texture(ctx, 0, source.texture);
+    ctx.load("source_texture", 0i32);
+    ctx.load("source_size", source.size.as_vec2());
+    bind_post_process_texture(ctx, 1, history.textures[previous]);
+    ctx.load("history_texture", 1i32);
+    if let Some(depth_texture) = depth_texture {
+        bind_post_process_texture(ctx, 2, depth_texture);
+        ctx.load("depth_texture", 2i32);
+    }
+    // In window pixels with GL's bottom left origin, like gl_FragCoord.
+    let viewport_rect = frame.viewport.map_or(
+        vec4(0.0, 0.0, source.size.x as f32, source.size.y as f32),
+        |rect| {
+            vec4(
+                rect.min.x as f32,
+                source.size.y.saturating_sub(rect.max.y) as f32,
+                rect.width() as f32,
+                rect.height() as f32,
+            )
+        },
+    );
+    ctx.load("taa_viewport", viewport_rect);
+    ctx.load(
+        "history_from_clip",
+        previous_frame.map_or(Mat4::IDENTITY, |previous| {
+            previous.clip_from_world * frame.clip_from_world.inverse()
+        }),
+    );
+    // The first frame has nothing to blend with.
+    ctx.load(
+        "taa_blend",
+        if previous_frame.is_some() {
+            settings.blend.clamp(0.0, 1.0)
+        } else {
+            1.0
+        },
+    );
+    source.triangle.draw(ctx, program);
+    history.frame = Some(frame);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_halton() {
+        assert_eq!(
+            (1..5).map(|i| halton(i, 2)).collect::<Vec<_>>(),
+            [0.5, 0.25, 0.75, 0.125]
+        );
+        let third = halton(1, 3);
+        assert!((third - 1.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_taa_frame_continues() {
+        let camera = Entity::from_raw_u32(1).unwrap();
+        let view = |position: Vec3, fov: f32, jitter: Vec2| {
+            let world_from_view = Mat4::from_translation(position);
+            let clip_from_view = Mat4::from_translation(jitter.extend(0.0))
+                * Mat4::perspective_infinite_reverse_rh(fov, 1.0, 0.1);
+            let taa_view = TaaView {
+                camera,
+                viewport: None,
+                depth_prepass: false,
+                jitter,
+            };
+            let uniforms = ViewUniforms {
+                world_from_view,
+                view_from_world: world_from_view.inverse(),
+                clip_from_world: clip_from_view * world_from_view.inverse(),
+                view_position: position,
+                view_resolution: Vec2::ONE,
+                view_exposure: 1.0,
+                frame: 0.0,
+                time: 0.0,
+                tonemapping: default(),
+                hdr_output: false,
+            };
+            TaaFrame::new(&uniforms, Some(taa_view))
+        };
+        let previous = view(Vec3::ZERO, 1.0, Vec2::ZERO);
+        // Only the jitter changed.
+        let frame = view(Vec3::ZERO, 1.0, vec2(0.01, -0.01));
+        assert!(frame.continues(&previous, 10.0));
+        assert!(
+            frame
+                .clip_from_world
+                .abs_diff_eq(previous.clip_from_world, 1e-6)
+        );
+        assert!(view(Vec3::X, 1.0, Vec2::ZERO).continues(&previous, 10.0));
+        // A cut
+        assert!(!view(Vec3::X * 20.0, 1.0, Vec2::ZERO).continues(&previous, 10.0));
+        // The projection changed.
+        assert!(!view(Vec3::ZERO, 1.2, Vec2::ZERO).continues(&previous, 10.0));
+        let mut other_camera = previous;
+        other_camera.camera = Some(Entity::from_raw_u32(2).unwrap());
+        assert!(!frame.continues(&other_camera, 10.0));
+    }
+}
@@ -0,0 +1,327 @@
+use ab_glyph::{Font as _, FontRef, GlyphId, PxScale, ScaleFont, point};
+use bevy::{
+    asset::RenderAssetUsages,
+    image::ImageSampler,
+    math::Affine3A,
+    platform::collections::HashMap,
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+    window::PrimaryWindow,
+};
+
+use crate::{
+    prepare_image::send_images_to_gpu,
+    render::RenderSet,
+    sprite::{SpritePlugin, SpriteQuad, SpriteQuads},
+};
+
+const ATLAS_SIZE: u32 = 1024;
+/// Empty pixels between glyphs, so linear filtering doesn't pick up neighbors.
+const GLYPH_PADDING: u32 = 1;
+
+/// Draws the text added to ScreenText each frame as SpriteQuads. Glyphs are rasterized with ab_glyph into a shared
+/// atlas image the first time each font, glyph and pixel size is used. The atlas starts over when it's full.
+pub struct TextPlugin;
+
+impl Plugin for TextPlugin {
+    fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<SpritePlugin>() {
+            app.add_plugins(SpritePlugin);
+        }
+        app.init_resource::<ScreenText>()
+            .init_resource::<GlyphAtlas>()
+            .add_systems(
+                PostUpdate,
+                layout_screen_text
+                    .in_set(RenderSet::Prepare)
+                    .before(send_images_to_gpu),
+            );
+    }
+}
+
+/// Immediate mode text for HUDs and debug info. Everything added is drawn once over the Sprite entities and then
+/// cleared. Positions are in logical pixels from the center of the window with y up, like Sprite. Lines are split on
+/// '\n', there's no wrapping.
+#[derive(Resource, Default)]
+pub struct ScreenText {
+    /// Used for all text. The default handle is bevy's default font, which needs bevy's default_font feature.
+    pub font: Handle<Font>,
+    entries: Vec<TextEntry>,
+}
+
+struct TextEntry {
+    text: String,
+    position: Vec2,
+    size: f32,
+    color: Color,
+    justify: Justify,
+}
+
+impl ScreenText {
+    /// position is the top left of the first line, size is the font size in logical pixels. Colors are written after
+    /// tonemapping, so they show up as specified.
+    pub fn draw_text(
+        &mut self,
+        text: impl Into<String>,
+        position: Vec2,
+        size: f32,
+        color: impl Into<Color>,
+    ) {
+        self.draw_text_justified(text, position, size, color, Justify::Left);
+    }
+
+    /// Each line is aligned to position.x by justify. Justify::Justified is treated as Left.
+    pub fn draw_text_justified(
+        &mut self,
+        text: impl Into<String>,
+        position: Vec2,
+        size: f32,
+        color: impl Into<Color>,
+        justify: Justify,
+    ) {
+        self.entries.push(TextEntry {
+            text: text.into(),
+            position,
+            size,
+            color: color.into(),
+            justify,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct GlyphKey {
+    font: AssetId<Font>,
+    glyph: GlyphId,
+    /// Font size in physical pixels
+    px: u32,
+}
+
+#[derive(Clone, Copy)]
+struct AtlasGlyph {
+    /// Top left in the atlas, in pixels.
+    min: UVec2,
+    size: UVec2,
+    /// Top left relative to the pen position on the baseline, y down.
+    offset: Vec2,
+}
+
+/// Glyphs are packed in rows, left to right and top to bottom.
+#[derive(Resource, Default)]
+struct GlyphAtlas {
+    image: Option<Handle<Image>>,
+    /// None for glyphs without an outline, like spaces.
+    glyphs: HashMap<GlyphKey, Option<AtlasGlyph>>,
+    cursor: UVec2,
+    row_height: u32,
+}
+
+/// The atlas ran out of space and was cleared, glyphs looked up before are no longer valid.
+struct AtlasFull;
+
+impl GlyphAtlas {
+    fn atlas_image() -> Image {
+        let mut image = Image::new_fill(
+            Extent3d {
+                width: ATLAS_SIZE,
+                height: ATLAS_SIZE,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &[255, 255, 255, 0],
+            TextureFormat::Rgba8Unorm,
+            RenderAssetUsages::default(),
+        );
+        image.sampler = ImageSampler::linear();
+        image
+    }
+
+    fn clear(&mut self, images: &mut Assets<Image>) {
+        self.glyphs.clear();
+        self.cursor = UVec2::ZERO;
+        self.row_height = 0;
+        if let Some(image) = &self.image {
+            let _ = images.insert(image, Self::atlas_image());
+        }
+    }
+
+    fn glyph(
+        &mut self,
+        key: GlyphKey,
+        font: &FontRef,
+        images: &mut Assets<Image>,
+    ) -> Result<Option<AtlasGlyph>, AtlasFull> {
+        if let Some(glyph) = self.glyphs.get(&key) {
+            return Ok(*glyph);
+        }
+        let outlined = font.outline_glyph(
+            key.glyph
+                .with_scale_and_position(PxScale::from(key.px as f32), point(0.0, 0.0)),
+        );
+        let Some(outlined) = outlined else {
+            self.glyphs.insert(key, None);
+            return Ok(None);
+        };
+        let bounds = outlined.px_bounds();
+        let size = uvec2(bounds.width() as u32, bounds.height() as u32);
+
+        if self.cursor.x + size.x > ATLAS_SIZE {
+            self.cursor = uvec2(0, self.cursor.y + self.row_height + GLYPH_PADDING);
+            self.row_height = 0;
+        }
+        if size.x > ATLAS_SIZE || self.cursor.y + size.y > ATLAS_SIZE {
+            self.clear(images);
+            return Err(AtlasFull);
+        }
+        let min = self.cursor;
+        self.cursor.x += size.x + GLYPH_PADDING;
+        self.row_height = self.row_height.max(size.y);
+
+        let image = self
+            .image
+            .get_or_insert_with(|| images.add(Self::atlas_image()));
+        if let Some(data) = images
+            .get_mut(image.id())
+            .and_then(|image| image.data.as_mut())
+        {
+            outlined.draw(|x, y, coverage| {
+                let index = ((min.y + y) * ATLAS_SIZE + min.x + x) as usize * 4;
+                data[index + 3] = (coverage.clamp(0.0, 1.0) * 255.0) as u8;
+            });
+        }
+
+        let glyph = AtlasGlyph {
+            min,
+            size,
+            offset: vec2(bounds.min.x, bounds.min.y),
+        };
+        self.glyphs.insert(key, Some(glyph));
+        Ok(Some(glyph))
+    }
+}
+
+fn layout_screen_text(
+    mut text: ResMut<ScreenText>,
+    mut atlas: ResMut<GlyphAtlas>,
+    mut sprite_quads: ResMut<SpriteQuads>,
+    mut images: ResMut<Assets<Image>>,
+    fonts: Res<Assets<Font>>,
+    window: Option<Single<&Window, With<PrimaryWindow>>>,
+) {
+    if text.is_empty() {
+        return;
+    }
+    let entries = std::mem::take(&mut text.entries);
+    let (Some(window), Some(font)) = (window, fonts.get(&text.font)) else {
+        return;
+    };
+    let Ok(font_ref) = FontRef::try_from_slice(&font.data) else {
+        warn_once!("ScreenText font couldn't be read");
+        return;
+    };
+    let scale_factor = window.scale_factor();
+
+    // If the atlas fills up partway through, lay everything out again so no quad refers to cleared glyphs. Nothing
+    // is drawn if the text doesn't fit in an empty atlas.
+    let mut quads = Vec::new();
+    for attempt in 0..2 {
+        quads.clear();
+        let complete = entries.iter().all(|entry| {
+            layout_entry(
+                entry,
+                text.font.id(),
+                &font_ref,
+                scale_factor,
+                &mut atlas,
+                &mut images,
+                &mut quads,
+            )
+            .is_ok()
+        });
+        if complete {
+            break;
+        }
+        if attempt == 1 {
+            warn_once!("ScreenText doesn't fit in the glyph atlas");
+            return;
+        }
+    }
+    for quad in quads {
+        sprite_quads.push(quad);
+    }
+}
+
+fn layout_entry(
+    entry: &TextEntry,
+    font_id: AssetId<Font>,
+    font: &FontRef,
+    scale_factor: f32,
+    atlas: &mut GlyphAtlas,
+    images: &mut Assets<Image>,
+    quads: &mut Vec<SpriteQuad>,
+) -> Result<(), AtlasFull> {
+    // Rasterized at the physical size so the glyphs map 1:1 to pixels.
+    let px = (entry.size * scale_factor).round().max(1.0);
+    let scaled = font.as_scaled(PxScale::from(px));
+    let line_height = scaled.height() + scaled.line_gap();
+
+    for (i, line) in entry.text.lines().enumerate() {
+        let ids = line.chars().map(|c| font.glyph_id(c)).collect::<Vec<_>>();
+        let advance = |index: usize| {
+            let kern = index
+                .checked_sub(1)
+                .map_or(0.0, |prev| scaled.kern(ids[prev], ids[index]));
+            kern + scaled.h_advance(ids[index])
+        };
+        let width = (0..ids.len()).map(advance).sum::<f32>();
+        let mut pen = match entry.justify {
+            Justify::Center => -width * 0.5,
+            Justify::Right => -width,
+            _ => 0.0,
+        };
+        let baseline = scaled.ascent() + i as f32 * line_height;
+
+        for (index, id) in ids.iter().enumerate() {
+            if index > 0 {
+                pen += scaled.kern(ids[index - 1], *id);
+            }
+            let key = GlyphKey {
+                font: font_id,
+                glyph: *id,
+                px: px as u32,
+            };
+            if let Some(glyph) = atlas.glyph(key, font, images)?
+                && let Some(image) = &atlas.image
+            {
+                let size = glyph.size.as_vec2();
+                // Physical pixels relative to position, y down.
+                let center = vec2(pen.round(), baseline.round()) + glyph.offset + size * 0.5;
+                let translation = entry.position + vec2(center.x, -center.y) / scale_factor;
+                let uv_min = glyph.min.as_vec2() / ATLAS_SIZE as f32;
+                let uv_max = (glyph.min + glyph.size).as_vec2() / ATLAS_SIZE as f32;
+                quads.push(SpriteQuad {
+                    image: Some(image.clone()),
+                    transform: Affine3A::from_scale_rotation_translation(
+                        (size / scale_factor).extend(1.0),
+                        Quat::IDENTITY,
+                        translation.extend(0.0),
+                    ),
+                    // Atlas rows go down, screen y goes up.
+                    uv_min: vec2(uv_min.x, uv_max.y),
+                    uv_max: vec2(uv_max.x, uv_min.y),
+                    color: entry.color,
+                });
+            }
+            pen += scaled.h_advance(*id);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,119 @@
+use bevy::{
+    asset::RenderAssetUsages,
+    core_pipeline::tonemapping::{Tonemapping, TonemappingLuts},
+    image::{ImageFilterMode, ImageSampler, ImageSamplerDescriptor},
+    prelude::*,
+    render::render_resource::{Extent3d, TextureDimension, TextureFormat},
+};
+use shared_exponent_formats::rgb9e5::rgb9e5_to_vec3;
+
+use crate::{
+    command_encoder::{CommandEncoder, GlContextRestored},
+    prepare_image::{GpuImages, TextureQualitySettings, TextureRef},
+};
+
+/// bevy's TonyMcMapface LUT, uploaded the first time a camera uses Tonemapping::TonyMcMapface. Needs bevy's
+/// tonemapping_luts feature, without it TonemappingLuts only has a placeholder and AgX is used instead.
+/// See StandardLightingUniforms::tonemapping_lut
+#[derive(Resource, Default)]
+pub struct TonyMcMapfaceLut {
+    pub texture: TextureRef,
+    requested: bool,
+}
+
+pub fn prepare_tony_mc_mapface_lut(
+    mut lut: ResMut<TonyMcMapfaceLut>,
+    cameras: Query<&Tonemapping>,
+    luts: Option<Res<TonemappingLuts>>,
+    images: Res<Assets<Image>>,
+    mut enc: ResMut<CommandEncoder>,
+    mut restored: MessageReader<GlContextRestored>,
+) {
+    if !restored.is_empty() {
+        restored.clear();
+        // Upload it again, add_bevy_image_set_ref() points the TextureRef at the new texture.
+        lut.requested = false;
+    }
+    if lut.requested
+        || !cameras
+            .iter()
+            .any(|tonemapping| *tonemapping == Tonemapping::TonyMcMapface)
+    {
+        return;
+    }
+    let Some(luts) = luts else {
+        return;
+    };
+    let Some(source) = images.get(&luts.tony_mc_mapface) else {
+        return;
+    };
+    lut.requested = true;
+    if source.texture_descriptor.format != TextureFormat::Rgb9e5Ufloat || source.data.is_none() {
+        warn!("TonyMcMapface tonemapping needs bevy's tonemapping_luts feature, using AgX instead");
+        return;
+    }
+    let source = source.clone();
+    let texture_ref = lut.texture.clone();
+    enc.record(move |ctx, world| {
+        let image = tony_mc_mapface_lut_image(&source, ctx.has_texture_3d);
+        world.resource_mut::<GpuImages>().add_bevy_image_set_ref(
+            ctx,
+            None,
+            &TextureQualitySettings::default(),
+            &image,
+            &texture_ref,
+        );
+    });
+}
+
+/// Converts the Rgb9e5Ufloat LUT to gamma encoded Rgba8Unorm, TonyMcMapface's output is already in 0..1 so it can be
+/// used directly as the tonemapped color. If as_3d is false the slices are tiled left to right in a
+/// (width * depth) x height 2D texture, see tonemapping_tony_mc_mapface() in tonemapping.glsl.
+pub fn tony_mc_mapface_lut_image(source: &Image, as_3d: bool) -> Image {
+    let size = source.texture_descriptor.size;
+    let (width, height, depth) = (
+        size.width as usize,
+        size.height as usize,
+        size.depth_or_array_layers as usize,
+    );
+    let texels = bytemuck::cast_slice::<u8, u32>(source.data.as_deref().unwrap_or_default())
+        .iter()
+        .map(|c| {
+            let color = Vec3::from(rgb9e5_to_vec3(*c)).powf(1.0 / 2.2);
+            let color = (color.clamp(Vec3::ZERO, Vec3::ONE) * 255.0).round();
+            [color.x as u8, color.y as u8, color.z as u8, 255]
+        })
+        .collect::<Vec<_>>();
+
+    let (extent, dimension, texels) = if as_3d {
+        (size, TextureDimension::D3, texels)
+    } else {
+        let mut tiled = Vec::with_capacity(texels.len());
+        for y in 0..height {
+            for z in 0..depth {
+                let row = (z * height + y) * width;
+                tiled.extend_from_slice(&texels[row..row + width]);
+            }
+        }
+        let extent = Extent3d {
+            width: size.width * size.depth_or_array_layers,
+            height: size.height,
+            depth_or_array_layers: 1,
+        };
+        (extent, TextureDimension::D2, tiled)
+    };
+
+    let mut image = Image::new(
+        extent,
+        dimension,
+        texels.into_iter().flatten().collect(),
+        TextureFormat::Rgba8Unorm,
+        RenderAssetUsages::default(),
+    );
+    image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+        mag_filter: ImageFilterMode::Linear,
+        min_filter: ImageFilterMode::Linear,
+        ..default()
+    });
+    image
+}
@@ -1,11 +1,15 @@
 use std::{
-    path::Path,
+    path::{Path, PathBuf},
     sync::{
         Arc,
         atomic::{AtomicBool, Ordering},
     },
 };
 
+use bevy::{platform::collections::HashMap, prelude::*};
+
+use crate::BevyGlContext;
+
 #[derive(Default)]
 pub struct Watchers {
     has_changes: Arc<AtomicBool>,
@@ -18,41 +22,89 @@ impl Watchers {
         I: IntoIterator<Item = P>,
         P: AsRef<Path>,
     {
-        let has_changes = Arc::new(AtomicBool::new(false));
-        let _watchers = paths
-            .into_iter()
-            .map(|path| {
-                let watcher_has_changes = has_changes.clone();
-                let mut _watcher =
-                    notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
-                        let event =
-                            event.unwrap_or_else(|_| notify::Event::new(notify::EventKind::Any));
-                        if matches!(
-                            event.kind,
-                            notify::EventKind::Any
-                                | notify::EventKind::Modify(_)
-                                | notify::EventKind::Other
-                        ) {
-                            watcher_has_changes.store(true, Ordering::Relaxed);
-                        }
-                    })
-                    .unwrap();
-                notify::Watcher::watch(
-                    &mut _watcher,
-                    path.as_ref(),
-                    notify::RecursiveMode::NonRecursive,
-                )
-                .unwrap();
-                _watcher
-            })
-            .collect::<Vec<_>>();
-        Self {
-            has_changes,
-            _watchers,
+        let mut watchers = Self::default();
+        for path in paths {
+            watchers.add(path);
         }
+        watchers
+    }
+
+    /// Also watch path, changes to it are reported by the same check() as the others.
+    pub fn add(&mut self, path: impl AsRef<Path>) {
+        let watcher_has_changes = self.has_changes.clone();
+        let mut _watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = event.unwrap_or_else(|_| notify::Event::new(notify::EventKind::Any));
+                if matches!(
+                    event.kind,
+                    notify::EventKind::Any
+                        | notify::EventKind::Modify(_)
+                        | notify::EventKind::Other
+                ) {
+                    watcher_has_changes.store(true, Ordering::Relaxed);
+                }
+            })
+            .unwrap();
+        notify::Watcher::watch(
+            &mut _watcher,
+            path.as_ref(),
+            notify::RecursiveMode::NonRecursive,
+        )
+        .unwrap();
+        self._watchers.push(_watcher);
     }
 
     pub fn check(&self) -> bool {
         self.has_changes.swap(false, Ordering::Relaxed)
     }
+
+    /// Makes the next check() return true.
+    pub fn mark_changed(&self) {
+        self.has_changes.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Render world resource that reloads shader includes from disk when they change. Vertex and fragment files used with
+/// shader_cached() are already watched, this covers includes which are otherwise registered from strings embedded at
+/// compile time.
+#[derive(Resource, Default)]
+pub struct ShaderHotReload {
+    watchers: Watchers,
+    include_paths: HashMap<String, PathBuf>,
+}
+
+impl ShaderHotReload {
+    /// Reload the shader include name from path whenever path changes.
+    pub fn watch_include(&mut self, name: &str, path: impl AsRef<Path>) {
+        // Shaders are bundled into the binary, there's nothing on disk to watch.
+        if cfg!(any(target_arch = "wasm32", feature = "bundle_shaders")) {
+            return;
+        }
+        let path = path.as_ref().to_path_buf();
+        if self.include_paths.get(name) == Some(&path) {
+            return;
+        }
+        self.watchers.add(&path);
+        self.include_paths.insert(String::from(name), path);
+    }
+
+    /// If any watched include changed, re-reads them all and marks every cached shader to be recompiled the next time
+    /// it's used. If recompiling fails the last good program is kept.
+    pub fn reload_if_changed(&self, ctx: &mut BevyGlContext) {
+        if !self.watchers.check() {
+            return;
+        }
+        for (name, path) in &self.include_paths {
+            match std::fs::read_to_string(path) {
+                Ok(src) => {
+                    ctx.shader_includes.insert(name.clone(), src);
+                }
+                Err(e) => warn!("Couldn't reload shader include {name} from {path:?}: {e}"),
+            }
+        }
+        // Any shader could use these includes.
+        for (_, watchers) in ctx.shader_cache_map.values() {
+            watchers.mark_changed();
+        }
+    }
 }
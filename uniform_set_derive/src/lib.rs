@@ -1,9 +1,11 @@
-use quote::quote;
+use quote::{quote, quote_spanned};
 use syn::{
-    Attribute, Data, DeriveInput, Field, Fields, GenericArgument, LitStr, PathArguments, Type,
-    TypePath, parse_macro_input, spanned::Spanned,
+    Attribute, Data, DeriveInput, Field, Fields, GenericArgument, Index, LitStr, Member, Meta,
+    PathArguments, Type, TypePath, parse_macro_input, spanned::Spanned,
 };
 
+use std::collections::HashSet;
+
 use proc_macro::TokenStream;
 use proc_macro_crate::{FoundCrate, crate_name};
 use proc_macro2::Span;
@@ -19,95 +21,348 @@ fn bgl2_path() -> proc_macro2::TokenStream {
     }
 }
 
-#[proc_macro_derive(UniformSet, attributes(array_max, base_type, exclude, uniform_set))]
+/// `#[flatten]` or `#[flatten("prefix")]` embeds a field whose type implements UniformSet. Its uniforms keep their own
+/// names, with the optional prefix prepended. The flattened type can't be a generic parameter of the struct.
+///
+/// `#[uniform_name("u_baseColor")]` sets the GLSL name of a field, the struct prefix isn't added to it.
+///
+/// `#[array_max("N")] Vec<T>` where T implements UniformSet declares a GLSL struct named after T and binds
+/// `uniform T name[N];`, each element's fields are loaded individually.
+///
+/// `LinearRgba` and `Color` fields are bound as `vec4`. Color is converted to linear RGBA on upload.
+///
+/// `#[texture]` binds a field as a sampler even if its type isn't recognized as a texture, like a type alias or a
+/// newtype around `Handle<Image>`. The type must be Clone and convert into `bgl2::Tex`, otherwise it's a compile error
+/// at the field.
+#[proc_macro_derive(
+    UniformSet,
+    attributes(
+        array_max,
+        base_type,
+        exclude,
+        flatten,
+        texture,
+        uniform_name,
+        uniform_set
+    )
+)]
 pub fn derive_uniform_set(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
+    expand_uniform_set(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
 
+fn expand_uniform_set(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
     let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
 
     let prefix = parse_uniform_set_prefix(&input.attrs);
 
-    let fields = match &input.data {
+    // Tuple struct fields are named field0, field1, etc. in GLSL.
+    let fields: Vec<(Member, String, &Field)> = match &input.data {
         Data::Struct(s) => match &s.fields {
-            Fields::Named(named) => &named.named,
-            _ => {
-                return syn::Error::new(
-                    input.span(),
-                    "UniformSet derive only supports structs with named fields",
-                )
-                .to_compile_error()
-                .into();
+            Fields::Named(named) => named
+                .named
+                .iter()
+                .filter_map(|field| {
+                    let field_ident = field.ident.clone()?;
+                    let field_name = field_ident.to_string();
+                    Some((Member::Named(field_ident), field_name, field))
+                })
+                .collect(),
+            Fields::Unnamed(unnamed) => unnamed
+                .unnamed
+                .iter()
+                .enumerate()
+                .map(|(i, field)| (Member::Unnamed(Index::from(i)), format!("field{i}"), field))
+                .collect(),
+            Fields::Unit => {
+                return Err(syn::Error::new(
+                    ident.span(),
+                    format!(
+                        "UniformSet derive on unit struct `{ident}` has no uniforms, add named fields \
+                        (`struct {ident} {{ color: Vec4 }}`) or tuple fields (`struct {ident}(Vec4);`)"
+                    ),
+                ));
             }
         },
         _ => {
-            return syn::Error::new(input.span(), "UniformSet derive only supports structs")
-                .to_compile_error()
-                .into();
+            return Err(syn::Error::new(
+                ident.span(),
+                format!(
+                    "UniformSet derive only supports structs, `{ident}` is not a struct. Use a struct with named \
+                    fields, e.g. `struct {ident} {{ color: Vec4 }}`"
+                ),
+            ));
         }
     };
 
+    let crate_path = bgl2_path();
+    let uniform_set = quote! { #crate_path::UniformSet };
+
+    // Per uniform entries. A flattened field contributes all of its nested set's entries and an array of structs
+    // binding is built from the element's bindings, so these are statements that push to a Vec rather than array
+    // elements when there are any such fields.
     let mut name_entries = Vec::with_capacity(fields.len());
     let mut glsl_types = Vec::with_capacity(fields.len());
     let mut glsl_bindings = Vec::with_capacity(fields.len());
-
+    // Each load step handles index 0 or returns index minus the number of uniforms it covers.
+    let mut load_steps = Vec::with_capacity(fields.len());
     let mut load_arms = Vec::with_capacity(fields.len());
+    // Same as the load steps and arms but for UniformSet::struct_array_fields()
+    let mut struct_array_steps = Vec::with_capacity(fields.len());
+    let mut struct_array_arms = Vec::new();
+    let mut uniform_names = HashSet::new();
+    let included = |field: &Field| !has_attr(&field.attrs, "exclude");
+    let has_flatten = fields
+        .iter()
+        .any(|(_, _, field)| included(field) && has_attr(&field.attrs, "flatten"));
+    let has_struct_array = fields
+        .iter()
+        .any(|(_, _, field)| included(field) && struct_array_element(field).is_some());
+    let dynamic = has_flatten || has_struct_array;
+
+    for (member, field_name, field) in &fields {
+        if has_attr(&field.attrs, "exclude") {
+            continue;
+        }
 
-    let crate_path = bgl2_path();
-
-    for (i, field) in fields.iter().enumerate() {
-        let Some(field_ident) = &field.ident else {
+        if has_attr(&field.attrs, "flatten") {
+            let ty = &field.ty;
+            let flatten_prefix = parse_attr_str_optional(&field.attrs, "flatten")?
+                .map(|v| v.value())
+                .unwrap_or_default();
+            name_entries.push(quote! {
+                v.extend(#crate_path::prefix_uniform_names(#flatten_prefix, <#ty as #uniform_set>::names()))
+            });
+            glsl_bindings.push(quote! {
+                v.extend(#crate_path::prefix_uniform_bindings(
+                    #flatten_prefix,
+                    <#ty as #uniform_set>::names(),
+                    <#ty as #uniform_set>::bindings(),
+                ))
+            });
+            glsl_types.push(quote! {
+                v.extend_from_slice(<#ty as #uniform_set>::glsl_types())
+            });
+            load_steps.push(quote! {
+                let len = <#ty as #uniform_set>::names().len() as u32;
+                if index < len {
+                    return #uniform_set::load(&self.#member, gl, gpu_images, index, slot, temp);
+                }
+                let index = index - len;
+            });
+            struct_array_steps.push(quote! {
+                let len = <#ty as #uniform_set>::names().len() as u32;
+                if index < len {
+                    return <#ty as #uniform_set>::struct_array_fields(index);
+                }
+                let index = index - len;
+            });
             continue;
+        }
+
+        // An explicit #[uniform_name("...")] is used as is, without the struct prefix.
+        let uniform_name = match parse_attr_str(&field.attrs, "uniform_name") {
+            Some(lit) => lit.value(),
+            None => format!("{prefix}{field_name}"),
         };
-        if has_attr(&field.attrs, "exclude") {
-            continue;
+        if !uniform_names.insert(uniform_name.clone()) {
+            return Err(syn::Error::new(
+                field.span(),
+                format!("duplicate uniform name {uniform_name:?} in UniformSet `{ident}`"),
+            ));
         }
-        let field_name = field_ident.to_string();
-        let uniform_name = format!("{prefix}{field_name}");
 
-        let is_tex = is_glow_texture(&field.ty)
+        let is_sampled = is_sampled_image(&field.ty);
+        let is_known_tex = is_glow_texture(&field.ty)
             | is_texture_ref(&field.ty)
             | is_handle_image(&field.ty)
-            | is_option_handle_image(&field.ty);
-        name_entries.push(quote! { #uniform_name });
+            | is_option_handle_image(&field.ty)
+            | is_sampled;
+        let is_forced_tex = !is_known_tex && has_attr(&field.attrs, "texture");
+        let is_tex = is_known_tex | is_forced_tex;
+
+        // Index into names(), excluded fields don't have a slot.
+        let idx = load_arms.len() as u32;
+
+        if let Some(element_ty) = struct_array_element(field) {
+            let struct_name = type_name(element_ty);
+            let arr_max = parse_attr_str(&field.attrs, "array_max")
+                .ok_or_else(|| {
+                    syn::Error::new(
+                        field.span(),
+                        format!("Vec field {uniform_name:?} is missing array_max()"),
+                    )
+                })?
+                .value();
+            name_entries.push(quote! { v.push(#uniform_name) });
+            glsl_bindings.push(quote! {
+                v.push(#crate_path::struct_array_binding(
+                    #struct_name,
+                    #uniform_name,
+                    #arr_max,
+                    <#element_ty as #uniform_set>::bindings(),
+                ))
+            });
+            glsl_types.push(quote! { v.push(#struct_name) });
+
+            let load = quote! {
+                #crate_path::load_struct_array_if_new(&self.#member, gl, gpu_images, slot, temp)
+            };
+            load_arms.push(quote! { #idx => #load });
+            load_steps.push(quote! {
+                if index == 0 {
+                    return #load;
+                }
+                let index = index - 1;
+            });
+
+            let fields = quote! {
+                Some((<#element_ty as #uniform_set>::names(), <#element_ty as #uniform_set>::glsl_types()))
+            };
+            struct_array_arms.push(quote! { #idx => #fields });
+            struct_array_steps.push(quote! {
+                if index == 0 {
+                    return #fields;
+                }
+                let index = index - 1;
+            });
+            continue;
+        }
 
-        let binding = get_glsl_binding(&field, &field_name, &prefix, is_tex);
-        glsl_bindings.push(quote! { #binding });
+        let binding = get_glsl_binding(field, &uniform_name, is_tex);
         let field_gl_type = get_gl_type(field, is_tex);
-        glsl_types.push(quote! { #field_gl_type });
 
-        let idx = i as u32;
+        let load = if is_sampled {
+            quote! {
+                #crate_path::load_sampled_tex_if_new(
+                    &self.#member.image.clone().into(),
+                    self.#member.sampler,
+                    gl,
+                    gpu_images,
+                    slot,
+                )
+            }
+        } else if is_forced_tex {
+            // Spanned so a type that doesn't convert into Tex is reported at the field.
+            let ty = &field.ty;
+            quote_spanned! {ty.span()=>
+                #crate_path::load_tex_if_new(
+                    &#crate_path::texture_field_to_tex::<#ty>(&self.#member),
+                    gl,
+                    gpu_images,
+                    slot,
+                )
+            }
+        } else if is_tex {
+            quote! {
+                #crate_path::load_tex_if_new(&self.#member.clone().into(), gl, gpu_images, slot)
+            }
+        } else {
+            quote! {
+                #crate_path::load_if_new(&self.#member, gl, slot, temp)
+            }
+        };
 
-        if is_tex {
-            load_arms.push(quote! {
-                #idx => {
-                    #crate_path::load_tex_if_new(&self.#field_ident.clone().into(), gl, gpu_images, slot);
-                }
-            });
+        load_arms.push(quote! { #idx => #load });
+        load_steps.push(quote! {
+            if index == 0 {
+                return #load;
+            }
+            let index = index - 1;
+        });
+        struct_array_steps.push(quote! {
+            if index == 0 {
+                return None;
+            }
+            let index = index - 1;
+        });
+
+        if dynamic {
+            name_entries.push(quote! { v.push(#uniform_name) });
+            glsl_bindings.push(quote! { v.push(#binding) });
+            glsl_types.push(quote! { v.push(#field_gl_type) });
         } else {
-            load_arms.push(quote! {
-                #idx => #crate_path::load_if_new(&self.#field_ident, gl, slot, temp)
-            });
+            name_entries.push(quote! { #uniform_name });
+            glsl_bindings.push(quote! { #binding });
+            glsl_types.push(quote! { #field_gl_type });
         }
     }
 
-    let expanded = quote! {
-        impl #crate_path::UniformSet for #ident {
-            fn names() -> &'static [&'static str] {
+    // The nested set's lengths and bindings aren't known at compile time so the entries are collected once on first
+    // use.
+    let static_entries = |static_ident: &str, entries: &[proc_macro2::TokenStream]| {
+        if dynamic {
+            let static_ident = Ident::new(static_ident, Span::call_site());
+            quote! {
+                static #static_ident: ::std::sync::LazyLock<Vec<&'static str>> = ::std::sync::LazyLock::new(|| {
+                    let mut v = Vec::new();
+                    #(#entries;)*
+                    v
+                });
+                &#static_ident
+            }
+        } else {
+            quote! {
                 &[
-                    #(#name_entries,)*
+                    #(#entries,)*
                 ]
             }
+        }
+    };
+    let names = static_entries("NAMES", &name_entries);
+    let bindings = static_entries("BINDINGS", &glsl_bindings);
+    let glsl_types = static_entries("GLSL_TYPES", &glsl_types);
+
+    let load = if has_flatten {
+        quote! {
+            #(#load_steps)*
+            unreachable!("uniform index {index} out of range")
+        }
+    } else {
+        quote! {
+            match index {
+                #(#load_arms,)*
+                _ => unreachable!(),
+            }
+        }
+    };
+
+    let struct_array_fields = if has_flatten {
+        quote! {
+            fn struct_array_fields(index: u32) -> Option<(&'static [&'static str], &'static [&'static str])> {
+                #(#struct_array_steps)*
+                let _ = index;
+                None
+            }
+        }
+    } else if has_struct_array {
+        quote! {
+            fn struct_array_fields(index: u32) -> Option<(&'static [&'static str], &'static [&'static str])> {
+                match index {
+                    #(#struct_array_arms,)*
+                    _ => None,
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    Ok(quote! {
+        impl #impl_generics #uniform_set for #ident #ty_generics #where_clause {
+            fn names() -> &'static [&'static str] {
+                #names
+            }
 
             fn bindings() -> &'static [&'static str] {
-                &[
-                    #(#glsl_bindings,)*
-                ]
+                #bindings
             }
 
             fn glsl_types() -> &'static [&'static str] {
-                &[
-                    #(#glsl_types,)*
-                ]
+                #glsl_types
             }
 
             fn load(
@@ -118,15 +373,12 @@ pub fn derive_uniform_set(input: TokenStream) -> TokenStream {
                 slot: &mut #crate_path::SlotData,
                 temp: &mut #crate_path::faststack::StackStack<u32, 16>,
             ) {
-                match index {
-                    #(#load_arms,)*
-                    _ => unreachable!(),
-                }
+                #load
             }
-        }
-    };
 
-    expanded.into()
+            #struct_array_fields
+        }
+    })
 }
 
 fn as_type_path(ty: &Type) -> Option<&TypePath> {
@@ -156,21 +408,33 @@ fn is_texture_ref(ty: &Type) -> bool {
     last.ident == "TextureRef"
 }
 
-fn get_glsl_binding(field: &Field, field_name: &str, prefix: &str, texture: bool) -> String {
+fn is_sampled_image(ty: &Type) -> bool {
+    let Some(tp) = as_type_path(ty) else {
+        return false;
+    };
+    let Some(last) = &tp.path.segments.last() else {
+        return false;
+    };
+    last.ident == "SampledImage"
+}
+
+fn get_glsl_binding(field: &Field, uniform_name: &str, texture: bool) -> String {
     let ty = &field.ty;
 
     let gl_ty = get_gl_type(field, texture);
 
     let arr_max = if vec_of(ty).is_some() {
         let arr_max = parse_attr_str(&field.attrs, "array_max")
-            .expect(&format!("Vec field {field_name:?} is missing array_max()"))
+            .expect(&format!(
+                "Vec field {uniform_name:?} is missing array_max()"
+            ))
             .value();
         format!("[{arr_max}]")
     } else {
         String::from("")
     };
 
-    format!("uniform {gl_ty} {prefix}{field_name}{arr_max};")
+    format!("uniform {gl_ty} {uniform_name}{arr_max};")
 }
 
 fn get_gl_type(field: &Field, texture: bool) -> String {
@@ -197,26 +461,65 @@ fn get_gl_type(field: &Field, texture: bool) -> String {
         if texture {
             "sampler2D"
         } else {
-            match base_ty {
-                "f32" => "float",
-                "Vec2" => "vec2",
-                "Vec3" => "vec3",
-                "Vec4" => "vec4",
-                "i32" => "int",
-                "IVec2" => "ivec2",
-                "IVec3" => "ivec3",
-                "IVec4" => "ivec4",
-                "Mat2" => "mat2",
-                "Mat3" => "mat3",
-                "Mat4" => "mat4",
-                "bool" => "bool",
-                _ => panic!("unrecognized type {base_ty}"),
-            }
+            glsl_primitive(base_ty).unwrap_or_else(|| panic!("unrecognized type {base_ty}"))
         }
     };
     String::from(gl_ty)
 }
 
+/// The element type of a `Vec<T>` field where T isn't a GLSL primitive, T is expected to implement UniformSet.
+fn struct_array_element(field: &Field) -> Option<&Type> {
+    if parse_attr_str(&field.attrs, "base_type").is_some() {
+        return None;
+    }
+    let tp = as_type_path(&field.ty)?;
+    let last = tp.path.segments.last()?;
+    if last.ident != "Vec" {
+        return None;
+    }
+    let PathArguments::AngleBracketed(args) = &last.arguments else {
+        return None;
+    };
+    let Some(GenericArgument::Type(element_ty)) = args.args.first() else {
+        return None;
+    };
+    if glsl_primitive(&type_name(element_ty)).is_some() {
+        return None;
+    }
+    Some(element_ty)
+}
+
+fn type_name(ty: &Type) -> String {
+    as_type_path(ty)
+        .and_then(|tp| tp.path.segments.last())
+        .map(|last| last.ident.to_string())
+        .unwrap_or_default()
+}
+
+fn glsl_primitive(ty_str: &str) -> Option<&'static str> {
+    Some(match ty_str {
+        "f32" => "float",
+        "Vec2" => "vec2",
+        "Vec3" => "vec3",
+        "Vec4" => "vec4",
+        // Converted to linear on the CPU when uploaded, see the UniformValue impls.
+        "LinearRgba" | "Color" => "vec4",
+        "i32" => "int",
+        "IVec2" => "ivec2",
+        "IVec3" => "ivec3",
+        "IVec4" => "ivec4",
+        "u32" => "uint",
+        "UVec2" => "uvec2",
+        "UVec3" => "uvec3",
+        "UVec4" => "uvec4",
+        "Mat2" => "mat2",
+        "Mat3" => "mat3",
+        "Mat4" => "mat4",
+        "bool" => "bool",
+        _ => return None,
+    })
+}
+
 fn is_handle_image(ty: &Type) -> bool {
     let Some(tp) = as_type_path(ty) else {
         return false;
@@ -302,6 +605,19 @@ fn parse_attr_str(attrs: &[Attribute], ident: &str) -> Option<LitStr> {
     None
 }
 
+/// Like parse_attr_str() but also accepts the attribute without arguments, e.g. both #[flatten] and #[flatten("v_")]
+fn parse_attr_str_optional(attrs: &[Attribute], ident: &str) -> syn::Result<Option<LitStr>> {
+    for attr in attrs {
+        if attr.path().is_ident(ident) {
+            if let Meta::Path(_) = attr.meta {
+                return Ok(None);
+            }
+            return attr.parse_args().map(Some);
+        }
+    }
+    Ok(None)
+}
+
 fn has_attr(attrs: &[Attribute], ident: &str) -> bool {
     for attr in attrs {
         if attr.path().is_ident(ident) {
@@ -331,3 +647,185 @@ fn parse_uniform_set_prefix(attrs: &[Attribute]) -> String {
     }
     return prefix;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exclude_load_index() {
+        let input: DeriveInput = syn::parse_quote! {
+            #[uniform_set(prefix = "ub_")]
+            struct Lighting {
+                depth_bias: f32,
+                #[exclude]
+                filter: ShadowFilter,
+                light_count: i32,
+            }
+        };
+        let expanded = expand_uniform_set(&input).unwrap().to_string();
+        // light_count is names()[1], so load() has to take it at index 1.
+        assert!(expanded.contains("1u32 => :: bgl2 :: load_if_new (& self . light_count"));
+        assert!(!expanded.contains("2u32 =>"));
+    }
+
+    #[test]
+    fn test_tuple_struct_field_names() {
+        let input: DeriveInput = syn::parse_quote! {
+            #[uniform_set(prefix = "ub_")]
+            struct Tint(Vec4, #[exclude] bool, f32);
+        };
+        let expanded = expand_uniform_set(&input).unwrap().to_string();
+        assert!(expanded.contains("\"ub_field0\""));
+        assert!(expanded.contains("\"uniform vec4 ub_field0;\""));
+        assert!(expanded.contains("\"uniform float ub_field2;\""));
+        assert!(!expanded.contains("ub_field1"));
+        assert!(expanded.contains("self . 2"));
+    }
+
+    #[test]
+    fn test_uint_types() {
+        let input: DeriveInput = syn::parse_quote! {
+            #[uniform_set(prefix = "ub_")]
+            struct Flags {
+                flags: u32,
+                ids: UVec4,
+            }
+        };
+        let expanded = expand_uniform_set(&input).unwrap().to_string();
+        assert!(expanded.contains("\"uniform uint ub_flags;\""));
+        assert!(expanded.contains("\"uniform uvec4 ub_ids;\""));
+    }
+
+    #[test]
+    fn test_color_types() {
+        let input: DeriveInput = syn::parse_quote! {
+            #[uniform_set(prefix = "ub_")]
+            struct Tinted {
+                tint: LinearRgba,
+                fog_color: Color,
+            }
+        };
+        let expanded = expand_uniform_set(&input).unwrap().to_string();
+        assert!(expanded.contains("\"uniform vec4 ub_tint;\""));
+        assert!(expanded.contains("\"uniform vec4 ub_fog_color;\""));
+    }
+
+    #[test]
+    fn test_sampled_image() {
+        let input: DeriveInput = syn::parse_quote! {
+            #[uniform_set(prefix = "ub_")]
+            struct Sprite {
+                sheet: SampledImage,
+            }
+        };
+        let expanded = expand_uniform_set(&input).unwrap().to_string();
+        assert!(expanded.contains("\"uniform sampler2D ub_sheet;\""));
+        assert!(expanded.contains("load_sampled_tex_if_new"));
+        assert!(expanded.contains("self . sheet . sampler"));
+    }
+
+    #[test]
+    fn test_texture_attr() {
+        let input: DeriveInput = syn::parse_quote! {
+            #[uniform_set(prefix = "ub_")]
+            struct Wrapped {
+                #[texture]
+                albedo: AlbedoTexture,
+                #[texture]
+                #[base_type("samplerCube")]
+                sky: SkyHandle,
+            }
+        };
+        let expanded = expand_uniform_set(&input).unwrap().to_string();
+        assert!(expanded.contains("\"uniform sampler2D ub_albedo;\""));
+        assert!(expanded.contains("\"uniform samplerCube ub_sky;\""));
+        assert!(expanded.contains("texture_field_to_tex :: < AlbedoTexture >"));
+    }
+
+    #[test]
+    fn test_flatten() {
+        let input: DeriveInput = syn::parse_quote! {
+            #[uniform_set(prefix = "ub_")]
+            struct Material {
+                base_color: Vec4,
+                #[flatten("reflect_")]
+                view: ViewUniforms,
+                roughness: f32,
+            }
+        };
+        let expanded = expand_uniform_set(&input).unwrap().to_string();
+        assert!(expanded.contains("prefix_uniform_names (\"reflect_\" , < ViewUniforms as"));
+        assert!(expanded.contains("v . push (\"ub_base_color\")"));
+        assert!(expanded.contains("v . push (\"uniform float ub_roughness;\")"));
+        assert!(expanded.contains("LazyLock"));
+    }
+
+    #[test]
+    fn test_uniform_name() {
+        let input: DeriveInput = syn::parse_quote! {
+            #[uniform_set(prefix = "ub_")]
+            struct Ported {
+                #[uniform_name("u_baseColor")]
+                base_color: Vec4,
+                #[uniform_name("u_lights")]
+                #[array_max("MAX_LIGHTS")]
+                lights: Vec<Vec4>,
+                roughness: f32,
+            }
+        };
+        let expanded = expand_uniform_set(&input).unwrap().to_string();
+        assert!(expanded.contains("\"u_baseColor\""));
+        assert!(expanded.contains("\"uniform vec4 u_baseColor;\""));
+        assert!(expanded.contains("\"uniform vec4 u_lights[MAX_LIGHTS];\""));
+        assert!(expanded.contains("\"uniform float ub_roughness;\""));
+        assert!(expanded.contains("self . base_color"));
+    }
+
+    #[test]
+    fn test_duplicate_uniform_name_error() {
+        let input: DeriveInput = syn::parse_quote! {
+            #[uniform_set(prefix = "ub_")]
+            struct Ported {
+                #[uniform_name("ub_roughness")]
+                base_color: Vec4,
+                roughness: f32,
+            }
+        };
+        let err = expand_uniform_set(&input).unwrap_err().to_string();
+        assert!(err.contains("duplicate uniform name \"ub_roughness\""));
+    }
+
+    #[test]
+    fn test_struct_array() {
+        let input: DeriveInput = syn::parse_quote! {
+            #[uniform_set(prefix = "ub_")]
+            struct Lighting {
+                #[array_max("MAX_POINT_LIGHTS")]
+                point_lights: Vec<PointLightUniforms>,
+                #[array_max("MAX_POINT_LIGHTS")]
+                point_light_radius: Vec<f32>,
+            }
+        };
+        let expanded = expand_uniform_set(&input).unwrap().to_string();
+        assert!(expanded.contains(
+            "struct_array_binding (\"PointLightUniforms\" , \"ub_point_lights\" , \"MAX_POINT_LIGHTS\""
+        ));
+        assert!(
+            expanded
+                .contains("v . push (\"uniform float ub_point_light_radius[MAX_POINT_LIGHTS];\")")
+        );
+        assert!(expanded.contains("load_struct_array_if_new (& self . point_lights"));
+        assert!(expanded.contains("fn struct_array_fields"));
+    }
+
+    #[test]
+    fn test_unit_struct_error() {
+        let input: DeriveInput = syn::parse_quote! {
+            struct Empty;
+        };
+        let err = expand_uniform_set(&input).unwrap_err().to_string();
+        assert!(err.contains("unit struct `Empty`"));
+        assert!(err.contains("add named fields"));
+    }
+}